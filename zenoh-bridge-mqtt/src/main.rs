@@ -13,8 +13,20 @@
 //
 use clap::{App, Arg};
 use std::str::FromStr;
+use std::time::Duration;
 use zenoh::config::{Config, ModeDependentValue};
+use zenoh::prelude::r#async::AsyncResolve;
 use zenoh::prelude::*;
+use zenoh::subscriber::Subscriber;
+
+mod selftest;
+mod supervisor;
+
+const CONFIG_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+// the exit code when the MQTT configuration changes (see --config-key)
+const CONFIG_CHANGE_EXIT_CODE: i32 = 3;
+// the log filter if neither RUST_LOG nor the 'logging' setting set one
+const DEFAULT_LOG_FILTER: &str = "z=info";
 
 macro_rules! insert_json5 {
     ($config: expr, $args: expr, $key: expr, if $name: expr) => {
@@ -34,7 +46,7 @@ macro_rules! insert_json5 {
     };
 }
 
-fn parse_args() -> Config {
+fn parse_args() -> (Config, Option<String>, bool, Option<selftest::Mode>, bool) {
     let app = App::new("zenoh bridge for MQTT")
         .version(zenoh_plugin_mqtt::GIT_VERSION)
         .long_version(zenoh_plugin_mqtt::LONG_VERSION.as_str())
@@ -57,6 +69,11 @@ r"-c, --config=[FILE] \
 'The configuration file. Currently, this file must be a valid JSON5 file.'",
             ))
         .arg(Arg::from_usage(
+r"--config-key=[KEY_EXPR] \
+'A zenoh key expression where to fetch the MQTT configuration (as JSON) at startup, overriding the one from the configuration file.
+The bridge also subscribes to this key expression and exits on configuration change, to be restarted with the new configuration.'",
+            ))
+        .arg(Arg::from_usage(
 r"-l, --listen=[ENDPOINT]... \
 'A locator on which this router will listen for incoming sessions.
 Repeat this option to open several listeners.'",
@@ -104,6 +121,19 @@ r#"-r, --generalise-sub=[String]...   'A list of key expression to use for gener
         .arg(Arg::from_usage(
 r#"-w, --generalise-pub=[String]...   'A list of key expression to use for generalising publications (usable multiple times).'"#
         ));
+    let app = app.arg(Arg::from_usage(
+r#"--self-test   'Run a battery of MQTT 3.1.1 and 5 conformance checks against the bridge started with a default configuration on a local port, print a report and exit (with code 1 if a check failed).'"#
+        ));
+    let app = app.arg(Arg::from_usage(
+r#"--check-config   'Validate the MQTT configuration (from the configuration file and the arguments) without starting the bridge, print the issues found as JSON and exit (with code 1 if an error was found).'"#
+        ));
+    let app = app.arg(Arg::from_usage(
+r#"--bench-fanout=[SUBSCRIBERS]   'Measure the fan-out throughput of the bridge started with a default configuration on a local port: this number of MQTT clients subscribe to a same topic on which 1000 publications are made. Print the aggregate delivery rate and exit. The MQTT configuration (from the configuration file) applies, except the port.'"#
+        ));
+    #[cfg(windows)]
+    let app = app.arg(Arg::from_usage(
+r#"--windows-service   'Run as a Windows service (registered with the name "zenoh-bridge-mqtt"), reporting its status to the Service Control Manager.'"#
+        ));
     let args = app.get_matches();
 
     // load config file at first
@@ -162,28 +192,247 @@ r#"-w, --generalise-pub=[String]...   'A list of key expression to use for gener
     insert_json5!(config, args, "plugins/mqtt/deny", if "deny", );
     insert_json5!(config, args, "plugins/mqtt/generalise_pubs", for "generalise-pub", .collect::<Vec<_>>());
     insert_json5!(config, args, "plugins/mqtt/generalise_subs", for "generalise-sub", .collect::<Vec<_>>());
-    config
+    let self_test = match args.value_of("bench-fanout") {
+        Some(subscribers) => {
+            Some(selftest::Mode::FanoutBenchmark(subscribers.parse().expect(
+                "--bench-fanout value must be a number of subscribers",
+            )))
+        }
+        None => args
+            .is_present("self-test")
+            .then_some(selftest::Mode::Conformance),
+    };
+    (
+        config,
+        args.value_of("config-key").map(String::from),
+        cfg!(windows) && args.is_present("windows-service"),
+        self_test,
+        args.is_present("check-config"),
+    )
+}
+
+// Validate the MQTT configuration, print the issues found and exit
+fn check_config(config: &Config) -> ! {
+    let mqtt_config = config
+        .plugin("mqtt")
+        .cloned()
+        .unwrap_or_else(|| serde_json::json!({}));
+    let diagnostics = zenoh_plugin_mqtt::validation::validate(&mqtt_config);
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&diagnostics).unwrap_or_default()
+    );
+    let failed = diagnostics
+        .iter()
+        .any(|d| d.severity == zenoh_plugin_mqtt::validation::Severity::Error);
+    std::process::exit(if failed { 1 } else { 0 });
+}
+
+// Fetch the MQTT configuration from zenoh, and exit on configuration change
+// (the returned subscriber must be kept alive)
+async fn provision_config(
+    runtime: &zenoh::runtime::Runtime,
+    config_key: &str,
+) -> Option<Subscriber<'static, ()>> {
+    let session = match zenoh::init(runtime.clone()).res().await {
+        Ok(session) => session.into_arc(),
+        Err(e) => {
+            log::error!(
+                "Failed to fetch MQTT configuration on {}: {}",
+                config_key,
+                e
+            );
+            return None;
+        }
+    };
+    let fetched = match session
+        .get(config_key)
+        .timeout(CONFIG_FETCH_TIMEOUT)
+        .res()
+        .await
+    {
+        Ok(replies) => match replies.recv_async().await.map(|r| r.sample) {
+            Ok(Ok(sample)) => {
+                Some(String::from_utf8_lossy(&sample.payload.contiguous()).into_owned())
+            }
+            Ok(Err(e)) => {
+                log::warn!("Error fetching MQTT configuration on {}: {}", config_key, e);
+                None
+            }
+            Err(_) => {
+                log::warn!(
+                    "No MQTT configuration found on {} - use the local one",
+                    config_key
+                );
+                None
+            }
+        },
+        Err(e) => {
+            log::warn!(
+                "Failed to fetch MQTT configuration on {}: {}",
+                config_key,
+                e
+            );
+            None
+        }
+    };
+    if let Some(json) = &fetched {
+        match serde_json::from_str::<zenoh_plugin_mqtt::config::Config>(json) {
+            Ok(_) => {
+                log::info!("Use MQTT configuration fetched from {}", config_key);
+                if let Err(e) = runtime.config.lock().insert_json5("plugins/mqtt", json) {
+                    log::error!(
+                        "Invalid MQTT configuration fetched from {}: {}",
+                        config_key,
+                        e
+                    );
+                }
+            }
+            Err(e) => log::error!(
+                "Invalid MQTT configuration fetched from {}: {}",
+                config_key,
+                e
+            ),
+        }
+    }
+
+    // The MQTT plugin doesn't support hot configuration changes: on change, exit to be restarted by a supervisor
+    let key = config_key.to_string();
+    let subscriber = session
+        .declare_subscriber(config_key)
+        .callback(move |sample| {
+            let json = String::from_utf8_lossy(&sample.payload.contiguous()).into_owned();
+            if fetched.as_ref() == Some(&json) {
+                return;
+            }
+            match serde_json::from_str::<zenoh_plugin_mqtt::config::Config>(&json) {
+                Ok(_) => {
+                    log::warn!(
+                        "MQTT configuration changed on {} - exit to be restarted",
+                        key
+                    );
+                    std::process::exit(CONFIG_CHANGE_EXIT_CODE);
+                }
+                Err(e) => log::error!(
+                    "Ignore invalid MQTT configuration received on {}: {}",
+                    key,
+                    e
+                ),
+            }
+        })
+        .res()
+        .await;
+    match subscriber {
+        Ok(subscriber) => Some(subscriber),
+        Err(e) => {
+            log::error!(
+                "Failed to subscribe to MQTT configuration changes on {}: {}",
+                config_key,
+                e
+            );
+            None
+        }
+    }
+}
+
+// Install the logger according to the 'logging' setting of the MQTT plugin, if any
+fn init_logging(config: &Config) {
+    use zenoh_plugin_mqtt::{config::LoggingConfig, logging};
+    let logging = config
+        .plugin("mqtt")
+        .and_then(|mqtt| mqtt.get("logging"))
+        .map(|conf| serde_json::from_value::<LoggingConfig>(conf.clone()))
+        .transpose();
+    match logging {
+        Ok(Some(conf)) => {
+            logging::init_with(&conf, DEFAULT_LOG_FILTER);
+        }
+        Ok(None) => {
+            logging::init(DEFAULT_LOG_FILTER);
+        }
+        Err(e) => {
+            logging::init(DEFAULT_LOG_FILTER);
+            log::warn!("Invalid 'logging' configuration: {} - log to stderr", e);
+        }
+    }
 }
 
 #[async_std::main]
 async fn main() {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("z=info")).init();
+    let (mut config, config_key, windows_service, self_test, check) = parse_args();
+    init_logging(&config);
     log::info!("zenoh-bridge-mqtt {}", *zenoh_plugin_mqtt::LONG_VERSION);
-
-    let config = parse_args();
+    if check {
+        check_config(&config);
+    }
+    let self_test_addr = self_test
+        .as_ref()
+        .map(|mode| selftest::configure(&mut config, mode));
+    let config_key = config_key.filter(|_| self_test.is_none());
     let rest_plugin = config.plugin("rest").is_some();
 
     // create a zenoh Runtime (to share with plugins)
     let runtime = zenoh::runtime::Runtime::new(config).await.unwrap();
 
+    // fetch the MQTT configuration from zenoh if required
+    let _config_subscriber = match config_key {
+        Some(config_key) => provision_config(&runtime, &config_key).await,
+        None => None,
+    };
+
     // start REST plugin
     if rest_plugin {
         use zenoh_plugin_trait::Plugin;
         zenoh_plugin_rest::RestPlugin::start("rest", &runtime).unwrap();
     }
 
+    // on SIGTERM/SIGINT, drain the MQTT connections before exit (a 2nd signal forces the exit)
+    #[cfg(unix)]
+    {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        let mut signals = signal_hook::iterator::Signals::new([SIGTERM, SIGINT]).unwrap();
+        std::thread::spawn(move || {
+            let mut signals = signals.forever();
+            if let Some(signal) = signals.next() {
+                log::info!(
+                    "Received signal {} - drain MQTT connections before exit",
+                    signal
+                );
+                zenoh_plugin_mqtt::request_drain();
+            }
+            if signals.next().is_some() {
+                log::warn!("Received signal again - exit without draining");
+                std::process::exit(1);
+            }
+        });
+    }
+
+    // report the bridge status to the process supervisor (systemd or Windows Service Control Manager)
+    if cfg!(not(windows)) || windows_service {
+        supervisor::start();
+    }
+
     // start MQTT plugin
     use zenoh_plugin_trait::Plugin;
     zenoh_plugin_mqtt::MqttPlugin::start("mqtt", &runtime).unwrap();
-    async_std::future::pending::<()>().await;
+    // exit if the MQTT plugin fails to start (the error being logged by the plugin)
+    async_std::task::spawn(async {
+        if zenoh_plugin_mqtt::ready().await.is_err() {
+            std::process::exit(1);
+        }
+    });
+
+    // in self-test mode, run the checks (or the benchmark) once the bridge is ready and exit
+    if let (Some(mode), Some(addr)) = (self_test, self_test_addr) {
+        if zenoh_plugin_mqtt::ready().await.is_err() {
+            std::process::exit(1);
+        }
+        let passed = async_std::task::spawn_blocking(move || selftest::run(mode, addr)).await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    // run until the MQTT server is drained (on signal, via the admin space or on service stop)
+    zenoh_plugin_mqtt::drained().await;
+    supervisor::stopping();
+    log::info!("Exit after MQTT server draining");
 }
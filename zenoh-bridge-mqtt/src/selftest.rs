@@ -0,0 +1,531 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The conformance self-test (--self-test option): the bridge is started with a default configuration on a
+// local port, and a battery of MQTT 3.1.1 and 5 checks run against it with a minimal MQTT client written
+// over a plain TCP stream (so the checks don't depend on the MQTT library used by the bridge).
+// The same client is used by the fan-out benchmark (--bench-fanout option).
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+use zenoh::config::Config;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+// the time to wait for a publication that must not be received
+const NOT_RECEIVED_TIMEOUT: Duration = Duration::from_millis(500);
+
+// the number and size of the publications of the fan-out benchmark
+const BENCH_MESSAGES: usize = 1000;
+const BENCH_PAYLOAD_SIZE: usize = 64;
+
+const MQTT_V3: u8 = 4;
+const MQTT_V5: u8 = 5;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const UNSUBSCRIBE: u8 = 0xA2;
+const UNSUBACK: u8 = 0xB0;
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+const DISCONNECT: u8 = 0xE0;
+
+pub(crate) enum Mode {
+    // the conformance checks
+    Conformance,
+    // the fan-out benchmark, with a number of subscribers
+    FanoutBenchmark(usize),
+}
+
+// Configure the bridge for the self-test: a MQTT server on a free local port, and a zenoh session isolated
+// from the other zenoh applications. The conformance checks use a default MQTT configuration, while the
+// benchmark keeps the configured one (e.g. to measure the effect of a setting).
+pub(crate) fn configure(config: &mut Config, mode: &Mode) -> SocketAddr {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .expect("no free local port for the MQTT self-test");
+    match mode {
+        Mode::FanoutBenchmark(_) if config.plugin("mqtt").is_some() => config
+            .insert_json5("plugins/mqtt/port", &format!(r#""{addr}""#))
+            .unwrap(),
+        _ => config
+            .insert_json5("plugins/mqtt", &format!(r#"{{"port": "{addr}"}}"#))
+            .unwrap(),
+    }
+    config.connect.endpoints.clear();
+    config.listen.endpoints.clear();
+    config.scouting.multicast.set_enabled(Some(false)).unwrap();
+    addr
+}
+
+// Run the self-test against the bridge and print the report. Returns true if it succeeded.
+pub(crate) fn run(mode: Mode, addr: SocketAddr) -> bool {
+    match mode {
+        Mode::Conformance => run_checks(addr),
+        Mode::FanoutBenchmark(subscribers) => bench_fanout(addr, subscribers),
+    }
+}
+
+// Run the checks against the bridge and print the report. Returns true if all the checks passed.
+fn run_checks(addr: SocketAddr) -> bool {
+    let prefix = format!("selftest/{}", std::process::id());
+    let checks: Vec<(&str, Box<dyn Fn() -> Result<(), String>>)> = vec![
+        (
+            "MQTT 3.1.1 connect",
+            Box::new(move || check_connect(addr, MQTT_V3)),
+        ),
+        (
+            "MQTT 5 connect",
+            Box::new(move || check_connect(addr, MQTT_V5)),
+        ),
+        (
+            "MQTT 3.1.1 ping",
+            Box::new(move || check_ping(addr, MQTT_V3)),
+        ),
+        ("MQTT 5 ping", Box::new(move || check_ping(addr, MQTT_V5))),
+        ("QoS 0 publication (3.1.1 to 3.1.1)", {
+            let prefix = prefix.clone();
+            Box::new(move || check_routing(addr, MQTT_V3, MQTT_V3, &prefix, 0))
+        }),
+        ("QoS 1 publication (5 to 3.1.1)", {
+            let prefix = prefix.clone();
+            Box::new(move || check_routing(addr, MQTT_V5, MQTT_V3, &prefix, 1))
+        }),
+        ("QoS 1 publication (3.1.1 to 5)", {
+            let prefix = prefix.clone();
+            Box::new(move || check_routing(addr, MQTT_V3, MQTT_V5, &prefix, 1))
+        }),
+        ("Single-level wildcard '+'", {
+            let prefix = prefix.clone();
+            Box::new(move || {
+                check_wildcard(addr, &prefix, "single", "+/temp", "a/temp", "a/b/temp")
+            })
+        }),
+        ("Multi-level wildcard '#'", {
+            let prefix = prefix.clone();
+            Box::new(move || check_wildcard(addr, &prefix, "multi", "a/#", "a/b/c", "b/c"))
+        }),
+        ("Retained publication", {
+            let prefix = prefix.clone();
+            Box::new(move || check_retained(addr, &prefix))
+        }),
+        ("Unsubscribe", {
+            let prefix = prefix.clone();
+            Box::new(move || check_unsubscribe(addr, &prefix))
+        }),
+    ];
+
+    println!("MQTT self-test against {addr}:");
+    let mut failed = 0;
+    for (name, check) in &checks {
+        match check() {
+            Ok(()) => println!("  [PASS] {name}"),
+            Err(e) => {
+                failed += 1;
+                println!("  [FAIL] {name}: {e}");
+            }
+        }
+    }
+    println!("{} checks passed, {} failed", checks.len() - failed, failed);
+    failed == 0
+}
+
+fn check_connect(addr: SocketAddr, version: u8) -> Result<(), String> {
+    let mut client = Client::connect(addr, version, "selftest-connect")?;
+    client.disconnect()
+}
+
+fn check_ping(addr: SocketAddr, version: u8) -> Result<(), String> {
+    let mut client = Client::connect(addr, version, "selftest-ping")?;
+    client.send(PINGREQ, &[])?;
+    client.expect(PINGRESP)?;
+    client.disconnect()
+}
+
+fn check_routing(
+    addr: SocketAddr,
+    pub_version: u8,
+    sub_version: u8,
+    prefix: &str,
+    qos: u8,
+) -> Result<(), String> {
+    let topic = format!("{prefix}/routing/{pub_version}/{sub_version}/{qos}");
+    let mut sub = Client::connect(addr, sub_version, "selftest-sub")?;
+    sub.subscribe(&topic, qos)?;
+    let mut publ = Client::connect(addr, pub_version, "selftest-pub")?;
+    publ.publish(&topic, b"hello", qos, false)?;
+    sub.expect_publication(&topic, b"hello")?;
+    publ.disconnect()?;
+    sub.disconnect()
+}
+
+fn check_wildcard(
+    addr: SocketAddr,
+    prefix: &str,
+    name: &str,
+    filter: &str,
+    matching: &str,
+    not_matching: &str,
+) -> Result<(), String> {
+    let prefix = format!("{prefix}/wildcard/{name}");
+    let mut sub = Client::connect(addr, MQTT_V3, "selftest-sub")?;
+    sub.subscribe(&format!("{prefix}/{filter}"), 0)?;
+    let mut publ = Client::connect(addr, MQTT_V3, "selftest-pub")?;
+    publ.publish(&format!("{prefix}/{not_matching}"), b"no", 0, false)?;
+    publ.publish(&format!("{prefix}/{matching}"), b"yes", 0, false)?;
+    // the publications are routed in order: the 1st received must be the matching one
+    sub.expect_publication(&format!("{prefix}/{matching}"), b"yes")?;
+    publ.disconnect()?;
+    sub.disconnect()
+}
+
+// The bridge doesn't store the retained publications (that's the role of the zenoh storages),
+// but must accept them and route them to the current subscribers
+fn check_retained(addr: SocketAddr, prefix: &str) -> Result<(), String> {
+    let topic = format!("{prefix}/retained");
+    let mut sub = Client::connect(addr, MQTT_V5, "selftest-sub")?;
+    sub.subscribe(&topic, 0)?;
+    let mut publ = Client::connect(addr, MQTT_V5, "selftest-pub")?;
+    publ.publish(&topic, b"retained", 1, true)?;
+    sub.expect_publication(&topic, b"retained")?;
+    publ.disconnect()?;
+    sub.disconnect()
+}
+
+fn check_unsubscribe(addr: SocketAddr, prefix: &str) -> Result<(), String> {
+    let topic = format!("{prefix}/unsubscribe");
+    let mut sub = Client::connect(addr, MQTT_V3, "selftest-sub")?;
+    sub.subscribe(&topic, 0)?;
+    sub.unsubscribe(&topic)?;
+    let mut publ = Client::connect(addr, MQTT_V3, "selftest-pub")?;
+    publ.publish(&topic, b"hello", 0, false)?;
+    sub.stream
+        .set_read_timeout(Some(NOT_RECEIVED_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    if matches!(sub.read(), Ok((header, _)) if header & 0xF0 == PUBLISH) {
+        return Err("publication received after UNSUBACK".into());
+    }
+    publ.disconnect()
+}
+
+// Measure the fan-out throughput: `subscribers` clients subscribe to a same topic, on which BENCH_MESSAGES
+// publications are made by another client. Prints the aggregate delivery rate. Returns false on error.
+fn bench_fanout(addr: SocketAddr, subscribers: usize) -> bool {
+    let topic = format!("bench/{}/fanout", std::process::id());
+    println!(
+        "MQTT fan-out benchmark against {addr}: {subscribers} subscribers, {BENCH_MESSAGES} publications of {BENCH_PAYLOAD_SIZE} bytes"
+    );
+    let mut clients = Vec::with_capacity(subscribers);
+    for i in 0..subscribers {
+        let client = Client::connect(addr, MQTT_V3, &format!("bench-sub-{i}"))
+            .and_then(|mut c| c.subscribe(&topic, 0).map(|()| c));
+        match client {
+            Ok(client) => clients.push(client),
+            Err(e) => {
+                println!("  subscriber {i} failed: {e}");
+                return false;
+            }
+        }
+    }
+    let mut publ = match Client::connect(addr, MQTT_V3, "bench-pub") {
+        Ok(publ) => publ,
+        Err(e) => {
+            println!("  publisher failed: {e}");
+            return false;
+        }
+    };
+    // each subscriber counts its publications until all are received (or none for TIMEOUT)
+    let readers: Vec<_> = clients
+        .into_iter()
+        .map(|mut client| {
+            std::thread::spawn(move || {
+                let mut received = 0;
+                let mut last = None;
+                while received < BENCH_MESSAGES {
+                    match client.read() {
+                        Ok((header, _)) if header & 0xF0 == PUBLISH => {
+                            received += 1;
+                            last = Some(Instant::now());
+                        }
+                        Ok(_) => (),
+                        Err(_) => break,
+                    }
+                }
+                let _ = client.disconnect();
+                (received, last)
+            })
+        })
+        .collect();
+    let payload = vec![0u8; BENCH_PAYLOAD_SIZE];
+    let start = Instant::now();
+    for _ in 0..BENCH_MESSAGES {
+        if let Err(e) = publ.publish(&topic, &payload, 0, false) {
+            println!("  publication failed: {e}");
+            return false;
+        }
+    }
+    let mut delivered = 0;
+    let mut end = start;
+    for reader in readers {
+        if let Ok((received, last)) = reader.join() {
+            delivered += received;
+            end = last.map_or(end, |last| last.max(end));
+        }
+    }
+    let _ = publ.disconnect();
+    let secs = (end - start).as_secs_f64().max(f64::EPSILON);
+    println!(
+        "  {delivered}/{} publications delivered in {secs:.3} s: {:.0} msg/s",
+        subscribers * BENCH_MESSAGES,
+        delivered as f64 / secs
+    );
+    true
+}
+
+// A minimal MQTT client
+struct Client {
+    stream: TcpStream,
+    version: u8,
+    next_packet_id: u16,
+}
+
+impl Client {
+    fn connect(addr: SocketAddr, version: u8, client_id: &str) -> Result<Client, String> {
+        let stream = TcpStream::connect_timeout(&addr, TIMEOUT).map_err(|e| e.to_string())?;
+        stream
+            .set_read_timeout(Some(TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        let mut client = Client {
+            stream,
+            version,
+            next_packet_id: 1,
+        };
+        let mut body = Vec::new();
+        put_string(&mut body, "MQTT");
+        body.push(version);
+        // clean session
+        body.push(0x02);
+        body.extend_from_slice(&30u16.to_be_bytes());
+        client.put_properties(&mut body);
+        put_string(&mut body, &format!("{client_id}-{version}"));
+        client.send(CONNECT, &body)?;
+        let body = client.expect(CONNACK)?;
+        match body.get(1) {
+            Some(0) => Ok(client),
+            Some(code) => Err(format!("CONNACK with return code {code}")),
+            None => Err("malformed CONNACK".into()),
+        }
+    }
+
+    fn subscribe(&mut self, filter: &str, qos: u8) -> Result<(), String> {
+        let packet_id = self.packet_id();
+        let mut body = packet_id.to_be_bytes().to_vec();
+        self.put_properties(&mut body);
+        put_string(&mut body, filter);
+        body.push(qos);
+        self.send(SUBSCRIBE, &body)?;
+        let body = self.expect(SUBACK)?;
+        let codes = self.skip_properties(&body, 2)?;
+        self.check_packet_id(&body, packet_id)?;
+        match codes.first() {
+            Some(code) if *code <= qos => Ok(()),
+            Some(code) => Err(format!("SUBACK for '{filter}' with code {code:#04x}")),
+            None => Err("malformed SUBACK".into()),
+        }
+    }
+
+    fn unsubscribe(&mut self, filter: &str) -> Result<(), String> {
+        let packet_id = self.packet_id();
+        let mut body = packet_id.to_be_bytes().to_vec();
+        self.put_properties(&mut body);
+        put_string(&mut body, filter);
+        self.send(UNSUBSCRIBE, &body)?;
+        let body = self.expect(UNSUBACK)?;
+        self.check_packet_id(&body, packet_id)
+    }
+
+    fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: u8,
+        retain: bool,
+    ) -> Result<(), String> {
+        let mut body = Vec::new();
+        put_string(&mut body, topic);
+        let packet_id = self.packet_id();
+        if qos > 0 {
+            body.extend_from_slice(&packet_id.to_be_bytes());
+        }
+        self.put_properties(&mut body);
+        body.extend_from_slice(payload);
+        self.send(PUBLISH | (qos << 1) | u8::from(retain), &body)?;
+        if qos > 0 {
+            let body = self.expect(PUBACK)?;
+            self.check_packet_id(&body, packet_id)?;
+            // with MQTT 5, the reason code is omitted on success
+            if let Some(code) = body.get(2).filter(|c| **c != 0) {
+                return Err(format!("PUBACK with reason code {code:#04x}"));
+            }
+        }
+        Ok(())
+    }
+
+    fn expect_publication(&mut self, topic: &str, payload: &[u8]) -> Result<(), String> {
+        let (header, body) = self.read()?;
+        if header & 0xF0 != PUBLISH {
+            return Err(format!("expected PUBLISH, received packet {header:#04x}"));
+        }
+        let qos = (header >> 1) & 0x03;
+        let (received_topic, rest) = get_string(&body)?;
+        let rest = if qos > 0 {
+            let packet_id = rest.get(..2).ok_or("malformed PUBLISH")?;
+            self.send(PUBACK, packet_id)?;
+            &rest[2..]
+        } else {
+            rest
+        };
+        let received_payload = self.skip_properties(rest, 0)?;
+        if received_topic != topic {
+            return Err(format!(
+                "expected publication on '{topic}', received one on '{received_topic}'"
+            ));
+        }
+        if received_payload != payload {
+            return Err(format!("unexpected payload on '{topic}'"));
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self) -> Result<(), String> {
+        self.send(DISCONNECT, &[])
+    }
+
+    fn packet_id(&mut self) -> u16 {
+        let id = self.next_packet_id;
+        self.next_packet_id += 1;
+        id
+    }
+
+    fn check_packet_id(&self, body: &[u8], packet_id: u16) -> Result<(), String> {
+        match body.get(..2) {
+            Some(id) if id == packet_id.to_be_bytes() => Ok(()),
+            _ => Err(format!(
+                "acknowledgement not matching packet id {packet_id}"
+            )),
+        }
+    }
+
+    // With MQTT 5, add empty properties
+    fn put_properties(&self, body: &mut Vec<u8>) {
+        if self.version == MQTT_V5 {
+            body.push(0);
+        }
+    }
+
+    // With MQTT 5, skip the properties starting at `offset`, returning the rest of the body
+    fn skip_properties<'a>(&self, body: &'a [u8], offset: usize) -> Result<&'a [u8], String> {
+        let body = body.get(offset..).ok_or("malformed packet")?;
+        if self.version != MQTT_V5 {
+            return Ok(body);
+        }
+        let (len, read) = get_varint(body)?;
+        body.get(read + len..)
+            .ok_or_else(|| "malformed properties".to_string())
+    }
+
+    fn send(&mut self, header: u8, body: &[u8]) -> Result<(), String> {
+        let mut packet = vec![header];
+        put_varint(&mut packet, body.len());
+        packet.extend_from_slice(body);
+        self.stream.write_all(&packet).map_err(|e| e.to_string())
+    }
+
+    fn read(&mut self) -> Result<(u8, Vec<u8>), String> {
+        let mut header = [0u8; 1];
+        self.stream
+            .read_exact(&mut header)
+            .map_err(|e| format!("no packet received: {e}"))?;
+        let mut len = 0;
+        for shift in (0..28).step_by(7) {
+            let mut byte = [0u8; 1];
+            self.stream
+                .read_exact(&mut byte)
+                .map_err(|e| e.to_string())?;
+            len |= ((byte[0] & 0x7F) as usize) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0u8; len];
+        self.stream
+            .read_exact(&mut body)
+            .map_err(|e| e.to_string())?;
+        Ok((header[0], body))
+    }
+
+    // Read the next packet, that must be of type `expected`
+    fn expect(&mut self, expected: u8) -> Result<Vec<u8>, String> {
+        let (header, body) = self.read()?;
+        if header & 0xF0 != expected & 0xF0 {
+            return Err(format!(
+                "expected packet {expected:#04x}, received packet {header:#04x}"
+            ));
+        }
+        Ok(body)
+    }
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn get_string(buf: &[u8]) -> Result<(String, &[u8]), String> {
+    let len = buf
+        .get(..2)
+        .map(|l| u16::from_be_bytes([l[0], l[1]]) as usize)
+        .ok_or("malformed string")?;
+    let s = buf.get(2..2 + len).ok_or("malformed string")?;
+    Ok((String::from_utf8_lossy(s).into_owned(), &buf[2 + len..]))
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let mut byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value > 0 {
+            byte |= 0x80;
+        }
+        buf.push(byte);
+        if value == 0 {
+            break;
+        }
+    }
+}
+
+fn get_varint(buf: &[u8]) -> Result<(usize, usize), String> {
+    let mut value = 0;
+    for (i, byte) in buf.iter().take(4).enumerate() {
+        value |= ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    Err("malformed variable byte integer".into())
+}
@@ -0,0 +1,165 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// Integration with the process supervisors: systemd on Linux (readiness and watchdog notifications,
+// with "Type=notify" and "WatchdogSec=" in the unit file), and the Service Control Manager on Windows.
+// The bridge is reported as ready once the MQTT listener is bound and the zenoh session is open.
+
+#[cfg(target_os = "linux")]
+pub(crate) use self::systemd::*;
+#[cfg(windows)]
+pub(crate) use self::windows::*;
+
+#[cfg(target_os = "linux")]
+mod systemd {
+    use sd_notify::NotifyState;
+    use std::time::Duration;
+
+    // Notify systemd once the bridge is ready, and then periodically ping its watchdog (if enabled)
+    pub(crate) fn start() {
+        async_std::task::spawn(async {
+            if zenoh_plugin_mqtt::ready().await.is_err() {
+                return;
+            }
+            log::debug!("Bridge ready - notify systemd");
+            if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+                log::warn!("Failed to notify systemd of the bridge readiness: {}", e);
+            }
+            let mut usec = 0;
+            if sd_notify::watchdog_enabled(false, &mut usec) {
+                // ping the watchdog twice per timeout period, as recommended by systemd
+                let period = Duration::from_micros(usec / 2);
+                log::debug!("systemd watchdog enabled - ping it every {:?}", period);
+                loop {
+                    async_std::task::sleep(period).await;
+                    if let Err(e) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+                        log::warn!("Failed to ping systemd watchdog: {}", e);
+                    }
+                }
+            }
+        });
+    }
+
+    // Notify systemd that the bridge is stopping
+    pub(crate) fn stopping() {
+        let _ = sd_notify::notify(false, &[NotifyState::Stopping]);
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::ffi::OsString;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    const SERVICE_NAME: &str = "zenoh-bridge-mqtt";
+
+    lazy_static::lazy_static! {
+        // to notify the service thread that the bridge is stopping
+        static ref STOPPING: (flume::Sender<()>, flume::Receiver<()>) = flume::bounded(1);
+        // to notify the main thread that the service status has been set as stopped
+        static ref STOPPED: (flume::Sender<()>, flume::Receiver<()>) = flume::bounded(1);
+    }
+
+    // true if running as a Windows service
+    static IS_SERVICE: AtomicBool = AtomicBool::new(false);
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    // Run the bridge as a Windows service: connect to the Service Control Manager in a dedicated thread
+    pub(crate) fn start() {
+        IS_SERVICE.store(true, Ordering::Release);
+        std::thread::spawn(|| {
+            if let Err(e) = service_dispatcher::start(SERVICE_NAME, ffi_service_main) {
+                log::error!("Failed to run as Windows service '{}': {}", SERVICE_NAME, e);
+                let _ = STOPPED.0.send(());
+            }
+        });
+    }
+
+    // Report the service as stopped, before the bridge exits
+    pub(crate) fn stopping() {
+        if IS_SERVICE.load(Ordering::Acquire) {
+            let _ = STOPPING.0.send(());
+            let _ = STOPPED.1.recv();
+        }
+    }
+
+    fn service_main(_args: Vec<OsString>) {
+        if let Err(e) = run_service() {
+            log::error!("Windows service '{}' failed: {}", SERVICE_NAME, e);
+        }
+        let _ = STOPPED.0.send(());
+    }
+
+    fn run_service() -> windows_service::Result<()> {
+        let status_handle =
+            service_control_handler::register(SERVICE_NAME, |control| match control {
+                ServiceControl::Stop | ServiceControl::Shutdown => {
+                    log::info!(
+                        "Windows service stop requested - drain MQTT connections before exit"
+                    );
+                    zenoh_plugin_mqtt::request_drain();
+                    ServiceControlHandlerResult::NoError
+                }
+                ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+                _ => ServiceControlHandlerResult::NotImplemented,
+            })?;
+
+        status_handle.set_service_status(status(
+            ServiceState::StartPending,
+            ServiceControlAccept::empty(),
+        ))?;
+        if async_std::task::block_on(zenoh_plugin_mqtt::ready()).is_err() {
+            return status_handle
+                .set_service_status(status(ServiceState::Stopped, ServiceControlAccept::empty()));
+        }
+        status_handle.set_service_status(status(
+            ServiceState::Running,
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        ))?;
+
+        let _ = STOPPING.1.recv();
+        status_handle
+            .set_service_status(status(ServiceState::Stopped, ServiceControlAccept::empty()))
+    }
+
+    fn status(
+        current_state: ServiceState,
+        controls_accepted: ServiceControlAccept,
+    ) -> ServiceStatus {
+        ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state,
+            controls_accepted,
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        }
+    }
+}
+
+// No process supervisor integration on the other platforms
+#[cfg(not(any(target_os = "linux", windows)))]
+pub(crate) fn start() {}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub(crate) fn stopping() {}
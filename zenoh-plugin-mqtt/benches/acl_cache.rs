@@ -0,0 +1,72 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The benchmarks of the 'allow' and 'deny' decisions of the MQTT publications: the evaluation of large
+// regular expressions without cache, on a hit in the session's cache, with the LRU evictions of topics
+// exceeding the cache capacity, and after each change of the settings (the cache being cleared).
+// Run with: cargo bench -p zenoh-plugin-mqtt --features bench --bench acl_cache
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use regex::Regex;
+use zenoh_plugin_mqtt::bench::AclDecisions;
+use zenoh_plugin_mqtt::config::Config;
+
+const IDENTITY: Option<&str> = Some("sensor-gateway");
+const TOPIC: &str = "building-12/floor-3/room-42/sensor/temperature";
+// the number of distinct topics of the LRU evictions benchmark, exceeding the cache capacity
+const CHURN_TOPICS: usize = 10000;
+const CHURN_CAPACITY: usize = 1000;
+
+// 'allow' and 'deny' settings with many alternatives
+fn config(cache_size: usize, allowed_buildings: usize) -> Config {
+    let buildings: Vec<String> = (0..allowed_buildings)
+        .map(|i| format!("building-{i}"))
+        .collect();
+    Config {
+        allow: Some(Regex::new(&format!("^({})/", buildings.join("|"))).unwrap()),
+        deny: Some(Regex::new("/(firmware|debug|private)(/|$)").unwrap()),
+        acl_cache_size: Some(cache_size),
+        ..Default::default()
+    }
+}
+
+fn bench_decisions(c: &mut Criterion) {
+    let mut group = c.benchmark_group("acl_cache");
+    let uncached = AclDecisions::new(&config(0, 100));
+    group.bench_function("uncached", |b| b.iter(|| uncached.is_allowed(TOPIC)));
+    let hit = AclDecisions::new(&config(1024, 100));
+    group.bench_function("hit", |b| b.iter(|| hit.is_allowed_cached(IDENTITY, TOPIC)));
+    let topics: Vec<String> = (0..CHURN_TOPICS)
+        .map(|i| format!("building-12/floor-3/room-{i}/sensor/temperature"))
+        .collect();
+    let churn = AclDecisions::new(&config(CHURN_CAPACITY, 100));
+    let mut next = topics.iter().cycle();
+    group.bench_function("lru_eviction", |b| {
+        b.iter(|| churn.is_allowed_cached(IDENTITY, next.next().unwrap()))
+    });
+    // alternating between 2 settings, each decision being made after a change
+    let reloaded = AclDecisions::new(&config(1024, 100));
+    let configs = [config(1024, 100), config(1024, 101)];
+    let mut next = configs.iter().cycle();
+    group.bench_function("reload", |b| {
+        b.iter(|| {
+            reloaded.update(next.next().unwrap());
+            reloaded.is_allowed_cached(IDENTITY, TOPIC)
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_decisions);
+criterion_main!(benches);
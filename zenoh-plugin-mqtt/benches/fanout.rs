@@ -0,0 +1,73 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The benchmarks of the registry of the zenoh subscribers shared by the MQTT clients:
+// the delivery of a publication to all the members of a shared subscriber, and the (un)subscription
+// of a member. Run with: cargo bench -p zenoh-plugin-mqtt --features bench --bench fanout
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::sync::Arc;
+use zenoh::prelude::sync::*;
+use zenoh::Session;
+use zenoh_plugin_mqtt::bench::Fanout;
+
+const KEY_EXPR: &str = "bench/fanout";
+const PAYLOAD: [u8; 64] = [0u8; 64];
+const SHARDS: usize = 16;
+
+// A zenoh session without scouting nor connectivity: the publications are only delivered locally
+fn local_session() -> Arc<Session> {
+    let mut config = zenoh::config::Config::default();
+    config.scouting.multicast.set_enabled(Some(false)).unwrap();
+    Arc::new(zenoh::open(config).res().unwrap())
+}
+
+fn bench_delivery(c: &mut Criterion) {
+    let zsession = local_session();
+    let mut group = c.benchmark_group("fanout_delivery");
+    for members in [1, 10, 100, 1000] {
+        let mut fanout = Fanout::new(zsession.clone(), SHARDS, KEY_EXPR).unwrap();
+        for _ in 0..members {
+            fanout.join().unwrap();
+        }
+        group.throughput(Throughput::Elements(members));
+        group.bench_with_input(BenchmarkId::from_parameter(members), &members, |b, _| {
+            b.iter(|| zsession.put(KEY_EXPR, &PAYLOAD[..]).res().unwrap())
+        });
+        assert!(fanout.received() >= members);
+    }
+    group.finish();
+}
+
+fn bench_membership(c: &mut Criterion) {
+    let zsession = local_session();
+    let mut group = c.benchmark_group("fanout_membership");
+    // joining and leaving an existing shared subscriber (without zenoh (un)declaration)
+    for members in [1, 1000] {
+        let mut fanout = Fanout::new(zsession.clone(), SHARDS, KEY_EXPR).unwrap();
+        for _ in 0..members {
+            fanout.join().unwrap();
+        }
+        group.bench_with_input(BenchmarkId::from_parameter(members), &members, |b, _| {
+            b.iter(|| {
+                fanout.join().unwrap();
+                fanout.leave();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_delivery, bench_membership);
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The benchmarks of the key expressions caches of the MQTT publication topics: the mapping of a topic
+// without cache, on a hit in the session's cache or in the global one, on a miss, and with the LRU
+// evictions of topics exceeding the caches capacity.
+// Run with: cargo bench -p zenoh-plugin-mqtt --features bench --bench ke_cache
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use zenoh_plugin_mqtt::bench::KeMapping;
+use zenoh_plugin_mqtt::config::KeCacheConfig;
+
+const SCOPE: &str = "site/paris";
+// a topic with characters escaped in the key expressions
+const TOPIC: &str = "building-12/floor-3/room-42/sensor$temp/value";
+// the number of distinct topics of the LRU evictions benchmark, exceeding the caches capacity
+const CHURN_TOPICS: usize = 10000;
+const CHURN_CAPACITY: usize = 1000;
+
+fn mapping(session_size: usize, global_size: usize) -> KeMapping {
+    KeMapping::new(
+        &KeCacheConfig {
+            session_size,
+            global_size,
+        },
+        Some(SCOPE),
+    )
+    .unwrap()
+}
+
+fn bench_mapping(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ke_cache");
+    let uncached = mapping(0, 0);
+    group.bench_function("uncached", |b| b.iter(|| uncached.map(TOPIC).unwrap()));
+    let session_hit = mapping(1024, 1024);
+    group.bench_function("session_hit", |b| {
+        b.iter(|| session_hit.map_cached(TOPIC).unwrap())
+    });
+    // without session cache, the topic is found in the global one
+    let global_hit = mapping(0, 1024);
+    group.bench_function("global_hit", |b| {
+        b.iter(|| global_hit.map_cached(TOPIC).unwrap())
+    });
+    // without any cache, the overhead of the lookups on a miss
+    let miss = mapping(0, 0);
+    group.bench_function("miss", |b| b.iter(|| miss.map_cached(TOPIC).unwrap()));
+    let topics: Vec<String> = (0..CHURN_TOPICS)
+        .map(|i| format!("building-12/floor-3/room-{i}/sensor/value"))
+        .collect();
+    let churn = mapping(CHURN_CAPACITY, CHURN_CAPACITY);
+    let mut next = topics.iter().cycle();
+    group.bench_function("lru_eviction", |b| {
+        b.iter(|| churn.map_cached(next.next().unwrap()).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_mapping);
+criterion_main!(benches);
@@ -0,0 +1,45 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// An application embedding the MQTT bridge on its own zenoh session: it prints the MQTT clients
+// session events for a minute, then the bridge statistics, and stops the bridge.
+
+use std::sync::Arc;
+use std::time::Duration;
+use zenoh::prelude::r#async::*;
+use zenoh_plugin_mqtt::config::Config;
+
+#[async_std::main]
+async fn main() -> zenoh::Result<()> {
+    zenoh_plugin_mqtt::logging::try_init();
+    let zsession = Arc::new(zenoh::open(zenoh::config::peer()).res().await?);
+    let config = Config::builder().port("127.0.0.1:1883").build()?;
+
+    let bridge = zenoh_plugin_mqtt::spawn(config, zsession);
+    let events = bridge.events();
+    zenoh_plugin_mqtt::ready().await?;
+    println!("MQTT bridge listening on 127.0.0.1:1883");
+
+    let _ = async_std::future::timeout(Duration::from_secs(60), async {
+        while let Ok(event) = events.recv_async().await {
+            println!("{event:?}");
+        }
+    })
+    .await;
+
+    if let Some(stats) = bridge.stats() {
+        println!("{stats:#}");
+    }
+    bridge.stop().await
+}
@@ -0,0 +1,202 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The time windows restricting the access to some topics (e.g. the firmware update topics only during the
+// maintenance windows). A topic matching some rules is only routed (in both directions) while the window of
+// one of them is open: between its 'start' and 'end' times, and daily between the 'daily' times (on the
+// configured 'days'). All times are UTC, evaluated for each message, and the windows are widened by a
+// tolerance for the clock skew between the clients and the bridge.
+
+use regex::Regex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zenoh::plugins::ZResult;
+
+use crate::config::{AccessWindowRule, Config};
+use crate::process_wide::ProcessWide;
+
+const SECS_PER_DAY: u64 = 86400;
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+struct Window {
+    topics: Regex,
+    // the absolute bounds, in seconds since UNIX epoch
+    start: Option<u64>,
+    end: Option<u64>,
+    // the daily bounds, in seconds since midnight (the window crossing midnight if start > end)
+    daily: Option<(u64, u64)>,
+    // the days of the week the daily window opens (0 being Sunday), all if empty
+    days: Vec<u64>,
+}
+
+impl Window {
+    fn new(rule: &AccessWindowRule) -> ZResult<Window> {
+        let topics = Regex::new(&rule.topics)
+            .map_err(|e| zerror!("Invalid regex 'topics={}': {}", rule.topics, e))?;
+        let daily = match &rule.daily {
+            Some(daily) => {
+                let Some((start, end)) = daily.split_once('-') else {
+                    bail!("Invalid daily window '{}': expected 'HH:MM-HH:MM'", daily);
+                };
+                let (start, end) = (parse_time_of_day(start)?, parse_time_of_day(end)?);
+                if start == end {
+                    bail!(
+                        "Invalid daily window '{}': its start and end are equal",
+                        daily
+                    );
+                }
+                Some((start, end))
+            }
+            None => None,
+        };
+        let days = rule
+            .days
+            .iter()
+            .map(|day| {
+                WEEKDAYS
+                    .iter()
+                    .position(|d| day.eq_ignore_ascii_case(d))
+                    .map(|i| i as u64)
+                    .ok_or_else(|| zerror!("Invalid day '{}': expected one of {:?}", day, WEEKDAYS))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Window {
+            topics,
+            start: rule.start.as_deref().map(parse_utc_time).transpose()?,
+            end: rule.end.as_deref().map(parse_utc_time).transpose()?,
+            daily,
+            days,
+        })
+    }
+
+    // Returns true if the window is open at this time (in seconds since UNIX epoch), give or take the tolerance
+    fn is_open(&self, now: u64, tolerance: u64) -> bool {
+        if self.start.map_or(false, |start| now + tolerance < start)
+            || self.end.map_or(false, |end| now >= end + tolerance)
+        {
+            return false;
+        }
+        let Some((start, end)) = self.daily else {
+            return true;
+        };
+        // the time elapsed since the last opening of the daily window, and its length
+        let elapsed = (now % SECS_PER_DAY + SECS_PER_DAY - start) % SECS_PER_DAY;
+        let length = (end + SECS_PER_DAY - start) % SECS_PER_DAY;
+        // the window is open since its last opening (on the day of this opening, even if it crosses midnight),
+        // or about to open (on the day of its next opening, possibly tomorrow)
+        let last_opening = now.saturating_sub(elapsed);
+        let next_opening = last_opening + SECS_PER_DAY;
+        (elapsed < length + tolerance && self.opens_on_day_of(last_opening))
+            || (next_opening - now <= tolerance && self.opens_on_day_of(next_opening))
+    }
+
+    // Returns true if the daily window opens on the day of this time (in seconds since UNIX epoch)
+    fn opens_on_day_of(&self, time: u64) -> bool {
+        // the day of the week, 1970-01-01 being a Thursday
+        let weekday = (time / SECS_PER_DAY + 4) % 7;
+        self.days.is_empty() || self.days.contains(&weekday)
+    }
+}
+
+struct AccessWindows {
+    windows: Vec<Window>,
+    tolerance: u64,
+}
+
+static ACCESS_WINDOWS: ProcessWide<AccessWindows> = ProcessWide::new("access_windows");
+
+// Parse "HH:MM" (or "HH:MM:SS") as a number of seconds since midnight
+fn parse_time_of_day(s: &str) -> ZResult<u64> {
+    let s = s.trim();
+    let fields = s
+        .split(':')
+        .map(|f| f.parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| zerror!("Invalid time of day '{}': expected 'HH:MM'", s))?;
+    match fields[..] {
+        [h, m] if h < 24 && m < 60 => Ok(h * 3600 + m * 60),
+        [h, m, sec] if h < 24 && m < 60 && sec < 60 => Ok(h * 3600 + m * 60 + sec),
+        _ => bail!("Invalid time of day '{}': expected 'HH:MM'", s),
+    }
+}
+
+// Parse a UTC time as "YYYY-MM-DDTHH:MM:SSZ", in seconds since UNIX epoch
+fn parse_utc_time(s: &str) -> ZResult<u64> {
+    let invalid = || zerror!("Invalid time '{}': expected 'YYYY-MM-DDTHH:MM:SSZ'", s);
+    let (date, time) = s
+        .strip_suffix('Z')
+        .and_then(|s| s.split_once('T'))
+        .ok_or_else(invalid)?;
+    let fields = date
+        .split('-')
+        .map(|f| f.parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid())?;
+    let [year, month, day] = fields[..] else {
+        return Err(invalid().into());
+    };
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid().into());
+    }
+    // the days since UNIX epoch of the date (see http://howardhinnant.github.io/date_algorithms.html)
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * m + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Ok(days * SECS_PER_DAY + parse_time_of_day(time)?)
+}
+
+// Set the access windows, if configured
+pub(crate) fn init(config: &Config) -> ZResult<()> {
+    ACCESS_WINDOWS.init(&config.access_windows, || {
+        let Some(conf) = &config.access_windows else {
+            return Ok(None);
+        };
+        let windows = conf
+            .rules
+            .iter()
+            .map(Window::new)
+            .collect::<ZResult<Vec<_>>>()
+            .map_err(|e| zerror!("Invalid 'access_windows' configuration: {}", e))?;
+        Ok(Some(AccessWindows {
+            windows,
+            tolerance: conf.tolerance_secs,
+        }))
+    })?;
+    Ok(())
+}
+
+// Returns true if the topic matches no access window rule, or if the window of one of its rules is open
+pub(crate) fn is_open(topic: &str) -> bool {
+    let Some(access_windows) = ACCESS_WINDOWS.get() else {
+        return true;
+    };
+    let mut matching = access_windows
+        .windows
+        .iter()
+        .filter(|w| w.topics.is_match(topic))
+        .peekable();
+    if matching.peek().is_none() {
+        return true;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    matching.any(|w| w.is_open(now, access_windows.tolerance))
+}
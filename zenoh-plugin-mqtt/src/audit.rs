@@ -0,0 +1,258 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The audit log of the MQTT clients connections (separate from the plugin's log): the connections,
+// disconnections, authentication decisions, rejected connections and ACL denials are recorded as JSON
+// lines in a rotating file and/or published on a zenoh key expression.
+
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zenoh::plugins::ZResult;
+use zenoh::prelude::r#async::*;
+
+use crate::auth::AuthDecision;
+use crate::config::{AuditConfig, Config};
+use crate::events::ProtocolVersion;
+use crate::process_wide::ProcessWide;
+
+static AUDIT: ProcessWide<flume::Sender<Value>> = ProcessWide::new("audit");
+
+// A file renamed to "<path>.1" (and the older ones to "<path>.2", ...) when exceeding its maximum size
+// (also used for the plugin's log file)
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    max_file_size: u64,
+    max_files: usize,
+    file: Option<File>,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub(crate) fn new(path: PathBuf, max_file_size: u64, max_files: usize) -> RotatingFile {
+        RotatingFile {
+            path,
+            max_file_size,
+            max_files,
+            file: None,
+            size: 0,
+        }
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        self.write_all(format!("{line}\n").as_bytes())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&self) -> std::io::Result<()> {
+        if self.max_files == 0 {
+            return std::fs::remove_file(&self.path);
+        }
+        let _ = std::fs::remove_file(self.rotated_path(self.max_files));
+        for index in (1..self.max_files).rev() {
+            let _ = std::fs::rename(self.rotated_path(index), self.rotated_path(index + 1));
+        }
+        std::fs::rename(&self.path, self.rotated_path(1))
+    }
+}
+
+impl Write for RotatingFile {
+    // Each write is expected to be a whole record, never split between 2 files
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.file.is_some() && self.size + buf.len() as u64 > self.max_file_size {
+            self.file = None;
+            self.rotate()?;
+        }
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            self.size = file.metadata()?.len();
+            self.file = Some(file);
+        }
+        let written = match &mut self.file {
+            Some(file) => file.write(buf)?,
+            None => 0,
+        };
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+// Spawn the task writing the audit records, if the audit log is configured
+pub(crate) fn init(config: &Config, zsession: Arc<Session>) -> ZResult<()> {
+    let mut receiver = None;
+    let set = AUDIT.init(&config.audit, || {
+        if config.audit.is_none() {
+            return Ok(None);
+        }
+        let (tx, rx) = flume::unbounded::<Value>();
+        receiver = Some(rx);
+        Ok(Some(tx))
+    })?;
+    let (true, Some(conf), Some(rx)) = (set, &config.audit, receiver) else {
+        return Ok(());
+    };
+    let AuditConfig {
+        file,
+        max_file_size,
+        max_files,
+        key_expr,
+    } = conf.clone();
+    let mut file = file.map(|path| RotatingFile::new(path.into(), max_file_size, max_files));
+    async_std::task::spawn(async move {
+        while let Ok(record) = rx.recv_async().await {
+            let line = record.to_string();
+            if let Some(file) = &mut file {
+                if let Err(e) = file.write_line(&line) {
+                    log::warn!(
+                        "Failed to write MQTT audit record to {}: {}",
+                        file.path.display(),
+                        e
+                    );
+                }
+            }
+            if let Some(ke) = &key_expr {
+                if let Err(e) = zsession
+                    .put(ke, line)
+                    .encoding(Encoding::APP_JSON)
+                    .res()
+                    .await
+                {
+                    log::warn!("Failed to publish MQTT audit record on {}: {}", ke, e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+fn record(event: &str, client_id: Option<&str>, mut fields: Value) {
+    let Some(tx) = AUDIT.get() else {
+        return;
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+    if let Value::Object(fields) = &mut fields {
+        fields.insert("timestamp".into(), Value::from(timestamp));
+        fields.insert("event".into(), Value::from(event));
+        if let Some(client_id) = client_id {
+            fields.insert("client_id".into(), Value::from(client_id));
+        }
+    }
+    let _ = tx.send(fields);
+}
+
+pub(crate) fn connected(
+    client_id: &str,
+    protocol: ProtocolVersion,
+    username: Option<&str>,
+    address: Option<IpAddr>,
+) {
+    record(
+        "connect",
+        Some(client_id),
+        serde_json::json!({
+            "protocol": protocol.as_str(),
+            "username": username,
+            "address": address.map(|a| a.to_string()),
+        }),
+    );
+}
+
+pub(crate) fn rejected(client_id: &str, address: Option<IpAddr>, reason: &str) {
+    record(
+        "reject",
+        Some(client_id),
+        serde_json::json!({
+            "address": address.map(|a| a.to_string()),
+            "reason": reason,
+        }),
+    );
+}
+
+pub(crate) fn authenticated(
+    client_id: &str,
+    username: Option<&str>,
+    address: Option<IpAddr>,
+    decision: &AuthDecision,
+) {
+    let decision = match decision {
+        AuthDecision::Granted => "granted",
+        AuthDecision::Denied => "denied",
+        AuthDecision::LockedOut => "locked_out",
+    };
+    record(
+        "auth",
+        Some(client_id),
+        serde_json::json!({
+            "username": username,
+            "address": address.map(|a| a.to_string()),
+            "decision": decision,
+        }),
+    );
+}
+
+pub(crate) fn disconnected(client_id: &str, username: Option<&str>) {
+    record(
+        "disconnect",
+        Some(client_id),
+        serde_json::json!({ "username": username }),
+    );
+}
+
+// `operation`: the denied operation (e.g. "subscribe" or "publish"), on a topic or key expression
+pub(crate) fn acl_denied(client_id: &str, username: Option<&str>, operation: &str, target: &str) {
+    record(
+        "acl_deny",
+        Some(client_id),
+        serde_json::json!({
+            "username": username,
+            "operation": operation,
+            "target": target,
+        }),
+    );
+}
+
+// `operation`: the admin space operation (e.g. "pseudonyms/reveal") on a target, refused if not authorized
+pub(crate) fn admin(operation: &str, target: Option<&str>, authorized: bool) {
+    record(
+        "admin",
+        None,
+        serde_json::json!({
+            "operation": operation,
+            "target": target,
+            "authorized": authorized,
+        }),
+    );
+}
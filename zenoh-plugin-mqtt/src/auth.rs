@@ -0,0 +1,211 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use zenoh::plugins::ZResult;
+use zenoh_core::zlock;
+
+use crate::config::AuthConfig;
+
+// The MQTT 5 enhanced authentication method supported by the plugin:
+// the authentication data are encoded as for the SASL PLAIN mechanism (RFC 4616).
+pub(crate) const AUTH_METHOD_PLAIN: &str = "PLAIN";
+
+// Decode SASL PLAIN authentication data ("[authzid] NUL authcid NUL passwd")
+// returning the username and the password
+pub(crate) fn decode_sasl_plain(data: &[u8]) -> Option<(&str, &[u8])> {
+    let mut parts = data.splitn(3, |b| *b == 0);
+    let _authzid = parts.next()?;
+    let username = std::str::from_utf8(parts.next()?).ok()?;
+    let password = parts.next()?;
+    Some((username, password))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AuthDecision {
+    Granted,
+    Denied,
+    // too many failed attempts from this client id or IP address
+    LockedOut,
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+    // the failures are forgotten after 'max_lockout_secs' without new failure (once the lockout ended)
+    forget_at: Instant,
+}
+
+// Authentication of MQTT clients with a username/password dictionary.
+// Successful authentications are cached, and repeated failures from a same
+// client id or IP address lead to an exponentially growing lockout.
+// The failures of a client id or IP address are forgotten after a while without new failure.
+#[derive(Debug)]
+pub(crate) struct Authenticator {
+    conf: AuthConfig,
+    dictionary: HashMap<String, String>,
+    cache: Mutex<HashMap<(String, Vec<u8>), Instant>>,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+impl Authenticator {
+    pub(crate) fn new(conf: &AuthConfig) -> ZResult<Authenticator> {
+        let content = std::fs::read_to_string(&conf.dictionary_file).map_err(|e| {
+            zerror!(
+                "Failed to read auth dictionary file '{}': {}",
+                conf.dictionary_file,
+                e
+            )
+        })?;
+        let mut dictionary = HashMap::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((user, password)) => {
+                    dictionary.insert(user.to_string(), password.to_string());
+                }
+                None => bail!(
+                    "Invalid line {} in auth dictionary file '{}': expecting '<username>:<password>'",
+                    i + 1,
+                    conf.dictionary_file
+                ),
+            }
+        }
+        Ok(Authenticator {
+            conf: conf.clone(),
+            dictionary,
+            cache: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub(crate) fn authenticate(
+        &self,
+        client_id: &str,
+        peer_ip: Option<IpAddr>,
+        username: Option<&str>,
+        password: Option<&[u8]>,
+    ) -> AuthDecision {
+        let failure_keys = failure_keys(client_id, peer_ip);
+        let now = Instant::now();
+        if self.is_locked_out(&failure_keys, now) {
+            return AuthDecision::LockedOut;
+        }
+
+        let (username, password) = match (username, password) {
+            (Some(u), Some(p)) => (u, p),
+            _ => {
+                self.record_failure(&failure_keys, now);
+                return AuthDecision::Denied;
+            }
+        };
+
+        let cache_key = (username.to_string(), Sha256::digest(password).to_vec());
+        if self.conf.cache_ttl_secs > 0 {
+            if let Some(expiry) = zlock!(self.cache).get(&cache_key) {
+                if *expiry > now {
+                    return AuthDecision::Granted;
+                }
+            }
+        }
+
+        if self
+            .dictionary
+            .get(username)
+            .map_or(false, |p| p.as_bytes() == password)
+        {
+            self.record_success(&failure_keys);
+            if self.conf.cache_ttl_secs > 0 {
+                let mut cache = zlock!(self.cache);
+                cache.retain(|_, expiry| *expiry > now);
+                cache.insert(
+                    cache_key,
+                    now + Duration::from_secs(self.conf.cache_ttl_secs),
+                );
+            }
+            AuthDecision::Granted
+        } else {
+            self.record_failure(&failure_keys, now);
+            AuthDecision::Denied
+        }
+    }
+
+    // True if the username is in the dictionary
+    pub(crate) fn has_user(&self, username: &str) -> bool {
+        self.dictionary.contains_key(username)
+    }
+
+    fn is_locked_out(&self, keys: &[String], now: Instant) -> bool {
+        let failures = zlock!(self.failures);
+        keys.iter().any(|k| {
+            failures
+                .get(k)
+                .and_then(|f| f.locked_until)
+                .map_or(false, |until| until > now)
+        })
+    }
+
+    fn record_success(&self, keys: &[String]) {
+        let mut failures = zlock!(self.failures);
+        for k in keys {
+            failures.remove(k);
+        }
+    }
+
+    fn record_failure(&self, keys: &[String], now: Instant) {
+        let mut failures = zlock!(self.failures);
+        failures.retain(|_, f| f.forget_at > now);
+        for k in keys {
+            let f = failures.entry(k.clone()).or_insert(Failures {
+                count: 0,
+                locked_until: None,
+                forget_at: now,
+            });
+            f.count += 1;
+            if f.count >= self.conf.max_failures {
+                // lockout duration doubles at each new failure, up to max_lockout_secs
+                let exp = (f.count - self.conf.max_failures).min(31);
+                let secs = self
+                    .conf
+                    .lockout_secs
+                    .saturating_mul(1u64 << exp)
+                    .min(self.conf.max_lockout_secs);
+                log::warn!(
+                    "{} failed authentication attempts for {} - locked out for {} seconds",
+                    f.count,
+                    k,
+                    secs
+                );
+                f.locked_until = Some(now + Duration::from_secs(secs));
+            }
+            f.forget_at = f.locked_until.map_or(now, |until| until.max(now))
+                + Duration::from_secs(self.conf.max_lockout_secs);
+        }
+    }
+}
+
+fn failure_keys(client_id: &str, peer_ip: Option<IpAddr>) -> Vec<String> {
+    let mut keys = vec![format!("client_id {client_id}")];
+    if let Some(ip) = peer_ip {
+        keys.push(format!("IP {ip}"));
+    }
+    keys
+}
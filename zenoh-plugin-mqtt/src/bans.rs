@@ -0,0 +1,274 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The ban lists and allow lists of the MQTT clients, by client id patterns (regular expressions)
+// and by IP address ranges (CIDR). They're initialized from the configuration, can be changed at
+// runtime via the admin space, and are checked before the connection is acknowledged.
+// Optionally, the clients with too many consecutive authentication failures are banned for a while.
+
+use regex::Regex;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use zenoh::plugins::ZResult;
+use zenoh_core::{zread, zwrite};
+
+use crate::config::{AutoBanConfig, BansConfig};
+
+// A range of IP addresses, in CIDR notation (e.g. "10.0.0.0/8"), or a single address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| format!("Invalid IP address range '{s}': {e}"))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max_prefix)
+                .ok_or_else(|| format!("Invalid IP address range '{s}': invalid prefix length"))?,
+            None => max_prefix,
+        };
+        Ok(IpRange { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Lists {
+    client_ids: Vec<Regex>,
+    addresses: Vec<IpRange>,
+    allowed_client_ids: Vec<Regex>,
+    allowed_addresses: Vec<IpRange>,
+}
+
+#[derive(Debug, Default)]
+struct AuthFailures {
+    count: u32,
+    banned_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub(crate) struct BanList {
+    lists: RwLock<Lists>,
+    auto_ban: Option<AutoBanConfig>,
+    // per client id and per IP address
+    auth_failures: RwLock<HashMap<String, AuthFailures>>,
+}
+
+fn parse_regexes(patterns: &[String]) -> ZResult<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|p| {
+            Regex::new(p).map_err(|e| zerror!("Invalid client id pattern '{}': {}", p, e).into())
+        })
+        .collect()
+}
+
+fn parse_ranges(ranges: &[String]) -> ZResult<Vec<IpRange>> {
+    ranges
+        .iter()
+        .map(|r| r.parse().map_err(|e: String| zerror!("{}", e).into()))
+        .collect()
+}
+
+// The keys of a client in the authentication failures map
+fn failure_keys(client_id: &str, peer_ip: Option<IpAddr>) -> Vec<String> {
+    let mut keys = vec![format!("client_id:{client_id}")];
+    if let Some(ip) = peer_ip {
+        keys.push(format!("ip:{ip}"));
+    }
+    keys
+}
+
+impl BanList {
+    pub(crate) fn new(config: Option<&BansConfig>) -> ZResult<BanList> {
+        let config = config.cloned().unwrap_or_default();
+        Ok(BanList {
+            lists: RwLock::new(Lists {
+                client_ids: parse_regexes(&config.client_ids)?,
+                addresses: parse_ranges(&config.addresses)?,
+                allowed_client_ids: parse_regexes(&config.allowed_client_ids)?,
+                allowed_addresses: parse_ranges(&config.allowed_addresses)?,
+            }),
+            auto_ban: config.auto_ban,
+            auth_failures: RwLock::new(HashMap::new()),
+        })
+    }
+
+    // Check if a client is allowed to connect. Returns the reason of the refusal otherwise.
+    pub(crate) fn check(&self, client_id: &str, peer_ip: Option<IpAddr>) -> Result<(), String> {
+        let lists = zread!(self.lists);
+        if let Some(re) = lists.client_ids.iter().find(|re| re.is_match(client_id)) {
+            return Err(format!("client id banned (matching '{re}')"));
+        }
+        if !lists.allowed_client_ids.is_empty()
+            && !lists
+                .allowed_client_ids
+                .iter()
+                .any(|re| re.is_match(client_id))
+        {
+            return Err("client id not in the allow list".into());
+        }
+        if let Some(ip) = peer_ip {
+            if let Some(range) = lists.addresses.iter().find(|r| r.contains(ip)) {
+                return Err(format!("address {ip} banned (in {range})"));
+            }
+            if !lists.allowed_addresses.is_empty()
+                && !lists.allowed_addresses.iter().any(|r| r.contains(ip))
+            {
+                return Err(format!("address {ip} not in the allow list"));
+            }
+        } else if !lists.allowed_addresses.is_empty() {
+            return Err("unknown address with an addresses allow list".into());
+        }
+        let now = Instant::now();
+        let auth_failures = zread!(self.auth_failures);
+        if failure_keys(client_id, peer_ip)
+            .iter()
+            .filter_map(|k| auth_failures.get(k))
+            .any(|f| f.banned_until.map_or(false, |until| now < until))
+        {
+            return Err("banned after too many authentication failures".into());
+        }
+        Ok(())
+    }
+
+    pub(crate) fn record_auth_failure(&self, client_id: &str, peer_ip: Option<IpAddr>) {
+        let Some(auto_ban) = &self.auto_ban else {
+            return;
+        };
+        let now = Instant::now();
+        let mut auth_failures = zwrite!(self.auth_failures);
+        auth_failures.retain(|_, f| f.banned_until.map_or(true, |until| now < until));
+        for key in failure_keys(client_id, peer_ip) {
+            let f = auth_failures.entry(key).or_default();
+            f.count += 1;
+            if f.count >= auto_ban.max_auth_failures && f.banned_until.is_none() {
+                log::warn!(
+                    "MQTT client {} ({:?}) banned for {} seconds after {} authentication failures",
+                    client_id,
+                    peer_ip,
+                    auto_ban.ban_secs,
+                    f.count
+                );
+                f.banned_until = Some(now + Duration::from_secs(auto_ban.ban_secs));
+            }
+        }
+    }
+
+    pub(crate) fn record_auth_success(&self, client_id: &str, peer_ip: Option<IpAddr>) {
+        if self.auto_ban.is_some() {
+            let mut auth_failures = zwrite!(self.auth_failures);
+            for key in failure_keys(client_id, peer_ip) {
+                auth_failures.remove(&key);
+            }
+        }
+    }
+
+    // Add a client id pattern or an IP address range to the ban list (via admin space)
+    pub(crate) fn ban(&self, client_id: Option<&str>, address: Option<&str>) -> ZResult<()> {
+        let mut lists = zwrite!(self.lists);
+        if let Some(pattern) = client_id {
+            let re = Regex::new(pattern)
+                .map_err(|e| zerror!("Invalid client id pattern '{}': {}", pattern, e))?;
+            if !lists.client_ids.iter().any(|r| r.as_str() == pattern) {
+                lists.client_ids.push(re);
+            }
+        }
+        if let Some(range) = address {
+            let range: IpRange = range.parse().map_err(|e: String| zerror!("{}", e))?;
+            if !lists.addresses.contains(&range) {
+                lists.addresses.push(range);
+            }
+        }
+        Ok(())
+    }
+
+    // Remove a client id pattern or an IP address range from the ban list (via admin space),
+    // as well as the auto-ban of a client id or an IP address
+    pub(crate) fn unban(&self, client_id: Option<&str>, address: Option<&str>) -> ZResult<()> {
+        let mut lists = zwrite!(self.lists);
+        let mut auth_failures = zwrite!(self.auth_failures);
+        if let Some(pattern) = client_id {
+            lists.client_ids.retain(|r| r.as_str() != pattern);
+            auth_failures.remove(&format!("client_id:{pattern}"));
+        }
+        if let Some(range) = address {
+            let range: IpRange = range.parse().map_err(|e: String| zerror!("{}", e))?;
+            lists.addresses.retain(|r| *r != range);
+            auth_failures.remove(&format!("ip:{}", range.addr));
+        }
+        Ok(())
+    }
+
+    // The lists as JSON, for the admin space
+    pub(crate) fn to_json(&self) -> Value {
+        let lists = zread!(self.lists);
+        let now = Instant::now();
+        let auto_banned: serde_json::Map<String, Value> = zread!(self.auth_failures)
+            .iter()
+            .filter_map(|(k, f)| {
+                f.banned_until
+                    .filter(|until| now < *until)
+                    .map(|until| (k.clone(), Value::from((until - now).as_secs())))
+            })
+            .collect();
+        serde_json::json!({
+            "client_ids": lists.client_ids.iter().map(|r| r.as_str()).collect::<Vec<_>>(),
+            "addresses": lists.addresses.iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+            "allowed_client_ids": lists.allowed_client_ids.iter().map(|r| r.as_str()).collect::<Vec<_>>(),
+            "allowed_addresses": lists.allowed_addresses.iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+            "auto_banned": auto_banned,
+        })
+    }
+}
@@ -0,0 +1,147 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+//! The internals of the plugin exercised by the benchmarks (see the "benches" directory).
+//! Only built with the "bench" feature: this is not part of the plugin's API.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use zenoh::plugins::ZResult;
+use zenoh::prelude::*;
+use zenoh::subscriber::Reliability;
+use zenoh::Session;
+
+use crate::config::{Config, KeCacheConfig, QueueStrategy};
+use crate::fanout::{FanoutRegistry, SampleHandler, TopicMember};
+use crate::ke_cache::{AclCache, KeCache, KeCaches};
+use crate::mqtt_helpers::{mqtt_topic_to_ke, TopicAcl};
+
+/// A shared topic subscriber of the fan-out registry, whose members count the samples they receive
+/// (as the MQTT clients' members only enqueue them in their sink).
+pub struct Fanout {
+    registry: Arc<FanoutRegistry>,
+    ke: OwnedKeyExpr,
+    members: Vec<TopicMember>,
+    received: Arc<AtomicU64>,
+}
+
+impl Fanout {
+    /// A registry with `shards` shards, for the subscribers on `ke`
+    pub fn new(zsession: Arc<Session>, shards: usize, ke: &str) -> ZResult<Fanout> {
+        Ok(Fanout {
+            registry: Arc::new(FanoutRegistry::new(
+                zsession,
+                None,
+                shards,
+                QueueStrategy::RoundRobin,
+            )),
+            ke: OwnedKeyExpr::try_from(ke)?,
+            members: Vec::new(),
+            received: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Add a member, declaring the shared subscriber if it's the 1st one
+    pub fn join(&mut self) -> ZResult<()> {
+        let received = self.received.clone();
+        let handler: SampleHandler = Arc::new(move |_| {
+            received.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        });
+        let member = self.registry.join_topic(
+            &KeyExpr::from(self.ke.clone()),
+            Locality::Any,
+            Reliability::Reliable,
+            handler,
+        )?;
+        self.members.push(member);
+        Ok(())
+    }
+
+    /// Remove the last added member, undeclaring the shared subscriber if it was the last one
+    pub fn leave(&mut self) {
+        self.members.pop();
+    }
+
+    /// The number of samples received by all the members
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+}
+
+/// The mapping of the MQTT publication topics to key expressions of a MQTT session,
+/// through the session's and the global key expressions caches.
+pub struct KeMapping {
+    caches: KeCaches,
+    session: Mutex<KeCache>,
+    scope: Option<OwnedKeyExpr>,
+}
+
+impl KeMapping {
+    pub fn new(config: &KeCacheConfig, scope: Option<&str>) -> ZResult<KeMapping> {
+        Ok(KeMapping {
+            caches: KeCaches::new(config),
+            session: Mutex::new(KeCache::new(config.session_size)),
+            scope: scope.map(OwnedKeyExpr::try_from).transpose()?,
+        })
+    }
+
+    /// Map a topic without the caches
+    pub fn map(&self, topic: &str) -> ZResult<OwnedKeyExpr> {
+        mqtt_topic_to_ke(topic, &self.scope, None).map(OwnedKeyExpr::from)
+    }
+
+    /// Map a topic through the caches
+    pub fn map_cached(&self, topic: &str) -> ZResult<OwnedKeyExpr> {
+        self.caches
+            .get_or_map(&self.session, &self.scope, topic, || self.map(topic))
+    }
+}
+
+/// The 'allow' and 'deny' decisions of a MQTT session, through the session's decisions cache
+/// (of `acl_cache_size` entries).
+pub struct AclDecisions {
+    acl: TopicAcl,
+    cache: Mutex<AclCache>,
+}
+
+impl AclDecisions {
+    pub fn new(config: &Config) -> AclDecisions {
+        AclDecisions {
+            acl: TopicAcl::new(config),
+            cache: Mutex::new(AclCache::new(config.acl_cache_size.unwrap_or(0))),
+        }
+    }
+
+    /// Evaluate the 'allow' and 'deny' regular expressions for a topic
+    pub fn is_allowed(&self, topic: &str) -> bool {
+        self.acl.is_allowed(topic)
+    }
+
+    /// The decision for a client identity and a topic, through the cache
+    pub fn is_allowed_cached(&self, identity: Option<&str>, topic: &str) -> bool {
+        let generation = self.acl.generation();
+        if let Some(allowed) = zlock!(self.cache).get(generation, identity, topic) {
+            return allowed;
+        }
+        let allowed = self.acl.is_allowed(topic);
+        zlock!(self.cache).insert(generation, identity, topic, allowed);
+        allowed
+    }
+
+    /// Replace the 'allow' and 'deny' settings (as on a hot change of the configuration)
+    pub fn update(&self, config: &Config) -> bool {
+        self.acl.update(config)
+    }
+}
@@ -0,0 +1,222 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The chunking of the large MQTT payloads routed over Zenoh between bridges.
+// A payload larger than the chunk size is put as several publications on the same key expression, each with
+// an attachment "mqtt_chunk" set to "<message id>:<index>:<count>". The receiving bridges reassemble the
+// chunks of a message (per MQTT client), within the configured size and time bounds, before re-publishing it.
+// The total size of the messages being reassembled is bounded, the oldest ones being dropped above it.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use zenoh::plugins::ZResult;
+use zenoh::prelude::r#async::*;
+use zenoh_core::zlock;
+
+use crate::config::{ChunkingConfig, Config};
+use crate::process_wide::ProcessWide;
+
+// The attachment key carrying the chunk metadata
+pub(crate) const ATTACHMENT_KEY_CHUNK: &str = "mqtt_chunk";
+
+// A message being reassembled
+struct Pending {
+    chunks: BTreeMap<usize, Vec<u8>>,
+    count: usize,
+    size: usize,
+    started: Instant,
+}
+
+struct Chunking {
+    config: ChunkingConfig,
+    next_id: AtomicU64,
+    // the messages being reassembled, per MQTT client id and message id
+    pending: Mutex<HashMap<(String, String), Pending>>,
+}
+
+static CHUNKING: ProcessWide<Chunking> = ProcessWide::new("chunking");
+
+pub(crate) fn init(config: &Config) -> ZResult<()> {
+    CHUNKING.init(&config.chunking, || {
+        let Some(conf) = &config.chunking else {
+            return Ok(None);
+        };
+        if conf.chunk_size == 0 {
+            bail!("Invalid 'chunking' configuration: 'chunk_size' must be greater than 0");
+        }
+        if conf.max_pending_size < conf.max_message_size {
+            bail!("Invalid 'chunking' configuration: 'max_pending_size' can't be lower than 'max_message_size'");
+        }
+        Ok(Some(Chunking {
+            config: conf.clone(),
+            next_id: AtomicU64::new(0),
+            pending: Mutex::new(HashMap::new()),
+        }))
+    })?;
+    Ok(())
+}
+
+// The chunks of a payload to be put on Zenoh (as ranges of the payload, with their attachment value),
+// or None if the payload doesn't need to be chunked
+pub(crate) fn split(zid: &ZenohId, len: usize) -> ZResult<Option<Vec<(String, Range<usize>)>>> {
+    let Some(chunking) = CHUNKING.get() else {
+        return Ok(None);
+    };
+    let conf = &chunking.config;
+    if len <= conf.chunk_size {
+        return Ok(None);
+    }
+    if len > conf.max_message_size {
+        bail!(
+            "payload of {} bytes exceeds the maximum chunked message size of {} bytes",
+            len,
+            conf.max_message_size
+        );
+    }
+    let id = format!(
+        "{}-{}",
+        zid,
+        chunking.next_id.fetch_add(1, Ordering::Relaxed)
+    );
+    let count = (len + conf.chunk_size - 1) / conf.chunk_size;
+    Ok(Some(
+        (0..count)
+            .map(|i| {
+                let start = i * conf.chunk_size;
+                let end = (start + conf.chunk_size).min(len);
+                (format!("{id}:{i}:{count}"), start..end)
+            })
+            .collect(),
+    ))
+}
+
+// Parse "<message id>:<index>:<count>"
+fn parse(value: &[u8]) -> Option<(String, usize, usize)> {
+    let value = std::str::from_utf8(value).ok()?;
+    let mut it = value.rsplitn(3, ':');
+    let count: usize = it.next()?.parse().ok()?;
+    let index: usize = it.next()?.parse().ok()?;
+    let id = it.next()?;
+    (index < count).then(|| (id.to_string(), index, count))
+}
+
+// The payload of a sample to be routed to a MQTT client: if the sample is a chunk, the reassembled
+// payload once all the chunks of its message are received (None until then)
+pub(crate) fn reassemble<'a>(
+    client_id: &str,
+    sample: &'a Sample,
+) -> ZResult<Option<Cow<'a, [u8]>>> {
+    let payload = sample.payload.contiguous();
+    let Some(chunking) = CHUNKING.get() else {
+        return Ok(Some(payload));
+    };
+    let Some(value) = sample
+        .attachment()
+        .and_then(|a| a.get(&ATTACHMENT_KEY_CHUNK))
+    else {
+        return Ok(Some(payload));
+    };
+    let Some((id, index, count)) = parse(value.as_slice()) else {
+        bail!(
+            "Invalid chunk received on {}: '{}' attachment is not '<message id>:<index>:<count>'",
+            sample.key_expr,
+            ATTACHMENT_KEY_CHUNK
+        );
+    };
+    let conf = &chunking.config;
+    let timeout = Duration::from_millis(conf.timeout_ms);
+    let mut pending = zlock!(chunking.pending);
+    pending.retain(|(client, id), p| {
+        let expired = p.started.elapsed() > timeout;
+        if expired {
+            log::debug!(
+                "MQTT client {}: drop chunked message {} ({}/{} chunks received within {} ms)",
+                client,
+                id,
+                p.chunks.len(),
+                p.count,
+                conf.timeout_ms
+            );
+        }
+        !expired
+    });
+    let key = (client_id.to_string(), id);
+    let message = pending.entry(key.clone()).or_insert_with(|| Pending {
+        chunks: BTreeMap::new(),
+        count,
+        size: 0,
+        started: Instant::now(),
+    });
+    if message.count != count {
+        pending.remove(&key);
+        bail!(
+            "Invalid chunk received on {}: inconsistent count of chunks for message {}",
+            sample.key_expr,
+            key.1
+        );
+    }
+    if message.chunks.contains_key(&index) {
+        // duplicated chunk
+        return Ok(None);
+    }
+    message.size += payload.len();
+    if message.size > conf.max_message_size {
+        pending.remove(&key);
+        bail!(
+            "Chunked message {} received on {} exceeds the maximum size of {} bytes - dropped",
+            key.1,
+            sample.key_expr,
+            conf.max_message_size
+        );
+    }
+    message.chunks.insert(index, payload.into_owned());
+    if message.chunks.len() < message.count {
+        drop_oldest(&mut pending, conf.max_pending_size);
+        return Ok(None);
+    }
+    let message = pending.remove(&key).expect("pending message");
+    let mut payload = Vec::with_capacity(message.size);
+    for chunk in message.chunks.into_values() {
+        payload.extend_from_slice(&chunk);
+    }
+    Ok(Some(Cow::Owned(payload)))
+}
+
+// Drop the oldest messages being reassembled while their total size exceeds the maximum
+fn drop_oldest(pending: &mut HashMap<(String, String), Pending>, max_size: usize) {
+    let mut size: usize = pending.values().map(|p| p.size).sum();
+    while size > max_size {
+        let Some(oldest) = pending
+            .iter()
+            .min_by_key(|(_, p)| p.started)
+            .map(|(key, _)| key.clone())
+        else {
+            return;
+        };
+        let p = pending.remove(&oldest).expect("pending message");
+        log::debug!(
+            "MQTT client {}: drop chunked message {} ({}/{} chunks received) - more than {} bytes being reassembled",
+            oldest.0,
+            oldest.1,
+            p.chunks.len(),
+            p.count,
+            max_size
+        );
+        size -= p.size;
+    }
+}
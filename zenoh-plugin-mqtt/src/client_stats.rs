@@ -0,0 +1,128 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use zenoh_core::zlock;
+
+use crate::config::ProtocolErrorsConfig;
+
+const ERRORS_WINDOW: Duration = Duration::from_secs(60);
+// the stats of a client without protocol error for this duration are forgotten
+const STATS_RETENTION: Duration = Duration::from_secs(3600);
+
+#[derive(Debug)]
+struct ProtocolErrors {
+    total: u64,
+    last_error: String,
+    last_error_time: SystemTime,
+    last_error_instant: Instant,
+    window_start: Instant,
+    window_count: u32,
+    banned_until: Option<Instant>,
+}
+
+impl ProtocolErrors {
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.map_or(false, |until| now < until)
+    }
+}
+
+// The protocol errors statistics per MQTT client id.
+#[derive(Debug)]
+pub(crate) struct ClientStats {
+    config: Option<ProtocolErrorsConfig>,
+    protocol_errors: Mutex<HashMap<String, ProtocolErrors>>,
+}
+
+impl ClientStats {
+    pub(crate) fn new(config: Option<ProtocolErrorsConfig>) -> ClientStats {
+        ClientStats {
+            config,
+            protocol_errors: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Record a protocol error from a client, banning it if it exceeds the configured rate.
+    pub(crate) fn record_protocol_error(&self, client_id: &str, error: &str) {
+        let now = Instant::now();
+        let mut clients = zlock!(self.protocol_errors);
+        clients.retain(|_, e| {
+            e.is_banned(now) || now.duration_since(e.last_error_instant) < STATS_RETENTION
+        });
+        let stats = clients
+            .entry(client_id.to_string())
+            .or_insert_with(|| ProtocolErrors {
+                total: 0,
+                last_error: String::new(),
+                last_error_time: SystemTime::now(),
+                last_error_instant: now,
+                window_start: now,
+                window_count: 0,
+                banned_until: None,
+            });
+        stats.total += 1;
+        stats.last_error = error.to_string();
+        stats.last_error_time = SystemTime::now();
+        stats.last_error_instant = now;
+        if now.duration_since(stats.window_start) > ERRORS_WINDOW {
+            stats.window_start = now;
+            stats.window_count = 0;
+        }
+        stats.window_count += 1;
+        if let Some(conf) = &self.config {
+            if stats.window_count > conf.max_per_minute && !stats.is_banned(now) {
+                log::warn!(
+                    "MQTT client {} banned for {} seconds: {} protocol errors in the last minute",
+                    client_id,
+                    conf.ban_secs,
+                    stats.window_count
+                );
+                stats.banned_until = Some(now + Duration::from_secs(conf.ban_secs));
+            }
+        }
+    }
+
+    pub(crate) fn is_banned(&self, client_id: &str) -> bool {
+        zlock!(self.protocol_errors)
+            .get(client_id)
+            .map_or(false, |e| e.is_banned(Instant::now()))
+    }
+
+    // The statistics as JSON, for the admin space
+    pub(crate) fn to_json(&self) -> Value {
+        let now = Instant::now();
+        let clients = zlock!(self.protocol_errors);
+        Value::Object(
+            clients
+                .iter()
+                .map(|(client_id, e)| {
+                    (
+                        client_id.clone(),
+                        serde_json::json!({
+                            "protocol_errors": e.total,
+                            "last_error": e.last_error,
+                            "last_error_timestamp": e.last_error_time
+                                .duration_since(UNIX_EPOCH)
+                                .map(|d| d.as_secs())
+                                .unwrap_or_default(),
+                            "banned": e.is_banned(now),
+                        }),
+                    )
+                })
+                .collect(),
+        )
+    }
+}
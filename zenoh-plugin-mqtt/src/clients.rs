@@ -0,0 +1,354 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The registry of the connected MQTT clients, with their subscriptions, for the admin space.
+
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use zenoh::prelude::OwnedKeyExpr;
+use zenoh_core::zlock;
+
+use crate::config::KeChunksConfig;
+use crate::events::ProtocolVersion;
+use crate::fanout::parse_queue_topic;
+use crate::mqtt_helpers::{mqtt_topic_to_ke, MqttSink};
+
+#[derive(Debug)]
+struct Client {
+    // identifies the connection, a client reconnecting with the same id replacing the previous one
+    connection_id: u64,
+    protocol: ProtocolVersion,
+    username: Option<String>,
+    scope: Option<OwnedKeyExpr>,
+    connected_at: SystemTime,
+    negotiated: Negotiated,
+    // true while the client's subscriptions are hibernated
+    hibernated: bool,
+    // set when the re-declaration of the client's zenoh subscribers is requested
+    resubscribe: bool,
+    subscriptions: BTreeSet<String>,
+    sink: MqttSink,
+}
+
+// The connection parameters negotiated with a client
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Negotiated {
+    // the protocol level of the CONNECT packet (3 for MQTT 3.1, 4 for 3.1.1, 5 for 5.0, 1 for MQTT-SN)
+    pub(crate) protocol_level: u8,
+    // the keep alive in seconds (the server keep alive if it overrides the client's one)
+    pub(crate) keep_alive: u16,
+    pub(crate) clean_session: bool,
+    // the maximum packet size accepted by the client (MQTT 5 only)
+    pub(crate) max_packet_size: Option<u32>,
+}
+
+// A count of events, with the number of events during the last complete minute
+#[derive(Debug)]
+struct ChurnCounter {
+    total: u64,
+    minute_start: Instant,
+    current_minute: u64,
+    last_minute: u64,
+}
+
+impl Default for ChurnCounter {
+    fn default() -> Self {
+        ChurnCounter {
+            total: 0,
+            minute_start: Instant::now(),
+            current_minute: 0,
+            last_minute: 0,
+        }
+    }
+}
+
+impl ChurnCounter {
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn roll(&mut self) {
+        let elapsed = self.minute_start.elapsed();
+        if elapsed >= Self::MINUTE {
+            // no event during the last minute if more than 1 minute elapsed since the end of the current one
+            self.last_minute = if elapsed < 2 * Self::MINUTE {
+                self.current_minute
+            } else {
+                0
+            };
+            self.current_minute = 0;
+            self.minute_start = Instant::now();
+        }
+    }
+
+    fn add(&mut self, count: u64) {
+        self.roll();
+        self.total += count;
+        self.current_minute += count;
+    }
+
+    fn to_json(&mut self) -> Value {
+        self.roll();
+        serde_json::json!({
+            "total": self.total,
+            "last_minute": self.last_minute,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ClientsRegistry {
+    clients: Mutex<HashMap<String, Client>>,
+    next_connection_id: AtomicU64,
+    // the subscriptions created and removed (including on disconnection)
+    subscriptions_created: Mutex<ChurnCounter>,
+    subscriptions_removed: Mutex<ChurnCounter>,
+}
+
+impl ClientsRegistry {
+    // Register a connected client, returning the id of its connection
+    pub(crate) fn register(
+        &self,
+        client_id: &str,
+        protocol: ProtocolVersion,
+        username: Option<String>,
+        scope: Option<OwnedKeyExpr>,
+        sink: MqttSink,
+    ) -> u64 {
+        let connection_id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let previous = zlock!(self.clients).insert(
+            client_id.to_string(),
+            Client {
+                connection_id,
+                protocol,
+                username,
+                scope,
+                connected_at: SystemTime::now(),
+                negotiated: Negotiated::default(),
+                hibernated: false,
+                resubscribe: false,
+                subscriptions: BTreeSet::new(),
+                sink,
+            },
+        );
+        // the subscriptions of a replaced connection are removed
+        if let Some(previous) = previous {
+            zlock!(self.subscriptions_removed).add(previous.subscriptions.len() as u64);
+        }
+        connection_id
+    }
+
+    pub(crate) fn unregister(&self, client_id: &str, connection_id: u64) {
+        let mut clients = zlock!(self.clients);
+        if clients
+            .get(client_id)
+            .map_or(false, |c| c.connection_id == connection_id)
+        {
+            if let Some(client) = clients.remove(client_id) {
+                zlock!(self.subscriptions_removed).add(client.subscriptions.len() as u64);
+            }
+        }
+    }
+
+    // Record the connection parameters negotiated with a registered client
+    pub(crate) fn set_negotiated(
+        &self,
+        client_id: &str,
+        connection_id: u64,
+        negotiated: Negotiated,
+    ) {
+        if let Some(client) = zlock!(self.clients)
+            .get_mut(client_id)
+            .filter(|c| c.connection_id == connection_id)
+        {
+            client.negotiated = negotiated;
+        }
+    }
+
+    pub(crate) fn set_hibernated(&self, client_id: &str, connection_id: u64, hibernated: bool) {
+        if let Some(client) = zlock!(self.clients)
+            .get_mut(client_id)
+            .filter(|c| c.connection_id == connection_id)
+        {
+            client.hibernated = hibernated;
+        }
+    }
+
+    // Request the re-declaration of the zenoh subscribers of a client (or of all the clients), returning the key
+    // expressions of their subscriptions (or None if the client is not connected). The subscribers shared with other
+    // clients are re-declared by the caller, and the client's own ones by its session (see take_resubscribe()).
+    pub(crate) fn request_resubscribe(
+        &self,
+        client_id: Option<&str>,
+        ke_chunks: Option<&KeChunksConfig>,
+    ) -> Option<HashSet<String>> {
+        let mut clients = zlock!(self.clients);
+        let mut kes = HashSet::new();
+        let mut found = false;
+        for (id, client) in clients.iter_mut() {
+            if client_id.map_or(true, |client_id| client_id == id) {
+                found = true;
+                client.resubscribe = true;
+                for topic in &client.subscriptions {
+                    let filter = parse_queue_topic(topic).map_or(topic.as_str(), |(_, t)| t);
+                    if let Ok(ke) = mqtt_topic_to_ke(filter, &client.scope, ke_chunks) {
+                        kes.insert(ke.to_string());
+                    }
+                }
+            }
+        }
+        (found || client_id.is_none()).then_some(kes)
+    }
+
+    // Returns true if the re-declaration of the client's zenoh subscribers was requested (resetting the request)
+    pub(crate) fn take_resubscribe(&self, client_id: &str, connection_id: u64) -> bool {
+        zlock!(self.clients)
+            .get_mut(client_id)
+            .filter(|c| c.connection_id == connection_id)
+            .map_or(false, |c| std::mem::take(&mut c.resubscribe))
+    }
+
+    pub(crate) fn subscribed(&self, client_id: &str, topic: &str) {
+        if let Some(client) = zlock!(self.clients).get_mut(client_id) {
+            if client.subscriptions.insert(topic.to_string()) {
+                zlock!(self.subscriptions_created).add(1);
+            }
+        }
+    }
+
+    pub(crate) fn unsubscribed(&self, client_id: &str, topic: &str) {
+        if let Some(client) = zlock!(self.clients).get_mut(client_id) {
+            if client.subscriptions.remove(topic) {
+                zlock!(self.subscriptions_removed).add(1);
+            }
+        }
+    }
+
+    // The subscriptions churn (created and removed, in total and during the last minute), for the admin space
+    pub(crate) fn churn_to_json(&self) -> Value {
+        let active: usize = zlock!(self.clients)
+            .values()
+            .map(|c| c.subscriptions.len())
+            .sum();
+        serde_json::json!({
+            "active": active,
+            "created": zlock!(self.subscriptions_created).to_json(),
+            "removed": zlock!(self.subscriptions_removed).to_json(),
+        })
+    }
+
+    // The sinks of all the clients, per client id
+    pub(crate) fn sink(&self, client_id: &str) -> Option<MqttSink> {
+        zlock!(self.clients).get(client_id).map(|c| c.sink.clone())
+    }
+
+    // The username of a connected client
+    pub(crate) fn username(&self, client_id: &str) -> Option<String> {
+        zlock!(self.clients).get(client_id)?.username.clone()
+    }
+
+    pub(crate) fn sinks(&self) -> Vec<(String, MqttSink)> {
+        zlock!(self.clients)
+            .iter()
+            .map(|(id, c)| (id.clone(), c.sink.clone()))
+            .collect()
+    }
+
+    // The memory used by the publications queued for all the clients
+    pub(crate) fn queued_bytes(&self) -> usize {
+        zlock!(self.clients)
+            .values()
+            .map(|c| c.sink.queued_bytes())
+            .sum()
+    }
+
+    // Disconnect a client. Returns false if it's not connected.
+    pub(crate) fn disconnect(&self, client_id: &str) -> bool {
+        match zlock!(self.clients).get(client_id) {
+            Some(client) => {
+                log::info!("Disconnect MQTT client {} (via admin space)", client_id);
+                client.sink.disconnect();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Disconnect all the clients, returning their number
+    pub(crate) fn disconnect_all(&self) -> usize {
+        let clients = zlock!(self.clients);
+        for client in clients.values() {
+            client.sink.disconnect();
+        }
+        clients.len()
+    }
+
+    // The connected clients as JSON, for the admin space
+    pub(crate) fn to_json(&self) -> Value {
+        let clients = zlock!(self.clients);
+        Value::Object(
+            clients
+                .iter()
+                .map(|(client_id, c)| {
+                    (
+                        client_id.clone(),
+                        serde_json::json!({
+                            "protocol": c.protocol.as_str(),
+                            "username": c.username,
+                            "scope": c.scope.as_ref().map(|s| s.as_str()),
+                            "connected_timestamp": c.connected_at
+                                .duration_since(UNIX_EPOCH)
+                                .map(|d| d.as_secs())
+                                .unwrap_or_default(),
+                            "protocol_level": c.negotiated.protocol_level,
+                            "keep_alive": c.negotiated.keep_alive,
+                            "clean_session": c.negotiated.clean_session,
+                            "max_packet_size": c.negotiated.max_packet_size,
+                            // the listeners don't support TLS
+                            "tls_cipher": Value::Null,
+                            "hibernated": c.hibernated,
+                            "queued_publications": c.sink.queued(),
+                            "link_quality": c.sink.link_quality().to_json(),
+                        }),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    // The routes of the connected clients' subscriptions (MQTT topic filter => zenoh key expression), for the admin space
+    pub(crate) fn routes_to_json(&self, ke_chunks: Option<&KeChunksConfig>) -> Value {
+        let clients = zlock!(self.clients);
+        Value::Object(
+            clients
+                .iter()
+                .map(|(client_id, c)| {
+                    let routes = c
+                        .subscriptions
+                        .iter()
+                        .map(|topic| {
+                            let ke = mqtt_topic_to_ke(topic, &c.scope, ke_chunks).ok();
+                            (
+                                topic.clone(),
+                                ke.map_or(Value::Null, |ke| Value::from(ke.as_str())),
+                            )
+                        })
+                        .collect();
+                    (client_id.clone(), Value::Object(routes))
+                })
+                .collect(),
+        )
+    }
+}
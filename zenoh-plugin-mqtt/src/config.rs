@@ -14,42 +14,1622 @@
 use regex::Regex;
 use serde::de::{Unexpected, Visitor};
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::fmt;
 use zenoh::prelude::*;
+use zenoh::publication::Priority;
+use zenoh::Result as ZResult;
 
 const DEFAULT_MQTT_INTERFACE: &str = "0.0.0.0";
 const DEFAULT_MQTT_PORT: &str = "1883";
+const DEFAULT_MQTT_SN_PORT: &str = "0.0.0.0:1884";
+const DEFAULT_LISTENER_DRAIN_SECS: u64 = 30;
+const DEFAULT_DRAIN_DEADLINE_SECS: u64 = 30;
+const DEFAULT_PUT_RETRY_MAX_RETRIES: u32 = 3;
+const DEFAULT_PUT_RETRY_INITIAL_BACKOFF_MS: u64 = 100;
+const DEFAULT_PUT_RETRY_MAX_BACKOFF_MS: u64 = 5000;
+const DEFAULT_PUT_RETRY_QUEUE_SIZE: usize = 100;
+const DEFAULT_OPENTELEMETRY_SERVICE_NAME: &str = "zenoh-plugin-mqtt";
+const DEFAULT_SOCKET_BACKLOG: i32 = 2048;
+const DEFAULT_SHM_THRESHOLD: usize = 64 * 1024;
+const DEFAULT_SHM_SIZE: usize = 32 * 1024 * 1024;
+const DEFAULT_AUTH_MAX_FAILURES: u32 = 5;
+const DEFAULT_AUTH_LOCKOUT_SECS: u64 = 10;
+const DEFAULT_AUTH_MAX_LOCKOUT_SECS: u64 = 3600;
+const DEFAULT_COALESCE_SUBS_THRESHOLD: usize = 10;
+const DEFAULT_COALESCE_SUBS_DEPTH: usize = 1;
+const DEFAULT_HIBERNATION_IDLE_SECS: u64 = 3600;
+const DEFAULT_STORE_FORWARD_MAX_MESSAGES: usize = 10000;
+const DEFAULT_STORE_FORWARD_FLUSH_INTERVAL_MS: u64 = 1000;
+const DEFAULT_SCRIPTS_MAX_OPERATIONS: u64 = 100_000;
+const DEFAULT_SCRIPTS_MAX_TIME_MS: u64 = 100;
+const DEFAULT_PROTOCOL_ERRORS_BAN_SECS: u64 = 600;
+const DEFAULT_AUTO_BAN_MAX_AUTH_FAILURES: u32 = 10;
+const DEFAULT_AUTO_BAN_BAN_SECS: u64 = 3600;
+const DEFAULT_UNS_META_PREFIX: &str = "_meta";
+const DEFAULT_SHARED_SESSIONS_KEY_PREFIX: &str = "mqtt/sessions";
+const DEFAULT_SHARED_SESSIONS_QUERY_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_CHUNKING_CHUNK_SIZE: usize = 64 * 1024;
+const DEFAULT_CHUNKING_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+const DEFAULT_CHUNKING_TIMEOUT_MS: u64 = 10000;
+const DEFAULT_CHUNKING_MAX_PENDING_SIZE: usize = 64 * 1024 * 1024;
+const DEFAULT_RESOURCE_GUARD_CHECK_INTERVAL_MS: u64 = 1000;
+const DEFAULT_RESOURCE_GUARD_MAX_FDS_RATIO: f64 = 0.9;
+const DEFAULT_RESOURCE_GUARD_MAX_LOOP_LAG_MS: u64 = 1000;
+const DEFAULT_SLOW_CONSUMERS_HIGH_WATER_MARK: usize = 768;
+const DEFAULT_SLOW_CONSUMERS_PERIOD_MS: u64 = 10000;
+const DEFAULT_QUERY_RULES_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_CONGESTION_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_FANOUT_SHARDS: usize = 16;
+const DEFAULT_KE_CACHE_SESSION_SIZE: usize = 256;
+const DEFAULT_KE_CACHE_GLOBAL_SIZE: usize = 4096;
+const DEFAULT_LAST_VALUES_MAX_TOPICS: usize = 10000;
+const DEFAULT_HANDSHAKES_QUEUE_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_ENROLLMENT_TOPIC_PREFIX: &str = "$enroll";
+const DEFAULT_ENROLLMENT_TIMEOUT_MS: u64 = 10000;
+const DEFAULT_ACCESS_WINDOWS_TOLERANCE_SECS: u64 = 30;
+const DEFAULT_PAUSE_MAX_BUFFERED_MESSAGES: usize = 10000;
+const DEFAULT_PAUSE_MAX_BUFFERED_BYTES: usize = 16 * 1024 * 1024;
+const DEFAULT_TENANT_SCOPES_PROPERTY: &str = "scope";
+// the key of the tenant scopes allowed for any client
+const TENANT_SCOPES_ANY_CLIENT: &str = "*";
+const DEFAULT_PRIORITIES_PROPERTY: &str = "priority";
+const DEFAULT_KE_OVERRIDE_PROPERTY: &str = "zenoh-ke";
+const DEFAULT_AUDIT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_AUDIT_MAX_FILES: usize = 5;
+const DEFAULT_LOGGING_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_LOGGING_MAX_FILES: usize = 5;
+// the key of the priority applying to any client
+const PRIORITIES_ANY_CLIENT: &str = "*";
 
+/// The configuration of the MQTT plugin.
+///
+/// It's usually deserialized from the "plugins/mqtt" section of the zenoh configuration (see
+/// `DEFAULT_CONFIG.json5` for the documentation of each setting), but can also be built programmatically
+/// with [`Config::builder()`] (or from [`Config::default()`]) and inserted in a zenoh configuration with
+/// [`Config::insert_into()`]. A configuration serialized and deserialized back is equivalent, except for
+/// the secrets (e.g. the encryption key) which are never serialized.
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
+    /// The address the MQTT server binds (`<ip>:<port>`).
     #[serde(
         default = "default_mqtt_port",
         deserialize_with = "deserialize_mqtt_port"
     )]
     pub port: String,
+    /// The time (in seconds) given to the connected clients to disconnect when the server is drained.
+    #[serde(default = "default_listener_drain_secs")]
+    pub listener_drain_secs: u64,
+    /// The deadline (in seconds) for the in-flight publications when the server is drained.
+    #[serde(default = "default_drain_deadline_secs")]
+    pub drain_deadline_secs: u64,
+    /// The condition for binding the MQTT server (and the MQTT-SN gateway).
+    #[serde(default)]
+    pub bind_on: BindCondition,
+    /// The options of the MQTT server socket.
+    #[serde(default)]
+    pub socket: Option<SocketConfig>,
+    /// If set, the MQTT-SN gateway configuration.
+    #[serde(default)]
+    pub mqtt_sn: Option<MqttSnConfig>,
+    /// If set, the keep alive (in seconds) imposed to the MQTT 5 clients.
+    #[serde(default)]
+    pub server_keep_alive: Option<u16>,
+    /// If set, the maximum session expiry interval (in seconds) granted to the MQTT 5 clients.
+    #[serde(default)]
+    pub max_session_expiry: Option<u32>,
+    /// If true, the bridge identity is sent as user properties in the MQTT 5 CONNACK.
+    #[serde(default)]
+    pub connack_identity: bool,
+    /// If set, the memory budget (in bytes) of the publications queued for all the clients.
+    #[serde(default)]
+    pub max_inflight_bytes: Option<usize>,
+    /// If set, the limits of resources above which the new connections are refused.
+    #[serde(default)]
+    pub resource_guard: Option<ResourceGuardConfig>,
+    /// If set, the limit of the MQTT handshakes processed concurrently.
+    #[serde(default)]
+    pub handshakes: Option<HandshakesConfig>,
+    /// If set, the detection of the clients not consuming their publications fast enough, and the policy applied to them.
+    #[serde(default)]
+    pub slow_consumers: Option<SlowConsumersConfig>,
+    /// If set, the maximum length (in bytes) of a topic.
+    #[serde(default)]
+    pub max_topic_length: Option<usize>,
+    /// If set, the maximum number of levels of a topic.
+    #[serde(default)]
+    pub max_topic_levels: Option<usize>,
+    /// If set, the maximum number of chunks of the key expressions mapped from the MQTT topics.
+    #[serde(default)]
+    pub ke_chunks: Option<KeChunksConfig>,
+    /// If set, the maximum number of distinct topics a client may publish to during its connection.
+    #[serde(default)]
+    pub max_published_topics: Option<usize>,
+    /// If set, the prefix added to the key expressions mapped from the MQTT topics.
     #[serde(default)]
     pub scope: Option<OwnedKeyExpr>,
+    /// If set, the prefix added (below `scope`) for the MQTT 3.1.1 clients.
+    #[serde(default)]
+    pub scope_v3: Option<OwnedKeyExpr>,
+    /// If set, the prefix added (below `scope`) for the MQTT 5 clients.
+    #[serde(default)]
+    pub scope_v5: Option<OwnedKeyExpr>,
+    /// If set, the regular expression matching the topics routed over zenoh.
     #[serde(
         default,
         deserialize_with = "deserialize_regex",
-        serialize_with = "serialize_allow"
+        serialize_with = "serialize_regex",
+        skip_serializing_if = "Option::is_none"
     )]
     pub allow: Option<Regex>,
+    /// If set, the regular expression matching the topics not routed over zenoh.
     #[serde(
         default,
         deserialize_with = "deserialize_regex",
-        serialize_with = "serialize_deny"
+        serialize_with = "serialize_regex",
+        skip_serializing_if = "Option::is_none"
     )]
     pub deny: Option<Regex>,
+    /// The MQTT topic filters a wildcard subscription not allowed by 'allow'/'deny' is narrowed to over zenoh.
+    #[serde(default)]
+    pub allowed_filters: Vec<String>,
+    /// If set, the time windows out of which the matching topics are not routed.
+    #[serde(default)]
+    pub access_windows: Option<AccessWindowsConfig>,
+    /// The origin of the zenoh publications routed to the MQTT subscribers.
+    #[serde(default)]
+    pub allowed_origin: Option<SubscriberOrigin>,
+    /// If true, the publications of a client are not routed back to its own subscriptions.
+    #[serde(default)]
+    pub no_local: bool,
+    /// The key expressions used to generalise the zenoh subscriptions.
+    #[serde(default)]
+    pub generalise_subs: Vec<OwnedKeyExpr>,
+    /// The key expressions used to generalise the zenoh publications.
+    #[serde(default)]
+    pub generalise_pubs: Vec<OwnedKeyExpr>,
+    /// If set, the format of the envelope wrapping the payloads routed to zenoh (with their MQTT metadata).
+    #[serde(default)]
+    pub envelope: Option<EnvelopeFormat>,
+    /// If set, the delivery receipts of the QoS 1 publications configuration.
+    #[serde(default)]
+    pub delivery_receipts: Option<DeliveryReceiptsConfig>,
+    /// If set, the Unified Namespace validation and metadata configuration.
+    #[serde(default)]
+    pub uns: Option<UnsConfig>,
+    /// If set, the persistent sessions shared between bridges configuration.
+    #[serde(default)]
+    pub shared_sessions: Option<SharedSessionsConfig>,
+    /// If set, the restrictions of the subscriptions on the whole key space.
+    #[serde(default)]
+    pub root_wildcard: Option<RootWildcardConfig>,
+    /// If set, the coalescing of the sibling subscriptions of a client into a single zenoh subscriber.
+    #[serde(default)]
+    pub coalesce_subs: Option<CoalesceSubsConfig>,
+    /// If set, the hibernation of the subscriptions of the idle clients.
+    #[serde(default)]
+    pub hibernation: Option<HibernationConfig>,
+    /// The selection of the member of a queue group receiving a publication.
+    #[serde(default)]
+    pub queue_strategy: QueueStrategy,
+    /// If set, the time (in seconds) a queue group subscriber without member is kept.
+    #[serde(default)]
+    pub stale_subscribers_secs: Option<u64>,
+    /// The number of shards of the registry of the zenoh subscribers shared per topic filter.
+    #[serde(default = "default_fanout_shards")]
+    pub fanout_shards: usize,
+    /// The reliability of the zenoh subscribers, per QoS of the MQTT subscriptions.
+    #[serde(default)]
+    pub subscriber_reliability: SubscriberReliabilityConfig,
+    /// The maximum numbers of in-flight zenoh publications, per topic filter.
+    #[serde(default)]
+    pub max_inflight_puts: Vec<InflightPutsConfig>,
+    /// The routing (or rejection) rules of the large payloads, per topic filter.
+    #[serde(default)]
+    pub large_payloads: Vec<LargePayloadsConfig>,
+    /// The policies of the QoS 1 publications whose routing is blocked by a congestion of zenoh, per topic filter.
+    #[serde(default)]
+    pub congestion: Vec<CongestionRuleConfig>,
+    /// The additional scopes into which the publications are copied, per topic filter.
+    #[serde(default)]
+    pub scope_copies: Vec<ScopeCopiesConfig>,
+    /// If set, the chunking of the large payloads routed between bridges.
+    #[serde(default)]
+    pub chunking: Option<ChunkingConfig>,
+    /// If set, the caches of the key expressions mapped from the publication topics.
+    #[serde(default)]
+    pub ke_cache: Option<KeCacheConfig>,
+    /// If set, the maximum number of allow/deny decisions cached per MQTT client.
+    #[serde(default)]
+    pub acl_cache_size: Option<usize>,
+    /// If set, the cache of the last values of the MQTT publications, answering the zenoh queries.
+    #[serde(default)]
+    pub last_values: Option<LastValuesConfig>,
+    /// If set, the limits of the buffering of the MQTT publications while the routing is paused.
+    #[serde(default)]
+    pub pause: Option<PauseConfig>,
+    /// The rules making a publication on a trigger topic query zenoh.
+    #[serde(default)]
+    pub query_rules: Vec<QueryRuleConfig>,
+    /// If set, the retry of the zenoh publications that failed.
+    #[serde(default)]
+    pub put_retry: Option<PutRetryConfig>,
+    /// If set, the storage of the publications that failed, forwarded later.
+    #[serde(default)]
+    pub store_forward: Option<StoreForwardConfig>,
+    /// If set, the scripts rewriting the publications.
+    #[serde(default)]
+    pub scripts: Option<ScriptsConfig>,
+    /// If set, the encryption of the payloads routed to zenoh (its key is never serialized).
+    #[serde(default)]
+    pub encryption: Option<EncryptionConfig>,
+    /// If set, the pseudonymization of topic levels (its key is never serialized).
+    #[serde(default)]
+    pub pseudonyms: Option<PseudonymsConfig>,
+    /// If set, the authentication of the MQTT clients.
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    /// If set, the clients' publications are rejected or dropped (the bridge being a read-only window on zenoh).
+    #[serde(default)]
+    pub read_only: Option<ReadOnlyMode>,
+    /// If true, the clients' subscriptions are refused (the bridge only routing their publications to zenoh).
+    #[serde(default)]
+    pub write_only: bool,
+    /// If true, the MQTT specification rules otherwise tolerated are enforced (client ids and topics).
+    #[serde(default)]
+    pub strict: bool,
+    /// If set, the filter, output file and format of the plugin's log (if no logger is installed before the plugin).
+    #[serde(default)]
+    pub logging: Option<LoggingConfig>,
+    /// If set, the audit log of the clients connections, authentications and ACL denials.
+    #[serde(default)]
+    pub audit: Option<AuditConfig>,
+    /// If set, the credential required by the sensitive operations of the admin space.
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+    /// If set, the recording of the routed messages in a file (to be replayed via the admin space).
+    #[serde(default)]
+    pub record: Option<RecordConfig>,
+    /// If set, the replay of the recordings (via the admin space) is enabled, for the files in its directory.
+    #[serde(default)]
+    pub replay: Option<ReplayConfig>,
+    /// If set, the forwarding of the certificate signing requests of the clients to an enrollment service.
+    #[serde(default)]
+    pub enrollment: Option<EnrollmentConfig>,
+    /// If set, the provisioning of the clients connecting for the 1st time.
+    #[serde(default)]
+    pub provisioning: Option<ProvisioningConfig>,
+    /// If set, the bridge is part of an active/passive group (e.g. behind a floating IP): only the elected active
+    /// instance accepts MQTT connections.
+    #[serde(default)]
+    pub ha: Option<HaConfig>,
+    /// If set, the scopes the clients can select at connection.
+    #[serde(default)]
+    pub tenant_scopes: Option<TenantScopesConfig>,
+    /// If set, the key expressions the trusted clients can select for their publications.
+    #[serde(default)]
+    pub ke_override: Option<KeOverrideConfig>,
+    /// If set, the zenoh priorities of the clients' publications.
+    #[serde(default)]
+    pub priorities: Option<PrioritiesConfig>,
+    /// If set, the handling of the clients' protocol errors.
+    #[serde(default)]
+    pub protocol_errors: Option<ProtocolErrorsConfig>,
+    /// If set, the banned clients and addresses.
+    #[serde(default)]
+    pub bans: Option<BansConfig>,
+    /// If set, the export of traces and metrics to an OpenTelemetry collector.
+    #[serde(default)]
+    pub opentelemetry: Option<OpenTelemetryConfig>,
+    /// If set, the use of shared memory for the large payloads.
+    #[serde(default)]
+    pub shm: Option<ShmConfig>,
+    #[serde(default, skip_serializing)]
+    __required__: bool,
+    #[serde(default, skip_serializing, deserialize_with = "deserialize_paths")]
+    __path__: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        serde_json::from_value(serde_json::Value::Object(Default::default()))
+            .expect("the default MQTT plugin configuration must be valid")
+    }
+}
+
+impl Config {
+    /// Returns a builder of a configuration, starting from the default one.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder {
+            config: Config::default(),
+            error: None,
+        }
+    }
+
+    /// Insert this configuration as the "plugins/mqtt" section of a zenoh configuration
+    /// (without the secrets, which are never serialized).
+    pub fn insert_into(&self, config: &mut zenoh::config::Config) -> ZResult<()> {
+        let json = serde_json::to_string(self)?;
+        config
+            .insert_json5("plugins/mqtt", &json)
+            .map_err(|e| zerror!("Failed to insert the MQTT plugin configuration: {:?}", e))?;
+        Ok(())
+    }
+
+    /// The SHA-256 hash (as an hexadecimal string) of the configuration serialized as JSON.
+    /// Secrets are never serialized, so they're not part of the hash.
+    pub fn hash(&self) -> String {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        hex::encode(Sha256::digest(json.as_bytes()))
+    }
+
+    // The configuration applying to the MQTT-SN clients, if the MQTT-SN gateway overrides
+    // the 'scope', 'allow' or 'deny' settings
+    pub(crate) fn for_mqtt_sn(&self) -> Option<Config> {
+        let sn = self.mqtt_sn.as_ref()?;
+        if sn.scope.is_none() && sn.allow.is_none() && sn.deny.is_none() {
+            return None;
+        }
+        let mut config = self.clone();
+        if sn.scope.is_some() {
+            config.scope = sn.scope.clone();
+        }
+        if sn.allow.is_some() {
+            config.allow = sn.allow.clone();
+        }
+        if sn.deny.is_some() {
+            config.deny = sn.deny.clone();
+        }
+        Some(config)
+    }
+}
+
+/// A builder of [`Config`], for the most common settings.
+/// The other ones can be set with [`ConfigBuilder::with()`], or on the built configuration whose fields are public.
+#[derive(Debug)]
+pub struct ConfigBuilder {
+    config: Config,
+    // the first invalid setting, returned by build()
+    error: Option<String>,
+}
+
+impl ConfigBuilder {
+    fn fail(mut self, error: String) -> Self {
+        self.error.get_or_insert(error);
+        self
+    }
+
+    /// The address the MQTT server binds: a port number, or `<ip>:<port>`.
+    pub fn port(mut self, port: &str) -> Self {
+        match MqttPortVisitor.visit_str::<de::value::Error>(port) {
+            Ok(port) => {
+                self.config.port = port;
+                self
+            }
+            Err(e) => self.fail(format!("Invalid port '{port}': {e}")),
+        }
+    }
+
+    /// The prefix added to the key expressions mapped from the MQTT topics.
+    pub fn scope(mut self, scope: &str) -> Self {
+        match OwnedKeyExpr::try_from(scope) {
+            Ok(scope) => {
+                self.config.scope = Some(scope);
+                self
+            }
+            Err(e) => self.fail(format!("Invalid scope '{scope}': {e}")),
+        }
+    }
+
+    /// The regular expression matching the topics routed over zenoh.
+    pub fn allow(mut self, allow: &str) -> Self {
+        match Regex::new(allow) {
+            Ok(re) => {
+                self.config.allow = Some(re);
+                self
+            }
+            Err(e) => self.fail(format!("Invalid regex 'allow={allow}': {e}")),
+        }
+    }
+
+    /// The regular expression matching the topics not routed over zenoh.
+    pub fn deny(mut self, deny: &str) -> Self {
+        match Regex::new(deny) {
+            Ok(re) => {
+                self.config.deny = Some(re);
+                self
+            }
+            Err(e) => self.fail(format!("Invalid regex 'deny={deny}': {e}")),
+        }
+    }
+
+    /// Add a key expression used to generalise the zenoh subscriptions.
+    pub fn generalise_sub(mut self, ke: &str) -> Self {
+        match OwnedKeyExpr::try_from(ke) {
+            Ok(ke) => {
+                self.config.generalise_subs.push(ke);
+                self
+            }
+            Err(e) => self.fail(format!("Invalid key expression '{ke}': {e}")),
+        }
+    }
+
+    /// Add a key expression used to generalise the zenoh publications.
+    pub fn generalise_pub(mut self, ke: &str) -> Self {
+        match OwnedKeyExpr::try_from(ke) {
+            Ok(ke) => {
+                self.config.generalise_pubs.push(ke);
+                self
+            }
+            Err(e) => self.fail(format!("Invalid key expression '{ke}': {e}")),
+        }
+    }
+
+    /// Modify any other setting of the configuration.
+    pub fn with(mut self, f: impl FnOnce(&mut Config)) -> Self {
+        f(&mut self.config);
+        self
+    }
+
+    /// Returns the configuration, or the error of the first invalid setting.
+    pub fn build(self) -> ZResult<Config> {
+        match self.error {
+            Some(e) => bail!("Invalid MQTT plugin configuration: {}", e),
+            None => Ok(self.config),
+        }
+    }
+}
+
+/// The options of the MQTT server socket (inherited by the accepted connections).
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SocketConfig {
+    /// If true, TCP_NODELAY is set (i.e. Nagle's algorithm is disabled).
+    #[serde(default)]
+    pub nodelay: bool,
+    /// If set, the idle time (in seconds) before the TCP keepalive probes are sent.
+    #[serde(default)]
+    pub keepalive_secs: Option<u64>,
+    /// If true, SO_REUSEPORT is set (Unix only), to balance the connections between several processes.
+    #[serde(default)]
+    pub reuseport: bool,
+    /// The maximum length of the queue of pending connections.
+    #[serde(default = "default_socket_backlog")]
+    pub backlog: i32,
+}
+
+fn default_socket_backlog() -> i32 {
+    DEFAULT_SOCKET_BACKLOG
+}
+
+/// The format of the envelope wrapping the payloads routed to zenoh.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvelopeFormat {
+    /// CBOR (encoding "application/cbor").
+    Cbor,
+    /// JSON (encoding "application/json").
+    Json,
+}
+
+/// The origin of the zenoh publications routed to the MQTT subscribers.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubscriberOrigin {
+    /// The publications from anywhere, including the other MQTT clients of this plugin.
+    Any,
+    /// Only the publications routed via the zenoh routers.
+    Remote,
+}
+
+/// The delivery receipts of the QoS 1 publications.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DeliveryReceiptsConfig {
+    /// The key expression prefix on which the receipts are published.
+    pub prefix: OwnedKeyExpr,
+}
+
+/// The selection of the member of a queue group receiving a publication.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueStrategy {
+    /// Each member in turn.
+    #[default]
+    RoundRobin,
+    /// A member picked at random.
+    Random,
+}
+
+/// The condition for binding the MQTT server (and the MQTT-SN gateway).
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BindCondition {
+    /// Once the zenoh session is open.
+    #[default]
+    SessionOpen,
+    /// Once the zenoh session is connected to a zenoh router or peer.
+    Connected,
+}
+
+/// The MQTT-SN gateway configuration.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MqttSnConfig {
+    /// The address the MQTT-SN UDP listener binds (`<ip>:<port>`).
+    #[serde(default = "default_mqtt_sn_port")]
+    pub port: String,
+    /// If set, overrides the `scope` setting for the MQTT-SN clients.
+    #[serde(default)]
+    pub scope: Option<OwnedKeyExpr>,
+    /// If set, overrides the `allow` setting for the MQTT-SN clients.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_regex",
+        serialize_with = "serialize_regex",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub allow: Option<Regex>,
+    /// If set, overrides the `deny` setting for the MQTT-SN clients.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_regex",
+        serialize_with = "serialize_regex",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub deny: Option<Regex>,
+}
+
+fn default_mqtt_sn_port() -> String {
+    DEFAULT_MQTT_SN_PORT.into()
+}
+
+/// The Unified Namespace validation and metadata configuration.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UnsConfig {
+    /// The names of the hierarchy levels (a topic must have at least as many levels).
+    pub levels: Vec<String>,
+    /// If set, the maximum number of levels of a topic.
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+    /// If set, the regular expression the topics must match.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_regex",
+        serialize_with = "serialize_uns_pattern"
+    )]
+    pub pattern: Option<Regex>,
+    /// The key expression prefix of the nodes metadata.
+    #[serde(default = "default_uns_meta_prefix")]
+    pub meta_prefix: OwnedKeyExpr,
+    /// The descriptions of the nodes, per node path.
+    #[serde(default)]
+    pub descriptions: HashMap<String, String>,
+}
+
+fn default_uns_meta_prefix() -> OwnedKeyExpr {
+    DEFAULT_UNS_META_PREFIX.parse().unwrap()
+}
+
+/// The persistent sessions shared between bridges configuration.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SharedSessionsConfig {
+    /// The key expression prefix on which the sessions are published.
+    #[serde(default = "default_shared_sessions_key_prefix")]
+    pub key_prefix: OwnedKeyExpr,
+    /// The timeout (in milliseconds) of the query fetching the session of a reconnecting client.
+    #[serde(default = "default_shared_sessions_query_timeout_ms")]
+    pub query_timeout_ms: u64,
+}
+
+fn default_shared_sessions_key_prefix() -> OwnedKeyExpr {
+    DEFAULT_SHARED_SESSIONS_KEY_PREFIX.parse().unwrap()
+}
+
+fn default_shared_sessions_query_timeout_ms() -> u64 {
+    DEFAULT_SHARED_SESSIONS_QUERY_TIMEOUT_MS
+}
+
+/// The restrictions of the subscriptions on the whole key space.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RootWildcardConfig {
+    /// If true, such subscriptions are refused.
+    #[serde(default)]
+    pub deny: bool,
+    /// If set, the maximum number of publications per second routed to such a subscription.
+    #[serde(default)]
+    pub max_rate: Option<u32>,
+}
+
+/// The reliability of a zenoh subscriber.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriberReliability {
+    /// Best effort.
+    BestEffort,
+    /// Reliable.
+    Reliable,
+}
+
+/// The reliability of the zenoh subscribers, per QoS of the MQTT subscription.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SubscriberReliabilityConfig {
+    /// The reliability for the QoS 0 subscriptions.
+    #[serde(default = "default_qos0_reliability")]
+    pub qos0: SubscriberReliability,
+    /// The reliability for the QoS 1 subscriptions (also used for the QoS 2 subscriptions).
+    #[serde(default = "default_qos1_reliability")]
+    pub qos1: SubscriberReliability,
+}
+
+impl Default for SubscriberReliabilityConfig {
+    fn default() -> Self {
+        SubscriberReliabilityConfig {
+            qos0: default_qos0_reliability(),
+            qos1: default_qos1_reliability(),
+        }
+    }
+}
+
+fn default_qos0_reliability() -> SubscriberReliability {
+    SubscriberReliability::BestEffort
+}
+
+fn default_qos1_reliability() -> SubscriberReliability {
+    SubscriberReliability::Reliable
+}
+
+/// The coalescing of the subscriptions of a client under a same root.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CoalesceSubsConfig {
+    /// The number of subscriptions under a same root above which they are coalesced.
+    #[serde(default = "default_coalesce_subs_threshold")]
+    pub threshold: usize,
+    /// The number of levels of the topics making their root.
+    #[serde(default = "default_coalesce_subs_depth")]
+    pub depth: usize,
+}
+
+fn default_coalesce_subs_threshold() -> usize {
+    DEFAULT_COALESCE_SUBS_THRESHOLD
+}
+
+fn default_coalesce_subs_depth() -> usize {
+    DEFAULT_COALESCE_SUBS_DEPTH
+}
+
+/// The hibernation of the subscriptions of the idle clients.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HibernationConfig {
+    /// The idle time (in seconds) after which the subscriptions of a client are hibernated.
+    #[serde(default = "default_hibernation_idle_secs")]
+    pub idle_secs: u64,
+}
+
+fn default_hibernation_idle_secs() -> u64 {
+    DEFAULT_HIBERNATION_IDLE_SECS
+}
+
+/// The authentication of the MQTT clients with a username/password dictionary.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    /// The path of the file of the allowed `<username>:<password>` pairs, one per line.
+    pub dictionary_file: String,
+    /// The time (in seconds) a successful authentication is cached (0 for no cache).
+    #[serde(default)]
+    pub cache_ttl_secs: u64,
+    /// The number of consecutive failures from a same client id or IP address leading to a lockout.
+    #[serde(default = "default_auth_max_failures")]
+    pub max_failures: u32,
+    /// The initial lockout period (in seconds), doubled at each new failure.
+    #[serde(default = "default_auth_lockout_secs")]
+    pub lockout_secs: u64,
+    /// The maximum lockout period (in seconds).
+    #[serde(default = "default_auth_max_lockout_secs")]
+    pub max_lockout_secs: u64,
+}
+
+fn default_auth_max_failures() -> u32 {
+    DEFAULT_AUTH_MAX_FAILURES
+}
+
+fn default_auth_lockout_secs() -> u64 {
+    DEFAULT_AUTH_LOCKOUT_SECS
+}
+
+fn default_auth_max_lockout_secs() -> u64 {
+    DEFAULT_AUTH_MAX_LOCKOUT_SECS
+}
+
+/// The handling of the MQTT publications when the bridge is read-only.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadOnlyMode {
+    /// The publications are rejected (nack for MQTT 5 clients).
+    Reject,
+    /// The publications are silently dropped.
+    Drop,
+}
+
+/// The time windows out of which the matching topics are not routed.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AccessWindowsConfig {
+    /// The rules, each matching some topics with a window.
+    pub rules: Vec<AccessWindowRule>,
+    /// The widening of the windows (in seconds) for the clock skew between the clients and the bridge.
+    #[serde(default = "default_access_windows_tolerance_secs")]
+    pub tolerance_secs: u64,
+}
+
+fn default_access_windows_tolerance_secs() -> u64 {
+    DEFAULT_ACCESS_WINDOWS_TOLERANCE_SECS
+}
+
+/// A rule of the access windows: the topics it matches and their window (in UTC).
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AccessWindowRule {
+    /// The regular expression matching the topics of the rule.
+    pub topics: String,
+    /// If set, the time the window opens (`YYYY-MM-DDTHH:MM:SSZ`).
+    #[serde(default)]
+    pub start: Option<String>,
+    /// If set, the time the window closes (`YYYY-MM-DDTHH:MM:SSZ`).
+    #[serde(default)]
+    pub end: Option<String>,
+    /// If set, the daily window (`HH:MM-HH:MM`, possibly crossing midnight).
+    #[serde(default)]
+    pub daily: Option<String>,
+    /// The days the daily window opens (`sun` to `sat`), all if empty.
+    #[serde(default)]
+    pub days: Vec<String>,
+}
+
+/// The maximum number of chunks of the key expressions mapped from the MQTT topics.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KeChunksConfig {
+    /// The maximum number of chunks (below the scope).
+    pub max: usize,
+    /// The handling of the topics with more levels.
+    #[serde(default)]
+    pub overflow: KeChunksOverflow,
+}
+
+/// The handling of the topics with more levels than the maximum number of chunks.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeChunksOverflow {
+    /// The excess levels are merged into the last chunk.
+    #[default]
+    Merge,
+    /// The topic is rejected.
+    Reject,
+}
+
+/// The filter, output and format of the log.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// If set, the log filter (with the `RUST_LOG` syntax).
+    #[serde(default)]
+    pub filter: Option<String>,
+    /// If set, the path of the log file (instead of stderr).
+    #[serde(default)]
+    pub file: Option<String>,
+    /// The size (in bytes) above which the log file is rotated.
+    #[serde(default = "default_logging_max_file_size")]
+    pub max_file_size: u64,
+    /// The number of rotated log files kept.
+    #[serde(default = "default_logging_max_files")]
+    pub max_files: usize,
+    /// The format of the log records.
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+fn default_logging_max_file_size() -> u64 {
+    DEFAULT_LOGGING_MAX_FILE_SIZE
+}
+
+fn default_logging_max_files() -> usize {
+    DEFAULT_LOGGING_MAX_FILES
+}
+
+/// The format of the log records.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// A line of text per record.
+    #[default]
+    Text,
+    /// A JSON object per line.
+    Json,
+}
+
+/// The audit log of the connections, authentications, ACL denials and admin operations.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+    /// If set, the path of the audit log file.
+    #[serde(default)]
+    pub file: Option<String>,
+    /// The size (in bytes) above which the audit log file is rotated.
+    #[serde(default = "default_audit_max_file_size")]
+    pub max_file_size: u64,
+    /// The number of rotated audit log files kept.
+    #[serde(default = "default_audit_max_files")]
+    pub max_files: usize,
+    /// If set, the key expression on which the audit records are published.
+    #[serde(default)]
+    pub key_expr: Option<OwnedKeyExpr>,
+}
+
+fn default_audit_max_file_size() -> u64 {
+    DEFAULT_AUDIT_MAX_FILE_SIZE
+}
+
+fn default_audit_max_files() -> usize {
+    DEFAULT_AUDIT_MAX_FILES
+}
+
+/// The recording of the routed messages.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RecordConfig {
+    /// The path of the file the messages are recorded to (as JSON lines).
+    pub file: String,
+    /// The MQTT topic filter of the recorded messages.
+    #[serde(default = "default_record_topic")]
+    pub topic: String,
+}
+
+fn default_record_topic() -> String {
+    "#".into()
+}
+
+/// The replay of the recordings via the admin space.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ReplayConfig {
+    /// The directory of the recordings that can be replayed.
+    pub dir: String,
+}
+
+/// The provisioning events of the never-seen client ids.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ProvisioningConfig {
+    /// The key expression prefix of the provisioning events.
+    pub key_expr: OwnedKeyExpr,
+    /// If set, the event is queried, and the CONNACK held until its 1st reply (or this timeout in milliseconds).
+    #[serde(default)]
+    pub wait_ms: Option<u64>,
+    /// If true, the connection is rejected if the query gets no reply in time.
+    #[serde(default)]
+    pub reject_on_timeout: bool,
+    /// If set, the file where the already seen client ids are saved.
+    #[serde(default)]
+    pub seen_file: Option<String>,
+}
+
+/// The forwarding of the certificate signing requests to an enrollment service.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct EnrollmentConfig {
+    /// The key expression prefix of the queries to the enrollment service.
+    pub key_expr: OwnedKeyExpr,
+    /// The prefix of the enrollment topics (not routed over zenoh).
+    #[serde(default = "default_enrollment_topic_prefix")]
+    pub topic_prefix: String,
+    /// The timeout (in milliseconds) of the query to the enrollment service.
+    #[serde(default = "default_enrollment_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_enrollment_topic_prefix() -> String {
+    DEFAULT_ENROLLMENT_TOPIC_PREFIX.into()
+}
+
+fn default_enrollment_timeout_ms() -> u64 {
+    DEFAULT_ENROLLMENT_TIMEOUT_MS
+}
+
+/// The active/passive group of bridges this bridge is part of.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HaConfig {
+    /// The key expression prefix of the liveliness tokens of the group.
+    pub key_expr: OwnedKeyExpr,
+    /// If set, the id of the bridge in the group (the zenoh id otherwise).
     #[serde(default)]
-    pub generalise_subs: Vec<OwnedKeyExpr>,
+    pub id: Option<String>,
+}
+
+/// The tenant scopes the MQTT 5 clients can select at connection.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TenantScopesConfig {
+    /// The name of the CONNECT user property selecting the tenant scope.
+    #[serde(default = "default_tenant_scopes_property")]
+    pub property: String,
+    /// The tenant scopes allowed per username (`*` for any client).
+    pub allowed: HashMap<String, Vec<OwnedKeyExpr>>,
+}
+
+impl TenantScopesConfig {
+    /// Returns true if the client with this username is allowed to select this tenant scope.
+    pub fn is_allowed(&self, username: Option<&str>, scope: &keyexpr) -> bool {
+        username
+            .and_then(|u| self.allowed.get(u))
+            .into_iter()
+            .chain(self.allowed.get(TENANT_SCOPES_ANY_CLIENT))
+            .flatten()
+            .any(|s| **s == *scope)
+    }
+}
+
+fn default_tenant_scopes_property() -> String {
+    DEFAULT_TENANT_SCOPES_PROPERTY.into()
+}
+
+/// The exact key expressions the trusted MQTT 5 clients can publish on.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KeOverrideConfig {
+    /// The name of the PUBLISH user property setting the key expression.
+    #[serde(default = "default_ke_override_property")]
+    pub property: String,
+    /// The key expressions (possibly with wildcards) including the ones allowed per username (`*` for any client).
+    pub allowed: HashMap<String, Vec<OwnedKeyExpr>>,
+}
+
+impl KeOverrideConfig {
+    /// Returns true if the client with this username is allowed to publish on this key expression.
+    pub fn is_allowed(&self, username: Option<&str>, ke: &keyexpr) -> bool {
+        username
+            .and_then(|u| self.allowed.get(u))
+            .into_iter()
+            .chain(self.allowed.get(TENANT_SCOPES_ANY_CLIENT))
+            .flatten()
+            .any(|allowed| allowed.includes(ke))
+    }
+}
+
+fn default_ke_override_property() -> String {
+    DEFAULT_KE_OVERRIDE_PROPERTY.into()
+}
+
+/// The zenoh priorities, from the highest to the lowest.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum PublicationPriority {
+    /// The real time priority.
+    RealTime,
+    /// The interactive high priority.
+    InteractiveHigh,
+    /// The interactive low priority.
+    InteractiveLow,
+    /// The data high priority.
+    DataHigh,
+    /// The data priority (zenoh's default).
+    Data,
+    /// The data low priority.
+    DataLow,
+    /// The background priority.
+    Background,
+}
+
+impl From<PublicationPriority> for Priority {
+    fn from(priority: PublicationPriority) -> Self {
+        match priority {
+            PublicationPriority::RealTime => Priority::RealTime,
+            PublicationPriority::InteractiveHigh => Priority::InteractiveHigh,
+            PublicationPriority::InteractiveLow => Priority::InteractiveLow,
+            PublicationPriority::DataHigh => Priority::DataHigh,
+            PublicationPriority::Data => Priority::Data,
+            PublicationPriority::DataLow => Priority::DataLow,
+            PublicationPriority::Background => Priority::Background,
+        }
+    }
+}
+
+/// The priorities of the publications of the trusted MQTT clients.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PrioritiesConfig {
+    /// The name of the CONNECT user property requesting a priority.
+    #[serde(default = "default_priorities_property")]
+    pub property: String,
+    /// The priority per username (`*` for any other client), which is the highest one it may request.
+    pub users: HashMap<String, PublicationPriority>,
+}
+
+impl PrioritiesConfig {
+    /// Returns the priority of the publications of the client with this username, if it didn't request one.
+    pub fn profile(&self, username: Option<&str>) -> Option<PublicationPriority> {
+        username
+            .and_then(|u| self.users.get(u))
+            .or_else(|| self.users.get(PRIORITIES_ANY_CLIENT))
+            .copied()
+    }
+
+    /// Returns true if the client with this username is allowed to request this priority
+    /// (i.e. its profile priority or a lower one).
+    pub fn is_allowed(&self, username: Option<&str>, priority: PublicationPriority) -> bool {
+        self.profile(username).map_or(false, |max| priority >= max)
+    }
+}
+
+fn default_priorities_property() -> String {
+    DEFAULT_PRIORITIES_PROPERTY.into()
+}
+
+/// The ban lists and allow lists of the MQTT clients.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BansConfig {
+    /// The regular expressions matching the banned client ids.
     #[serde(default)]
-    pub generalise_pubs: Vec<OwnedKeyExpr>,
+    pub client_ids: Vec<String>,
+    /// The banned IP addresses ranges (CIDR notation, or single addresses).
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// If not empty, the regular expressions matching the only allowed client ids.
+    #[serde(default)]
+    pub allowed_client_ids: Vec<String>,
+    /// If not empty, the only allowed IP addresses ranges.
+    #[serde(default)]
+    pub allowed_addresses: Vec<String>,
+    /// If set, the ban of the client ids and IP addresses with too many authentication failures.
+    #[serde(default)]
+    pub auto_ban: Option<AutoBanConfig>,
+}
+
+/// The ban of the client ids and IP addresses with too many authentication failures.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AutoBanConfig {
+    /// The number of consecutive authentication failures leading to a ban.
+    #[serde(default = "default_auto_ban_max_auth_failures")]
+    pub max_auth_failures: u32,
+    /// The duration (in seconds) of the ban.
+    #[serde(default = "default_auto_ban_ban_secs")]
+    pub ban_secs: u64,
+}
+
+fn default_auto_ban_max_auth_failures() -> u32 {
+    DEFAULT_AUTO_BAN_MAX_AUTH_FAILURES
+}
+
+fn default_auto_ban_ban_secs() -> u64 {
+    DEFAULT_AUTO_BAN_BAN_SECS
+}
+
+/// The ban of the clients causing too many protocol errors.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ProtocolErrorsConfig {
+    /// The maximum number of protocol errors per minute for a same client id.
+    pub max_per_minute: u32,
+    /// The duration (in seconds) of the ban.
+    #[serde(default = "default_protocol_errors_ban_secs")]
+    pub ban_secs: u64,
+}
+
+fn default_protocol_errors_ban_secs() -> u64 {
+    DEFAULT_PROTOCOL_ERRORS_BAN_SECS
+}
+
+/// The limit of the MQTT handshakes processed concurrently.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HandshakesConfig {
+    /// The maximum number of handshakes processed concurrently.
+    pub max_concurrent: usize,
+    /// The maximum time (in milliseconds) a handshake waits for a slot.
+    #[serde(default = "default_handshakes_queue_timeout_ms")]
+    pub queue_timeout_ms: u64,
+}
+
+fn default_handshakes_queue_timeout_ms() -> u64 {
+    DEFAULT_HANDSHAKES_QUEUE_TIMEOUT_MS
+}
+
+/// The limits of resources above which the new connections are refused.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ResourceGuardConfig {
+    /// The interval (in milliseconds) between 2 checks.
+    #[serde(default = "default_resource_guard_check_interval_ms")]
+    pub check_interval_ms: u64,
+    /// The maximum ratio of open file descriptors over the process limit (only on Linux).
+    #[serde(default = "default_resource_guard_max_fds_ratio")]
+    pub max_fds_ratio: f64,
+    /// If set, the maximum memory (in bytes) used by the publications queued for all the clients.
+    #[serde(default)]
+    pub max_queued_bytes: Option<usize>,
+    /// The maximum delay (in milliseconds) of the checks behind their schedule.
+    #[serde(default = "default_resource_guard_max_loop_lag_ms")]
+    pub max_loop_lag_ms: u64,
+}
+
+fn default_resource_guard_check_interval_ms() -> u64 {
+    DEFAULT_RESOURCE_GUARD_CHECK_INTERVAL_MS
+}
+
+fn default_resource_guard_max_fds_ratio() -> f64 {
+    DEFAULT_RESOURCE_GUARD_MAX_FDS_RATIO
+}
+
+fn default_resource_guard_max_loop_lag_ms() -> u64 {
+    DEFAULT_RESOURCE_GUARD_MAX_LOOP_LAG_MS
+}
+
+/// The detection of the slow consumers, and the policy applied to them.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SlowConsumersConfig {
+    /// The number of queued publications above which a client might be a slow consumer.
+    #[serde(default = "default_slow_consumers_high_water_mark")]
+    pub high_water_mark: usize,
+    /// The time (in milliseconds) a client's queue must stay above the high-water mark.
+    #[serde(default = "default_slow_consumers_period_ms")]
+    pub period_ms: u64,
+    /// The policy applied to a slow consumer.
+    #[serde(default)]
+    pub policy: SlowConsumerPolicy,
+}
+
+fn default_slow_consumers_high_water_mark() -> usize {
+    DEFAULT_SLOW_CONSUMERS_HIGH_WATER_MARK
+}
+
+fn default_slow_consumers_period_ms() -> u64 {
+    DEFAULT_SLOW_CONSUMERS_PERIOD_MS
+}
+
+/// The policy applied to a slow consumer.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// Drop the queued QoS 0 publications.
+    DropQos0,
+    /// Disconnect the client (with "Quota exceeded" reason code for MQTT 5).
+    #[default]
+    Disconnect,
+    /// Send only the latest of the queued QoS 0 publications on a same topic.
+    Conflate,
+}
+
+impl SlowConsumerPolicy {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            SlowConsumerPolicy::DropQos0 => "drop_qos0",
+            SlowConsumerPolicy::Disconnect => "disconnect",
+            SlowConsumerPolicy::Conflate => "conflate",
+        }
+    }
+}
+
+/// A cap on the number of concurrent zenoh puts for the publications on some topics.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct InflightPutsConfig {
+    /// The MQTT topic filter of the capped topics.
+    pub topic: String,
+    /// The maximum number of concurrent puts.
+    pub max: usize,
+}
+
+/// A rule for the publications with a payload larger than a threshold.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LargePayloadsConfig {
+    /// The MQTT topic filter of the rule.
+    #[serde(default = "default_large_payloads_topic")]
+    pub topic: String,
+    /// The payload size (in bytes) above which the rule applies.
+    pub threshold: usize,
+    /// If set, the suffix added to the key expression of the large payloads (rejected otherwise).
+    #[serde(default)]
+    pub suffix: Option<OwnedKeyExpr>,
+}
+
+fn default_large_payloads_topic() -> String {
+    "#".into()
+}
+
+/// The policy applied to the QoS 1 publications blocked by a congestion.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CongestionPolicy {
+    /// Wait for the congestion to end (stalling the MQTT client).
+    Block,
+    /// Reject the publication.
+    Nack,
+    /// Store the publication to be forwarded later (see store_forward).
+    Spill,
+}
+
+/// A congestion policy for the QoS 1 publications on some topics.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CongestionRuleConfig {
+    /// The MQTT topic filter of the rule.
+    #[serde(default = "default_congestion_topic")]
+    pub topic: String,
+    /// The policy applied to the blocked publications.
+    pub policy: CongestionPolicy,
+    /// The time (in milliseconds) after which a blocked publication is nacked or spilled.
+    #[serde(default = "default_congestion_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_congestion_topic() -> String {
+    "#".into()
+}
+
+fn default_congestion_timeout_ms() -> u64 {
+    DEFAULT_CONGESTION_TIMEOUT_MS
+}
+
+/// A rule copying the publications on some topics into other zenoh scopes.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ScopeCopiesConfig {
+    /// The MQTT topic filter of the rule.
+    pub topic: String,
+    /// The scopes of the copies.
+    pub scopes: Vec<ScopeCopyConfig>,
+}
+
+/// A scope the publications are copied into.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ScopeCopyConfig {
+    /// The scope prefixing the key expression of the copy.
+    pub scope: OwnedKeyExpr,
+    /// If set, the priority of the copy (the one of the client's publications otherwise).
+    #[serde(default)]
+    pub priority: Option<PublicationPriority>,
+}
+
+/// A rule turning a MQTT publication into a zenoh query.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct QueryRuleConfig {
+    /// The MQTT topic filter of the publications triggering the query.
+    pub trigger: String,
+    /// The selector of the query (`{N}` being the level N of the trigger topic, `{payload}` its payload).
+    pub selector: String,
+    /// The MQTT topic on which the replies are published (`{N}` being the level N of the trigger topic).
+    pub response_topic: String,
+    /// The time (in milliseconds) the replies are awaited.
+    #[serde(default = "default_query_rules_timeout_ms")]
+    pub timeout_ms: u64,
+    /// If set, the time (in milliseconds) a response is reused for the same selector.
+    #[serde(default)]
+    pub cache_ms: Option<u64>,
+}
+
+fn default_query_rules_timeout_ms() -> u64 {
+    DEFAULT_QUERY_RULES_TIMEOUT_MS
+}
+
+/// The chunking of the large payloads routed over zenoh.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ChunkingConfig {
+    /// The maximum size (in bytes) of a chunk.
+    #[serde(default = "default_chunking_chunk_size")]
+    pub chunk_size: usize,
+    /// The maximum size (in bytes) of a chunked message.
+    #[serde(default = "default_chunking_max_message_size")]
+    pub max_message_size: usize,
+    /// The maximum time (in milliseconds) to receive all the chunks of a message.
+    #[serde(default = "default_chunking_timeout_ms")]
+    pub timeout_ms: u64,
+    /// The maximum size (in bytes) of all the chunks being reassembled.
+    #[serde(default = "default_chunking_max_pending_size")]
+    pub max_pending_size: usize,
+}
+
+fn default_chunking_chunk_size() -> usize {
+    DEFAULT_CHUNKING_CHUNK_SIZE
+}
+
+fn default_chunking_max_message_size() -> usize {
+    DEFAULT_CHUNKING_MAX_MESSAGE_SIZE
+}
+
+fn default_chunking_timeout_ms() -> u64 {
+    DEFAULT_CHUNKING_TIMEOUT_MS
+}
+
+fn default_chunking_max_pending_size() -> usize {
+    DEFAULT_CHUNKING_MAX_PENDING_SIZE
+}
+
+/// The caches of the key expressions mapped from the publication topics.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KeCacheConfig {
+    /// The maximum number of topics cached per client.
+    #[serde(default = "default_ke_cache_session_size")]
+    pub session_size: usize,
+    /// The maximum number of topics cached globally.
+    #[serde(default = "default_ke_cache_global_size")]
+    pub global_size: usize,
+}
+
+fn default_ke_cache_session_size() -> usize {
+    DEFAULT_KE_CACHE_SESSION_SIZE
+}
+
+fn default_ke_cache_global_size() -> usize {
+    DEFAULT_KE_CACHE_GLOBAL_SIZE
+}
+
+/// The cache of the last values of the publications, replying to the zenoh queries.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LastValuesConfig {
+    /// The maximum number of cached key expressions.
+    #[serde(default = "default_last_values_max_topics")]
+    pub max_topics: usize,
+    /// If true, only the retained publications are cached.
+    #[serde(default)]
+    pub retained_only: bool,
+}
+
+fn default_last_values_max_topics() -> usize {
+    DEFAULT_LAST_VALUES_MAX_TOPICS
+}
+
+/// The limits of the buffering of the publications while the routing is paused.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PauseConfig {
+    /// The maximum number of buffered publications.
+    #[serde(default = "default_pause_max_buffered_messages")]
+    pub max_buffered_messages: usize,
+    /// The maximum size (in bytes) of the buffered payloads.
+    #[serde(default = "default_pause_max_buffered_bytes")]
+    pub max_buffered_bytes: usize,
+}
+
+impl Default for PauseConfig {
+    fn default() -> Self {
+        PauseConfig {
+            max_buffered_messages: DEFAULT_PAUSE_MAX_BUFFERED_MESSAGES,
+            max_buffered_bytes: DEFAULT_PAUSE_MAX_BUFFERED_BYTES,
+        }
+    }
+}
+
+fn default_pause_max_buffered_messages() -> usize {
+    DEFAULT_PAUSE_MAX_BUFFERED_MESSAGES
+}
+
+fn default_pause_max_buffered_bytes() -> usize {
+    DEFAULT_PAUSE_MAX_BUFFERED_BYTES
+}
+
+/// The retries of the publications that failed to be routed to zenoh.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PutRetryConfig {
+    /// The maximum number of retries.
+    #[serde(default = "default_put_retry_max_retries")]
+    pub max_retries: u32,
+    /// The delay (in milliseconds) before the 1st retry, doubled after each retry.
+    #[serde(default = "default_put_retry_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    /// The maximum delay (in milliseconds) between 2 retries.
+    #[serde(default = "default_put_retry_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    /// The maximum number of publications being retried per client.
+    #[serde(default = "default_put_retry_queue_size")]
+    pub queue_size: usize,
+}
+
+fn default_put_retry_max_retries() -> u32 {
+    DEFAULT_PUT_RETRY_MAX_RETRIES
+}
+
+fn default_put_retry_initial_backoff_ms() -> u64 {
+    DEFAULT_PUT_RETRY_INITIAL_BACKOFF_MS
+}
+
+fn default_put_retry_max_backoff_ms() -> u64 {
+    DEFAULT_PUT_RETRY_MAX_BACKOFF_MS
+}
+
+fn default_put_retry_queue_size() -> usize {
+    DEFAULT_PUT_RETRY_QUEUE_SIZE
+}
+
+/// The storage of the publications while zenoh is unreachable, to be forwarded later.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StoreForwardConfig {
+    /// The path of the file where the publications are stored.
+    pub path: String,
+    /// The maximum number of stored publications.
+    #[serde(default = "default_store_forward_max_messages")]
+    pub max_messages: usize,
+    /// The interval (in milliseconds) between 2 attempts to forward the stored publications.
+    #[serde(default = "default_store_forward_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+}
+
+fn default_store_forward_max_messages() -> usize {
+    DEFAULT_STORE_FORWARD_MAX_MESSAGES
+}
+
+fn default_store_forward_flush_interval_ms() -> u64 {
+    DEFAULT_STORE_FORWARD_FLUSH_INTERVAL_MS
+}
+
+/// The encryption of the payloads routed over zenoh on some topics.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// The hex-encoded keys, per key id (never exposed).
     #[serde(default, skip_serializing)]
-    __required__: bool,
-    #[serde(default, skip_serializing, deserialize_with = "deserialize_paths")]
-    __path__: Vec<String>,
+    pub keys: HashMap<String, String>,
+    /// The rules selecting the key per topic.
+    pub rules: Vec<EncryptionRuleConfig>,
+}
+
+impl fmt::Debug for EncryptionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionConfig")
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .field("rules", &self.rules)
+            .finish()
+    }
+}
+
+/// A rule of the encryption: the key used for some topics.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionRuleConfig {
+    /// The MQTT topic filter of the rule.
+    pub topic: String,
+    /// The id of the key.
+    pub key: String,
+}
+
+/// The credential required by the sensitive admin space operations.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// The admin token (never exposed).
+    #[serde(default, skip_serializing)]
+    pub token: Option<String>,
+}
+
+impl fmt::Debug for AdminConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdminConfig")
+            .field("token", &self.token.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+/// The pseudonymization of a level of some topics in the zenoh key expressions.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PseudonymsConfig {
+    /// The hex-encoded key (never exposed).
+    #[serde(default, skip_serializing)]
+    pub key: Option<String>,
+    /// The rules selecting the pseudonymized level per topic.
+    pub rules: Vec<PseudonymRuleConfig>,
+}
+
+impl fmt::Debug for PseudonymsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PseudonymsConfig")
+            .field("key", &self.key.as_ref().map(|_| "***"))
+            .field("rules", &self.rules)
+            .finish()
+    }
+}
+
+/// A rule of the pseudonymization: the level pseudonymized in some topics.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PseudonymRuleConfig {
+    /// The MQTT topic filter of the rule.
+    pub topic: String,
+    /// The index of the pseudonymized level in the topic (starting at 0).
+    pub level: usize,
+}
+
+/// The Rhai scripts rewriting the topics and payloads.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptsConfig {
+    /// If set, the script applied to the MQTT publications before their routing to zenoh.
+    #[serde(default)]
+    pub mqtt_to_zenoh: Option<String>,
+    /// If set, the script applied to the zenoh publications before their routing to MQTT.
+    #[serde(default)]
+    pub zenoh_to_mqtt: Option<String>,
+    /// The maximum number of operations a script can execute per message.
+    #[serde(default = "default_scripts_max_operations")]
+    pub max_operations: u64,
+    /// The maximum execution time (in milliseconds) of a script per message.
+    #[serde(default = "default_scripts_max_time_ms")]
+    pub max_time_ms: u64,
+}
+
+fn default_scripts_max_operations() -> u64 {
+    DEFAULT_SCRIPTS_MAX_OPERATIONS
+}
+
+fn default_scripts_max_time_ms() -> u64 {
+    DEFAULT_SCRIPTS_MAX_TIME_MS
+}
+
+/// The export of the traces and metrics to an OpenTelemetry collector.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct OpenTelemetryConfig {
+    /// The OTLP/gRPC endpoint of the collector.
+    pub endpoint: String,
+    /// The service name reported to the collector.
+    #[serde(default = "default_opentelemetry_service_name")]
+    pub service_name: String,
+}
+
+fn default_opentelemetry_service_name() -> String {
+    DEFAULT_OPENTELEMETRY_SERVICE_NAME.into()
+}
+
+/// The allocation of the large payloads in shared memory.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ShmConfig {
+    /// The minimal payload size (in bytes) for allocation in shared memory.
+    #[serde(default = "default_shm_threshold")]
+    pub threshold: usize,
+    /// The size (in bytes) of the shared memory segment.
+    #[serde(default = "default_shm_size")]
+    pub size: usize,
+}
+
+fn default_shm_threshold() -> usize {
+    DEFAULT_SHM_THRESHOLD
+}
+
+fn default_shm_size() -> usize {
+    DEFAULT_SHM_SIZE
+}
+
+fn default_fanout_shards() -> usize {
+    DEFAULT_FANOUT_SHARDS
+}
+
+fn default_listener_drain_secs() -> u64 {
+    DEFAULT_LISTENER_DRAIN_SECS
+}
+
+fn default_drain_deadline_secs() -> u64 {
+    DEFAULT_DRAIN_DEADLINE_SECS
 }
 
 fn default_mqtt_port() -> String {
@@ -107,24 +1687,22 @@ where
         .map_err(|e| de::Error::custom(format!("Invalid regex 'allow={s}': {e}")))
 }
 
-fn serialize_allow<S>(v: &Option<Regex>, serializer: S) -> Result<S::Ok, S::Error>
+// Only called for Some (None being skipped), since an empty regex would match all the topics
+fn serialize_regex<S>(v: &Option<Regex>, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
-    serializer.serialize_str(
-        &v.as_ref()
-            .map_or_else(|| ".*".to_string(), |re| re.to_string()),
-    )
+    serializer.serialize_str(v.as_ref().map_or("", |re| re.as_str()))
 }
 
-fn serialize_deny<S>(v: &Option<Regex>, serializer: S) -> Result<S::Ok, S::Error>
+fn serialize_uns_pattern<S>(v: &Option<Regex>, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
-    serializer.serialize_str(
-        &v.as_ref()
-            .map_or_else(|| "".to_string(), |re| re.to_string()),
-    )
+    match v {
+        Some(re) => serializer.serialize_some(re.as_str()),
+        None => serializer.serialize_none(),
+    }
 }
 
 struct MqttPortVisitor;
@@ -0,0 +1,85 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// Draining of the MQTT server, e.g. before a rolling upgrade: the server stops accepting new
+// connections, waits for the in-flight QoS 1 messages to complete (up to a deadline),
+// and then closes all the connections.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+const INFLIGHT_CHECK_PERIOD: Duration = Duration::from_millis(100);
+
+lazy_static::lazy_static! {
+    static ref DRAIN_REQUESTS: (flume::Sender<()>, flume::Receiver<()>) = flume::unbounded();
+    static ref DRAINED: (flume::Sender<()>, flume::Receiver<()>) = flume::unbounded();
+}
+
+// The number of QoS 1 messages being routed, in both directions
+static INFLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+// A QoS 1 message being routed, until dropped
+pub(crate) struct Inflight;
+
+impl Inflight {
+    pub(crate) fn new() -> Inflight {
+        INFLIGHT.fetch_add(1, Ordering::AcqRel);
+        Inflight
+    }
+}
+
+impl Drop for Inflight {
+    fn drop(&mut self) {
+        INFLIGHT.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Request the MQTT server to drain its connections: it stops accepting new connections,
+/// waits for the in-flight QoS 1 messages to complete (at most `drain_deadline_secs`)
+/// and closes all the connections.
+pub fn request_drain() {
+    let _ = DRAIN_REQUESTS.0.send(());
+}
+
+/// Wait for the end of the draining of the MQTT server.
+pub async fn drained() {
+    let _ = DRAINED.1.recv_async().await;
+}
+
+pub(crate) async fn drain_requested() {
+    let _ = DRAIN_REQUESTS.1.recv_async().await;
+}
+
+pub(crate) fn notify_drained() {
+    let _ = DRAINED.0.send(());
+}
+
+// Wait for all the in-flight QoS 1 messages to complete. Returns false if the deadline expired before.
+pub(crate) async fn wait_inflight(deadline: Duration) -> bool {
+    let start = Instant::now();
+    loop {
+        let inflight = INFLIGHT.load(Ordering::Acquire);
+        if inflight == 0 {
+            return true;
+        }
+        if start.elapsed() >= deadline {
+            log::warn!(
+                "MQTT server draining deadline expired with {} in-flight QoS 1 messages",
+                inflight
+            );
+            return false;
+        }
+        async_std::task::sleep(INFLIGHT_CHECK_PERIOD).await;
+    }
+}
@@ -0,0 +1,146 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+//! Encryption of the MQTT payloads routed over Zenoh, for the topics matching configured rules.
+//!
+//! The payloads are encrypted with AES-256-GCM when routed from MQTT to Zenoh, and decrypted when
+//! routed from Zenoh to MQTT, so they can traverse an untrusted Zenoh segment between 2 bridges
+//! while the MQTT clients remain unmodified. An encrypted payload is the 12 bytes nonce followed by
+//! the ciphertext (including the authentication tag).
+//!
+//! The keys are taken from the configuration, or from a [`KeyProvider`] registered by an application
+//! embedding the plugin with [`register_key_provider()`] (e.g. to fetch them from a vault).
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+use zenoh::plugins::ZResult;
+use zenoh_core::{zread, zwrite};
+
+use crate::config::Config;
+use crate::mqtt_helpers::topic_matches_filter;
+use crate::process_wide::ProcessWide;
+
+const NONCE_SIZE: usize = 12;
+
+/// A provider of the encryption keys that are not set in the configuration.
+pub trait KeyProvider: Send + Sync {
+    /// Returns the 256 bits key with this id, if known.
+    fn key(&self, key_id: &str) -> Option<Vec<u8>>;
+}
+
+lazy_static::lazy_static! {
+    static ref KEY_PROVIDER: RwLock<Option<Arc<dyn KeyProvider>>> = RwLock::new(None);
+}
+
+/// Register the provider of the encryption keys (replacing the previous one, if any).
+pub fn register_key_provider(provider: Arc<dyn KeyProvider>) {
+    *zwrite!(KEY_PROVIDER) = Some(provider);
+}
+
+struct Rules {
+    // the MQTT topic filters and the ids of their keys
+    rules: Vec<(String, String)>,
+    // the ciphers of the keys set in the configuration, per key id
+    ciphers: HashMap<String, Aes256Gcm>,
+}
+
+static RULES: ProcessWide<Rules> = ProcessWide::new("encryption");
+
+pub(crate) fn init(config: &Config) -> ZResult<()> {
+    // the keys are not serialized with the settings
+    let keys = config
+        .encryption
+        .as_ref()
+        .map(|c| c.keys.iter().collect::<BTreeMap<_, _>>());
+    RULES.init(&(&config.encryption, keys), || {
+        let Some(conf) = &config.encryption else {
+            return Ok(None);
+        };
+        let mut ciphers = HashMap::new();
+        for (key_id, key) in &conf.keys {
+            let cipher = hex::decode(key)
+                .ok()
+                .and_then(|k| Aes256Gcm::new_from_slice(&k).ok())
+                .ok_or_else(|| {
+                    zerror!(
+                        "Invalid encryption key '{}': must be 64 hexadecimal characters (256 bits)",
+                        key_id
+                    )
+                })?;
+            ciphers.insert(key_id.clone(), cipher);
+        }
+        let rules = conf
+            .rules
+            .iter()
+            .map(|r| (r.topic.clone(), r.key.clone()))
+            .collect();
+        Ok(Some(Rules { rules, ciphers }))
+    })?;
+    Ok(())
+}
+
+// The cipher for a MQTT topic, if it matches an encryption rule
+fn cipher(topic: &str) -> ZResult<Option<Aes256Gcm>> {
+    let Some(rules) = RULES.get() else {
+        return Ok(None);
+    };
+    let Some((_, key_id)) = rules
+        .rules
+        .iter()
+        .find(|(filter, _)| topic_matches_filter(topic, filter))
+    else {
+        return Ok(None);
+    };
+    if let Some(cipher) = rules.ciphers.get(key_id) {
+        return Ok(Some(cipher.clone()));
+    }
+    let key = zread!(KEY_PROVIDER)
+        .as_ref()
+        .and_then(|p| p.key(key_id))
+        .ok_or_else(|| zerror!("Unknown encryption key '{}' for '{}'", key_id, topic))?;
+    let cipher = Aes256Gcm::new_from_slice(&key)
+        .map_err(|_| zerror!("Invalid encryption key '{}': must be 256 bits", key_id))?;
+    Ok(Some(cipher))
+}
+
+// Encrypt the payload of a publication from a MQTT client, if its topic matches an encryption rule
+pub(crate) fn encrypt(topic: &str, payload: &[u8]) -> ZResult<Option<Vec<u8>>> {
+    let Some(cipher) = cipher(topic)? else {
+        return Ok(None);
+    };
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, payload)
+        .map_err(|e| zerror!("Failed to encrypt payload for '{}': {}", topic, e))?;
+    let mut encrypted = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend_from_slice(&ciphertext);
+    Ok(Some(encrypted))
+}
+
+// Decrypt the payload of a publication from Zenoh, if its topic matches an encryption rule
+pub(crate) fn decrypt(topic: &str, payload: &[u8]) -> ZResult<Option<Vec<u8>>> {
+    let Some(cipher) = cipher(topic)? else {
+        return Ok(None);
+    };
+    if payload.len() < NONCE_SIZE {
+        bail!("Failed to decrypt payload for '{}': too short", topic);
+    }
+    let (nonce, ciphertext) = payload.split_at(NONCE_SIZE);
+    let decrypted = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|e| zerror!("Failed to decrypt payload for '{}': {}", topic, e))?;
+    Ok(Some(decrypted))
+}
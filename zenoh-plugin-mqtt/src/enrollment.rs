@@ -0,0 +1,135 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The certificates enrollment passthrough: a MQTT client publishes a certificate signing request (CSR) on
+// "<topic_prefix>/csr", forwarded as the value of a zenoh query on "<key_expr>/<client_id>" to an enrollment
+// service (e.g. an EST or SCEP gateway). The certificate replied is published to the client on
+// "<topic_prefix>/cert", or the error on "<topic_prefix>/error". Those topics are not routed over zenoh.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use zenoh::plugins::ZResult;
+use zenoh::prelude::r#async::*;
+
+use crate::config::EnrollmentConfig;
+use crate::mqtt_helpers::{escape_topic_level, MqttSink, OutgoingProperties, MQTT_SEPARATOR};
+
+const CSR_SUFFIX: &str = "csr";
+const CERT_SUFFIX: &str = "cert";
+const ERROR_SUFFIX: &str = "error";
+
+#[derive(Debug)]
+pub(crate) struct Enrollment {
+    zsession: Arc<Session>,
+    key_expr: OwnedKeyExpr,
+    topic_prefix: String,
+    timeout: Duration,
+    requests: AtomicU64,
+    issued: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl Enrollment {
+    pub(crate) fn new(zsession: Arc<Session>, config: &EnrollmentConfig) -> Enrollment {
+        Enrollment {
+            zsession,
+            key_expr: config.key_expr.clone(),
+            topic_prefix: config.topic_prefix.clone(),
+            timeout: Duration::from_millis(config.timeout_ms),
+            requests: AtomicU64::new(0),
+            issued: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        }
+    }
+
+    fn topic(&self, suffix: &str) -> String {
+        format!("{}{MQTT_SEPARATOR}{suffix}", self.topic_prefix)
+    }
+
+    // Returns true if the topic (or topic filter) is one of the enrollment topics
+    pub(crate) fn is_enrollment_topic(&self, topic: &str) -> bool {
+        topic
+            .strip_prefix(&self.topic_prefix)
+            .map_or(false, |rest| rest.starts_with(MQTT_SEPARATOR))
+    }
+
+    pub(crate) fn is_csr_topic(&self, topic: &str) -> bool {
+        topic == self.topic(CSR_SUFFIX)
+    }
+
+    // Forward the CSR of the client to the enrollment service, publishing the reply to the client
+    pub(crate) fn submit(self: &Arc<Self>, client_id: &str, csr: &[u8], sink: MqttSink) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        log::debug!(
+            "MQTT client {} submits a CSR of {} bytes for enrollment",
+            client_id,
+            csr.len()
+        );
+        let this = self.clone();
+        let client_id = client_id.to_string();
+        let csr = csr.to_vec();
+        async_std::task::spawn(async move {
+            let (topic, payload) = match this.enroll(&client_id, csr).await {
+                Ok(cert) => {
+                    this.issued.fetch_add(1, Ordering::Relaxed);
+                    (this.topic(CERT_SUFFIX), cert)
+                }
+                Err(e) => {
+                    this.failed.fetch_add(1, Ordering::Relaxed);
+                    log::warn!("MQTT client {}: enrollment failed: {}", client_id, e);
+                    (this.topic(ERROR_SUFFIX), e.to_string().into_bytes())
+                }
+            };
+            if let Err(e) = sink.publish_at_most_once(
+                topic,
+                payload.into(),
+                OutgoingProperties::default(),
+                None,
+            ) {
+                log::debug!(
+                    "MQTT client {}: failed to send enrollment reply: {}",
+                    client_id,
+                    e
+                );
+            }
+        });
+    }
+
+    async fn enroll(&self, client_id: &str, csr: Vec<u8>) -> ZResult<Vec<u8>> {
+        let ke = &*self.key_expr / keyexpr::new(escape_topic_level(client_id).as_ref())?;
+        let replies = self
+            .zsession
+            .get(&ke)
+            .with_value(Value::from(csr).encoding(Encoding::APP_OCTET_STREAM))
+            .timeout(self.timeout)
+            .res()
+            .await?;
+        match replies.recv_async().await {
+            Ok(reply) => match reply.sample {
+                Ok(sample) => Ok(sample.payload.contiguous().into_owned()),
+                Err(e) => bail!("enrollment rejected: {}", e),
+            },
+            Err(_) => bail!("no reply from enrollment service on {}", ke),
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "requests": self.requests.load(Ordering::Relaxed),
+            "issued": self.issued.load(Ordering::Relaxed),
+            "failed": self.failed.load(Ordering::Relaxed),
+        })
+    }
+}
@@ -0,0 +1,93 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zenoh::plugins::ZResult;
+use zenoh::prelude::*;
+
+use crate::config::EnvelopeFormat;
+
+pub(crate) const CBOR_ENCODING: &str = "application/cbor";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Payload {
+    Text(String),
+    Binary(#[serde(with = "serde_bytes")] Vec<u8>),
+}
+
+// The envelope wrapping a MQTT payload with the MQTT metadata, when routed to Zenoh
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    topic: String,
+    client_id: String,
+    qos: u8,
+    retain: bool,
+    // reception time, in milliseconds since UNIX_EPOCH
+    timestamp: u64,
+    payload: Payload,
+}
+
+// Wrap a MQTT payload in an envelope, returning the envelope and its encoding
+pub(crate) fn wrap(
+    format: EnvelopeFormat,
+    topic: &str,
+    client_id: &str,
+    qos: u8,
+    retain: bool,
+    payload: &[u8],
+) -> ZResult<(Vec<u8>, Encoding)> {
+    let envelope = Envelope {
+        topic: topic.into(),
+        client_id: client_id.into(),
+        qos,
+        retain,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default(),
+        payload: match std::str::from_utf8(payload) {
+            Ok(s) if format == EnvelopeFormat::Json => Payload::Text(s.into()),
+            _ => Payload::Binary(payload.to_vec()),
+        },
+    };
+    match format {
+        EnvelopeFormat::Cbor => {
+            let mut buf = Vec::new();
+            ciborium::ser::into_writer(&envelope, &mut buf)
+                .map_err(|e| zerror!("Failed to encode CBOR envelope: {}", e))?;
+            Ok((buf, Encoding::from(CBOR_ENCODING)))
+        }
+        EnvelopeFormat::Json => Ok((serde_json::to_vec(&envelope)?, Encoding::APP_JSON)),
+    }
+}
+
+// Unwrap a payload from an envelope. If it's not an envelope, the payload is returned as is.
+pub(crate) fn unwrap(format: EnvelopeFormat, data: Vec<u8>) -> Vec<u8> {
+    let envelope: Option<Envelope> = match format {
+        EnvelopeFormat::Cbor => ciborium::de::from_reader(data.as_slice()).ok(),
+        EnvelopeFormat::Json => serde_json::from_slice(&data).ok(),
+    };
+    match envelope {
+        Some(Envelope {
+            payload: Payload::Text(s),
+            ..
+        }) => s.into_bytes(),
+        Some(Envelope {
+            payload: Payload::Binary(b),
+            ..
+        }) => b,
+        None => data,
+    }
+}
@@ -0,0 +1,140 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+use ntex_mqtt::v5;
+use std::fmt;
+use zenoh_core::zresult::ZError;
+
+/// The errors raised by the MQTT plugin while serving a MQTT client.
+///
+/// Each error is mapped to a MQTT 5 reason code. For MQTT 3.1.1 (that has no reason codes
+/// for publications and subscriptions), an error on a publication closes the connection.
+#[derive(Debug)]
+pub enum MqttPluginError {
+    /// The client failed to authenticate
+    AuthFailure(String),
+    /// The operation is denied by the configuration (e.g. a subscription to a root wildcard)
+    AclDenied(String),
+    /// The MQTT topic cannot be converted to a valid Zenoh key expression
+    KeyExprInvalid(String),
+    /// The MQTT topic doesn't comply with the configured limits or Unified Namespace hierarchy
+    TopicNonCompliant(String),
+    /// The publication couldn't be routed to Zenoh
+    ZenohPutFailed(String),
+    /// A limit has been exceeded (e.g. a full store-and-forward queue)
+    QuotaExceeded(String),
+    /// A rewrite script failed
+    ScriptFailed(String),
+    /// The publication couldn't be prepared for routing (e.g. its envelope or encryption failed)
+    Unspecified(String),
+    /// Any other error
+    Internal(Box<dyn std::error::Error + Send + Sync + 'static>),
+}
+
+impl MqttPluginError {
+    /// The class of the error, as a short static string (e.g. usable as a metric attribute).
+    pub fn class(&self) -> &'static str {
+        match self {
+            MqttPluginError::AuthFailure(_) => "auth_failure",
+            MqttPluginError::AclDenied(_) => "acl_denied",
+            MqttPluginError::KeyExprInvalid(_) => "key_expr_invalid",
+            MqttPluginError::TopicNonCompliant(_) => "topic_non_compliant",
+            MqttPluginError::ZenohPutFailed(_) => "zenoh_put_failed",
+            MqttPluginError::QuotaExceeded(_) => "quota_exceeded",
+            MqttPluginError::ScriptFailed(_) => "script_failed",
+            MqttPluginError::Unspecified(_) => "unspecified",
+            MqttPluginError::Internal(_) => "internal",
+        }
+    }
+
+    /// The MQTT 5 reason code for a negative PUBACK.
+    pub fn publish_ack_reason(&self) -> v5::codec::PublishAckReason {
+        use v5::codec::PublishAckReason;
+        match self {
+            MqttPluginError::AuthFailure(_) | MqttPluginError::AclDenied(_) => {
+                PublishAckReason::NotAuthorized
+            }
+            MqttPluginError::KeyExprInvalid(_) | MqttPluginError::TopicNonCompliant(_) => {
+                PublishAckReason::TopicNameInvalid
+            }
+            MqttPluginError::QuotaExceeded(_) => PublishAckReason::QuotaExceeded,
+            MqttPluginError::ZenohPutFailed(_) | MqttPluginError::ScriptFailed(_) => {
+                PublishAckReason::ImplementationSpecificError
+            }
+            MqttPluginError::Unspecified(_) | MqttPluginError::Internal(_) => {
+                PublishAckReason::UnspecifiedError
+            }
+        }
+    }
+
+    /// The MQTT 5 reason code for a failed subscription.
+    pub fn subscribe_ack_reason(&self) -> v5::codec::SubscribeAckReason {
+        use v5::codec::SubscribeAckReason;
+        match self {
+            MqttPluginError::AuthFailure(_) | MqttPluginError::AclDenied(_) => {
+                SubscribeAckReason::NotAuthorized
+            }
+            MqttPluginError::KeyExprInvalid(_) | MqttPluginError::TopicNonCompliant(_) => {
+                SubscribeAckReason::TopicFilterInvalid
+            }
+            MqttPluginError::QuotaExceeded(_) => SubscribeAckReason::QuotaExceeded,
+            MqttPluginError::Unspecified(_) => SubscribeAckReason::UnspecifiedError,
+            MqttPluginError::ZenohPutFailed(_)
+            | MqttPluginError::ScriptFailed(_)
+            | MqttPluginError::Internal(_) => SubscribeAckReason::ImplementationSpecificError,
+        }
+    }
+}
+
+impl fmt::Display for MqttPluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttPluginError::AuthFailure(s) => write!(f, "authentication failure: {s}"),
+            MqttPluginError::AclDenied(s) => write!(f, "denied: {s}"),
+            MqttPluginError::KeyExprInvalid(s) => write!(f, "invalid key expression: {s}"),
+            MqttPluginError::TopicNonCompliant(s) => write!(f, "non-compliant topic: {s}"),
+            MqttPluginError::ZenohPutFailed(s) => write!(f, "Zenoh put failed: {s}"),
+            MqttPluginError::QuotaExceeded(s) => write!(f, "quota exceeded: {s}"),
+            MqttPluginError::ScriptFailed(s) => write!(f, "script failure: {s}"),
+            MqttPluginError::Unspecified(s) => write!(f, "{s}"),
+            MqttPluginError::Internal(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for MqttPluginError {}
+
+impl From<ZError> for MqttPluginError {
+    fn from(e: ZError) -> Self {
+        MqttPluginError::Internal(e.into())
+    }
+}
+
+impl From<Box<dyn std::error::Error + Send + Sync + 'static>> for MqttPluginError {
+    fn from(err: Box<dyn std::error::Error + Send + Sync + 'static>) -> Self {
+        MqttPluginError::Internal(err)
+    }
+}
+
+// mqtt5 supports negative acks, so service error could be converted to PublishAck
+// (weird way to do it, but that's how it's done in ntex-mqtt examples...)
+// Internal errors are not acknowledged but close the connection.
+impl std::convert::TryFrom<MqttPluginError> for v5::PublishAck {
+    type Error = MqttPluginError;
+    fn try_from(err: MqttPluginError) -> Result<Self, Self::Error> {
+        match err {
+            MqttPluginError::Internal(_) => Err(err),
+            _ => Ok(v5::PublishAck::new(err.publish_ack_reason()).reason(err.to_string().into())),
+        }
+    }
+}
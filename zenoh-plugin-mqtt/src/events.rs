@@ -0,0 +1,124 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+//! Events on the MQTT clients sessions, for applications embedding the MQTT plugin as a library.
+//!
+//! An application can register a [`SessionEventHandler`] with [`register_event_handler()`]
+//! to be notified of the clients connections, disconnections and subscriptions,
+//! and to veto some of them. Or it can use [`event_stream()`] to receive those events on a channel.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use zenoh_core::{zread, zwrite};
+
+/// The MQTT protocol version used by a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    V3,
+    V5,
+    /// MQTT-SN (experimental)
+    MqttSn,
+}
+
+impl ProtocolVersion {
+    /// The short name of the protocol version ("v3", "v5" or "sn").
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProtocolVersion::V3 => "v3",
+            ProtocolVersion::V5 => "v5",
+            ProtocolVersion::MqttSn => "sn",
+        }
+    }
+}
+
+/// An event on a MQTT client session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// A client connected.
+    Connected {
+        client_id: String,
+        protocol: ProtocolVersion,
+        /// The remote address of the client (if known).
+        address: Option<SocketAddr>,
+        /// The listener the client connected to ("mqtt" or "mqtt-sn").
+        listener: &'static str,
+    },
+    /// A client disconnected.
+    Disconnected { client_id: String },
+    /// A client subscribed to a topic.
+    Subscribed { client_id: String, topic: String },
+    /// A client unsubscribed from a topic.
+    Unsubscribed { client_id: String, topic: String },
+}
+
+/// A handler of the MQTT clients session events.
+///
+/// The `accept_*` operations are called before a decision is taken by the plugin.
+/// Returning `false` from any registered handler rejects the connection or the subscription.
+pub trait SessionEventHandler: Send + Sync {
+    /// Called when a client tries to connect.
+    fn accept_connection(&self, _client_id: &str, _username: Option<&str>) -> bool {
+        true
+    }
+
+    /// Called when a client tries to subscribe to a topic.
+    fn accept_subscription(&self, _client_id: &str, _topic: &str) -> bool {
+        true
+    }
+
+    /// Called for each event, once it occured.
+    fn on_event(&self, _event: &SessionEvent) {}
+}
+
+lazy_static::lazy_static! {
+    static ref EVENT_HANDLERS: RwLock<Vec<Arc<dyn SessionEventHandler>>> = RwLock::new(Vec::new());
+}
+
+/// Register a handler of the MQTT clients session events.
+pub fn register_event_handler(handler: Arc<dyn SessionEventHandler>) {
+    zwrite!(EVENT_HANDLERS).push(handler);
+}
+
+struct ChannelHandler(flume::Sender<SessionEvent>);
+
+impl SessionEventHandler for ChannelHandler {
+    fn on_event(&self, event: &SessionEvent) {
+        let _ = self.0.send(event.clone());
+    }
+}
+
+/// Returns a channel receiving all the MQTT clients session events.
+pub fn event_stream() -> flume::Receiver<SessionEvent> {
+    let (tx, rx) = flume::unbounded();
+    register_event_handler(Arc::new(ChannelHandler(tx)));
+    rx
+}
+
+pub(crate) fn accept_connection(client_id: &str, username: Option<&str>) -> bool {
+    zread!(EVENT_HANDLERS)
+        .iter()
+        .all(|h| h.accept_connection(client_id, username))
+}
+
+pub(crate) fn accept_subscription(client_id: &str, topic: &str) -> bool {
+    zread!(EVENT_HANDLERS)
+        .iter()
+        .all(|h| h.accept_subscription(client_id, topic))
+}
+
+pub(crate) fn notify(event: SessionEvent) {
+    for h in zread!(EVENT_HANDLERS).iter() {
+        h.on_event(&event);
+    }
+}
@@ -0,0 +1,537 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+use serde_json::Value;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use zenoh::plugins::ZResult;
+use zenoh::prelude::sync::*;
+use zenoh::subscriber::{Reliability, Subscriber};
+use zenoh_core::{zlock, zread, zwrite};
+
+use crate::config::QueueStrategy;
+
+pub(crate) const MQTT_QUEUE_PREFIX: &str = "$queue/";
+
+// A handler delivering a Zenoh sample to 1 MQTT client
+pub(crate) type SampleHandler = Arc<dyn Fn(&Sample) -> ZResult<()> + Send + Sync>;
+
+// If the topic has the "$queue/<group>/<topic>" form, returns the group and the actual topic
+pub(crate) fn parse_queue_topic(topic: &str) -> Option<(&str, &str)> {
+    topic
+        .strip_prefix(MQTT_QUEUE_PREFIX)
+        .and_then(|s| s.split_once('/'))
+        .filter(|(group, topic)| !group.is_empty() && !topic.is_empty())
+}
+
+struct QueueMembers {
+    strategy: QueueStrategy,
+    next: usize,
+    // the state of the pseudo-random generator (xorshift64) of the random strategy
+    seed: u64,
+    members: Vec<(u64, SampleHandler)>,
+}
+
+impl QueueMembers {
+    fn new(strategy: QueueStrategy, members: Vec<(u64, SampleHandler)>) -> QueueMembers {
+        QueueMembers {
+            strategy,
+            next: 0,
+            // a random non-zero seed
+            seed: RandomState::new().build_hasher().finish() | 1,
+            members,
+        }
+    }
+
+    // The index of the 1st member to try, according to the strategy
+    fn first(&mut self) -> usize {
+        match self.strategy {
+            QueueStrategy::RoundRobin => self.next,
+            QueueStrategy::Random => {
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 7;
+                self.seed ^= self.seed << 17;
+                (self.seed % self.members.len() as u64) as usize
+            }
+        }
+    }
+
+    // Deliver the sample to exactly 1 member, selected according to the strategy.
+    // If the delivery to a member fails (e.g. its connection is closing), the next member is tried.
+    fn dispatch(&mut self, sample: &Sample) {
+        let first = match self.members.len() {
+            0 => 0,
+            _ => self.first(),
+        };
+        for i in 0..self.members.len() {
+            let idx = (first + i) % self.members.len();
+            self.next = idx + 1;
+            match (self.members[idx].1)(sample) {
+                Ok(()) => return,
+                Err(e) => log::warn!("{}", e),
+            }
+        }
+        log::debug!(
+            "No member of queue group could receive publication on {}",
+            sample.key_expr
+        );
+    }
+}
+
+struct QueueGroup {
+    _subscriber: Subscriber<'static, ()>,
+    origin: Locality,
+    // the reliability requested by the 1st member
+    reliability: Reliability,
+    members: Arc<Mutex<QueueMembers>>,
+    // since when the group has no member (its subscriber being kept for the configured time)
+    empty_since: Option<Instant>,
+}
+
+// A Zenoh subscriber shared by the MQTT clients subscribing to a same key expression (with a same origin and reliability).
+// Each publication is delivered to all the members: their handlers only enqueue it in their sink,
+// that is written by the client's writer task.
+struct TopicSubscribers {
+    _subscriber: Subscriber<'static, ()>,
+    origin: Locality,
+    reliability: Reliability,
+    members: Arc<RwLock<Vec<(u64, SampleHandler)>>>,
+}
+
+fn dispatch_all(members: &RwLock<Vec<(u64, SampleHandler)>>, sample: &Sample) {
+    for (_, handler) in zread!(members).iter() {
+        if let Err(e) = handler(sample) {
+            log::warn!("{}", e);
+        }
+    }
+}
+
+// The key of a shared topic subscriber: the key expression, the allowed origin and the reliability
+type TopicKey = (String, &'static str, bool);
+
+fn topic_key(ke: &KeyExpr<'_>, origin: Locality, reliability: Reliability) -> TopicKey {
+    let origin = match origin {
+        Locality::SessionLocal => "local",
+        Locality::Remote => "remote",
+        Locality::Any => "any",
+    };
+    (ke.to_string(), origin, reliability == Reliability::Reliable)
+}
+
+// The registry of Zenoh subscribers shared by several MQTT clients.
+pub(crate) struct FanoutRegistry {
+    zsession: Arc<Session>,
+    queues: Mutex<HashMap<(String, String), QueueGroup>>,
+    queue_strategy: QueueStrategy,
+    // the shared topic subscribers, sharded per key expression
+    topics: Vec<Mutex<HashMap<TopicKey, TopicSubscribers>>>,
+    next_member_id: AtomicU64,
+    // if set, the time a subscriber without member is kept before being undeclared by the garbage collector
+    stale_timeout: Option<Duration>,
+    // the number of stale subscribers undeclared by the garbage collector
+    collected: AtomicU64,
+}
+
+impl fmt::Debug for FanoutRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FanoutRegistry")
+            .field("queues", &zlock!(self.queues).keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl FanoutRegistry {
+    pub(crate) fn new(
+        zsession: Arc<Session>,
+        stale_timeout: Option<Duration>,
+        shards: usize,
+        queue_strategy: QueueStrategy,
+    ) -> FanoutRegistry {
+        FanoutRegistry {
+            zsession,
+            queues: Mutex::new(HashMap::new()),
+            queue_strategy,
+            topics: (0..shards.max(1))
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+            next_member_id: AtomicU64::new(0),
+            stale_timeout,
+            collected: AtomicU64::new(0),
+        }
+    }
+
+    // Add a member to the queue group for the key expression, declaring the shared Zenoh subscriber if it's the 1st member.
+    // The member leaves the group when the returned QueueMember is dropped.
+    pub(crate) fn join_queue(
+        self: &Arc<Self>,
+        group: &str,
+        ke: &KeyExpr<'_>,
+        origin: Locality,
+        reliability: Reliability,
+        handler: SampleHandler,
+    ) -> ZResult<QueueMember> {
+        let key = (group.to_string(), ke.to_string());
+        let id = self.next_member_id.fetch_add(1, Ordering::Relaxed);
+        let mut queues = zlock!(self.queues);
+        match queues.get_mut(&key) {
+            Some(queue) => {
+                zlock!(queue.members).members.push((id, handler));
+                queue.empty_since = None;
+            }
+            None => {
+                log::debug!(
+                    "Declare shared Zenoh subscriber on {} for queue group '{}'",
+                    ke,
+                    group
+                );
+                let members = Arc::new(Mutex::new(QueueMembers::new(
+                    self.queue_strategy,
+                    vec![(id, handler)],
+                )));
+                let members2 = members.clone();
+                let subscriber = self
+                    .zsession
+                    .declare_subscriber(ke.clone())
+                    .callback(move |sample| zlock!(members2).dispatch(&sample))
+                    .allowed_origin(origin)
+                    .reliability(reliability)
+                    .res()?;
+                queues.insert(
+                    key.clone(),
+                    QueueGroup {
+                        _subscriber: subscriber,
+                        origin,
+                        reliability,
+                        members,
+                        empty_since: None,
+                    },
+                );
+            }
+        }
+        Ok(QueueMember {
+            registry: self.clone(),
+            key,
+            id,
+        })
+    }
+
+    fn leave_queue(&self, key: &(String, String), id: u64) {
+        let mut queues = zlock!(self.queues);
+        if let Some(queue) = queues.get_mut(key) {
+            let is_empty = {
+                let mut members = zlock!(queue.members);
+                members.members.retain(|(i, _)| *i != id);
+                members.members.is_empty()
+            };
+            if is_empty && self.stale_timeout.is_some() {
+                // kept until collected, in case a client (re)joins the group meanwhile
+                queue.empty_since = Some(Instant::now());
+            } else if is_empty {
+                log::debug!(
+                    "Undeclare shared Zenoh subscriber on {} for queue group '{}'",
+                    key.1,
+                    key.0
+                );
+                queues.remove(key);
+            }
+        }
+    }
+
+    fn topic_shard(&self, key: &TopicKey) -> &Mutex<HashMap<TopicKey, TopicSubscribers>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.topics[hasher.finish() as usize % self.topics.len()]
+    }
+
+    // Add a member to the subscribers of the key expression, declaring the shared Zenoh subscriber if it's the 1st member.
+    // The member is removed when the returned TopicMember is dropped.
+    pub(crate) fn join_topic(
+        self: &Arc<Self>,
+        ke: &KeyExpr<'_>,
+        origin: Locality,
+        reliability: Reliability,
+        handler: SampleHandler,
+    ) -> ZResult<TopicMember> {
+        let key = topic_key(ke, origin, reliability);
+        let id = self.next_member_id.fetch_add(1, Ordering::Relaxed);
+        let mut topics = zlock!(self.topic_shard(&key));
+        match topics.get(&key) {
+            Some(topic) => zwrite!(topic.members).push((id, handler)),
+            None => {
+                log::debug!(
+                    "Declare shared Zenoh subscriber on {} ({:?})",
+                    ke,
+                    reliability
+                );
+                let members = Arc::new(RwLock::new(vec![(id, handler)]));
+                let members2 = members.clone();
+                let subscriber = self
+                    .zsession
+                    .declare_subscriber(ke.clone())
+                    .callback(move |sample| dispatch_all(&members2, &sample))
+                    .allowed_origin(origin)
+                    .reliability(reliability)
+                    .res()?;
+                topics.insert(
+                    key.clone(),
+                    TopicSubscribers {
+                        _subscriber: subscriber,
+                        origin,
+                        reliability,
+                        members,
+                    },
+                );
+            }
+        }
+        Ok(TopicMember {
+            registry: self.clone(),
+            key,
+            id,
+        })
+    }
+
+    fn leave_topic(&self, key: &TopicKey, id: u64) {
+        let mut topics = zlock!(self.topic_shard(key));
+        let is_empty = topics.get(key).map_or(false, |topic| {
+            let mut members = zwrite!(topic.members);
+            members.retain(|(i, _)| *i != id);
+            members.is_empty()
+        });
+        if is_empty {
+            log::debug!("Undeclare shared Zenoh subscriber on {}", key.0);
+            topics.remove(key);
+        }
+    }
+
+    // Re-declare the shared subscribers on the given key expressions (or all of them), e.g. after an ACL change
+    // in the zenoh routers, returning their number. A new subscriber is declared before the old one is undeclared.
+    pub(crate) fn redeclare(&self, kes: Option<&HashSet<String>>) -> ZResult<usize> {
+        let matches = |ke: &str| kes.map_or(true, |kes| kes.contains(ke));
+        let mut count = 0;
+        for (key, queue) in zlock!(self.queues).iter_mut() {
+            if matches(&key.1) {
+                let members = queue.members.clone();
+                queue._subscriber = self
+                    .zsession
+                    .declare_subscriber(key.1.as_str())
+                    .callback(move |sample| zlock!(members).dispatch(&sample))
+                    .allowed_origin(queue.origin)
+                    .reliability(queue.reliability)
+                    .res()?;
+                count += 1;
+            }
+        }
+        for shard in &self.topics {
+            for (key, topic) in zlock!(shard).iter_mut() {
+                if matches(&key.0) {
+                    let members = topic.members.clone();
+                    topic._subscriber = self
+                        .zsession
+                        .declare_subscriber(key.0.as_str())
+                        .callback(move |sample| dispatch_all(&members, &sample))
+                        .allowed_origin(topic.origin)
+                        .reliability(topic.reliability)
+                        .res()?;
+                    count += 1;
+                }
+            }
+        }
+        log::info!("Re-declared {} shared Zenoh subscribers", count);
+        Ok(count)
+    }
+
+    // Undeclare the shared subscribers without member for longer than the configured time
+    fn collect_stale(&self, stale_timeout: Duration) {
+        zlock!(self.queues).retain(|key, queue| {
+            let stale = queue
+                .empty_since
+                .map_or(false, |since| since.elapsed() >= stale_timeout);
+            if stale {
+                log::debug!(
+                    "Undeclare stale shared Zenoh subscriber on {} for queue group '{}'",
+                    key.1,
+                    key.0
+                );
+                self.collected.fetch_add(1, Ordering::Relaxed);
+            }
+            !stale
+        });
+    }
+
+    // Spawn the task periodically undeclaring the stale shared subscribers (if configured)
+    pub(crate) fn spawn_gc_task(self: &Arc<Self>) {
+        let Some(stale_timeout) = self.stale_timeout else {
+            return;
+        };
+        let this = self.clone();
+        let interval = stale_timeout.clamp(Duration::from_secs(1), Duration::from_secs(10));
+        async_std::task::spawn(async move {
+            loop {
+                async_std::task::sleep(interval).await;
+                this.collect_stale(stale_timeout);
+            }
+        });
+    }
+
+    // The numbers of shared subscribers (with and without member), for the admin space
+    pub(crate) fn to_json(&self) -> Value {
+        let (topic_subscribers, topic_members, reliable) =
+            self.topics
+                .iter()
+                .fold((0, 0, 0), |(subscribers, members, reliable), shard| {
+                    let shard = zlock!(shard);
+                    (
+                        subscribers + shard.len(),
+                        members
+                            + shard
+                                .values()
+                                .map(|t| zread!(t.members).len())
+                                .sum::<usize>(),
+                        reliable + shard.keys().filter(|(_, _, reliable)| *reliable).count(),
+                    )
+                });
+        let queues = zlock!(self.queues);
+        let stale = queues.values().filter(|q| q.empty_since.is_some()).count();
+        serde_json::json!({
+            "shared_subscribers": queues.len() - stale,
+            "stale_shared_subscribers": stale,
+            "collected_stale_subscribers": self.collected.load(Ordering::Relaxed),
+            "topic_subscribers": topic_subscribers,
+            "topic_subscribers_members": topic_members,
+            "reliable_topic_subscribers": reliable,
+            "best_effort_topic_subscribers": topic_subscribers - reliable,
+        })
+    }
+}
+
+// The membership of a MQTT client in a queue group. Dropping it makes the client leave the group.
+#[derive(Debug)]
+pub(crate) struct QueueMember {
+    registry: Arc<FanoutRegistry>,
+    key: (String, String),
+    id: u64,
+}
+
+impl Drop for QueueMember {
+    fn drop(&mut self) {
+        self.registry.leave_queue(&self.key, self.id);
+    }
+}
+
+// The membership of a MQTT client subscription in a shared topic subscriber. Dropping it removes the member.
+#[derive(Debug)]
+pub(crate) struct TopicMember {
+    registry: Arc<FanoutRegistry>,
+    key: TopicKey,
+    id: u64,
+}
+
+impl Drop for TopicMember {
+    fn drop(&mut self) {
+        self.registry.leave_topic(&self.key, self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_queue_topic() {
+        assert_eq!(parse_queue_topic("$queue/g/a/b"), Some(("g", "a/b")));
+        assert_eq!(parse_queue_topic("$queue/g/#"), Some(("g", "#")));
+        assert_eq!(parse_queue_topic("$queue/g/+/b"), Some(("g", "+/b")));
+        assert_eq!(parse_queue_topic("$queue/g"), None);
+        assert_eq!(parse_queue_topic("$queue/g/"), None);
+        assert_eq!(parse_queue_topic("$queue//a"), None);
+        assert_eq!(parse_queue_topic("$share/g/a"), None);
+        assert_eq!(parse_queue_topic("a/b"), None);
+    }
+
+    // A queue group whose members record the indexes of the samples they receive,
+    // the members listed in `failing` failing to receive them
+    fn recording_members(
+        strategy: QueueStrategy,
+        n: usize,
+        failing: &[usize],
+    ) -> (QueueMembers, Arc<Mutex<Vec<usize>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let members = (0..n)
+            .map(|i| {
+                let received = received.clone();
+                let fails = failing.contains(&i);
+                let handler: SampleHandler = Arc::new(move |_| {
+                    if fails {
+                        bail!("member {} fails", i);
+                    }
+                    zlock!(received).push(i);
+                    Ok(())
+                });
+                (i as u64, handler)
+            })
+            .collect();
+        (QueueMembers::new(strategy, members), received)
+    }
+
+    fn sample() -> Sample {
+        Sample::new(KeyExpr::try_from("test/queue").unwrap(), "payload")
+    }
+
+    #[test]
+    fn test_round_robin_dispatch() {
+        let (mut members, received) = recording_members(QueueStrategy::RoundRobin, 3, &[]);
+        for _ in 0..7 {
+            members.dispatch(&sample());
+        }
+        assert_eq!(*zlock!(received), vec![0, 1, 2, 0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn test_random_dispatch() {
+        let (mut members, received) = recording_members(QueueStrategy::Random, 4, &[]);
+        for _ in 0..1000 {
+            members.dispatch(&sample());
+        }
+        let received = zlock!(received);
+        // each sample is received by exactly 1 member, and all the members receive some
+        assert_eq!(received.len(), 1000);
+        for i in 0..4 {
+            assert!(received.contains(&i), "member {i} never selected");
+        }
+    }
+
+    #[test]
+    fn test_dispatch_skips_failing_members() {
+        for strategy in [QueueStrategy::RoundRobin, QueueStrategy::Random] {
+            let (mut members, received) = recording_members(strategy, 3, &[0, 2]);
+            for _ in 0..10 {
+                members.dispatch(&sample());
+            }
+            assert_eq!(*zlock!(received), vec![1; 10]);
+        }
+    }
+
+    #[test]
+    fn test_dispatch_without_member() {
+        for strategy in [QueueStrategy::RoundRobin, QueueStrategy::Random] {
+            let (mut members, received) = recording_members(strategy, 0, &[]);
+            members.dispatch(&sample());
+            assert!(zlock!(received).is_empty());
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The active/passive high availability of several bridges serving the same MQTT clients (e.g. behind a floating
+// IP managed with VRRP). Each bridge of the group declares a zenoh liveliness token, and the alive bridge with
+// the lowest id is the active one. Only the active bridge accepts MQTT connections, so that the subscribers are
+// declared by a single bridge and the publications are not delivered twice during a failover.
+
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zenoh::plugins::ZResult;
+use zenoh::prelude::r#async::*;
+use zenoh_core::{bail, zlock};
+
+use crate::clients::ClientsRegistry;
+use crate::config::Config;
+use crate::process_wide::ProcessWide;
+
+#[derive(Default)]
+struct State {
+    id: String,
+    // the ids of the alive bridges of the group
+    members: BTreeSet<String>,
+    // the time of the election (in seconds since the UNIX epoch)
+    active_since: Option<u64>,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static GROUP: ProcessWide<()> = ProcessWide::new("ha");
+
+lazy_static::lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State::default());
+}
+
+// Returns true if the bridge can accept MQTT connections (always, if not part of a HA group)
+pub(crate) fn is_active() -> bool {
+    !ENABLED.load(Ordering::Acquire) || ACTIVE.load(Ordering::Acquire)
+}
+
+// The id of a bridge of the group, from the key expression of its liveliness token
+fn member_id(ke: &KeyExpr<'_>) -> Option<String> {
+    ke.as_str().rsplit('/').next().map(String::from)
+}
+
+// Declare the liveliness token of the bridge, and spawn the task electing the active bridge on the changes
+// of the group. The bridge is passive until the initial election.
+pub(crate) async fn init(
+    config: &Config,
+    zsession: Arc<Session>,
+    clients: Arc<ClientsRegistry>,
+) -> ZResult<()> {
+    let Some(conf) = &config.ha else {
+        GROUP.init(&config.ha, || Ok(None))?;
+        return Ok(());
+    };
+    let id = conf
+        .id
+        .clone()
+        .unwrap_or_else(|| zsession.zid().to_string());
+    let token_ke = conf.key_expr.join(&id)?;
+    let members_ke = conf.key_expr.join("*")?;
+    if token_ke.chunks().count() != conf.key_expr.chunks().count() + 1 {
+        bail!(
+            "Invalid 'ha/id' {}: must be a single chunk of key expression",
+            id
+        );
+    }
+    if !GROUP.init(&config.ha, || Ok(Some(())))? {
+        // the bridge already joined the group
+        return Ok(());
+    }
+    ENABLED.store(true, Ordering::Release);
+
+    let token = zsession.liveliness().declare_token(&token_ke).res().await?;
+    let subscriber = zsession
+        .liveliness()
+        .declare_subscriber(&members_ke)
+        .res()
+        .await?;
+    let replies = zsession.liveliness().get(&members_ke).res().await?;
+    let mut members = BTreeSet::from([id.clone()]);
+    while let Ok(reply) = replies.recv_async().await {
+        if let Some(member) = reply.sample.ok().and_then(|s| member_id(&s.key_expr)) {
+            members.insert(member);
+        }
+    }
+    {
+        let mut state = zlock!(STATE);
+        state.id = id;
+        state.members = members;
+    }
+    elect(&clients);
+
+    async_std::task::spawn(async move {
+        // the token is kept alive as long as the task
+        let _token = token;
+        while let Ok(sample) = subscriber.recv_async().await {
+            let Some(member) = member_id(&sample.key_expr) else {
+                continue;
+            };
+            {
+                let mut state = zlock!(STATE);
+                if sample.kind == SampleKind::Delete && member != state.id {
+                    log::info!("MQTT bridge '{}' left the HA group", member);
+                    state.members.remove(&member);
+                } else if state.members.insert(member.clone()) {
+                    log::info!("MQTT bridge '{}' joined the HA group", member);
+                }
+            }
+            elect(&clients);
+        }
+    });
+    Ok(())
+}
+
+// The bridge with the lowest id is active. A bridge becoming passive disconnects its clients,
+// that will reconnect to the active one.
+fn elect(clients: &ClientsRegistry) {
+    let mut state = zlock!(STATE);
+    let active = state.members.first() == Some(&state.id);
+    if active == ACTIVE.swap(active, Ordering::AcqRel) {
+        return;
+    }
+    if active {
+        log::info!("MQTT bridge '{}' elected active in its HA group", state.id);
+        state.active_since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs());
+    } else {
+        let disconnected = clients.disconnect_all();
+        log::warn!(
+            "MQTT bridge '{}' now passive in its HA group - {} MQTT clients disconnected",
+            state.id,
+            disconnected
+        );
+        state.active_since = None;
+    }
+}
+
+pub(crate) fn to_json() -> Value {
+    let state = zlock!(STATE);
+    serde_json::json!({
+        "id": state.id,
+        "active": ACTIVE.load(Ordering::Acquire),
+        "active_since": state.active_since,
+        "members": state.members,
+    })
+}
@@ -0,0 +1,96 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+//! The MQTT bridge as a library, for applications embedding it in their own binaries
+//! (independently of the zenohd plugins loader).
+
+use serde_json::Value;
+use std::sync::{Arc, OnceLock};
+use zenoh::plugins::ZResult;
+use zenoh::Session;
+
+use crate::config::Config;
+use crate::events::{self, SessionEvent};
+use crate::BridgeState;
+
+/// A handle on a MQTT bridge started with [`spawn()`].
+pub struct BridgeHandle {
+    port_tx: flume::Sender<String>,
+    stop_tx: flume::Sender<()>,
+    state: Arc<OnceLock<Arc<BridgeState>>>,
+    task: async_std::task::JoinHandle<ZResult<()>>,
+}
+
+/// Start a MQTT bridge routing over an existing zenoh session.
+///
+/// The zenoh session is used as is: the `generalise_subs` and `generalise_pubs` settings are ignored.
+/// As the plugin has some process-wide state, a single bridge can run per process: a bridge restarted in the
+/// same process fails to start if its process-wide settings (e.g. `encryption`, `audit` or `scripts`) differ.
+/// A failure to start is reported by [`crate::ready()`] and [`BridgeHandle::stop()`].
+pub fn spawn(config: Config, zsession: Arc<Session>) -> BridgeHandle {
+    if !config.generalise_subs.is_empty() || !config.generalise_pubs.is_empty() {
+        log::warn!("'generalise_subs' and 'generalise_pubs' are ignored for a MQTT bridge spawned on an existing zenoh session");
+    }
+    let (port_tx, port_rx) = flume::unbounded();
+    let (stop_tx, stop_rx) = flume::bounded(1);
+    let state = Arc::new(OnceLock::new());
+    let serve = crate::serve(zsession, config, port_rx, stop_rx, state.clone());
+    let task = async_std::task::spawn(async move {
+        let result = serve.await;
+        crate::report_failure(&result);
+        result
+    });
+    BridgeHandle {
+        port_tx,
+        stop_tx,
+        state,
+        task,
+    }
+}
+
+impl BridgeHandle {
+    /// Returns true once the bridge is started (see also [`crate::ready()`]).
+    pub fn is_started(&self) -> bool {
+        self.state.get().is_some()
+    }
+
+    /// Rebind the MQTT server on a new port (the old listener being closed after `listener_drain_secs`).
+    pub fn rebind(&self, port: &str) {
+        let _ = self.port_tx.send(port.to_string());
+    }
+
+    /// The statistics of the bridge, as in the admin space: the listeners, the connected clients and the
+    /// subscriptions churn. Returns `None` if the bridge is not started yet.
+    pub fn stats(&self) -> Option<Value> {
+        let bridge = self.state.get()?;
+        Some(serde_json::json!({
+            "listeners": bridge.listeners.to_json(),
+            "clients": bridge.clients.to_json(),
+            "subscriptions": bridge.clients.churn_to_json(),
+        }))
+    }
+
+    /// A stream of the MQTT clients session events (see [`crate::events`]).
+    pub fn events(&self) -> flume::Receiver<SessionEvent> {
+        events::event_stream()
+    }
+
+    /// Stop the bridge, closing all the MQTT connections, and wait for its end.
+    /// Returns the error that stopped the bridge before, if any (e.g. a failure to start).
+    /// For a graceful stop, see [`crate::request_drain()`].
+    pub async fn stop(self) -> ZResult<()> {
+        let _ = self.stop_tx.send(());
+        self.task.await
+    }
+}
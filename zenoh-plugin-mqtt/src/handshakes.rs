@@ -0,0 +1,113 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The limit of the MQTT handshakes processed concurrently (authentication, provisioning, session resumption...):
+// the excess handshakes wait for a slot (up to a timeout) or are rejected with "Server Busy", so that a reconnection
+// storm doesn't starve the routing of the established sessions.
+
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::HandshakesConfig;
+
+const SLOT_CHECK_PERIOD: Duration = Duration::from_millis(10);
+
+#[derive(Debug)]
+pub(crate) struct HandshakeLimiter {
+    max_concurrent: usize,
+    queue_timeout: Duration,
+    active: AtomicUsize,
+    waiting: AtomicUsize,
+    queued: AtomicU64,
+    rejected: AtomicU64,
+}
+
+// A handshake being processed, until dropped
+pub(crate) struct HandshakePermit(Arc<HandshakeLimiter>);
+
+impl Drop for HandshakePermit {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl HandshakeLimiter {
+    pub(crate) fn new(config: &HandshakesConfig) -> HandshakeLimiter {
+        HandshakeLimiter {
+            max_concurrent: config.max_concurrent,
+            queue_timeout: Duration::from_millis(config.queue_timeout_ms),
+            active: AtomicUsize::new(0),
+            waiting: AtomicUsize::new(0),
+            queued: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    fn try_take_slot(&self) -> bool {
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < self.max_concurrent).then_some(active + 1)
+            })
+            .is_ok()
+    }
+
+    // Take a slot without waiting (e.g. for the MQTT-SN gateway). Returns None if the limit is reached.
+    pub(crate) fn try_acquire(self: &Arc<Self>) -> Option<HandshakePermit> {
+        if self.try_take_slot() {
+            Some(HandshakePermit(self.clone()))
+        } else {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+
+    // Take a slot, waiting at most the queue timeout. Returns None if no slot was freed in time.
+    pub(crate) async fn acquire(self: &Arc<Self>) -> Option<HandshakePermit> {
+        if self.try_take_slot() {
+            return Some(HandshakePermit(self.clone()));
+        }
+        if self.queue_timeout.is_zero() {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.waiting.fetch_add(1, Ordering::AcqRel);
+        let start = Instant::now();
+        let mut permit = None;
+        while start.elapsed() < self.queue_timeout {
+            async_std::task::sleep(SLOT_CHECK_PERIOD).await;
+            if self.try_take_slot() {
+                permit = Some(HandshakePermit(self.clone()));
+                break;
+            }
+        }
+        self.waiting.fetch_sub(1, Ordering::AcqRel);
+        if permit.is_none() {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        permit
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        serde_json::json!({
+            "max_concurrent": self.max_concurrent,
+            "active": self.active.load(Ordering::Acquire),
+            "waiting": self.waiting.load(Ordering::Acquire),
+            "queued": self.queued.load(Ordering::Relaxed),
+            "rejected": self.rejected.load(Ordering::Relaxed),
+        })
+    }
+}
@@ -0,0 +1,214 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The caches of the key expressions mapped from the MQTT publication topics, avoiding the validation,
+// escaping, pseudonymization and scope joining of a topic for each publication: a LRU cache per MQTT
+// session, and a global one (per scope) for the hot topics published by many clients.
+// The same LRU cache is used per MQTT session for the allow/deny decisions per topic.
+
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use zenoh::prelude::OwnedKeyExpr;
+use zenoh_core::zlock;
+
+use crate::config::KeCacheConfig;
+
+// A LRU cache of values per MQTT topic
+#[derive(Debug)]
+pub(crate) struct TopicCache<V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<String, (V, u64)>,
+    // the topics per time of last use
+    lru: BTreeMap<u64, String>,
+}
+
+// A LRU cache of key expressions per MQTT topic
+pub(crate) type KeCache = TopicCache<OwnedKeyExpr>;
+
+impl<V: Clone> TopicCache<V> {
+    pub(crate) fn new(capacity: usize) -> TopicCache<V> {
+        TopicCache {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, topic: &str) -> Option<V> {
+        let (ke, used) = self.entries.get_mut(topic)?;
+        self.tick += 1;
+        if let Some(topic) = self.lru.remove(&*used) {
+            self.lru.insert(self.tick, topic);
+        }
+        *used = self.tick;
+        Some(ke.clone())
+    }
+
+    pub(crate) fn insert(&mut self, topic: String, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if let Some((_, used)) = self.entries.insert(topic.clone(), (value, self.tick)) {
+            self.lru.remove(&used);
+        }
+        self.lru.insert(self.tick, topic);
+        while self.entries.len() > self.capacity {
+            match self.lru.pop_first() {
+                Some((_, oldest)) => self.entries.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&String, &V)> {
+        self.entries
+            .iter()
+            .map(|(topic, (value, _))| (topic, value))
+    }
+}
+
+// A LRU cache of the allow/deny decisions per client identity and topic. The decisions of a previous
+// generation of the 'allow' and 'deny' settings are discarded.
+#[derive(Debug)]
+pub(crate) struct AclCache {
+    generation: u64,
+    decisions: TopicCache<bool>,
+}
+
+impl AclCache {
+    pub(crate) fn new(capacity: usize) -> AclCache {
+        AclCache {
+            generation: 0,
+            decisions: TopicCache::new(capacity),
+        }
+    }
+
+    fn key(identity: Option<&str>, topic: &str) -> String {
+        format!("{}\0{}", identity.unwrap_or_default(), topic)
+    }
+
+    pub(crate) fn get(
+        &mut self,
+        generation: u64,
+        identity: Option<&str>,
+        topic: &str,
+    ) -> Option<bool> {
+        if generation != self.generation {
+            self.decisions.clear();
+            self.generation = generation;
+            return None;
+        }
+        self.decisions.get(&Self::key(identity, topic))
+    }
+
+    pub(crate) fn insert(
+        &mut self,
+        generation: u64,
+        identity: Option<&str>,
+        topic: &str,
+        allowed: bool,
+    ) {
+        if generation == self.generation {
+            self.decisions.insert(Self::key(identity, topic), allowed);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.decisions.clear();
+    }
+}
+
+// The global cache, with the statistics of all the caches
+#[derive(Debug)]
+pub(crate) struct KeCaches {
+    pub(crate) session_size: usize,
+    global: Mutex<KeCache>,
+    session_hits: AtomicU64,
+    global_hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl KeCaches {
+    pub(crate) fn new(config: &KeCacheConfig) -> KeCaches {
+        KeCaches {
+            session_size: config.session_size,
+            global: Mutex::new(KeCache::new(config.global_size)),
+            session_hits: AtomicU64::new(0),
+            global_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // The key of a topic in the global cache, which is shared by the clients of different scopes
+    fn global_key(scope: &Option<OwnedKeyExpr>, topic: &str) -> String {
+        match scope {
+            Some(scope) => format!("{scope}\0{topic}"),
+            None => topic.to_string(),
+        }
+    }
+
+    // Get the key expression of a topic, looking in the session's cache, then in the global one,
+    // and otherwise mapping it with `map` (and caching the result)
+    pub(crate) fn get_or_map<E>(
+        &self,
+        session: &Mutex<KeCache>,
+        scope: &Option<OwnedKeyExpr>,
+        topic: &str,
+        map: impl FnOnce() -> Result<OwnedKeyExpr, E>,
+    ) -> Result<OwnedKeyExpr, E> {
+        if let Some(ke) = zlock!(session).get(topic) {
+            self.session_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(ke);
+        }
+        let global_key = Self::global_key(scope, topic);
+        let cached = zlock!(self.global).get(&global_key);
+        let ke = match cached {
+            Some(ke) => {
+                self.global_hits.fetch_add(1, Ordering::Relaxed);
+                ke
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                let ke = map()?;
+                zlock!(self.global).insert(global_key, ke.clone());
+                ke
+            }
+        };
+        zlock!(session).insert(topic.to_string(), ke.clone());
+        Ok(ke)
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        serde_json::json!({
+            "session_hits": self.session_hits.load(Ordering::Relaxed),
+            "global_hits": self.global_hits.load(Ordering::Relaxed),
+            "misses": self.misses.load(Ordering::Relaxed),
+            "global_size": zlock!(self.global).len(),
+        })
+    }
+}
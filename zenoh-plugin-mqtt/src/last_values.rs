@@ -0,0 +1,99 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The cache of the last value per key expression of the MQTT publications routed to zenoh (or only of the
+// retained ones), and its queryable: the zenoh applications can get the current MQTT state without a storage.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use zenoh::plugins::ZResult;
+use zenoh::prelude::r#async::*;
+use zenoh::queryable::Queryable;
+use zenoh_core::zlock;
+
+use crate::config::LastValuesConfig;
+use crate::ke_cache::TopicCache;
+
+#[derive(Debug)]
+pub(crate) struct LastValues {
+    zsession: Arc<Session>,
+    retained_only: bool,
+    // the last payload and encoding per key expression (the least recently updated being evicted first)
+    values: Mutex<TopicCache<(Vec<u8>, Encoding)>>,
+    queries: AtomicU64,
+}
+
+impl LastValues {
+    pub(crate) fn new(zsession: Arc<Session>, config: &LastValuesConfig) -> LastValues {
+        LastValues {
+            zsession,
+            retained_only: config.retained_only,
+            values: Mutex::new(TopicCache::new(config.max_topics)),
+            queries: AtomicU64::new(0),
+        }
+    }
+
+    // Record a publication routed to zenoh
+    pub(crate) fn record(&self, ke: &keyexpr, payload: &[u8], encoding: &Encoding, retain: bool) {
+        if retain || !self.retained_only {
+            zlock!(self.values).insert(ke.to_string(), (payload.to_vec(), encoding.clone()));
+        }
+    }
+
+    // Declare the queryable replying with the last values, on the scope (if any)
+    pub(crate) async fn declare_queryable(
+        self: &Arc<Self>,
+        scope: &Option<OwnedKeyExpr>,
+    ) -> ZResult<Queryable<'static, ()>> {
+        let queryable_ke = match scope {
+            Some(scope) => format!("{scope}/**"),
+            None => "**".to_string(),
+        };
+        log::debug!(
+            "Declare queryable of the MQTT last values on {}",
+            queryable_ke
+        );
+        let this = self.clone();
+        let queryable = self
+            .zsession
+            .declare_queryable(queryable_ke)
+            .callback(move |query| {
+                this.queries.fetch_add(1, Ordering::Relaxed);
+                let values = zlock!(this.values);
+                for (ke, (payload, encoding)) in values.iter() {
+                    let Ok(ke) = KeyExpr::try_from(ke.clone()) else {
+                        continue;
+                    };
+                    if query.selector().key_expr.intersects(&ke) {
+                        use zenoh::prelude::sync::SyncResolve;
+                        let value = Value::from(payload.clone()).encoding(encoding.clone());
+                        if let Err(e) = query.reply(Ok(Sample::new(ke, value))).res_sync() {
+                            log::warn!("Error replying to MQTT last values query: {}", e);
+                        }
+                    }
+                }
+            })
+            .res()
+            .await?;
+        Ok(queryable)
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "retained_only": self.retained_only,
+            "topics": zlock!(self.values).len(),
+            "queries": self.queries.load(Ordering::Relaxed),
+        })
+    }
+}
@@ -0,0 +1,137 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// Histograms of the latency of the messages routed through the bridge, since its start:
+//  - MQTT to zenoh: from the reception of a MQTT PUBLISH to the completion of the zenoh put
+//  - zenoh to MQTT: from the reception of a zenoh sample to its write to the MQTT client
+// The buckets are exponential (each one 25% larger than the previous one), so the percentiles
+// have a relative error below 25% with a constant memory and recording cost.
+
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// upper bound of the 1st bucket
+const MIN_BOUND_US: f64 = 10.0;
+const GROWTH: f64 = 1.25;
+// the upper bound of the last bucket is about 75 seconds (larger latencies are accounted in it)
+const BUCKETS: usize = 72;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Direction {
+    MqttToZenoh,
+    ZenohToMqtt,
+}
+
+impl Direction {
+    pub(crate) const ALL: [Direction; 2] = [Direction::MqttToZenoh, Direction::ZenohToMqtt];
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Direction::MqttToZenoh => "mqtt_to_zenoh",
+            Direction::ZenohToMqtt => "zenoh_to_mqtt",
+        }
+    }
+
+    fn histogram(&self) -> &'static Histogram {
+        match self {
+            Direction::MqttToZenoh => &MQTT_TO_ZENOH,
+            Direction::ZenohToMqtt => &ZENOH_TO_MQTT,
+        }
+    }
+}
+
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum_us: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, latency: Duration) {
+        let us = latency.as_micros() as f64;
+        let index = if us <= MIN_BOUND_US {
+            0
+        } else {
+            ((us / MIN_BOUND_US).ln() / GROWTH.ln()).ceil() as usize
+        };
+        self.buckets[index.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us as u64, Ordering::Relaxed);
+    }
+
+    // The upper bound of the bucket containing the percentile `p` (between 0 and 1)
+    fn percentile(&self, p: f64) -> Option<Duration> {
+        let count = self.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return None;
+        }
+        let rank = ((p * count as f64).ceil() as u64).max(1);
+        let mut cumulated = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulated += bucket.load(Ordering::Relaxed);
+            if cumulated >= rank {
+                return Some(bucket_bound(i));
+            }
+        }
+        Some(bucket_bound(BUCKETS - 1))
+    }
+
+    fn to_json(&self) -> Value {
+        let count = self.count.load(Ordering::Relaxed);
+        let ms = |d: Option<Duration>| d.map(|d| d.as_secs_f64() * 1000.0);
+        let mean_ms =
+            (count > 0).then(|| self.sum_us.load(Ordering::Relaxed) as f64 / count as f64 / 1000.0);
+        serde_json::json!({
+            "count": count,
+            "mean_ms": mean_ms,
+            "p50_ms": ms(self.percentile(0.50)),
+            "p95_ms": ms(self.percentile(0.95)),
+            "p99_ms": ms(self.percentile(0.99)),
+        })
+    }
+}
+
+fn bucket_bound(index: usize) -> Duration {
+    Duration::from_secs_f64(MIN_BOUND_US * GROWTH.powi(index as i32) / 1_000_000.0)
+}
+
+lazy_static::lazy_static! {
+    static ref MQTT_TO_ZENOH: Histogram = Histogram::new();
+    static ref ZENOH_TO_MQTT: Histogram = Histogram::new();
+}
+
+pub(crate) fn record(direction: Direction, latency: Duration) {
+    direction.histogram().record(latency);
+}
+
+pub(crate) fn percentile(direction: Direction, p: f64) -> Option<Duration> {
+    direction.histogram().percentile(p)
+}
+
+pub(crate) fn to_json() -> Value {
+    let mut json = serde_json::Map::new();
+    for direction in Direction::ALL {
+        json.insert(direction.as_str().into(), direction.histogram().to_json());
+    }
+    Value::Object(json)
+}
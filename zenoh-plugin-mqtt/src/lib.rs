@@ -11,29 +11,123 @@
 // Contributors:
 //   ADLINK zenoh team, <zenoh@adlink-labs.tech>
 //
+use futures::future::{select, Either};
 use git_version::git_version;
 use ntex::service::{fn_factory_with_config, fn_service};
 use ntex::util::Ready;
-use ntex_mqtt::{v3, v5, MqttServer};
+use ntex_mqtt::{v3, v5, MqttServer, QoS};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 use std::env;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 use zenoh::plugins::{Plugin, RunningPluginTrait, Runtime, ZenohPlugin};
 use zenoh::prelude::r#async::*;
 use zenoh::queryable::Query;
 use zenoh::Result as ZResult;
 use zenoh::Session;
-use zenoh_core::zresult::ZError;
-use zenoh_core::{bail, zerror};
+use zenoh_core::{bail, zerror, zread};
 
 #[macro_use]
 extern crate zenoh_core;
 
+mod access_windows;
+mod audit;
+mod auth;
+mod bans;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
+mod chunking;
+mod client_stats;
+mod clients;
 pub mod config;
+mod drain;
+pub mod encryption;
+mod enrollment;
+mod envelope;
+pub mod error;
+pub mod events;
+mod fanout;
+mod ha;
+mod handle;
+mod handshakes;
+mod ke_cache;
+mod last_values;
+mod latency;
+mod link_quality;
+mod listener;
+mod listener_stats;
+pub mod logging;
+mod memory_budget;
 mod mqtt_helpers;
 mod mqtt_session_state;
-use config::Config;
+mod mqtt_sn;
+mod pause;
+mod payload_filter;
+mod payload_format;
+mod payload_sizes;
+mod process_wide;
+mod provisioning;
+pub mod pseudonyms;
+mod put_caps;
+mod query_rules;
+mod readiness;
+mod recording;
+mod resource_guard;
+mod scripting;
+mod sessions;
+mod shm;
+mod slow_consumers;
+mod store_forward;
+mod sys_topics;
+mod telemetry;
+mod topic_trie;
+mod uns;
+pub mod validation;
+use auth::{decode_sasl_plain, AuthDecision, Authenticator, AUTH_METHOD_PLAIN};
+use bans::BanList;
+use client_stats::ClientStats;
+use clients::{ClientsRegistry, Negotiated};
+use config::{BindCondition, Config, PublicationPriority};
+pub use drain::{drained, request_drain};
+use enrollment::Enrollment;
+use error::MqttPluginError;
+use events::{ProtocolVersion, SessionEvent};
+use fanout::FanoutRegistry;
+pub use handle::{spawn, BridgeHandle};
+use handshakes::HandshakeLimiter;
+use ke_cache::KeCaches;
+use last_values::LastValues;
+use listener_stats::{ListenersStats, LISTENER_MQTT};
+use memory_budget::MemoryBudget;
+use mqtt_helpers::{MqttSink, TopicAcl};
 use mqtt_session_state::MqttSessionState;
+use payload_filter::PayloadFilter;
+use payload_format::{DeliveryOptions, PayloadFormat};
+use provisioning::Provisioning;
+use put_caps::PutCaps;
+use query_rules::QueryRules;
+pub use readiness::ready;
+use resource_guard::ResourceGuard;
+use sessions::SessionRegistry;
+use shm::ShmProvider;
+use slow_consumers::SlowConsumers;
+use store_forward::StoreForward;
+use uns::Uns;
+
+// The MQTT 5 SUBSCRIBE user property requesting the history of the subscribed topics (e.g. "history=10")
+const USER_PROPERTY_HISTORY: &str = "history";
+// The maximum number of historical publications a subscription can request
+const MAX_SUBSCRIPTION_HISTORY: usize = 1000;
+// The MQTT 5 SUBSCRIBE user property requesting a payload format for the subscribed topics (e.g. "format=json")
+const USER_PROPERTY_FORMAT: &str = "format";
+// The MQTT 5 SUBSCRIBE user property requesting the metadata of the zenoh samples as user properties ("meta=true")
+const USER_PROPERTY_META: &str = "meta";
+// The MQTT 5 SUBSCRIBE user property filtering the publications on their JSON payload (e.g. "filter=$.temp > 30")
+const USER_PROPERTY_FILTER: &str = "filter";
+// The MQTT 5 SUBACK user property returning a filter a wildcard subscription was narrowed to (see 'allowed_filters')
+const USER_PROPERTY_GRANTED_FILTER: &str = "granted_filter";
 
 macro_rules! ke_for_sure {
     ($val:expr) => {
@@ -42,11 +136,48 @@ macro_rules! ke_for_sure {
 }
 
 pub const GIT_VERSION: &str = git_version!(prefix = "v", cargo_prefix = "v");
+/// The version of zenoh the plugin is built against: the plugin can only be loaded by a zenohd of this version.
+pub const ZENOH_VERSION: &str = zenoh::GIT_VERSION;
 lazy_static::lazy_static! {
-    pub static ref LONG_VERSION: String = format!("{} built with {}", GIT_VERSION, env!("RUSTC_VERSION"));
+    pub static ref LONG_VERSION: String = format!("{} built with {} and zenoh {}", GIT_VERSION, env!("RUSTC_VERSION"), ZENOH_VERSION);
     static ref KE_PREFIX_ADMIN_SPACE: &'static keyexpr = ke_for_sure!("@/service");
     static ref ADMIN_SPACE_KE_VERSION: &'static keyexpr = ke_for_sure!("version");
     static ref ADMIN_SPACE_KE_CONFIG: &'static keyexpr = ke_for_sure!("config");
+    static ref ADMIN_SPACE_KE_CONFIG_HASH: &'static keyexpr = ke_for_sure!("config_hash");
+    static ref ADMIN_SPACE_KE_BUILD: &'static keyexpr = ke_for_sure!("build");
+    static ref ADMIN_SPACE_KE_UPTIME: &'static keyexpr = ke_for_sure!("uptime");
+    static ref ADMIN_SPACE_KE_CLIENTS_STATS: &'static keyexpr = ke_for_sure!("clients/stats");
+    static ref ADMIN_SPACE_KE_CLIENTS_LIST: &'static keyexpr = ke_for_sure!("clients/list");
+    static ref ADMIN_SPACE_KE_CLIENTS_ROUTES: &'static keyexpr = ke_for_sure!("clients/routes");
+    static ref ADMIN_SPACE_KE_SUBSCRIPTIONS: &'static keyexpr = ke_for_sure!("subscriptions");
+    static ref ADMIN_SPACE_KE_CLIENTS_DISCONNECT: &'static keyexpr = ke_for_sure!("clients/disconnect");
+    static ref ADMIN_SPACE_KE_CLIENTS_RESUBSCRIBE: &'static keyexpr = ke_for_sure!("clients/resubscribe");
+    static ref ADMIN_SPACE_KE_SCHEMA: &'static keyexpr = ke_for_sure!("schema");
+    static ref ADMIN_SPACE_KE_BANS: &'static keyexpr = ke_for_sure!("bans");
+    static ref ADMIN_SPACE_KE_LISTENERS: &'static keyexpr = ke_for_sure!("listeners");
+    static ref ADMIN_SPACE_KE_LOG_FILTER: &'static keyexpr = ke_for_sure!("log_filter");
+    static ref ADMIN_SPACE_KE_BANS_ADD: &'static keyexpr = ke_for_sure!("bans/add");
+    static ref ADMIN_SPACE_KE_BANS_REMOVE: &'static keyexpr = ke_for_sure!("bans/remove");
+    static ref ADMIN_SPACE_KE_MEMORY_BUDGET: &'static keyexpr = ke_for_sure!("memory_budget");
+    static ref ADMIN_SPACE_KE_RESOURCE_GUARD: &'static keyexpr = ke_for_sure!("resource_guard");
+    static ref ADMIN_SPACE_KE_HANDSHAKES: &'static keyexpr = ke_for_sure!("handshakes");
+    static ref ADMIN_SPACE_KE_SLOW_CONSUMERS: &'static keyexpr = ke_for_sure!("slow_consumers");
+    static ref ADMIN_SPACE_KE_KE_CACHE: &'static keyexpr = ke_for_sure!("ke_cache");
+    static ref ADMIN_SPACE_KE_LAST_VALUES: &'static keyexpr = ke_for_sure!("last_values");
+    static ref ADMIN_SPACE_KE_PROVISIONING: &'static keyexpr = ke_for_sure!("provisioning");
+    static ref ADMIN_SPACE_KE_ENROLLMENT: &'static keyexpr = ke_for_sure!("enrollment");
+    static ref ADMIN_SPACE_KE_ROUTING: &'static keyexpr = ke_for_sure!("routing");
+    static ref ADMIN_SPACE_KE_ROUTING_PAUSE: &'static keyexpr = ke_for_sure!("routing/pause");
+    static ref ADMIN_SPACE_KE_ROUTING_RESUME: &'static keyexpr = ke_for_sure!("routing/resume");
+    static ref ADMIN_SPACE_KE_DRAIN: &'static keyexpr = ke_for_sure!("drain");
+    static ref ADMIN_SPACE_KE_REPLAY: &'static keyexpr = ke_for_sure!("replay");
+    static ref ADMIN_SPACE_KE_HA: &'static keyexpr = ke_for_sure!("ha");
+    static ref ADMIN_SPACE_KE_LATENCY: &'static keyexpr = ke_for_sure!("latency");
+    static ref ADMIN_SPACE_KE_PAYLOAD_SIZES: &'static keyexpr = ke_for_sure!("payload_sizes");
+    static ref ADMIN_SPACE_KE_DASHBOARD: &'static keyexpr = ke_for_sure!("dashboard");
+    static ref ADMIN_SPACE_KE_SESSIONS_EXPORT: &'static keyexpr = ke_for_sure!("sessions/export");
+    static ref ADMIN_SPACE_KE_SESSIONS_IMPORT: &'static keyexpr = ke_for_sure!("sessions/import");
+    static ref ADMIN_SPACE_KE_PSEUDONYMS_REVEAL: &'static keyexpr = ke_for_sure!("pseudonyms/reveal");
 }
 
 zenoh_plugin_trait::declare_plugin!(MqttPlugin);
@@ -61,25 +192,72 @@ impl Plugin for MqttPlugin {
     const STATIC_NAME: &'static str = "zenoh-plugin-mqtt";
 
     fn start(name: &str, runtime: &Self::StartArgs) -> ZResult<zenoh::plugins::RunningPlugin> {
-        // Try to initiate login.
-        // Required in case of dynamic lib, otherwise no logs.
-        // But cannot be done twice in case of static link.
-        let _ = env_logger::try_init();
-
         let runtime_conf = runtime.config.lock();
         let plugin_conf = runtime_conf
             .plugin(name)
             .ok_or_else(|| zerror!("Plugin `{}`: missing config", name))?;
         let config: Config = serde_json::from_value(plugin_conf.clone())
             .map_err(|e| zerror!("Plugin `{}` configuration error: {}", name, e))?;
-        async_std::task::spawn(run(runtime.clone(), config));
-        Ok(Box::new(MqttPlugin))
+        // Try to initiate login, as configured.
+        // Required in case of dynamic lib, otherwise no logs.
+        // But cannot be done twice in case of static link.
+        logging::init_from_config(&config);
+        let (port_tx, port_rx) = flume::unbounded();
+        let state = Arc::new(OnceLock::new());
+        async_std::task::spawn(run(runtime.clone(), config, port_rx, state.clone()));
+        Ok(Box::new(RunningMqttPlugin { port_tx, state }))
     }
 }
 
-impl RunningPluginTrait for MqttPlugin {
+struct RunningMqttPlugin {
+    // to request the MQTT server to rebind on a new port
+    port_tx: flume::Sender<String>,
+    // set once the bridge is started
+    state: Arc<OnceLock<Arc<BridgeState>>>,
+}
+
+// The settings that can be changed at runtime
+const HOT_CHANGEABLE: [&str; 3] = ["port", "allow", "deny"];
+
+impl RunningPluginTrait for RunningMqttPlugin {
     fn config_checker(&self) -> zenoh::plugins::ValidationFunction {
-        Arc::new(|_, _, _| bail!("zenoh-plugin-mqtt does not support hot configuration changes."))
+        let port_tx = self.port_tx.clone();
+        let state = self.state.clone();
+        Arc::new(move |_, current, new| {
+            if current
+                .keys()
+                .chain(new.keys())
+                .any(|k| !HOT_CHANGEABLE.contains(&k.as_str()) && current.get(k) != new.get(k))
+            {
+                bail!("zenoh-plugin-mqtt only supports hot changes of 'port', 'allow' and 'deny'.")
+            }
+            let new_config: Config = serde_json::from_value(Value::Object(new.clone()))
+                .map_err(|e| zerror!("zenoh-plugin-mqtt configuration error: {}", e))?;
+            if current.get("allow") != new.get("allow") || current.get("deny") != new.get("deny") {
+                let bridge = state
+                    .get()
+                    .ok_or_else(|| zerror!("zenoh-plugin-mqtt is not started yet"))?;
+                // the settings overridden by the MQTT-SN gateway are unchanged
+                if let (Some(sn_acl), Some(sn_config)) =
+                    (&bridge.mqtt_sn_acl, new_config.for_mqtt_sn())
+                {
+                    sn_acl.update(&sn_config);
+                }
+                if bridge.acl.update(&new_config) {
+                    log::info!(
+                        "MQTT plugin 'allow' and 'deny' changed to {:?} and {:?}",
+                        new_config.allow.as_ref().map(|r| r.as_str()),
+                        new_config.deny.as_ref().map(|r| r.as_str())
+                    );
+                }
+            }
+            if current.get("port") != new.get("port") {
+                port_tx
+                    .send(new_config.port)
+                    .map_err(|_| zerror!("zenoh-plugin-mqtt is not running"))?;
+            }
+            Ok(None)
+        })
     }
 
     fn adminspace_getter<'a>(
@@ -105,14 +283,12 @@ impl RunningPluginTrait for MqttPlugin {
     }
 }
 
-async fn run(runtime: Runtime, config: Config) {
-    // Try to initiate login.
-    // Required in case of dynamic lib, otherwise no logs.
-    // But cannot be done twice in case of static link.
-    let _ = env_logger::try_init();
-    log::debug!("MQTT plugin {}", LONG_VERSION.as_str());
-    log::debug!("MQTT plugin {:?}", config);
-
+async fn run(
+    runtime: Runtime,
+    config: Config,
+    port_rx: flume::Receiver<String>,
+    state: Arc<OnceLock<Arc<BridgeState>>>,
+) {
     // init Zenoh Session with provided Runtime
     let zsession = match zenoh::init(runtime)
         .aggregated_subscribers(config.generalise_subs.clone())
@@ -123,85 +299,358 @@ async fn run(runtime: Runtime, config: Config) {
         Ok(session) => Arc::new(session),
         Err(e) => {
             log::error!("Unable to init zenoh session for MQTT plugin : {:?}", e);
+            readiness::notify_failed(format!("unable to init zenoh session: {e}"));
             return;
         }
     };
+    // the plugin is never stopped, but by a draining
+    let (_stop_tx, stop_rx) = flume::bounded(1);
+    let result = serve(zsession, config, port_rx, stop_rx, state).await;
+    report_failure(&result);
+}
+
+// Run the MQTT bridge on the zenoh session, until stopped or drained. `state` is set once the bridge is started.
+async fn serve(
+    zsession: Arc<Session>,
+    config: Config,
+    port_rx: flume::Receiver<String>,
+    stop_rx: flume::Receiver<()>,
+    state: Arc<OnceLock<Arc<BridgeState>>>,
+) -> ZResult<()> {
+    log::debug!("MQTT plugin {}", LONG_VERSION.as_str());
+    log::debug!("MQTT plugin {:?}", config);
+    lazy_static::initialize(&sys_topics::START_TIME);
+
+    telemetry::init(&config)?;
+    scripting::init(&config)?;
+    encryption::init(&config)?;
+    chunking::init(&config)?;
+    pseudonyms::init(&config)?;
+    mqtt_helpers::check_ke_chunks(&config)?;
+    access_windows::init(&config)?;
+    pause::init(&config)?;
+
+    audit::init(&config, zsession.clone())?;
+    recording::init(&config)?;
 
     // declare admin space queryable
     let admin_keyexpr_prefix =
         *KE_PREFIX_ADMIN_SPACE / &zsession.zid().into_keyexpr() / ke_for_sure!("mqtt");
     let admin_keyexpr_expr = (&admin_keyexpr_prefix) / ke_for_sure!("**");
+    let client_stats = Arc::new(ClientStats::new(config.protocol_errors.clone()));
+    let clients = Arc::new(ClientsRegistry::default());
+    let listeners = Arc::new(ListenersStats::default());
+    let bans = Arc::new(BanList::new(config.bans.as_ref())?);
+    let memory_budget = config
+        .max_inflight_bytes
+        .map(|max| Arc::new(MemoryBudget::new(max)));
+    let resource_guard = config
+        .resource_guard
+        .as_ref()
+        .map(|c| Arc::new(ResourceGuard::new(c, clients.clone())));
+    if let Some(resource_guard) = &resource_guard {
+        resource_guard.spawn_check_task();
+    }
+    let slow_consumers = config
+        .slow_consumers
+        .as_ref()
+        .map(|c| Arc::new(SlowConsumers::new(c, clients.clone())));
+    if let Some(slow_consumers) = &slow_consumers {
+        slow_consumers.spawn_check_task();
+    }
+
+    let auth = config
+        .auth
+        .as_ref()
+        .map(Authenticator::new)
+        .transpose()?
+        .map(Arc::new);
+    let store_forward = config
+        .store_forward
+        .as_ref()
+        .map(|c| StoreForward::new(zsession.clone(), c))
+        .transpose()?
+        .map(Arc::new);
+    if let Some(store_forward) = &store_forward {
+        store_forward.spawn_flush_task();
+    }
+    ha::init(&config, zsession.clone(), clients.clone()).await?;
+    let put_caps = (!config.max_inflight_puts.is_empty())
+        .then(|| PutCaps::new(&config.max_inflight_puts))
+        .transpose()?
+        .map(Arc::new);
+    let uns = config
+        .uns
+        .as_ref()
+        .map(|c| Arc::new(Uns::new(zsession.clone(), c, config.ke_chunks.clone())));
+    let _uns_queryable = match &uns {
+        Some(uns) => Some(uns.declare_queryable().await?),
+        None => None,
+    };
+    let sessions = config
+        .shared_sessions
+        .as_ref()
+        .map(|c| Arc::new(SessionRegistry::new(zsession.clone(), c)));
+    let _sessions_declarations = match &sessions {
+        Some(sessions) => Some(sessions.declare().await?),
+        None => None,
+    };
+
+    let last_values = config
+        .last_values
+        .as_ref()
+        .map(|c| Arc::new(LastValues::new(zsession.clone(), c)));
+    let _last_values_queryable = match &last_values {
+        Some(last_values) => Some(last_values.declare_queryable(&config.scope).await?),
+        None => None,
+    };
+
+    let provisioning = config
+        .provisioning
+        .as_ref()
+        .map(|c| Arc::new(Provisioning::new(zsession.clone(), c)));
+    let enrollment = config
+        .enrollment
+        .as_ref()
+        .map(|c| Arc::new(Enrollment::new(zsession.clone(), c)));
+
+    let config = Arc::new(config);
+    let acl = Arc::new(TopicAcl::new(&config));
+    let mqtt_sn_config = config.for_mqtt_sn();
+    let mqtt_sn_acl = mqtt_sn_config.as_ref().map(|c| Arc::new(TopicAcl::new(c)));
+    let query_rules = (!config.query_rules.is_empty()).then(|| {
+        Arc::new(QueryRules::new(
+            zsession.clone(),
+            config.clone(),
+            acl.clone(),
+        ))
+    });
+
+    // Start MQTT Server task
+    let bridge = Arc::new(BridgeState {
+        fanout: Arc::new(FanoutRegistry::new(
+            zsession.clone(),
+            config.stale_subscribers_secs.map(Duration::from_secs),
+            config.fanout_shards,
+            config.queue_strategy,
+        )),
+        shm: Arc::new(ShmProvider::new(&config, &zsession.zid().to_string())),
+        ke_cache: config.ke_cache.as_ref().map(|c| Arc::new(KeCaches::new(c))),
+        handshakes: config
+            .handshakes
+            .as_ref()
+            .map(|c| Arc::new(HandshakeLimiter::new(c))),
+        mqtt_sn_config: mqtt_sn_config.map(Arc::new),
+        mqtt_sn_acl,
+        config,
+        acl,
+        zsession,
+        auth,
+        store_forward,
+        client_stats,
+        clients,
+        listeners,
+        bans,
+        memory_budget,
+        resource_guard,
+        slow_consumers,
+        uns,
+        sessions,
+        put_caps,
+        query_rules,
+        last_values,
+        provisioning,
+        enrollment,
+    });
+    bridge.fanout.spawn_gc_task();
+    let _ = state.set(bridge.clone());
     log::debug!("Declare admin space on {}", admin_keyexpr_expr);
-    let config2 = config.clone();
-    let _admin_queryable = zsession
+    let bridge2 = bridge.clone();
+    let _admin_queryable = bridge
+        .zsession
         .declare_queryable(admin_keyexpr_expr)
-        .callback(move |query| treat_admin_query(query, &admin_keyexpr_prefix, &config2))
+        .callback(move |query| treat_admin_query(query, &admin_keyexpr_prefix, &bridge2))
         .res()
         .await
-        .expect("Failed to create AdminSpace queryable");
-
-    // Start MQTT Server task
-    let config = Arc::new(config);
+        .map_err(|e| zerror!("Failed to create AdminSpace queryable: {}", e))?;
+    if bridge.config.bind_on == BindCondition::Connected
+        && !wait_connected(&bridge.zsession, &stop_rx).await
+    {
+        log::info!("MQTT plugin stopped before being connected to zenoh");
+        readiness::notify_failed("stopped before being connected to zenoh".into());
+        return Ok(());
+    }
     ntex::rt::System::new(MqttPlugin::STATIC_NAME)
         .block_on(async move {
-            ntex::server::Server::build()
-                .bind("mqtt", config.port.clone(), move |_| {
-                    let zs_v3 = zsession.clone();
-                    let zs_v5 = zsession.clone();
-                    let config_v3 = config.clone();
-                    let config_v5 = config.clone();
-                    MqttServer::new()
-                        .v3(v3::MqttServer::new(fn_factory_with_config(move |_| {
-                            let zs = zs_v3.clone();
-                            let config = config_v3.clone();
-                            Ready::Ok::<_, ()>(fn_service(move |h| {
-                                handshake_v3(h, zs.clone(), config.clone())
-                            }))
-                        }))
-                        .publish(fn_factory_with_config(
-                            |session: v3::Session<MqttSessionState>| {
-                                Ready::Ok::<_, MqttPluginError>(fn_service(move |req| {
-                                    publish_v3(session.clone(), req)
-                                }))
-                            },
-                        ))
-                        .control(fn_factory_with_config(
-                            |session: v3::Session<MqttSessionState>| {
-                                Ready::Ok::<_, MqttPluginError>(fn_service(move |req| {
-                                    control_v3(session.clone(), req)
-                                }))
-                            },
-                        )))
-                        .v5(v5::MqttServer::new(fn_factory_with_config(move |_| {
-                            let zs = zs_v5.clone();
-                            let config = config_v5.clone();
-                            Ready::Ok::<_, ()>(fn_service(move |h| {
-                                handshake_v5(h, zs.clone(), config.clone())
-                            }))
-                        }))
-                        .publish(fn_factory_with_config(
-                            |session: v5::Session<MqttSessionState>| {
-                                Ready::Ok::<_, MqttPluginError>(fn_service(move |req| {
-                                    publish_v5(session.clone(), req)
-                                }))
-                            },
+            let mut server = start_mqtt_server(&bridge, &bridge.config.port)?;
+            readiness::notify_ready();
+            if let Some(mqtt_sn) = &bridge.config.mqtt_sn {
+                ntex::rt::spawn(mqtt_sn::run(bridge.clone(), mqtt_sn.port.clone()));
+            }
+            loop {
+                let next_port = async {
+                    match port_rx.recv_async().await {
+                        Ok(port) => port,
+                        // the plugin is no longer managed: only a draining can stop the server
+                        Err(_) => futures::future::pending().await,
+                    }
+                };
+                let stop = async {
+                    match stop_rx.recv_async().await {
+                        Ok(()) => (),
+                        // the handle is dropped: only a draining can stop the server
+                        Err(_) => futures::future::pending().await,
+                    }
+                };
+                let command = select(
+                    Box::pin(next_port),
+                    select(Box::pin(drain::drain_requested()), Box::pin(stop)),
+                );
+                match select(Box::pin(server.clone()), command).await {
+                    Either::Left((result, _)) => return result,
+                    Either::Right((Either::Right((Either::Right(_), _)), _)) => {
+                        log::info!("Stopping MQTT server");
+                        server.stop(true).await;
+                        return Ok(());
+                    }
+                    Either::Right((Either::Right(_), _)) => {
+                        log::info!("Draining MQTT server: stop accepting new connections");
+                        server.pause().await;
+                        drain::wait_inflight(Duration::from_secs(
+                            bridge.config.drain_deadline_secs,
                         ))
-                        .control(fn_factory_with_config(
-                            |session: v5::Session<MqttSessionState>| {
-                                Ready::Ok::<_, MqttPluginError>(fn_service(move |req| {
-                                    control_v5(session.clone(), req)
-                                }))
-                            },
-                        )))
-                })?
-                .workers(1)
-                .run()
-                .await
+                        .await;
+                        server.stop(true).await;
+                        log::info!("MQTT server drained");
+                        drain::notify_drained();
+                        return Ok(());
+                    }
+                    Either::Right((Either::Left((port, _)), _)) => {
+                        // bind the new listener, then stop accepting connections on the old one and
+                        // close it (with its remaining connections) after the drain period
+                        let new_server = match start_mqtt_server(&bridge, &port) {
+                            Ok(new_server) => new_server,
+                            Err(e) => {
+                                log::error!("Failed to bind MQTT server on {}: {}", port, e);
+                                continue;
+                            }
+                        };
+                        log::info!("MQTT server rebound on {}", port);
+                        let old_server = std::mem::replace(&mut server, new_server);
+                        old_server.pause().await;
+                        let drain = Duration::from_secs(bridge.config.listener_drain_secs);
+                        ntex::rt::spawn(async move {
+                            async_std::task::sleep(drain).await;
+                            old_server.stop(true).await;
+                        });
+                    }
+                }
+            }
         })
-        .unwrap();
+        .map_err(|e| zerror!("MQTT server failed: {}", e).into())
+}
+
+// Log the failure of the MQTT bridge, and report it to the readiness waiters if it failed to start
+fn report_failure(result: &ZResult<()>) {
+    if let Err(e) = result {
+        log::error!("MQTT plugin failed: {}", e);
+        readiness::notify_failed(e.to_string());
+    }
 }
 
-fn treat_admin_query(query: Query, admin_keyexpr_prefix: &keyexpr, config: &Config) {
+// Wait for the zenoh session to be connected to a zenoh router or peer (checked every second).
+// Returns false if the bridge is stopped meanwhile.
+async fn wait_connected(zsession: &Session, stop_rx: &flume::Receiver<()>) -> bool {
+    let mut logged = false;
+    loop {
+        let info = zsession.info();
+        if info.routers_zid().res().await.next().is_some()
+            || info.peers_zid().res().await.next().is_some()
+        {
+            if logged {
+                log::info!("Zenoh session connected - bind the MQTT server");
+            }
+            return true;
+        }
+        if !logged {
+            log::info!("Wait for the zenoh session to be connected to a router or peer before binding the MQTT server");
+            logged = true;
+        }
+        match async_std::future::timeout(Duration::from_secs(1), stop_rx.recv_async()).await {
+            Ok(Ok(())) => return false,
+            // the handle is dropped: only a draining can stop the server
+            Ok(Err(_)) => async_std::task::sleep(Duration::from_secs(1)).await,
+            Err(_) => (),
+        }
+    }
+}
+
+fn start_mqtt_server(
+    bridge: &Arc<BridgeState>,
+    port: &str,
+) -> std::io::Result<ntex::server::Server> {
+    let bridge = bridge.clone();
+    bridge.listeners.get(LISTENER_MQTT).set_address(port);
+    let socket_config = bridge.config.socket.clone();
+    let factory = move |_| {
+        let bridge_v3 = bridge.clone();
+        let bridge_v5 = bridge.clone();
+        MqttServer::new()
+            .v3(v3::MqttServer::new(fn_factory_with_config(move |_| {
+                let bridge = bridge_v3.clone();
+                Ready::Ok::<_, ()>(fn_service(move |h| handshake_v3(h, bridge.clone())))
+            }))
+            .publish(fn_factory_with_config(
+                |session: v3::Session<MqttSessionState>| {
+                    Ready::Ok::<_, MqttPluginError>(fn_service(move |req| {
+                        publish_v3(session.clone(), req)
+                    }))
+                },
+            ))
+            .control(fn_factory_with_config(
+                |session: v3::Session<MqttSessionState>| {
+                    Ready::Ok::<_, MqttPluginError>(fn_service(move |req| {
+                        control_v3(session.clone(), req)
+                    }))
+                },
+            )))
+            .v5(v5::MqttServer::new(fn_factory_with_config(move |_| {
+                let bridge = bridge_v5.clone();
+                Ready::Ok::<_, ()>(fn_service(move |h| handshake_v5(h, bridge.clone())))
+            }))
+            .publish(fn_factory_with_config(
+                |session: v5::Session<MqttSessionState>| {
+                    Ready::Ok::<_, MqttPluginError>(fn_service(move |req| {
+                        publish_v5(session.clone(), req)
+                    }))
+                },
+            ))
+            .control(fn_factory_with_config(
+                |session: v5::Session<MqttSessionState>| {
+                    Ready::Ok::<_, MqttPluginError>(fn_service(move |req| {
+                        control_v5(session.clone(), req)
+                    }))
+                },
+            )))
+    };
+    let builder = match socket_config {
+        Some(socket_config) => ntex::server::Server::build().listen(
+            "mqtt",
+            listener::create_listener(port, &socket_config)?,
+            factory,
+        )?,
+        None => ntex::server::Server::build().bind("mqtt", port, factory)?,
+    };
+    Ok(builder.workers(1).run())
+}
+
+fn treat_admin_query(query: Query, admin_keyexpr_prefix: &keyexpr, bridge: &BridgeState) {
+    let config = &*bridge.config;
+    let client_stats = &bridge.client_stats;
+    let clients = &bridge.clients;
+    let listeners = &bridge.listeners;
+    let bans = &bridge.bans;
+    let memory_budget = bridge.memory_budget.as_deref();
     let selector = query.selector();
     log::debug!("Query on admin space: {:?}", selector);
 
@@ -215,7 +664,7 @@ fn treat_admin_query(query: Query, admin_keyexpr_prefix: &keyexpr, config: &Conf
 
     // Get all matching keys/values
     let mut kvs: Vec<(&keyexpr, Value)> = Vec::with_capacity(sub_kes.len());
-    for sub_ke in sub_kes {
+    for sub_ke in sub_kes.iter().copied() {
         if sub_ke.intersects(&ADMIN_SPACE_KE_VERSION) {
             kvs.push((&ADMIN_SPACE_KE_VERSION, Value::String(LONG_VERSION.clone())));
         }
@@ -225,100 +674,919 @@ fn treat_admin_query(query: Query, admin_keyexpr_prefix: &keyexpr, config: &Conf
                 serde_json::to_value(config).unwrap(),
             ));
         }
+        if sub_ke.intersects(&ADMIN_SPACE_KE_CONFIG_HASH) {
+            kvs.push((&ADMIN_SPACE_KE_CONFIG_HASH, Value::String(config.hash())));
+        }
+        if sub_ke.intersects(&ADMIN_SPACE_KE_BUILD) {
+            kvs.push((
+                &ADMIN_SPACE_KE_BUILD,
+                serde_json::json!({
+                    "version": GIT_VERSION,
+                    "zenoh_version": ZENOH_VERSION,
+                    "rustc": env!("RUSTC_VERSION"),
+                    "target": env!("TARGET"),
+                    "features": enabled_features(),
+                }),
+            ));
+        }
+        if sub_ke.intersects(&ADMIN_SPACE_KE_UPTIME) {
+            kvs.push((
+                &ADMIN_SPACE_KE_UPTIME,
+                Value::from(sys_topics::uptime_secs()),
+            ));
+        }
+        if sub_ke.intersects(&ADMIN_SPACE_KE_CLIENTS_STATS) {
+            kvs.push((&ADMIN_SPACE_KE_CLIENTS_STATS, client_stats.to_json()));
+        }
+        if sub_ke.intersects(&ADMIN_SPACE_KE_CLIENTS_LIST) {
+            kvs.push((&ADMIN_SPACE_KE_CLIENTS_LIST, clients.to_json()));
+        }
+        if sub_ke.intersects(&ADMIN_SPACE_KE_CLIENTS_ROUTES) {
+            kvs.push((
+                &ADMIN_SPACE_KE_CLIENTS_ROUTES,
+                clients.routes_to_json(bridge.config.ke_chunks.as_ref()),
+            ));
+        }
+        if sub_ke.intersects(&ADMIN_SPACE_KE_SUBSCRIPTIONS) {
+            let mut subscriptions = clients.churn_to_json();
+            if let (Value::Object(subscriptions), Value::Object(fanout)) =
+                (&mut subscriptions, bridge.fanout.to_json())
+            {
+                subscriptions.extend(fanout);
+            }
+            kvs.push((&ADMIN_SPACE_KE_SUBSCRIPTIONS, subscriptions));
+        }
+        // a query on the exact "clients/disconnect/<client_id>" key (not on a wildcard) disconnects the client
+        if let Some(client_id) = sub_ke
+            .as_str()
+            .strip_prefix(ADMIN_SPACE_KE_CLIENTS_DISCONNECT.as_str())
+            .and_then(|s| s.strip_prefix('/'))
+            .filter(|_| !sub_ke.is_wild())
+        {
+            let result = if let Some(refusal) = admin_refusal(&query, config, sub_ke) {
+                refusal
+            } else if clients.disconnect(client_id) {
+                Value::from("disconnected")
+            } else {
+                Value::from("not connected")
+            };
+            kvs.push((sub_ke, result));
+        }
+        // a query on the exact "clients/resubscribe" key re-declares the zenoh subscribers of all the clients,
+        // and on the exact "clients/resubscribe/<client_id>" key the ones of the client (not on a wildcard)
+        let resubscribe = if sub_ke.as_str() == ADMIN_SPACE_KE_CLIENTS_RESUBSCRIBE.as_str() {
+            Some(None)
+        } else {
+            sub_ke
+                .as_str()
+                .strip_prefix(ADMIN_SPACE_KE_CLIENTS_RESUBSCRIBE.as_str())
+                .and_then(|s| s.strip_prefix('/'))
+                .filter(|_| !sub_ke.is_wild())
+                .map(Some)
+        };
+        if let Some(client_id) = resubscribe {
+            let result = match admin_refusal(&query, config, sub_ke) {
+                Some(refusal) => refusal,
+                None => match clients
+                    .request_resubscribe(client_id, bridge.config.ke_chunks.as_ref())
+                {
+                    // the subscribers owned by the clients are re-declared at their next PINGREQ
+                    Some(kes) => match bridge.fanout.redeclare(client_id.is_some().then_some(&kes))
+                    {
+                        Ok(count) => serde_json::json!({ "shared_subscribers": count }),
+                        Err(e) => {
+                            log::warn!("Failed to re-declare shared Zenoh subscribers: {}", e);
+                            Value::from(e.to_string())
+                        }
+                    },
+                    None => Value::from("not connected"),
+                },
+            };
+            kvs.push((sub_ke, result));
+        }
+        // a query on the exact "log_filter" key with a "set=<filter>" parameter changes the log filter
+        // (for "duration=<secs>" if set), the reply being the error if refused or failed
+        let mut log_filter_error = None;
+        if sub_ke.as_str() == ADMIN_SPACE_KE_LOG_FILTER.as_str() {
+            if let Some(filter) = query_parameter(query.parameters(), "set") {
+                log_filter_error = match admin_refusal(&query, config, sub_ke) {
+                    Some(refusal) => Some(refusal),
+                    None => {
+                        let duration = query_parameter(query.parameters(), "duration")
+                            .and_then(|d| d.parse().ok())
+                            .map(Duration::from_secs);
+                        logging::set_filter(filter, duration).err().map(|e| {
+                            log::warn!("{}", e);
+                            serde_json::json!({ "error": e.to_string() })
+                        })
+                    }
+                };
+            }
+        }
+        if sub_ke.intersects(&ADMIN_SPACE_KE_LOG_FILTER) {
+            kvs.push((
+                &ADMIN_SPACE_KE_LOG_FILTER,
+                log_filter_error
+                    .unwrap_or_else(|| logging::filter().map_or(Value::Null, Value::from)),
+            ));
+        }
+        if sub_ke.intersects(&ADMIN_SPACE_KE_LISTENERS) {
+            kvs.push((&ADMIN_SPACE_KE_LISTENERS, listeners.to_json()));
+        }
+        if sub_ke.intersects(&ADMIN_SPACE_KE_BANS) {
+            kvs.push((&ADMIN_SPACE_KE_BANS, bans.to_json()));
+        }
+        // a query on the exact "bans/add" or "bans/remove" key (not on a wildcard) changes the ban list,
+        // with a "client_id=<regex>" and/or an "address=<CIDR>" parameter
+        let ban_change = if sub_ke.as_str() == ADMIN_SPACE_KE_BANS_ADD.as_str() {
+            Some((*ADMIN_SPACE_KE_BANS_ADD, true))
+        } else if sub_ke.as_str() == ADMIN_SPACE_KE_BANS_REMOVE.as_str() {
+            Some((*ADMIN_SPACE_KE_BANS_REMOVE, false))
+        } else {
+            None
+        };
+        if let Some((ke, add)) = ban_change {
+            let client_id = query_parameter(query.parameters(), "client_id");
+            let address = query_parameter(query.parameters(), "address");
+            let result = if admin_refusal(&query, config, ke).is_some() {
+                Err(zerror!("{}", ADMIN_TOKEN_REFUSAL).into())
+            } else if add {
+                bans.ban(client_id, address)
+            } else {
+                bans.unban(client_id, address)
+            };
+            match result {
+                Ok(()) => {
+                    log::info!(
+                        "MQTT ban list changed via admin space: {} client_id={:?} address={:?}",
+                        if add { "add" } else { "remove" },
+                        client_id,
+                        address
+                    );
+                    kvs.push((ke, bans.to_json()));
+                }
+                Err(e) => {
+                    log::warn!("Invalid change of MQTT ban list via admin space: {}", e);
+                    kvs.push((ke, Value::from(e.to_string())));
+                }
+            }
+        }
+        if let Some(sessions) = &bridge.sessions {
+            // a query on the exact "sessions/export/<client_id>" key exports the client's persistent session
+            if let Some(client_id) = sub_ke
+                .as_str()
+                .strip_prefix(ADMIN_SPACE_KE_SESSIONS_EXPORT.as_str())
+                .and_then(|s| s.strip_prefix('/'))
+                .filter(|_| !sub_ke.is_wild())
+            {
+                kvs.push((
+                    sub_ke,
+                    sessions
+                        .export(client_id, clients.username(client_id))
+                        .map_or(Value::Null, Value::from),
+                ));
+            }
+            // a query on the exact "sessions/import" key with a "session=<blob>" parameter imports an exported session
+            if sub_ke.as_str() == ADMIN_SPACE_KE_SESSIONS_IMPORT.as_str() {
+                if let Some(blob) = query_parameter(query.parameters(), "session") {
+                    let result = match admin_refusal(&query, config, sub_ke) {
+                        Some(refusal) => refusal,
+                        None => match sessions.import(blob, |client_id, username| {
+                            check_imported_identity(bridge, client_id, username)
+                        }) {
+                            Ok(client_id) => {
+                                log::info!(
+                                    "Persistent session of MQTT client {} imported via admin space",
+                                    client_id
+                                );
+                                audit::admin(
+                                    ADMIN_SPACE_KE_SESSIONS_IMPORT.as_str(),
+                                    Some(&client_id),
+                                    true,
+                                );
+                                serde_json::json!({ "imported": client_id })
+                            }
+                            Err(e) => {
+                                log::warn!("{}", e);
+                                serde_json::json!({ "error": e.to_string() })
+                            }
+                        },
+                    };
+                    kvs.push((&ADMIN_SPACE_KE_SESSIONS_IMPORT, result));
+                }
+            }
+        }
+        // a query on the exact "pseudonyms/reveal" key with a "pseudonym=<pseudonym>" parameter reveals it,
+        // only with the admin token (the reveal being disabled without it)
+        if sub_ke.as_str() == ADMIN_SPACE_KE_PSEUDONYMS_REVEAL.as_str() {
+            if let Some(pseudonym) = query_parameter(query.parameters(), "pseudonym") {
+                let authorized = admin_token_matches(query.parameters(), config) == Some(true);
+                audit::admin(
+                    ADMIN_SPACE_KE_PSEUDONYMS_REVEAL.as_str(),
+                    Some(pseudonym),
+                    authorized,
+                );
+                if authorized {
+                    log::info!("Pseudonym {} revealed via admin space", pseudonym);
+                    kvs.push((
+                        &ADMIN_SPACE_KE_PSEUDONYMS_REVEAL,
+                        pseudonyms::reveal_pseudonym(pseudonym).map_or(Value::Null, Value::from),
+                    ));
+                } else {
+                    log::warn!(
+                        "Reveal of pseudonym {} via admin space refused: {}",
+                        pseudonym,
+                        ADMIN_TOKEN_REFUSAL
+                    );
+                    kvs.push((
+                        &ADMIN_SPACE_KE_PSEUDONYMS_REVEAL,
+                        serde_json::json!({ "error": ADMIN_TOKEN_REFUSAL }),
+                    ));
+                }
+            }
+        }
+        // a query on the exact "replay" key with a "file=<path>" parameter replays a recording of the
+        // replay directory (at "speed=<factor>" if set), if the replay is enabled
+        if sub_ke.intersects(&ADMIN_SPACE_KE_REPLAY) {
+            let file = query_parameter(query.parameters(), "file")
+                .filter(|_| sub_ke.as_str() == ADMIN_SPACE_KE_REPLAY.as_str());
+            let refusal = file.and_then(|_| admin_refusal(&query, config, sub_ke));
+            let status = match (file, refusal) {
+                (Some(_), Some(refusal)) => refusal,
+                (Some(file), None) => {
+                    let speed = query_parameter(query.parameters(), "speed")
+                        .and_then(|s| s.parse().ok())
+                        .unwrap_or(1.0);
+                    let result = match &bridge.config.replay {
+                        Some(conf) => recording::replay(
+                            bridge.zsession.clone(),
+                            clients.clone(),
+                            conf,
+                            file,
+                            speed,
+                        ),
+                        None => {
+                            Err(zerror!("the replay is disabled (see the 'replay' setting)").into())
+                        }
+                    };
+                    match result {
+                        Ok(()) => serde_json::json!({ "replaying": true }),
+                        Err(e) => {
+                            log::warn!("Failed to replay MQTT recording {}: {}", file, e);
+                            serde_json::json!({ "error": e.to_string() })
+                        }
+                    }
+                }
+                (None, _) => serde_json::json!({ "replaying": recording::is_replaying() }),
+            };
+            kvs.push((&ADMIN_SPACE_KE_REPLAY, status));
+        }
+        if sub_ke.intersects(&ADMIN_SPACE_KE_SCHEMA) {
+            kvs.push((&ADMIN_SPACE_KE_SCHEMA, admin_space_schema()));
+        }
+        if sub_ke.intersects(&ADMIN_SPACE_KE_LATENCY) {
+            kvs.push((&ADMIN_SPACE_KE_LATENCY, latency::to_json()));
+        }
+        if sub_ke.intersects(&ADMIN_SPACE_KE_PAYLOAD_SIZES) {
+            kvs.push((&ADMIN_SPACE_KE_PAYLOAD_SIZES, payload_sizes::to_json()));
+        }
+        // a query on the exact "drain" key (not on a wildcard) requests the draining of the MQTT server
+        if sub_ke.as_str() == ADMIN_SPACE_KE_DRAIN.as_str() {
+            let result = match admin_refusal(&query, config, sub_ke) {
+                Some(refusal) => refusal,
+                None => {
+                    log::info!("MQTT server draining requested via admin space");
+                    drain::request_drain();
+                    Value::from("draining")
+                }
+            };
+            kvs.push((&ADMIN_SPACE_KE_DRAIN, result));
+        }
+        if sub_ke.intersects(&ADMIN_SPACE_KE_ROUTING) {
+            kvs.push((&ADMIN_SPACE_KE_ROUTING, pause::to_json()));
+        }
+        // a query on the exact "routing/pause" key pauses the routing in both directions
+        if sub_ke.as_str() == ADMIN_SPACE_KE_ROUTING_PAUSE.as_str() {
+            let result = match admin_refusal(&query, config, sub_ke) {
+                Some(refusal) => refusal,
+                None => {
+                    if pause::pause() {
+                        log::info!("MQTT routing paused via admin space");
+                    }
+                    pause::to_json()
+                }
+            };
+            kvs.push((&ADMIN_SPACE_KE_ROUTING_PAUSE, result));
+        }
+        // a query on the exact "routing/resume" key resumes it
+        if sub_ke.as_str() == ADMIN_SPACE_KE_ROUTING_RESUME.as_str() {
+            let result = match admin_refusal(&query, config, sub_ke) {
+                Some(refusal) => refusal,
+                None => {
+                    if pause::resume(bridge.zsession.clone()) {
+                        log::info!("MQTT routing resume requested via admin space");
+                    }
+                    pause::to_json()
+                }
+            };
+            kvs.push((&ADMIN_SPACE_KE_ROUTING_RESUME, result));
+        }
+        if config.ha.is_some() && sub_ke.intersects(&ADMIN_SPACE_KE_HA) {
+            kvs.push((&ADMIN_SPACE_KE_HA, ha::to_json()));
+        }
+        if let Some(memory_budget) = memory_budget {
+            if sub_ke.intersects(&ADMIN_SPACE_KE_MEMORY_BUDGET) {
+                kvs.push((&ADMIN_SPACE_KE_MEMORY_BUDGET, memory_budget.to_json()));
+            }
+        }
+        if let Some(resource_guard) = &bridge.resource_guard {
+            if sub_ke.intersects(&ADMIN_SPACE_KE_RESOURCE_GUARD) {
+                kvs.push((&ADMIN_SPACE_KE_RESOURCE_GUARD, resource_guard.to_json()));
+            }
+        }
+        if let Some(handshakes) = &bridge.handshakes {
+            if sub_ke.intersects(&ADMIN_SPACE_KE_HANDSHAKES) {
+                kvs.push((&ADMIN_SPACE_KE_HANDSHAKES, handshakes.to_json()));
+            }
+        }
+        if let Some(slow_consumers) = &bridge.slow_consumers {
+            if sub_ke.intersects(&ADMIN_SPACE_KE_SLOW_CONSUMERS) {
+                kvs.push((&ADMIN_SPACE_KE_SLOW_CONSUMERS, slow_consumers.to_json()));
+            }
+        }
+        if let Some(ke_cache) = &bridge.ke_cache {
+            if sub_ke.intersects(&ADMIN_SPACE_KE_KE_CACHE) {
+                kvs.push((&ADMIN_SPACE_KE_KE_CACHE, ke_cache.to_json()));
+            }
+        }
+        if let Some(last_values) = &bridge.last_values {
+            if sub_ke.intersects(&ADMIN_SPACE_KE_LAST_VALUES) {
+                kvs.push((&ADMIN_SPACE_KE_LAST_VALUES, last_values.to_json()));
+            }
+        }
+        if let Some(provisioning) = &bridge.provisioning {
+            if sub_ke.intersects(&ADMIN_SPACE_KE_PROVISIONING) {
+                kvs.push((&ADMIN_SPACE_KE_PROVISIONING, provisioning.to_json()));
+            }
+        }
+        if let Some(enrollment) = &bridge.enrollment {
+            if sub_ke.intersects(&ADMIN_SPACE_KE_ENROLLMENT) {
+                kvs.push((&ADMIN_SPACE_KE_ENROLLMENT, enrollment.to_json()));
+            }
+        }
     }
 
     // send replies
+    use zenoh::prelude::sync::SyncResolve;
     for (ke, v) in kvs.drain(..) {
         let admin_keyexpr = admin_keyexpr_prefix / ke;
-        use zenoh::prelude::sync::SyncResolve;
         if let Err(e) = query.reply(Ok(Sample::new(admin_keyexpr, v))).res_sync() {
             log::warn!("Error replying to admin query {:?}: {}", query, e);
         }
     }
+    #[cfg(feature = "dashboard")]
+    if sub_kes
+        .iter()
+        .any(|sub_ke| sub_ke.intersects(&ADMIN_SPACE_KE_DASHBOARD))
+    {
+        let admin_keyexpr = admin_keyexpr_prefix / &ADMIN_SPACE_KE_DASHBOARD;
+        let html =
+            zenoh::value::Value::from(DASHBOARD_HTML).encoding(KnownEncoding::TextHtml.into());
+        if let Err(e) = query.reply(Ok(Sample::new(admin_keyexpr, html))).res_sync() {
+            log::warn!("Error replying to admin query {:?}: {}", query, e);
+        }
+    }
 }
 
-// NOTE: this types exists just because we can't implement TryFrom<Box<dyn std::error::Error + Send + Sync + 'static>> for v5::PublishAck
-// (required for MQTT V5 negative acks)
-#[derive(Debug)]
-struct MqttPluginError {
-    err: Box<dyn std::error::Error + Send + Sync + 'static>,
+// The value of a parameter of an admin space query (e.g. "client_id" in "bans/add?client_id=foo.*")
+fn query_parameter<'a>(parameters: &'a str, name: &str) -> Option<&'a str> {
+    parameters
+        .split('&')
+        .filter_map(|p| p.split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
 }
 
-impl From<ZError> for MqttPluginError {
-    fn from(e: ZError) -> Self {
-        MqttPluginError { err: e.into() }
+const ADMIN_TOKEN_REFUSAL: &str = "missing or invalid admin token";
+
+// The error replied to an admin space query changing the bridge state (on `ke`), if it doesn't have
+// the admin token while one is configured. The refusals are logged and recorded in the audit log.
+fn admin_refusal(query: &Query, config: &Config, ke: &keyexpr) -> Option<Value> {
+    if admin_token_matches(query.parameters(), config) != Some(false) {
+        return None;
     }
+    log::warn!(
+        "Admin space query on {} refused: {}",
+        ke,
+        ADMIN_TOKEN_REFUSAL
+    );
+    audit::admin(ke.as_str(), None, false);
+    Some(serde_json::json!({ "error": ADMIN_TOKEN_REFUSAL }))
 }
 
-impl From<Box<dyn std::error::Error + Send + Sync + 'static>> for MqttPluginError {
-    fn from(err: Box<dyn std::error::Error + Send + Sync + 'static>) -> Self {
-        MqttPluginError { err }
+// Check the identity of a persistent session imported via the admin space, as for a connecting client:
+// the client id must not be banned (nor invalid in 'strict' mode), and with 'auth' it must be bound to
+// a username of the dictionary (the session being only resumed by a client authenticated with it)
+fn check_imported_identity(
+    bridge: &BridgeState,
+    client_id: &str,
+    username: Option<&str>,
+) -> ZResult<()> {
+    if let Err(reason) = bridge.bans.check(client_id, None) {
+        bail!(
+            "Imported session of MQTT client {} refused: {}",
+            client_id,
+            reason
+        );
+    }
+    if let Some(violation) = bridge
+        .config
+        .strict
+        .then(|| mqtt_helpers::strict_client_id_violation(client_id))
+        .flatten()
+    {
+        bail!(
+            "Imported session of MQTT client {} refused: {}",
+            client_id,
+            violation
+        );
     }
+    if let Some(auth) = &bridge.auth {
+        match username {
+            Some(username) if auth.has_user(username) => (),
+            Some(username) => bail!(
+                "Imported session of MQTT client {} refused: unknown username '{}'",
+                client_id,
+                username
+            ),
+            None => bail!(
+                "Imported session of MQTT client {} refused: no username while 'auth' is configured",
+                client_id
+            ),
+        }
+    }
+    Ok(())
+}
+
+// Returns whether the "token=<token>" parameter of an admin space query matches the admin token,
+// or None if no admin token is configured
+fn admin_token_matches(parameters: &str, config: &Config) -> Option<bool> {
+    let expected = config.admin.as_ref()?.token.as_deref()?;
+    // compare the digests, for the time not to depend on the common prefix
+    Some(query_parameter(parameters, "token").map_or(false, |token| {
+        Sha256::digest(token) == Sha256::digest(expected)
+    }))
 }
 
-// mqtt5 supports negative acks, so service error could be converted to PublishAck
-// (weird way to do it, but that's how it's done in ntex-mqtt examples...)
-impl std::convert::TryFrom<MqttPluginError> for v5::PublishAck {
-    type Error = MqttPluginError;
-    fn try_from(err: MqttPluginError) -> Result<Self, Self::Error> {
-        Err(err)
+// The minimal web dashboard served from the admin space (e.g. by the REST plugin)
+#[cfg(feature = "dashboard")]
+const DASHBOARD_HTML: &str = include_str!("../dashboard/index.html");
+
+// The description of the admin space keys (relative to "@/service/<uuid>/mqtt"), for the admin tools
+fn admin_space_schema() -> Value {
+    serde_json::json!({
+        ADMIN_SPACE_KE_VERSION.as_str(): { "type": "string", "description": "The bridge version" },
+        ADMIN_SPACE_KE_CONFIG.as_str(): { "type": "object", "description": "The bridge configuration" },
+        ADMIN_SPACE_KE_CONFIG_HASH.as_str(): { "type": "string", "description": "The SHA-256 hash of the bridge configuration" },
+        ADMIN_SPACE_KE_BUILD.as_str(): { "type": "object", "description": "The build information" },
+        ADMIN_SPACE_KE_UPTIME.as_str(): { "type": "integer", "description": "The number of seconds since the bridge started" },
+        ADMIN_SPACE_KE_CLIENTS_LIST.as_str(): {
+            "type": "object",
+            "description": "The connected clients, per client id",
+            "additionalProperties": {
+                "type": "object",
+                "properties": {
+                    "protocol": { "type": "string", "enum": ["v3", "v5", "sn"] },
+                    "username": { "type": ["string", "null"] },
+                    "scope": { "type": ["string", "null"] },
+                    "connected_timestamp": { "type": "integer" },
+                    "protocol_level": { "type": "integer", "description": "The protocol level of the CONNECT packet (3: MQTT 3.1, 4: MQTT 3.1.1, 5: MQTT 5.0, 1: MQTT-SN)" },
+                    "keep_alive": { "type": "integer", "description": "The negotiated keep alive, in seconds" },
+                    "clean_session": { "type": "boolean" },
+                    "max_packet_size": { "type": ["integer", "null"], "description": "The maximum packet size accepted by the client (MQTT 5 only)" },
+                    "tls_cipher": { "type": "null", "description": "Always null: the listeners don't support TLS" },
+                    "queued_publications": { "type": "integer" },
+                    "link_quality": {
+                        "type": "object",
+                        "description": "The round-trip times measured on QoS 1 publications (with the number of unacknowledged ones), and the intervals between the client's PINGREQ",
+                    },
+                },
+            },
+        },
+        ADMIN_SPACE_KE_CLIENTS_ROUTES.as_str(): {
+            "type": "object",
+            "description": "Per client id, the subscribed MQTT topic filters with their zenoh key expressions",
+            "additionalProperties": { "type": "object", "additionalProperties": { "type": ["string", "null"] } },
+        },
+        ADMIN_SPACE_KE_SUBSCRIPTIONS.as_str(): {
+            "type": "object",
+            "description": "The subscriptions churn: the numbers of active subscriptions, of subscriptions created and removed (in total and during the last minute), and of shared zenoh subscribers (with and without member, and collected when stale)",
+            "properties": {
+                "active": { "type": "integer" },
+                "created": { "type": "object", "properties": { "total": { "type": "integer" }, "last_minute": { "type": "integer" } } },
+                "removed": { "type": "object", "properties": { "total": { "type": "integer" }, "last_minute": { "type": "integer" } } },
+                "shared_subscribers": { "type": "integer" },
+                "stale_shared_subscribers": { "type": "integer" },
+                "collected_stale_subscribers": { "type": "integer" },
+                "topic_subscribers": { "type": "integer", "description": "The zenoh subscribers shared by the clients subscribing to a same topic filter" },
+                "topic_subscribers_members": { "type": "integer", "description": "The subscriptions served by the shared topic subscribers" },
+            },
+        },
+        format!("{}/<client_id>", *ADMIN_SPACE_KE_CLIENTS_DISCONNECT): {
+            "type": "string",
+            "enum": ["disconnected", "not connected"],
+            "description": "A query on this exact key disconnects the client",
+        },
+        ADMIN_SPACE_KE_CLIENTS_RESUBSCRIBE.as_str(): {
+            "type": ["object", "string"],
+            "description": "A query on this exact key re-declares the zenoh subscribers of all the clients, returning the number of shared subscribers re-declared (the ones owned by a client being re-declared at its next PINGREQ)",
+        },
+        format!("{}/<client_id>", *ADMIN_SPACE_KE_CLIENTS_RESUBSCRIBE): {
+            "type": ["object", "string"],
+            "description": "A query on this exact key re-declares the zenoh subscribers of the client (or returns \"not connected\")",
+        },
+        ADMIN_SPACE_KE_CLIENTS_STATS.as_str(): { "type": "object", "description": "The protocol errors statistics, per client id" },
+        format!("{}/<client_id>", *ADMIN_SPACE_KE_SESSIONS_EXPORT): {
+            "type": ["string", "null"],
+            "description": "A query on this exact key exports the client's persistent session as a blob, or null if unknown (if 'shared_sessions' is configured)",
+        },
+        ADMIN_SPACE_KE_PSEUDONYMS_REVEAL.as_str(): {
+            "type": ["string", "null"],
+            "description": "A query on this exact key with 'pseudonym=<pseudonym>' and 'token=<admin token>' parameters returns the topic level it stands for, or null if it's not a valid pseudonym (if 'pseudonyms' and 'admin' are configured). Each reveal is recorded in the audit log",
+        },
+        ADMIN_SPACE_KE_SESSIONS_IMPORT.as_str(): {
+            "type": "object",
+            "description": "A query on this exact key with a 'session=<blob>' parameter imports a persistent session exported by another bridge, once its identity is checked (if 'shared_sessions' is configured, and with a 'token=<admin token>' parameter if 'admin' is configured)",
+        },
+        ADMIN_SPACE_KE_LOG_FILTER.as_str(): {
+            "type": ["string", "null"],
+            "description": "The log filter (RUST_LOG syntax), or null if the plugin's logger is not installed. A query on this exact key with a 'set=<filter>' parameter (and optionally 'duration=<secs>') changes it",
+        },
+        ADMIN_SPACE_KE_LISTENERS.as_str(): {
+            "type": "object",
+            "description": "Per listener ('mqtt' or 'mqtt-sn'), its address and the numbers of connections, messages and bytes received and sent, and errors",
+        },
+        ADMIN_SPACE_KE_BANS.as_str(): {
+            "type": "object",
+            "description": "The ban lists and allow lists of client id patterns and IP address ranges, and the auto-banned client ids and addresses",
+        },
+        ADMIN_SPACE_KE_BANS_ADD.as_str(): {
+            "type": "object",
+            "description": "A query on this exact key, with a 'client_id=<regex>' and/or an 'address=<CIDR>' parameter, adds them to the ban list",
+        },
+        ADMIN_SPACE_KE_BANS_REMOVE.as_str(): {
+            "type": "object",
+            "description": "A query on this exact key, with a 'client_id=<regex>' and/or an 'address=<CIDR>' parameter, removes them from the ban list",
+        },
+        ADMIN_SPACE_KE_LATENCY.as_str(): { "type": "object", "description": "The routing latencies percentiles, per direction" },
+        ADMIN_SPACE_KE_PAYLOAD_SIZES.as_str(): { "type": "object", "description": "The histograms of the routed payloads sizes, per direction" },
+        ADMIN_SPACE_KE_MEMORY_BUDGET.as_str(): { "type": "object", "description": "The memory budget usage (if 'max_inflight_bytes' is configured)" },
+        ADMIN_SPACE_KE_RESOURCE_GUARD.as_str(): {
+            "type": "object",
+            "description": "The resources measures (open file descriptors, queued bytes, tasks lag), the current alerts and the number of connections refused while overloaded (if 'resource_guard' is configured)",
+        },
+        ADMIN_SPACE_KE_HANDSHAKES.as_str(): {
+            "type": "object",
+            "description": "The numbers of handshakes in progress and waiting for a slot, and the numbers of handshakes queued and rejected (if 'handshakes' is configured)",
+            "properties": {
+                "max_concurrent": { "type": "integer" },
+                "active": { "type": "integer" },
+                "waiting": { "type": "integer" },
+                "queued": { "type": "integer" },
+                "rejected": { "type": "integer" },
+            },
+        },
+        ADMIN_SPACE_KE_SLOW_CONSUMERS.as_str(): {
+            "type": "object",
+            "description": "The slow consumers policy, the number of clients currently above the high-water mark, and the number of times the policy was applied (if 'slow_consumers' is configured)",
+        },
+        ADMIN_SPACE_KE_KE_CACHE.as_str(): {
+            "type": "object",
+            "description": "The hits (in the clients' caches and in the global one) and misses of the key expressions caches, and the size of the global cache (if 'ke_cache' is configured)",
+            "properties": {
+                "session_hits": { "type": "integer" },
+                "global_hits": { "type": "integer" },
+                "misses": { "type": "integer" },
+                "global_size": { "type": "integer" },
+            },
+        },
+        ADMIN_SPACE_KE_LAST_VALUES.as_str(): {
+            "type": "object",
+            "description": "The number of topics in the cache of the last values, and the number of zenoh queries it answered (if 'last_values' is configured)",
+            "properties": {
+                "retained_only": { "type": "boolean" },
+                "topics": { "type": "integer" },
+                "queries": { "type": "integer" },
+            },
+        },
+        ADMIN_SPACE_KE_PROVISIONING.as_str(): {
+            "type": "object",
+            "description": "The number of client ids already seen, and the numbers of clients provisioned, rejected by the backend and without reply in time (if 'provisioning' is configured)",
+            "properties": {
+                "seen_clients": { "type": "integer" },
+                "provisioned": { "type": "integer" },
+                "rejected": { "type": "integer" },
+                "timeouts": { "type": "integer" },
+            },
+        },
+        ADMIN_SPACE_KE_ENROLLMENT.as_str(): {
+            "type": "object",
+            "description": "The numbers of certificate signing requests forwarded, of certificates issued and of failed enrollments (if 'enrollment' is configured)",
+            "properties": {
+                "requests": { "type": "integer" },
+                "issued": { "type": "integer" },
+                "failed": { "type": "integer" },
+            },
+        },
+        ADMIN_SPACE_KE_ROUTING.as_str(): {
+            "type": "object",
+            "description": "The pause state of the routing: paused (or resuming, while the buffered publications are put on zenoh) since when, the numbers of buffered MQTT publications and bytes, and the numbers of publications dropped while paused in each direction",
+        },
+        ADMIN_SPACE_KE_ROUTING_PAUSE.as_str(): { "type": "object", "description": "A query on this exact key pauses the routing in both directions, keeping the connections alive, and returns the pause state" },
+        ADMIN_SPACE_KE_ROUTING_RESUME.as_str(): { "type": "object", "description": "A query on this exact key resumes the routing (after the buffered MQTT publications are put on zenoh), and returns the pause state" },
+        ADMIN_SPACE_KE_DRAIN.as_str(): { "type": "string", "description": "A query on this exact key drains the MQTT server" },
+        ADMIN_SPACE_KE_REPLAY.as_str(): {
+            "type": "object",
+            "description": "Whether a recording is being replayed. A query on this exact key with a 'file=<path>' parameter replays the recording of the 'replay' directory (at 'speed=<factor>' if set)",
+            "properties": {
+                "replaying": { "type": "boolean" },
+                "error": { "type": "string" },
+            },
+        },
+        ADMIN_SPACE_KE_HA.as_str(): {
+            "type": "object",
+            "description": "If 'ha' is configured, the id of the bridge in its HA group, whether it's active (and since when), and the ids of the alive bridges of the group",
+        },
+        ADMIN_SPACE_KE_DASHBOARD.as_str(): { "type": "string", "description": "A web dashboard (if built with the 'dashboard' feature)" },
+    })
+}
+
+fn enabled_features() -> Vec<&'static str> {
+    #[allow(unused_mut)]
+    let mut features = Vec::new();
+    #[cfg(feature = "dashboard")]
+    features.push("dashboard");
+    #[cfg(feature = "opentelemetry")]
+    features.push("opentelemetry");
+    #[cfg(feature = "rhai")]
+    features.push("rhai");
+    #[cfg(feature = "shared-memory")]
+    features.push("shared-memory");
+    #[cfg(feature = "stats")]
+    features.push("stats");
+    features
+}
+
+// The state shared by all the MQTT sessions
+#[derive(Debug)]
+pub(crate) struct BridgeState {
+    pub(crate) zsession: Arc<Session>,
+    pub(crate) config: Arc<Config>,
+    // the 'allow' and 'deny' settings, changed by a hot change of the configuration
+    pub(crate) acl: Arc<TopicAcl>,
+    // the configuration of the MQTT-SN clients, if the MQTT-SN gateway overrides some settings
+    pub(crate) mqtt_sn_config: Option<Arc<Config>>,
+    pub(crate) mqtt_sn_acl: Option<Arc<TopicAcl>>,
+    pub(crate) fanout: Arc<FanoutRegistry>,
+    pub(crate) shm: Arc<ShmProvider>,
+    pub(crate) auth: Option<Arc<Authenticator>>,
+    pub(crate) store_forward: Option<Arc<StoreForward>>,
+    pub(crate) client_stats: Arc<ClientStats>,
+    pub(crate) clients: Arc<ClientsRegistry>,
+    pub(crate) listeners: Arc<ListenersStats>,
+    pub(crate) bans: Arc<BanList>,
+    pub(crate) memory_budget: Option<Arc<MemoryBudget>>,
+    pub(crate) resource_guard: Option<Arc<ResourceGuard>>,
+    pub(crate) handshakes: Option<Arc<HandshakeLimiter>>,
+    pub(crate) slow_consumers: Option<Arc<SlowConsumers>>,
+    pub(crate) uns: Option<Arc<Uns>>,
+    pub(crate) sessions: Option<Arc<SessionRegistry>>,
+    pub(crate) put_caps: Option<Arc<PutCaps>>,
+    pub(crate) query_rules: Option<Arc<QueryRules>>,
+    pub(crate) ke_cache: Option<Arc<KeCaches>>,
+    pub(crate) last_values: Option<Arc<LastValues>>,
+    pub(crate) provisioning: Option<Arc<Provisioning>>,
+    pub(crate) enrollment: Option<Arc<Enrollment>>,
+}
+
+impl BridgeState {
+    // Returns true if a new connection must be refused, the plugin being overloaded (see "resource_guard")
+    pub(crate) fn refuse_connection(&self) -> bool {
+        self.resource_guard
+            .as_ref()
+            .map_or(false, |g| g.refuse_connection())
     }
 }
 
 async fn handshake_v3<'a>(
     handshake: v3::Handshake,
-    zsession: Arc<Session>,
-    config: Arc<Config>,
+    bridge: Arc<BridgeState>,
 ) -> Result<v3::HandshakeAck<MqttSessionState<'a>>, MqttPluginError> {
     let client_id = handshake.packet().client_id.to_string();
-    log::info!("MQTT client {} connects using v3", client_id);
+    let peer = mqtt_helpers::peer_addr(handshake.io());
+    log::info!(
+        "MQTT client {} connects using v3 from {} via '{}' listener",
+        client_id,
+        peer.map_or("unknown address".into(), |a| a.to_string()),
+        LISTENER_MQTT
+    );
+    let peer_ip = peer.map(|a| a.ip());
+    if bridge.refuse_connection() {
+        log::info!("MQTT client {} rejected: plugin overloaded", client_id);
+        audit::rejected(&client_id, peer_ip, "plugin overloaded");
+        return Ok(handshake.service_unavailable());
+    }
+    if !ha::is_active() {
+        log::info!("MQTT client {} rejected: passive HA bridge", client_id);
+        audit::rejected(&client_id, peer_ip, "passive HA bridge");
+        return Ok(handshake.service_unavailable());
+    }
+    // the slot is released at the end of the handshake
+    let _permit = match &bridge.handshakes {
+        Some(handshakes) => match handshakes.acquire().await {
+            Some(permit) => Some(permit),
+            None => {
+                log::info!("MQTT client {} rejected: too many handshakes", client_id);
+                audit::rejected(&client_id, peer_ip, "too many handshakes");
+                return Ok(handshake.service_unavailable());
+            }
+        },
+        None => None,
+    };
+    if let Some(violation) = bridge
+        .config
+        .strict
+        .then(|| mqtt_helpers::strict_client_id_violation(&client_id))
+        .flatten()
+    {
+        log::info!("MQTT client {} rejected: {}", client_id, violation);
+        audit::rejected(&client_id, peer_ip, &violation);
+        return Ok(handshake.identifier_rejected());
+    }
+    if let Err(reason) = bridge.bans.check(&client_id, peer_ip) {
+        log::info!("MQTT client {} rejected: {}", client_id, reason);
+        audit::rejected(&client_id, peer_ip, &reason);
+        return Ok(handshake.not_authorized());
+    }
+    if bridge.client_stats.is_banned(&client_id) {
+        log::info!(
+            "MQTT client {} rejected: banned after too many protocol errors",
+            client_id
+        );
+        audit::rejected(&client_id, peer_ip, "banned after too many protocol errors");
+        return Ok(handshake.not_authorized());
+    }
+    if let Some(auth) = &bridge.auth {
+        let packet = handshake.packet();
+        let decision = auth.authenticate(
+            &client_id,
+            peer_ip,
+            packet.username.as_deref(),
+            packet.password.as_deref(),
+        );
+        audit::authenticated(&client_id, packet.username.as_deref(), peer_ip, &decision);
+        match decision {
+            AuthDecision::Granted => bridge.bans.record_auth_success(&client_id, peer_ip),
+            AuthDecision::Denied => {
+                log::info!("MQTT client {} authentication failed", client_id);
+                bridge.bans.record_auth_failure(&client_id, peer_ip);
+                return Ok(handshake.bad_username_or_pwd());
+            }
+            AuthDecision::LockedOut => {
+                log::info!(
+                    "MQTT client {} rejected: too many failed authentications",
+                    client_id
+                );
+                return Ok(handshake.not_authorized());
+            }
+        }
+    }
+    let username = handshake.packet().username.as_ref().map(|u| u.to_string());
+    let clean_session = handshake.packet().clean_session;
+    let negotiated = Negotiated {
+        protocol_level: handshake.packet().protocol.level(),
+        keep_alive: handshake.packet().keep_alive,
+        clean_session,
+        max_packet_size: None,
+    };
+    if !events::accept_connection(&client_id, username.as_deref()) {
+        log::info!(
+            "MQTT client {} connection rejected by an event handler",
+            client_id
+        );
+        audit::rejected(&client_id, peer_ip, "rejected by an event handler");
+        return Ok(handshake.not_authorized());
+    }
+    if let Some(provisioning) = &bridge.provisioning {
+        if let Err(reason) = provisioning
+            .on_connect(
+                &client_id,
+                ProtocolVersion::V3,
+                username.as_deref(),
+                peer_ip,
+            )
+            .await
+        {
+            log::info!("MQTT client {} rejected: {}", client_id, reason);
+            audit::rejected(&client_id, peer_ip, &reason);
+            return Ok(handshake.not_authorized());
+        }
+    }
+    telemetry::client_connected("v3", LISTENER_MQTT);
+    audit::connected(
+        &client_id,
+        ProtocolVersion::V3,
+        username.as_deref(),
+        peer_ip,
+    );
+    events::notify(SessionEvent::Connected {
+        client_id: client_id.clone(),
+        protocol: ProtocolVersion::V3,
+        address: peer,
+        listener: LISTENER_MQTT,
+    });
 
-    let session = MqttSessionState::new(client_id, zsession, config);
-    Ok(handshake.ack(session, false))
+    let session = MqttSessionState::new(
+        client_id,
+        &bridge,
+        username,
+        MqttSink::v3(
+            handshake.sink(),
+            peer,
+            bridge.memory_budget.clone(),
+            bridge.listeners.get(LISTENER_MQTT),
+        ),
+        ProtocolVersion::V3,
+        None,
+        None,
+    );
+    bridge
+        .clients
+        .set_negotiated(&session.client_id, session.connection_id, negotiated);
+    let session_present = session.resume_session(clean_session).await;
+    Ok(handshake.ack(session, session_present))
 }
 
 async fn publish_v3(
     session: v3::Session<MqttSessionState<'_>>,
     publish: v3::Publish,
 ) -> Result<(), MqttPluginError> {
+    let _inflight = (publish.qos() != QoS::AtMostOnce).then(drain::Inflight::new);
     session
         .state()
-        .route_mqtt_to_zenoh(publish.topic(), publish.payload())
+        .route_mqtt_to_zenoh(
+            publish.topic(),
+            publish.payload(),
+            publish.qos(),
+            publish.retain(),
+            None,
+        )
         .await
-        .map_err(MqttPluginError::from)
 }
 
 async fn control_v3(
     session: v3::Session<MqttSessionState<'_>>,
     control: v3::ControlMessage<MqttPluginError>,
 ) -> Result<v3::ControlResult, MqttPluginError> {
-    log::trace!(
-        "MQTT client {} sent control: {:?}",
-        session.client_id,
-        control
-    );
+    log::trace!("MQTT client {} sent control: {:?}", session.tag, control);
 
     match control {
-        v3::ControlMessage::Ping(ref msg) => Ok(msg.ack()),
+        v3::ControlMessage::Ping(ref msg) => {
+            session.state().sink.link_quality().ping();
+            session.state().on_ping().await;
+            Ok(msg.ack())
+        }
         v3::ControlMessage::Disconnect(msg) => {
-            log::debug!("MQTT client {} disconnected", session.client_id);
+            log::debug!("MQTT client {} disconnected", session.tag);
             session.sink().close();
             Ok(msg.ack())
         }
         v3::ControlMessage::Subscribe(mut msg) => {
             for mut s in msg.iter_mut() {
                 let topic = s.topic().as_str();
-                log::debug!(
-                    "MQTT client {} subscribes to '{}'",
-                    session.client_id,
-                    topic
-                );
+                log::debug!("MQTT client {} subscribes to '{}'", session.tag, topic);
+                if session.state().is_denied_root_wildcard(topic) {
+                    log::warn!(
+                        "MQTT client {}: subscription to '{}' denied by 'root_wildcard' configuration",
+                        session.tag,
+                        topic
+                    );
+                    audit::acl_denied(
+                        &session.client_id,
+                        zread!(session.username).as_deref(),
+                        "subscribe",
+                        topic,
+                    );
+                    s.fail();
+                    continue;
+                }
+                let qos = session.state().granted_qos(s.qos());
                 match session
                     .state()
-                    .map_mqtt_subscription(topic, session.sink().clone().into())
+                    .map_mqtt_subscription(topic, s.qos(), false, DeliveryOptions::default())
                     .await
                 {
-                    Ok(()) => s.confirm(v5::QoS::AtMostOnce),
+                    Ok(()) => s.confirm(qos),
                     Err(e) => {
                         log::error!("Subscription to '{}' failed: {}", topic, e);
                         s.fail()
@@ -331,38 +1599,44 @@ async fn control_v3(
             for topic in msg.iter() {
                 log::debug!(
                     "MQTT client {} unsubscribes from '{}'",
-                    session.client_id,
+                    session.tag,
                     topic.as_str()
                 );
+                session
+                    .state()
+                    .unmap_mqtt_subscription(topic.as_str())
+                    .await;
             }
             Ok(msg.ack())
         }
         v3::ControlMessage::Closed(msg) => {
-            log::debug!("MQTT client {} closed connection", session.client_id);
+            log::debug!("MQTT client {} closed connection", session.tag);
             session.sink().force_close();
             Ok(msg.ack())
         }
         v3::ControlMessage::Error(msg) => {
             log::warn!(
                 "MQTT client {} Error received: {}",
-                session.client_id,
+                session.tag,
                 msg.get_ref().err
             );
             Ok(msg.ack())
         }
         v3::ControlMessage::ProtocolError(ref msg) => {
+            session.state().listener.error();
+            session
+                .state()
+                .client_stats
+                .record_protocol_error(&session.client_id, &msg.get_ref().to_string());
             log::warn!(
                 "MQTT client {}: ProtocolError received: {} => disconnect it",
-                session.client_id,
+                session.tag,
                 msg.get_ref()
             );
             Ok(control.disconnect())
         }
         v3::ControlMessage::PeerGone(msg) => {
-            log::debug!(
-                "MQTT client {}: PeerGone => close connection",
-                session.client_id
-            );
+            log::debug!("MQTT client {}: PeerGone => close connection", session.tag);
             session.sink().close();
             Ok(msg.ack())
         }
@@ -371,114 +1645,538 @@ async fn control_v3(
 
 async fn handshake_v5<'a>(
     handshake: v5::Handshake,
-    zsession: Arc<Session>,
-    config: Arc<Config>,
+    bridge: Arc<BridgeState>,
 ) -> Result<v5::HandshakeAck<MqttSessionState<'a>>, MqttPluginError> {
     let client_id = handshake.packet().client_id.to_string();
-    log::info!("MQTT client {} connects using v5", client_id);
+    let peer = mqtt_helpers::peer_addr(handshake.io());
+    log::info!(
+        "MQTT client {} connects using v5 from {} via '{}' listener",
+        client_id,
+        peer.map_or("unknown address".into(), |a| a.to_string()),
+        LISTENER_MQTT
+    );
+    let peer_ip = peer.map(|a| a.ip());
+    if bridge.refuse_connection() {
+        log::info!("MQTT client {} rejected: plugin overloaded", client_id);
+        audit::rejected(&client_id, peer_ip, "plugin overloaded");
+        return Ok(handshake.failed(v5::codec::ConnectAckReason::ServerBusy));
+    }
+    if !ha::is_active() {
+        log::info!("MQTT client {} rejected: passive HA bridge", client_id);
+        audit::rejected(&client_id, peer_ip, "passive HA bridge");
+        return Ok(handshake.failed(v5::codec::ConnectAckReason::ServerUnavailable));
+    }
+    // the slot is released at the end of the handshake
+    let _permit = match &bridge.handshakes {
+        Some(handshakes) => match handshakes.acquire().await {
+            Some(permit) => Some(permit),
+            None => {
+                log::info!("MQTT client {} rejected: too many handshakes", client_id);
+                audit::rejected(&client_id, peer_ip, "too many handshakes");
+                return Ok(handshake.failed(v5::codec::ConnectAckReason::ServerBusy));
+            }
+        },
+        None => None,
+    };
+    if let Some(violation) = bridge
+        .config
+        .strict
+        .then(|| mqtt_helpers::strict_client_id_violation(&client_id))
+        .flatten()
+    {
+        log::info!("MQTT client {} rejected: {}", client_id, violation);
+        audit::rejected(&client_id, peer_ip, &violation);
+        return Ok(handshake.failed(v5::codec::ConnectAckReason::ClientIdentifierNotValid));
+    }
+    if let Err(reason) = bridge.bans.check(&client_id, peer_ip) {
+        log::info!("MQTT client {} rejected: {}", client_id, reason);
+        audit::rejected(&client_id, peer_ip, &reason);
+        return Ok(handshake.failed(v5::codec::ConnectAckReason::Banned));
+    }
+    if bridge.client_stats.is_banned(&client_id) {
+        log::info!(
+            "MQTT client {} rejected: banned after too many protocol errors",
+            client_id
+        );
+        audit::rejected(&client_id, peer_ip, "banned after too many protocol errors");
+        return Ok(handshake.failed(v5::codec::ConnectAckReason::Banned));
+    }
+    let packet = handshake.packet();
+    // with the "PLAIN" authentication method, the credentials are in the authentication data
+    let (username, password) = match packet.auth_method.as_deref() {
+        Some(AUTH_METHOD_PLAIN) => match packet.auth_data.as_deref().and_then(decode_sasl_plain) {
+            Some((username, password)) => (Some(username), Some(password)),
+            None => (None, None),
+        },
+        _ => (packet.username.as_deref(), packet.password.as_deref()),
+    };
+    let username = username.map(String::from);
+    if let Some(auth) = &bridge.auth {
+        if let Some(method) = packet
+            .auth_method
+            .as_deref()
+            .filter(|m| *m != AUTH_METHOD_PLAIN)
+        {
+            log::info!(
+                "MQTT client {} uses unsupported authentication method '{}'",
+                client_id,
+                method
+            );
+            audit::rejected(
+                &client_id,
+                peer_ip,
+                &format!("unsupported authentication method '{method}'"),
+            );
+            return Ok(handshake.failed(v5::codec::ConnectAckReason::BadAuthenticationMethod));
+        }
+        let decision = auth.authenticate(&client_id, peer_ip, username.as_deref(), password);
+        audit::authenticated(&client_id, username.as_deref(), peer_ip, &decision);
+        match decision {
+            AuthDecision::Granted => bridge.bans.record_auth_success(&client_id, peer_ip),
+            AuthDecision::Denied => {
+                log::info!("MQTT client {} authentication failed", client_id);
+                bridge.bans.record_auth_failure(&client_id, peer_ip);
+                return Ok(handshake.failed(v5::codec::ConnectAckReason::BadUserNameOrPassword));
+            }
+            AuthDecision::LockedOut => {
+                log::info!(
+                    "MQTT client {} rejected: too many failed authentications",
+                    client_id
+                );
+                return Ok(handshake.failed(v5::codec::ConnectAckReason::ConnectionRateExceeded));
+            }
+        }
+    }
+    if !events::accept_connection(&client_id, username.as_deref()) {
+        log::info!(
+            "MQTT client {} connection rejected by an event handler",
+            client_id
+        );
+        audit::rejected(&client_id, peer_ip, "rejected by an event handler");
+        return Ok(handshake.failed(v5::codec::ConnectAckReason::NotAuthorized));
+    }
+    if let Some(provisioning) = &bridge.provisioning {
+        if let Err(reason) = provisioning
+            .on_connect(
+                &client_id,
+                ProtocolVersion::V5,
+                username.as_deref(),
+                peer_ip,
+            )
+            .await
+        {
+            log::info!("MQTT client {} rejected: {}", client_id, reason);
+            audit::rejected(&client_id, peer_ip, &reason);
+            return Ok(handshake.failed(v5::codec::ConnectAckReason::NotAuthorized));
+        }
+    }
+    // the tenant scope optionally requested by the client via a user property
+    let tenant_scope = match &bridge.config.tenant_scopes {
+        Some(conf) => match packet
+            .user_properties
+            .iter()
+            .find(|(k, _)| &**k == conf.property.as_str())
+        {
+            Some((_, requested)) => {
+                match OwnedKeyExpr::try_from(requested.to_string())
+                    .ok()
+                    .filter(|s| !s.is_wild() && conf.is_allowed(username.as_deref(), s))
+                {
+                    Some(tenant_scope) => Some(tenant_scope),
+                    None => {
+                        log::info!(
+                            "MQTT client {} rejected: scope '{}' not allowed",
+                            client_id,
+                            requested
+                        );
+                        audit::acl_denied(
+                            &client_id,
+                            username.as_deref(),
+                            "select_scope",
+                            requested,
+                        );
+                        return Ok(handshake.failed(v5::codec::ConnectAckReason::NotAuthorized));
+                    }
+                }
+            }
+            None => None,
+        },
+        None => None,
+    };
+    // the publications priority optionally requested by the client via a user property
+    let priority = match &bridge.config.priorities {
+        Some(conf) => match packet
+            .user_properties
+            .iter()
+            .find(|(k, _)| &**k == conf.property.as_str())
+        {
+            Some((_, requested)) => {
+                match serde_json::from_value::<PublicationPriority>(Value::from(
+                    requested.to_string(),
+                ))
+                .ok()
+                .filter(|p| conf.is_allowed(username.as_deref(), *p))
+                {
+                    Some(priority) => Some(priority),
+                    None => {
+                        log::info!(
+                            "MQTT client {} rejected: priority '{}' not allowed",
+                            client_id,
+                            requested
+                        );
+                        audit::acl_denied(
+                            &client_id,
+                            username.as_deref(),
+                            "select_priority",
+                            requested,
+                        );
+                        return Ok(handshake.failed(v5::codec::ConnectAckReason::NotAuthorized));
+                    }
+                }
+            }
+            None => None,
+        },
+        None => None,
+    };
+    let auth_method = packet.auth_method.clone();
+    let clean_start = packet.clean_start;
+    let negotiated = Negotiated {
+        protocol_level: 5,
+        keep_alive: bridge.config.server_keep_alive.unwrap_or(packet.keep_alive),
+        clean_session: clean_start,
+        max_packet_size: packet.max_packet_size.map(|size| size.get()),
+    };
+    // the session expiry interval requested by the client is bounded by the configured maximum
+    let session_expiry = bridge
+        .config
+        .max_session_expiry
+        .filter(|max| packet.session_expiry_interval_secs > *max);
+    telemetry::client_connected("v5", LISTENER_MQTT);
+    audit::connected(
+        &client_id,
+        ProtocolVersion::V5,
+        username.as_deref(),
+        peer_ip,
+    );
+    events::notify(SessionEvent::Connected {
+        client_id: client_id.clone(),
+        protocol: ProtocolVersion::V5,
+        address: peer,
+        listener: LISTENER_MQTT,
+    });
 
-    let session = MqttSessionState::new(client_id, zsession, config);
-    Ok(handshake.ack(session))
+    let session = MqttSessionState::new(
+        client_id,
+        &bridge,
+        username,
+        MqttSink::v5(
+            handshake.sink(),
+            peer,
+            bridge.memory_budget.clone(),
+            bridge.listeners.get(LISTENER_MQTT),
+        ),
+        ProtocolVersion::V5,
+        tenant_scope,
+        priority,
+    );
+    bridge
+        .clients
+        .set_negotiated(&session.client_id, session.connection_id, negotiated);
+    let session_present = session.resume_session(clean_start).await;
+    let identity = if bridge.config.connack_identity {
+        bridge_identity(&bridge)
+    } else {
+        Vec::new()
+    };
+    let mut ack = handshake.ack(session).with(|ack| {
+        ack.auth_method = auth_method;
+        ack.session_expiry_interval_secs = session_expiry;
+        ack.session_present = session_present;
+        ack.user_properties.extend(identity);
+    });
+    // the server keep alive overrides the one requested by the client
+    if let Some(keep_alive) = bridge.config.server_keep_alive {
+        ack = ack.keep_alive(keep_alive);
+    }
+    Ok(ack)
+}
+
+// The identity of this bridge, as MQTT 5 user properties
+fn bridge_identity(bridge: &BridgeState) -> v5::codec::UserProperties {
+    let mut identity = vec![
+        ("bridge_version".into(), GIT_VERSION.into()),
+        (
+            "bridge_zid".into(),
+            bridge.zsession.zid().to_string().into(),
+        ),
+    ];
+    if let Some(scope) = &bridge.config.scope {
+        identity.push(("bridge_scope".into(), scope.as_str().into()));
+    }
+    identity
 }
 
 async fn publish_v5(
     session: v5::Session<MqttSessionState<'_>>,
     publish: v5::Publish,
 ) -> Result<v5::PublishAck, MqttPluginError> {
+    let _inflight = (publish.qos() != QoS::AtMostOnce).then(drain::Inflight::new);
+    // the key expression optionally requested by the client via a user property
+    let ke_override = session
+        .state()
+        .config
+        .ke_override
+        .as_ref()
+        .and_then(|conf| {
+            publish
+                .packet()
+                .properties
+                .user_properties
+                .iter()
+                .find(|(k, _)| &**k == conf.property.as_str())
+        })
+        .map(|(_, requested)| requested.to_string());
     session
         .state()
-        .route_mqtt_to_zenoh(publish.topic(), publish.payload())
+        .route_mqtt_to_zenoh(
+            publish.topic(),
+            publish.payload(),
+            publish.qos(),
+            publish.retain(),
+            ke_override.as_deref(),
+        )
         .await
         .map(|()| publish.ack())
-        .map_err(MqttPluginError::from)
+}
+
+// The number of historical publications requested by a MQTT 5 client via the "history" user property of SUBSCRIBE
+fn requested_history(client_id: &str, packet: &v5::codec::Subscribe) -> usize {
+    let Some((_, value)) = packet
+        .user_properties
+        .iter()
+        .find(|(k, _)| &**k == USER_PROPERTY_HISTORY)
+    else {
+        return 0;
+    };
+    match value.parse::<usize>() {
+        Ok(count) if count > MAX_SUBSCRIPTION_HISTORY => {
+            log::warn!(
+                "MQTT client {} requested a history of {} publications - limited to {}",
+                client_id,
+                count,
+                MAX_SUBSCRIPTION_HISTORY
+            );
+            MAX_SUBSCRIPTION_HISTORY
+        }
+        Ok(count) => count,
+        Err(_) => {
+            log::warn!(
+                "MQTT client {} requested an invalid history: '{}' - ignore it",
+                client_id,
+                value
+            );
+            0
+        }
+    }
+}
+
+// The payload format requested by a MQTT 5 client via the "format" user property of SUBSCRIBE
+fn requested_format(client_id: &str, packet: &v5::codec::Subscribe) -> Option<PayloadFormat> {
+    let (_, value) = packet
+        .user_properties
+        .iter()
+        .find(|(k, _)| &**k == USER_PROPERTY_FORMAT)?;
+    let format = PayloadFormat::parse(value);
+    if format.is_none() {
+        log::warn!(
+            "MQTT client {} requested an unsupported payload format: '{}' - ignore it",
+            client_id,
+            value
+        );
+    }
+    format
+}
+
+// The delivery options requested by a MQTT 5 client via the properties of SUBSCRIBE
+fn requested_delivery(client_id: &str, packet: &v5::codec::Subscribe) -> DeliveryOptions {
+    let meta = packet
+        .user_properties
+        .iter()
+        .any(|(k, v)| &**k == USER_PROPERTY_META && v.eq_ignore_ascii_case("true"));
+    let filter = packet
+        .user_properties
+        .iter()
+        .find(|(k, _)| &**k == USER_PROPERTY_FILTER)
+        .and_then(|(_, v)| match PayloadFilter::parse(v) {
+            Ok(filter) => Some(filter),
+            Err(e) => {
+                log::warn!(
+                    "MQTT client {} requested an invalid filter: {} - ignore it",
+                    client_id,
+                    e
+                );
+                None
+            }
+        });
+    DeliveryOptions {
+        format: requested_format(client_id, packet),
+        meta,
+        filter,
+        subscription_id: packet.id,
+    }
 }
 
 async fn control_v5(
     session: v5::Session<MqttSessionState<'_>>,
     control: v5::ControlMessage<MqttPluginError>,
 ) -> Result<v5::ControlResult, MqttPluginError> {
-    log::trace!(
-        "MQTT client {} sent control: {:?}",
-        session.client_id,
-        control
-    );
+    log::trace!("MQTT client {} sent control: {:?}", session.tag, control);
 
     use v5::codec::{Disconnect, DisconnectReasonCode};
+    // a re-authentication failure requires to disconnect the client, so it's checked before consuming the control message
+    let reauth = match &control {
+        v5::ControlMessage::Auth(msg) => Some(session.state().reauthenticate(msg.packet())),
+        _ => None,
+    };
     match control {
-        v5::ControlMessage::Auth(_) => {
-            log::debug!(
-                "MQTT client {} wants to authenticate... not yet supported!",
-                session.client_id
-            );
-            Ok(control.disconnect_with(Disconnect::new(
-                DisconnectReasonCode::ImplementationSpecificError,
-            )))
+        v5::ControlMessage::Auth(_) if !matches!(reauth, Some(Ok(_))) => {
+            if let Some(Err(e)) = reauth {
+                log::warn!("{} => disconnect it", e);
+            }
+            Ok(control.disconnect_with(Disconnect::new(DisconnectReasonCode::NotAuthorized)))
+        }
+        v5::ControlMessage::Auth(msg) => {
+            if let Some(Ok(true)) = reauth {
+                session.state().recheck_subscriptions().await;
+            }
+            Ok(msg.ack(v5::codec::Auth {
+                auth_method: Some(AUTH_METHOD_PLAIN.into()),
+                ..Default::default()
+            }))
+        }
+        v5::ControlMessage::Ping(msg) => {
+            session.state().sink.link_quality().ping();
+            session.state().on_ping().await;
+            Ok(msg.ack())
         }
-        v5::ControlMessage::Ping(msg) => Ok(msg.ack()),
         v5::ControlMessage::Disconnect(msg) => {
-            log::debug!("MQTT client {} disconnected", session.client_id);
+            log::debug!("MQTT client {} disconnected", session.tag);
             session.sink().close();
             Ok(msg.ack())
         }
         v5::ControlMessage::Subscribe(mut msg) => {
+            let history = requested_history(&session.client_id, msg.packet());
+            let delivery = requested_delivery(&session.client_id, msg.packet());
+            let mut granted_filters = Vec::new();
             for mut s in msg.iter_mut() {
                 let topic = s.topic().as_str();
-                log::debug!(
-                    "MQTT client {} subscribes 'to' {}",
-                    session.client_id,
-                    topic
-                );
+                log::debug!("MQTT client {} subscribes 'to' {}", session.tag, topic);
+                if session.state().is_denied_root_wildcard(topic) {
+                    log::warn!(
+                        "MQTT client {}: subscription to '{}' denied by 'root_wildcard' configuration",
+                        session.tag,
+                        topic
+                    );
+                    audit::acl_denied(
+                        &session.client_id,
+                        zread!(session.username).as_deref(),
+                        "subscribe",
+                        topic,
+                    );
+                    s.fail(v5::codec::SubscribeAckReason::NotAuthorized);
+                    continue;
+                }
+                let qos = session.state().granted_qos(s.options().qos);
+                let no_local = s.options().no_local;
+                // the Retain Handling option applies to the history replay
+                let replay_history = history > 0
+                    && mqtt_helpers::sends_retained(
+                        s.options().retain_handling,
+                        session.state().is_subscribed(topic).await,
+                    );
                 match session
                     .state()
-                    .map_mqtt_subscription(topic, session.sink().clone().into())
+                    .map_mqtt_subscription(topic, s.options().qos, no_local, delivery.clone())
                     .await
                 {
-                    Ok(()) => s.confirm(v5::QoS::AtMostOnce),
+                    Ok(()) => {
+                        // the history is only replayed for a granted subscription
+                        if replay_history {
+                            if let Err(e) = session
+                                .state()
+                                .replay_history(topic, s.options().qos, history, delivery.clone())
+                                .await
+                            {
+                                log::warn!(
+                                    "MQTT client {}: failed to fetch history for subscription to '{}': {}",
+                                    session.tag,
+                                    topic,
+                                    e
+                                );
+                            }
+                        }
+                        granted_filters.extend(session.state().granted_filters(topic));
+                        s.confirm(qos)
+                    }
                     Err(e) => {
                         log::error!("Subscription to '{}' failed: {}", topic, e);
-                        s.fail(v5::codec::SubscribeAckReason::ImplementationSpecificError)
+                        s.fail(e.subscribe_ack_reason())
                     }
                 }
             }
-            Ok(msg.ack())
+            Ok(msg
+                .ack_properties(|props| {
+                    props.extend(
+                        granted_filters
+                            .into_iter()
+                            .map(|f| (USER_PROPERTY_GRANTED_FILTER.into(), f.into())),
+                    )
+                })
+                .ack())
         }
         v5::ControlMessage::Unsubscribe(msg) => {
             for topic in msg.iter() {
                 log::debug!(
                     "MQTT client {} unsubscribes from '{}'",
-                    session.client_id,
+                    session.tag,
                     topic.as_str()
                 );
+                session
+                    .state()
+                    .unmap_mqtt_subscription(topic.as_str())
+                    .await;
             }
             Ok(msg.ack())
         }
         v5::ControlMessage::Closed(msg) => {
-            log::debug!("MQTT client {} closed connection", session.client_id);
+            log::debug!("MQTT client {} closed connection", session.tag);
             session.sink().close();
             Ok(msg.ack())
         }
         v5::ControlMessage::Error(msg) => {
             log::warn!(
                 "MQTT client {} Error received: {}",
-                session.client_id,
+                session.tag,
                 msg.get_ref().err
             );
             Ok(msg.ack(DisconnectReasonCode::UnspecifiedError))
         }
         v5::ControlMessage::ProtocolError(msg) => {
+            session.state().listener.error();
+            session
+                .state()
+                .client_stats
+                .record_protocol_error(&session.client_id, &msg.get_ref().to_string());
             log::warn!(
                 "MQTT client {}: ProtocolError received: {}",
-                session.client_id,
+                session.tag,
                 msg.get_ref()
             );
             session.sink().close();
             Ok(msg.reason_code(DisconnectReasonCode::ProtocolError).ack())
         }
         v5::ControlMessage::PeerGone(msg) => {
-            log::debug!(
-                "MQTT client {}: PeerGone => close connection",
-                session.client_id
-            );
+            log::debug!("MQTT client {}: PeerGone => close connection", session.tag);
             session.sink().close();
             Ok(msg.ack())
         }
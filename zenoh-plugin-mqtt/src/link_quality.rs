@@ -0,0 +1,112 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The quality of the link with a MQTT client, for the admin space:
+//   - the round-trip time, measured between the sending of a QoS 1 publication and the reception of its PUBACK
+//   - the intervals between the PINGREQ sent by the client, that grow irregular on a degraded link
+
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use zenoh_core::zlock;
+
+// The weight of a new sample in the moving averages
+const EWMA_WEIGHT: f64 = 0.2;
+
+#[derive(Debug, Default)]
+struct Inner {
+    rtt_samples: u64,
+    rtt_last: Duration,
+    rtt_min: Option<Duration>,
+    rtt_max: Duration,
+    // moving average, in seconds
+    rtt_avg: f64,
+    unacked: u64,
+    pings: u64,
+    last_ping: Option<Instant>,
+    ping_interval_last: Duration,
+    ping_interval_max: Duration,
+    // moving average of the absolute difference between consecutive ping intervals, in seconds
+    ping_jitter: f64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct LinkQuality {
+    inner: Mutex<Inner>,
+}
+
+fn ewma(avg: f64, sample: f64, first: bool) -> f64 {
+    if first {
+        sample
+    } else {
+        avg + EWMA_WEIGHT * (sample - avg)
+    }
+}
+
+fn as_ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+impl LinkQuality {
+    // A QoS 1 publication has been acknowledged after `rtt`
+    pub(crate) fn acked(&self, rtt: Duration) {
+        let mut inner = zlock!(self.inner);
+        inner.rtt_avg = ewma(inner.rtt_avg, rtt.as_secs_f64(), inner.rtt_samples == 0);
+        inner.rtt_samples += 1;
+        inner.rtt_last = rtt;
+        inner.rtt_min = Some(inner.rtt_min.map_or(rtt, |min| min.min(rtt)));
+        inner.rtt_max = inner.rtt_max.max(rtt);
+    }
+
+    // A QoS 1 publication has not been acknowledged
+    pub(crate) fn unacked(&self) {
+        zlock!(self.inner).unacked += 1;
+    }
+
+    // A PINGREQ has been received from the client
+    pub(crate) fn ping(&self) {
+        let now = Instant::now();
+        let mut inner = zlock!(self.inner);
+        if let Some(last) = inner.last_ping.replace(now) {
+            let interval = now - last;
+            if inner.pings > 1 {
+                let delta = (interval.as_secs_f64() - inner.ping_interval_last.as_secs_f64()).abs();
+                inner.ping_jitter = ewma(inner.ping_jitter, delta, inner.pings == 2);
+            }
+            inner.ping_interval_last = interval;
+            inner.ping_interval_max = inner.ping_interval_max.max(interval);
+        }
+        inner.pings += 1;
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        let inner = zlock!(self.inner);
+        serde_json::json!({
+            "rtt": {
+                "samples": inner.rtt_samples,
+                "last_ms": as_ms(inner.rtt_last),
+                "avg_ms": inner.rtt_avg * 1000.0,
+                "min_ms": inner.rtt_min.map_or(0.0, as_ms),
+                "max_ms": as_ms(inner.rtt_max),
+                "unacked": inner.unacked,
+            },
+            "pings": {
+                "count": inner.pings,
+                "last_interval_ms": as_ms(inner.ping_interval_last),
+                "max_interval_ms": as_ms(inner.ping_interval_max),
+                "jitter_ms": inner.ping_jitter * 1000.0,
+            },
+        })
+    }
+}
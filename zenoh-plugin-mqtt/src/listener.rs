@@ -0,0 +1,47 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+use std::io;
+use std::net::{TcpListener, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::config::SocketConfig;
+
+// Create a TCP listener bound to `addr`, with the socket options from the configuration.
+// Note that accepted connections inherit TCP_NODELAY and SO_KEEPALIVE options from the listener.
+pub(crate) fn create_listener(addr: &str, conf: &SocketConfig) -> io::Result<TcpListener> {
+    let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Cannot resolve address {addr}"),
+        )
+    })?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if conf.reuseport {
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+        log::warn!("SO_REUSEPORT is not supported on this platform - ignore it");
+    }
+    socket.set_nodelay(conf.nodelay)?;
+    if let Some(secs) = conf.keepalive_secs {
+        socket.set_keepalive(true)?;
+        socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(Duration::from_secs(secs)))?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(conf.backlog)?;
+    log::debug!("MQTT listener bound on {} with {:?}", addr, conf);
+    Ok(socket.into())
+}
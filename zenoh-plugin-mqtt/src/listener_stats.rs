@@ -0,0 +1,126 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The statistics per listener (connections, messages, bytes and errors), for the admin space.
+
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use zenoh_core::{zlock, zread, zwrite};
+
+// The listener of the MQTT clients (over TCP)
+pub(crate) const LISTENER_MQTT: &str = "mqtt";
+// The listener of the MQTT-SN clients (over UDP)
+pub(crate) const LISTENER_MQTT_SN: &str = "mqtt-sn";
+
+#[derive(Debug, Default)]
+pub(crate) struct ListenerStats {
+    address: RwLock<String>,
+    connections: AtomicU64,
+    active_connections: AtomicU64,
+    // publications received from the clients
+    messages_in: AtomicU64,
+    bytes_in: AtomicU64,
+    // publications sent to the clients
+    messages_out: AtomicU64,
+    bytes_out: AtomicU64,
+    // protocol and routing errors
+    errors: AtomicU64,
+    // topics (of publications or subscriptions) exceeding the configured limits
+    rejected_topics: AtomicU64,
+    // publications rejected or dropped in read-only mode
+    read_only_publications: AtomicU64,
+    // subscriptions refused in write-only mode
+    write_only_subscriptions: AtomicU64,
+}
+
+impl ListenerStats {
+    pub(crate) fn set_address(&self, address: &str) {
+        *zwrite!(self.address) = address.to_string();
+    }
+
+    pub(crate) fn connected(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn disconnected(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self, bytes: usize) {
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sent(&self, bytes: usize) {
+        self.messages_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn rejected_topic(&self) {
+        self.rejected_topics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn read_only_publication(&self) {
+        self.read_only_publications.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn write_only_subscription(&self) {
+        self.write_only_subscriptions
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn to_json(&self) -> Value {
+        serde_json::json!({
+            "address": *zread!(self.address),
+            "connections": self.connections.load(Ordering::Relaxed),
+            "active_connections": self.active_connections.load(Ordering::Relaxed),
+            "messages_in": self.messages_in.load(Ordering::Relaxed),
+            "bytes_in": self.bytes_in.load(Ordering::Relaxed),
+            "messages_out": self.messages_out.load(Ordering::Relaxed),
+            "bytes_out": self.bytes_out.load(Ordering::Relaxed),
+            "errors": self.errors.load(Ordering::Relaxed),
+            "rejected_topics": self.rejected_topics.load(Ordering::Relaxed),
+            "read_only_publications": self.read_only_publications.load(Ordering::Relaxed),
+            "write_only_subscriptions": self.write_only_subscriptions.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ListenersStats {
+    listeners: Mutex<BTreeMap<&'static str, Arc<ListenerStats>>>,
+}
+
+impl ListenersStats {
+    pub(crate) fn get(&self, listener: &'static str) -> Arc<ListenerStats> {
+        zlock!(self.listeners).entry(listener).or_default().clone()
+    }
+
+    // The statistics per listener as JSON, for the admin space
+    pub(crate) fn to_json(&self) -> Value {
+        Value::Object(
+            zlock!(self.listeners)
+                .iter()
+                .map(|(name, stats)| (name.to_string(), stats.to_json()))
+                .collect(),
+        )
+    }
+}
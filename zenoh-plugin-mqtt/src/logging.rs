@@ -0,0 +1,202 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+//! Logging with a filter that can be changed at runtime, via the admin space.
+//!
+//! The filter has the `RUST_LOG` syntax (e.g. `"info,zenoh_plugin_mqtt=trace"`), including the
+//! optional regular expression on the messages (e.g. `"info,zenoh_plugin_mqtt=trace/my-client"`
+//! to trace a single MQTT client). An application embedding the plugin can install this logger
+//! with [`init()`] instead of `env_logger`, or with [`init_with()`] according to a `logging` configuration.
+//! When loaded in zenohd, the plugin installs it according to its `logging` setting (filter, rotating output
+//! file and text or JSON format), unless another logger is already installed in the process.
+
+use log::{Log, Metadata, Record};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Duration;
+use zenoh::plugins::ZResult;
+use zenoh_core::{zlock, zread, zwrite};
+
+use crate::audit::RotatingFile;
+use crate::config::{Config, LogFormat, LoggingConfig};
+
+// The output of the logger: stderr or a rotating file, as text or JSON lines
+#[derive(Clone, Default)]
+struct Output {
+    format: LogFormat,
+    file: Option<Arc<Mutex<RotatingFile>>>,
+}
+
+// The rotating file shared by the successive loggers (rebuilt on each filter change)
+struct SharedFile(Arc<Mutex<RotatingFile>>);
+
+impl Write for SharedFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        zlock!(self.0).write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        zlock!(self.0).write_all(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        zlock!(self.0).flush()
+    }
+}
+
+struct ReloadableLogger {
+    // the filter and the logger built from it
+    inner: RwLock<(String, env_logger::Logger)>,
+    output: Output,
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        zread!(self.inner).1.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        zread!(self.inner).1.log(record)
+    }
+
+    fn flush(&self) {
+        zread!(self.inner).1.flush()
+    }
+}
+
+static LOGGER: OnceLock<ReloadableLogger> = OnceLock::new();
+// false if another logger was installed before
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+fn installed() -> Option<&'static ReloadableLogger> {
+    LOGGER.get().filter(|_| INSTALLED.load(Ordering::Acquire))
+}
+
+fn build(filter: &str, output: &Output) -> env_logger::Logger {
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(filter);
+    if let Some(file) = &output.file {
+        builder.target(env_logger::Target::Pipe(Box::new(SharedFile(file.clone()))));
+    }
+    if output.format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "timestamp": buf.timestamp_millis().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{line}")
+        });
+    }
+    builder.build()
+}
+
+fn install(filter: String, output: Output) -> bool {
+    let logger = build(&filter, &output);
+    let max_level = logger.filter();
+    let logger = LOGGER.get_or_init(|| ReloadableLogger {
+        inner: RwLock::new((filter, logger)),
+        output,
+    });
+    if log::set_logger(logger).is_err() {
+        return false;
+    }
+    log::set_max_level(max_level);
+    INSTALLED.store(true, Ordering::Release);
+    true
+}
+
+/// Install the logger, with the filter set in the `RUST_LOG` environment variable or else `default_filter`.
+/// Returns `false` if a logger was already installed.
+pub fn init(default_filter: &str) -> bool {
+    let filter = std::env::var(env_logger::DEFAULT_FILTER_ENV)
+        .unwrap_or_else(|_| default_filter.to_string());
+    install(filter, Output::default())
+}
+
+/// Install the logger with the default filter (`RUST_LOG` or else "error"), unless a logger is already installed.
+pub fn try_init() {
+    init("error");
+}
+
+/// Install the logger according to a `logging` configuration (filter, rotating output file and text or JSON format),
+/// with the filter set in the `RUST_LOG` environment variable, or else the configured one, or else `default_filter`.
+/// Returns `false` if a logger was already installed.
+pub fn init_with(conf: &LoggingConfig, default_filter: &str) -> bool {
+    let LoggingConfig {
+        filter,
+        file,
+        max_file_size,
+        max_files,
+        format,
+    } = conf;
+    let filter = std::env::var(env_logger::DEFAULT_FILTER_ENV)
+        .ok()
+        .or_else(|| filter.clone())
+        .unwrap_or_else(|| default_filter.into());
+    let output = Output {
+        format: *format,
+        file: file.as_ref().map(|path| {
+            Arc::new(Mutex::new(RotatingFile::new(
+                path.into(),
+                *max_file_size,
+                *max_files,
+            )))
+        }),
+    };
+    install(filter, output)
+}
+
+// Install the logger according to the 'logging' setting, unless a logger is already installed
+// (e.g. by zenohd or another plugin, when statically linked, or by the bridge from the same setting).
+pub(crate) fn init_from_config(config: &Config) {
+    let Some(conf) = &config.logging else {
+        try_init();
+        return;
+    };
+    if !init_with(conf, "error") && installed().is_none() {
+        log::warn!("'logging' is configured, but a logger is already installed - ignore it");
+    }
+}
+
+// The current filter, if this logger is installed
+pub(crate) fn filter() -> Option<String> {
+    installed().map(|l| zread!(l.inner).0.clone())
+}
+
+// Change the filter. If a duration is set, the previous filter is restored after it
+// (unless the filter was changed again meanwhile).
+pub(crate) fn set_filter(filter: &str, duration: Option<Duration>) -> ZResult<()> {
+    let Some(installed) = installed() else {
+        bail!(
+            "The MQTT plugin's logger is not installed: the log filter can't be changed at runtime"
+        );
+    };
+    let logger = build(filter, &installed.output);
+    log::set_max_level(logger.filter());
+    let (previous, _) =
+        std::mem::replace(&mut *zwrite!(installed.inner), (filter.to_string(), logger));
+    log::info!("Log filter set to '{}' (was '{}')", filter, previous);
+    if let Some(duration) = duration {
+        let filter = filter.to_string();
+        async_std::task::spawn(async move {
+            async_std::task::sleep(duration).await;
+            if installed().map_or(false, |l| zread!(l.inner).0 == filter) {
+                let _ = set_filter(&previous, None);
+            }
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// A global budget for the memory used by the publications queued for all the MQTT clients.
+// When the budget is exhausted, a client using less than its fair share (budget / number of clients)
+// can still enqueue a publication, evicting the oldest publications of the client using the most memory.
+// A client using more than its fair share gets its new publications dropped.
+
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, Weak};
+use zenoh_core::zlock;
+
+// A queue of publications accounted in the memory budget
+pub(crate) trait BudgetedQueue: Send + Sync {
+    // The number of bytes currently queued
+    fn queued_bytes(&self) -> usize;
+    // Drop the oldest queued publication, releasing its size from the budget
+    fn evict_oldest(&self) -> bool;
+}
+
+pub(crate) struct MemoryBudget {
+    max_bytes: usize,
+    used_bytes: AtomicUsize,
+    queues: Mutex<Vec<Weak<dyn BudgetedQueue>>>,
+    dropped: AtomicU64,
+    evicted: AtomicU64,
+}
+
+impl std::fmt::Debug for MemoryBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryBudget")
+            .field("max_bytes", &self.max_bytes)
+            .field("used_bytes", &self.used_bytes)
+            .finish()
+    }
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(max_bytes: usize) -> MemoryBudget {
+        MemoryBudget {
+            max_bytes,
+            used_bytes: AtomicUsize::new(0),
+            queues: Mutex::new(Vec::new()),
+            dropped: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn register(&self, queue: Weak<dyn BudgetedQueue>) {
+        zlock!(self.queues).push(queue);
+    }
+
+    fn try_acquire(&self, size: usize) -> bool {
+        self.used_bytes
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                (used + size <= self.max_bytes).then_some(used + size)
+            })
+            .is_ok()
+    }
+
+    pub(crate) fn release(&self, size: usize) {
+        self.used_bytes.fetch_sub(size, Ordering::AcqRel);
+    }
+
+    // Reserve `size` bytes for a publication to be added to `requester` queue,
+    // evicting publications from the biggest queue if the requester is within its fair share.
+    // Returns false if the publication must be dropped.
+    pub(crate) fn reserve(&self, requester: &dyn BudgetedQueue, size: usize) -> bool {
+        let requester_ptr = requester as *const dyn BudgetedQueue as *const ();
+        loop {
+            if self.try_acquire(size) {
+                return true;
+            }
+            let mut queues = zlock!(self.queues);
+            queues.retain(|q| q.strong_count() > 0);
+            let fair_share = self.max_bytes / queues.len().max(1);
+            if requester.queued_bytes() + size > fair_share {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            let biggest = queues
+                .iter()
+                .filter_map(Weak::upgrade)
+                .filter(|q| !std::ptr::eq(std::sync::Arc::as_ptr(q) as *const (), requester_ptr))
+                .max_by_key(|q| q.queued_bytes());
+            match biggest {
+                Some(q) if q.queued_bytes() > fair_share && q.evict_oldest() => {
+                    self.evicted.fetch_add(1, Ordering::Relaxed);
+                }
+                _ => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return false;
+                }
+            }
+        }
+    }
+
+    // The budget metrics as JSON, for the admin space
+    pub(crate) fn to_json(&self) -> Value {
+        let queues = zlock!(self.queues);
+        serde_json::json!({
+            "max_bytes": self.max_bytes,
+            "used_bytes": self.used_bytes.load(Ordering::Relaxed),
+            "clients": queues.iter().filter(|q| q.strong_count() > 0).count(),
+            "dropped": self.dropped.load(Ordering::Relaxed),
+            "evicted": self.evicted.load(Ordering::Relaxed),
+        })
+    }
+}
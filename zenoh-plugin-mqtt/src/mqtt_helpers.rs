@@ -12,24 +12,121 @@
 //   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
 //
 
+use futures::future::{select, Either, LocalBoxFuture};
+use ntex::io::types::PeerAddr;
+use ntex::io::IoRef;
 use ntex::util::{ByteString, Bytes};
-use ntex_mqtt::{error::SendPacketError, v3, v5};
+use ntex_mqtt::{v3, v5};
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::convert::TryInto;
-use std::sync::{Arc, Mutex};
+use std::fmt;
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, Instant};
 use zenoh::plugins::ZResult;
 use zenoh::prelude::*;
-use zenoh_core::zlock;
+use zenoh_core::{zread, zwrite};
 
-use crate::config::Config;
+use crate::config::{Config, KeChunksConfig, KeChunksOverflow};
+use crate::drain::Inflight;
+use crate::latency::{self, Direction};
+use crate::link_quality::LinkQuality;
+use crate::listener_stats::ListenerStats;
+use crate::memory_budget::{BudgetedQueue, MemoryBudget};
+use crate::mqtt_sn::SnSink;
+use crate::pause;
+use crate::pseudonyms;
 
-const MQTT_SEPARATOR: char = '/';
+pub(crate) const MQTT_SEPARATOR: char = '/';
 const MQTT_EMPTY_LEVEL: &str = "//";
 const MQTT_SINGLE_WILD: char = '+';
 const MQTT_MULTI_WILD: char = '#';
 
+// The characters of the MQTT topics that are reserved in zenoh key expressions. They're escaped as "%XX"
+// in the key expressions, as well as '%' itself for the mapping to be reversible.
+const KE_ESCAPED_CHARS: &[char] = &['%', '*', '$', '?', '#'];
+// The separator of the topic levels merged into the last chunk of a key expression (see "ke_chunks")
+const MERGED_LEVELS_SEPARATOR: &str = "%2F";
+
+// Check the maximum number of chunks of the key expressions mapped from the MQTT topics, if configured
+pub(crate) fn check_ke_chunks(config: &Config) -> ZResult<()> {
+    if let Some(KeChunksConfig { max: 0, .. }) = &config.ke_chunks {
+        bail!("Invalid 'ke_chunks' configuration: 'max' must be greater than 0");
+    }
+    Ok(())
+}
+
+// Escape the characters of a MQTT topic level that are reserved in zenoh key expressions
+pub(crate) fn escape_topic_level(level: &str) -> Cow<'_, str> {
+    if !level.contains(KE_ESCAPED_CHARS) {
+        return Cow::Borrowed(level);
+    }
+    let mut escaped = String::with_capacity(level.len() + 4);
+    for c in level.chars() {
+        if KE_ESCAPED_CHARS.contains(&c) {
+            escaped.push_str(&format!("%{:02X}", c as u32));
+        } else {
+            escaped.push(c);
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+// Unescape the characters escaped by `escape_topic_level()`, and the separators of the merged levels.
+// Other '%' sequences are kept as is.
+pub(crate) fn unescape_ke(ke: &str) -> Cow<'_, str> {
+    if !ke.contains('%') {
+        return Cow::Borrowed(ke);
+    }
+    let mut topic = String::with_capacity(ke.len());
+    let mut rest = ke;
+    while let Some(i) = rest.find('%') {
+        topic.push_str(&rest[..i]);
+        let escaped = rest
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .map(char::from)
+            .filter(|c| KE_ESCAPED_CHARS.contains(c) || *c == MQTT_SEPARATOR);
+        match escaped {
+            Some(c) => {
+                topic.push(c);
+                rest = &rest[i + 3..];
+            }
+            None => {
+                topic.push('%');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    topic.push_str(rest);
+    Cow::Owned(topic)
+}
+
+// Map a MQTT topic (or topic filter) to a zenoh key expression. The wildcards '+' and '#' are
+// mapped to '*' and '**', and the characters reserved in key expressions are escaped.
+// A topic level might be replaced with a pseudonym (see the "pseudonyms" configuration), and the excess
+// levels merged into the last chunk (see the "ke_chunks" configuration).
 pub(crate) fn mqtt_topic_to_ke<'a>(
     topic: &'a str,
     scope: &Option<OwnedKeyExpr>,
+    ke_chunks: Option<&KeChunksConfig>,
+) -> ZResult<KeyExpr<'a>> {
+    match pseudonyms::pseudonymize(topic)? {
+        Some(pseudonymized) => {
+            topic_to_ke(&pseudonymized, scope, ke_chunks).map(KeyExpr::into_owned)
+        }
+        None => topic_to_ke(topic, scope, ke_chunks),
+    }
+}
+
+fn topic_to_ke<'a>(
+    topic: &'a str,
+    scope: &Option<OwnedKeyExpr>,
+    ke_chunks: Option<&KeChunksConfig>,
 ) -> ZResult<KeyExpr<'a>> {
     if topic.starts_with(MQTT_SEPARATOR) {
         bail!(
@@ -53,12 +150,30 @@ pub(crate) fn mqtt_topic_to_ke<'a>(
         );
     }
 
-    let ke: KeyExpr = if !topic.contains(|c| c == MQTT_SINGLE_WILD || c == MQTT_MULTI_WILD) {
+    if let Some(ke) = ke_chunks
+        .map(|conf| merge_excess_levels(topic, conf))
+        .transpose()?
+        .flatten()
+    {
+        return match scope {
+            Some(scope) => Ok((&**scope / &*ke).into()),
+            None => Ok(ke.into()),
+        };
+    }
+    let ke: KeyExpr = if !topic.contains(|c| {
+        c == MQTT_SINGLE_WILD || c == MQTT_MULTI_WILD || KE_ESCAPED_CHARS.contains(&c)
+    }) {
         topic.try_into()?
     } else {
         topic
-            .replace(MQTT_SINGLE_WILD, "*")
-            .replace(MQTT_MULTI_WILD, "**")
+            .split(MQTT_SEPARATOR)
+            .map(|level| match level {
+                "+" => Cow::Borrowed("*"),
+                "#" => Cow::Borrowed("**"),
+                level => escape_topic_level(level),
+            })
+            .collect::<Vec<_>>()
+            .join("/")
             .try_into()?
     };
 
@@ -68,6 +183,204 @@ pub(crate) fn mqtt_topic_to_ke<'a>(
     }
 }
 
+// If the topic has more levels than the configured maximum number of chunks, returns its key expression
+// with the excess levels merged into the last chunk (or an error if configured to reject such topics).
+// A topic filter can't be merged if it has wildcards in the excess levels.
+fn merge_excess_levels(topic: &str, conf: &KeChunksConfig) -> ZResult<Option<OwnedKeyExpr>> {
+    if topic.split(MQTT_SEPARATOR).count() <= conf.max {
+        return Ok(None);
+    }
+    if conf.overflow == KeChunksOverflow::Reject {
+        bail!(
+            "MQTT topic '{}' has more than {} levels (see 'ke_chunks' configuration)",
+            topic,
+            conf.max
+        );
+    }
+    let mut levels = topic.split(MQTT_SEPARATOR);
+    let mut chunks: Vec<Cow<str>> = levels
+        .by_ref()
+        .take(conf.max - 1)
+        .map(|level| match level {
+            "+" => Cow::Borrowed("*"),
+            "#" => Cow::Borrowed("**"),
+            level => escape_topic_level(level),
+        })
+        .collect();
+    let excess: Vec<&str> = levels.collect();
+    if excess
+        .iter()
+        .any(|level| level.contains([MQTT_SINGLE_WILD, MQTT_MULTI_WILD]))
+    {
+        bail!(
+            "MQTT topic filter '{}' has wildcards beyond level {}, that can't be merged (see 'ke_chunks' configuration)",
+            topic,
+            conf.max
+        );
+    }
+    chunks.push(Cow::Owned(
+        excess
+            .iter()
+            .map(|level| escape_topic_level(level))
+            .collect::<Vec<_>>()
+            .join(MERGED_LEVELS_SEPARATOR),
+    ));
+    Ok(Some(OwnedKeyExpr::try_from(chunks.join("/"))?))
+}
+
+// Returns true if the publications received for a topic filter must be checked against it: if the excess levels
+// are merged, a '+' at the last level (not merged) is mapped to a '*' also matching the merged chunk of a longer topic
+// (e.g. with 3 chunks at most, "a/b/+" is mapped to "a/b/*" matching "a/b/c%2Fd" for topic "a/b/c/d")
+pub(crate) fn needs_filter_check(filter: &str, ke_chunks: Option<&KeChunksConfig>) -> bool {
+    ke_chunks.map_or(false, |conf| {
+        conf.overflow == KeChunksOverflow::Merge
+            && filter.split(MQTT_SEPARATOR).count() == conf.max
+            && filter.rsplit(MQTT_SEPARATOR).next() == Some("+")
+    })
+}
+
+// Returns true if the retained publications (i.e. the history) must be sent for a MQTT 5 subscription,
+// according to its Retain Handling option and to the existence of the same subscription for the client
+pub(crate) fn sends_retained(
+    retain_handling: v5::codec::RetainHandling,
+    already_subscribed: bool,
+) -> bool {
+    match retain_handling {
+        v5::codec::RetainHandling::AtSubscribe => true,
+        v5::codec::RetainHandling::AtSubscribeNew => !already_subscribed,
+        v5::codec::RetainHandling::NoAtSubscribe => false,
+    }
+}
+
+// The attachment key carrying the id of the MQTT client that published a message
+pub(crate) const ATTACHMENT_KEY_CLIENT_ID: &str = "mqtt_client_id";
+
+// Returns true if the sample was published by this MQTT client
+pub(crate) fn is_local_echo(sample: &Sample, client_id: &str) -> bool {
+    sample
+        .attachment()
+        .and_then(|a| a.get(&ATTACHMENT_KEY_CLIENT_ID))
+        .map_or(false, |origin| origin.as_slice() == client_id.as_bytes())
+}
+
+// Returns the 1st `depth` levels of a topic, if it has more levels and if those don't contain wildcards
+pub(crate) fn topic_root(topic: &str, depth: usize) -> Option<&str> {
+    if depth == 0 {
+        return None;
+    }
+    let (idx, _) = topic.match_indices(MQTT_SEPARATOR).nth(depth - 1)?;
+    let root = &topic[..idx];
+    if root.contains(|c| c == MQTT_SINGLE_WILD || c == MQTT_MULTI_WILD) {
+        None
+    } else {
+        Some(root)
+    }
+}
+
+// The key expression "<prefix>/**" covering all the given key expressions, <prefix> being their longest
+// common sequence of 1st chunks without wildcard (or "**" if there is none)
+pub(crate) fn common_prefix_ke<'a>(
+    kes: impl IntoIterator<Item = &'a KeyExpr<'a>>,
+) -> ZResult<KeyExpr<'static>> {
+    let mut prefix: Option<Vec<&str>> = None;
+    for ke in kes {
+        let chunks = ke.as_str().split('/').take_while(|c| !c.contains('*'));
+        prefix = Some(match prefix {
+            None => chunks.collect(),
+            Some(prefix) => prefix
+                .into_iter()
+                .zip(chunks)
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect(),
+        });
+    }
+    match prefix {
+        Some(prefix) if !prefix.is_empty() => KeyExpr::try_from(format!("{}/**", prefix.join("/"))),
+        _ => KeyExpr::try_from("**"),
+    }
+}
+
+// Returns true if the MQTT topic matches the MQTT topic filter (that might contain wildcards)
+pub(crate) fn topic_matches_filter(topic: &str, filter: &str) -> bool {
+    let mut topic_levels = topic.split(MQTT_SEPARATOR);
+    let mut filter_levels = filter.split(MQTT_SEPARATOR);
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+// Returns the MQTT topic filter matching the topics matched by both filters, if any
+// (e.g. "sensors/+/temp" for "+/+/temp" and "sensors/#")
+pub(crate) fn intersect_filters(a: &str, b: &str) -> Option<String> {
+    let mut a_levels = a.split(MQTT_SEPARATOR);
+    let mut b_levels = b.split(MQTT_SEPARATOR);
+    let mut levels = Vec::new();
+    loop {
+        match (a_levels.next(), b_levels.next()) {
+            (Some("#"), Some(level)) => {
+                levels.push(level);
+                levels.extend(b_levels);
+                break;
+            }
+            (Some(level), Some("#")) => {
+                levels.push(level);
+                levels.extend(a_levels);
+                break;
+            }
+            (Some("+"), Some(level)) | (Some(level), Some("+")) => levels.push(level),
+            (Some(x), Some(y)) if x == y => levels.push(x),
+            // "#" also matches its parent level
+            (Some("#"), None) | (None, Some("#")) | (None, None) => break,
+            _ => return None,
+        }
+    }
+    Some(levels.join(&MQTT_SEPARATOR.to_string()))
+}
+
+// Returns true if the MQTT topic filter matches all the topics, possibly below some levels (e.g. "#" or "+/#")
+pub(crate) fn is_root_wildcard(filter: &str) -> bool {
+    filter.ends_with(MQTT_MULTI_WILD)
+        && filter
+            .split(MQTT_SEPARATOR)
+            .all(|level| level.len() == 1 && level.starts_with([MQTT_SINGLE_WILD, MQTT_MULTI_WILD]))
+}
+
+// A limiter of the number of messages per second
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    max_per_sec: u32,
+    window_start: Instant,
+    count: u32,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(max_per_sec: u32) -> RateLimiter {
+        RateLimiter {
+            max_per_sec,
+            window_start: Instant::now(),
+            count: 0,
+        }
+    }
+
+    // Returns true if a new message is allowed
+    pub(crate) fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.count = 0;
+        }
+        self.count += 1;
+        self.count <= self.max_per_sec
+    }
+}
+
 pub(crate) fn ke_to_mqtt_topic_publish(
     ke: &KeyExpr<'_>,
     scope: &Option<OwnedKeyExpr>,
@@ -75,32 +388,131 @@ pub(crate) fn ke_to_mqtt_topic_publish(
     if ke.is_wild() {
         bail!("Zenoh KeyExpr '{}' contains wildcards and cannot be converted to MQTT topic for publications", ke);
     }
-    match scope {
-        Some(scope) => {
-            let after_scope_idx = scope.as_str().len();
-            if ke.starts_with(scope.as_str()) && ke.chars().nth(after_scope_idx) == Some('/') {
-                Ok(ke[after_scope_idx + 1..].into())
-            } else {
-                bail!(
-                    "Zenoh KeyExpr '{}' doesn't start with the expected scope '{}'",
-                    ke,
-                    scope
-                );
-            }
+    let topic = match scope {
+        Some(scope) => match ke
+            .as_str()
+            .strip_prefix(scope.as_str())
+            .and_then(|t| t.strip_prefix('/'))
+        {
+            Some(topic) => topic,
+            None => bail!(
+                "Zenoh KeyExpr '{}' doesn't start with the expected scope '{}'",
+                ke,
+                scope
+            ),
+        },
+        None => ke.as_str(),
+    };
+    let topic = unescape_ke(topic);
+    match pseudonyms::reveal(&topic) {
+        Some(revealed) => Ok(revealed.into()),
+        None => Ok(topic.as_ref().into()),
+    }
+}
+
+// The maximum length of the client ids a server must accept (MQTT 3.1.1 and 5 specifications)
+const STRICT_MAX_CLIENT_ID_LENGTH: usize = 23;
+
+// In 'strict' mode, a client id must have 1 to 23 alphanumeric characters (the ones a server must accept)
+pub(crate) fn strict_client_id_violation(client_id: &str) -> Option<String> {
+    if client_id.is_empty() {
+        Some("empty client id".into())
+    } else if client_id.len() > STRICT_MAX_CLIENT_ID_LENGTH {
+        Some(format!(
+            "client id has {} characters, but the maximum is {STRICT_MAX_CLIENT_ID_LENGTH}",
+            client_id.len()
+        ))
+    } else if !client_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Some("client id has non-alphanumeric characters".into())
+    } else {
+        None
+    }
+}
+
+// In 'strict' mode, a topic name or filter must not be empty nor contain null or control characters, and
+// the wildcards are only allowed in a topic filter, as whole levels ('#' being the last level)
+pub(crate) fn strict_topic_violation(topic: &str, is_filter: bool) -> Option<String> {
+    if topic.is_empty() {
+        return Some("empty topic".into());
+    }
+    if topic.chars().any(char::is_control) {
+        return Some(format!(
+            "topic '{}' has control characters",
+            topic.escape_debug()
+        ));
+    }
+    if !is_filter {
+        return topic
+            .contains(['+', '#'])
+            .then(|| format!("topic name '{topic}' has wildcards"));
+    }
+    let levels: Vec<&str> = topic.split(MQTT_SEPARATOR).collect();
+    for (i, level) in levels.iter().enumerate() {
+        let misplaced = match *level {
+            "+" => false,
+            "#" => i != levels.len() - 1,
+            _ => level.contains(['+', '#']),
+        };
+        if misplaced {
+            return Some(format!("topic filter '{topic}' has misplaced wildcards"));
         }
-        None => Ok(ke.as_str().into()),
     }
+    None
+}
+
+// The 'allow' and 'deny' settings, that can be changed while the plugin runs.
+// The generation is incremented on each change, for the cached decisions to be discarded.
+#[derive(Debug)]
+pub(crate) struct TopicAcl {
+    rules: RwLock<(Option<Regex>, Option<Regex>)>,
+    generation: AtomicU64,
 }
 
-pub(crate) fn is_allowed(mqtt_topic: &str, config: &Config) -> bool {
-    match (&config.allow, &config.deny) {
-        (Some(allow), None) => allow.is_match(mqtt_topic),
-        (None, Some(deny)) => !deny.is_match(mqtt_topic),
-        (Some(allow), Some(deny)) => allow.is_match(mqtt_topic) && !deny.is_match(mqtt_topic),
-        (None, None) => true,
+impl TopicAcl {
+    pub(crate) fn new(config: &Config) -> TopicAcl {
+        TopicAcl {
+            rules: RwLock::new((config.allow.clone(), config.deny.clone())),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    // True if 'allow' or 'deny' is set
+    pub(crate) fn is_restricted(&self) -> bool {
+        let rules = zread!(self.rules);
+        rules.0.is_some() || rules.1.is_some()
+    }
+
+    pub(crate) fn is_allowed(&self, mqtt_topic: &str) -> bool {
+        match &*zread!(self.rules) {
+            (Some(allow), None) => allow.is_match(mqtt_topic),
+            (None, Some(deny)) => !deny.is_match(mqtt_topic),
+            (Some(allow), Some(deny)) => allow.is_match(mqtt_topic) && !deny.is_match(mqtt_topic),
+            (None, None) => true,
+        }
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    // Replace the rules with the ones of this configuration. Returns true if they changed.
+    pub(crate) fn update(&self, config: &Config) -> bool {
+        let as_str = |r: &Option<Regex>| r.as_ref().map(|r| r.as_str().to_string());
+        let mut rules = zwrite!(self.rules);
+        if as_str(&rules.0) == as_str(&config.allow) && as_str(&rules.1) == as_str(&config.deny) {
+            return false;
+        }
+        *rules = (config.allow.clone(), config.deny.clone());
+        // incremented once the new rules are visible
+        self.generation.fetch_add(1, Ordering::Release);
+        true
     }
 }
 
+pub(crate) fn peer_addr(io: &IoRef) -> Option<SocketAddr> {
+    io.query::<PeerAddr>().get().map(|addr| addr.0)
+}
+
 pub(crate) fn guess_encoding(payload: &[u8]) -> Encoding {
     if serde_json::from_slice::<serde_json::Value>(payload).is_ok() {
         Encoding::APP_JSON
@@ -117,47 +529,840 @@ pub(crate) fn guess_encoding(payload: &[u8]) -> Encoding {
     }
 }
 
-#[derive(Clone, Debug)]
-pub(crate) enum MqttSink {
-    V3(Arc<Mutex<v3::MqttSink>>),
-    V5(Arc<Mutex<v5::MqttSink>>),
+// The maximum number of publications waiting to be sent to a MQTT client
+const OUTGOING_QUEUE_SIZE: usize = 1024;
+// The maximum number of queued publications written at once to a MQTT client (in a single flush)
+const WRITE_BATCH_SIZE: usize = 64;
+
+enum RawSink {
+    V3(v3::MqttSink),
+    V5(v5::MqttSink),
+    Sn(SnSink),
+    // collecting the sent publications, in order
+    #[cfg(test)]
+    Test(flume::Sender<(ByteString, Bytes, OutgoingProperties)>),
+}
+
+impl RawSink {
+    // Only QoS 0 is supported for MQTT-SN
+    fn supports_qos1(&self) -> bool {
+        !matches!(self, RawSink::Sn(_))
+    }
+
+    // `properties`: only sent to a MQTT 5 client
+    fn publish_at_most_once(
+        &self,
+        topic: ByteString,
+        payload: Bytes,
+        properties: OutgoingProperties,
+    ) -> Result<(), String> {
+        match self {
+            RawSink::V3(s) => s
+                .publish(topic, payload)
+                .send_at_most_once()
+                .map_err(|e| e.to_string()),
+            RawSink::V5(s) => s
+                .publish(topic, payload)
+                .properties(|p| properties.apply(p))
+                .send_at_most_once()
+                .map_err(|e| e.to_string()),
+            RawSink::Sn(s) => s.publish(&topic, &payload),
+            #[cfg(test)]
+            RawSink::Test(tx) => tx
+                .send((topic, payload, properties))
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    // `reason`: the reason code sent to a MQTT 5 client
+    fn disconnect(&self, reason: v5::codec::DisconnectReasonCode) {
+        match self {
+            RawSink::V3(s) => s.close(),
+            RawSink::V5(s) => s.close_with_reason(v5::codec::Disconnect::new(reason)),
+            RawSink::Sn(s) => s.disconnect(),
+            #[cfg(test)]
+            RawSink::Test(_) => (),
+        }
+    }
+
+    // Send the publication with QoS 1, the returned future completing on PUBACK reception
+    fn publish_at_least_once(
+        &self,
+        topic: ByteString,
+        payload: Bytes,
+        properties: OutgoingProperties,
+    ) -> LocalBoxFuture<'static, Result<(), String>> {
+        match self {
+            RawSink::V3(s) => {
+                let fut = s.publish(topic, payload).send_at_least_once();
+                Box::pin(async move { fut.await.map_err(|e| e.to_string()) })
+            }
+            RawSink::V5(s) => {
+                let fut = s
+                    .publish(topic, payload)
+                    .properties(|p| properties.apply(p))
+                    .send_at_least_once();
+                Box::pin(async move { fut.await.map(|_| ()).map_err(|e| e.to_string()) })
+            }
+            // the QoS 1 publications to a MQTT-SN client are downgraded to QoS 0 when queued
+            RawSink::Sn(s) => {
+                let result = s.publish(&topic, &payload);
+                Box::pin(async move { result })
+            }
+            #[cfg(test)]
+            RawSink::Test(tx) => {
+                let result = tx
+                    .send((topic, payload, properties))
+                    .map_err(|e| e.to_string());
+                Box::pin(async move { result })
+            }
+        }
+    }
+}
+
+// A callback called when a QoS 1 publication is acknowledged by the MQTT client
+pub(crate) type OnAck = Box<dyn FnOnce() + Send>;
+
+// The MQTT 5 properties of a publication, specific to the subscription of the client it's routed to
+// (a zenoh sample routed to several clients by a shared subscriber gets the properties of each one)
+#[derive(Debug, Default)]
+pub(crate) struct OutgoingProperties {
+    pub(crate) user_properties: v5::codec::UserProperties,
+    // the identifier of the matching subscription, if set by the client in its SUBSCRIBE
+    pub(crate) subscription_id: Option<NonZeroU32>,
 }
 
-unsafe impl Send for MqttSink {}
-unsafe impl Sync for MqttSink {}
+impl OutgoingProperties {
+    fn apply(self, properties: &mut v5::codec::PublishProperties) {
+        properties.user_properties = self.user_properties;
+        properties.subscription_ids = self.subscription_id.map(|id| vec![id]);
+    }
+}
+
+struct Outgoing {
+    topic: ByteString,
+    payload: Bytes,
+    // only sent to a MQTT 5 client
+    properties: OutgoingProperties,
+    // if set, the publication is sent with QoS 1
+    on_ack: Option<OnAck>,
+    // if routed from zenoh, the reception time of the zenoh sample
+    received: Option<Instant>,
+}
+
+impl Outgoing {
+    // The memory used by the publication, accounted in the memory budget
+    fn size(&self) -> usize {
+        self.topic.len()
+            + self.payload.len()
+            + self
+                .properties
+                .user_properties
+                .iter()
+                .map(|(k, v)| k.len() + v.len())
+                .sum::<usize>()
+    }
+}
+
+// The queue of publications to a MQTT client, accounted in the memory budget if configured
+struct Queue {
+    tx: flume::Sender<Outgoing>,
+    // kept to allow the eviction of the oldest publications
+    rx: flume::Receiver<Outgoing>,
+    bytes: AtomicUsize,
+    closed: AtomicBool,
+    budget: Option<Arc<MemoryBudget>>,
+    // to request the writer task to disconnect the client
+    disconnect: flume::Sender<v5::codec::DisconnectReasonCode>,
+    // if set, the QoS 0 publications are dropped until the queue is empty (slow consumer)
+    shed_qos0: AtomicBool,
+    // if set, only the latest of the queued QoS 0 publications on a same topic is sent (slow consumer)
+    conflate: AtomicBool,
+}
+
+impl Queue {
+    fn dequeued(&self, out: &Outgoing) {
+        let size = out.size();
+        self.bytes.fetch_sub(size, Ordering::AcqRel);
+        if let Some(budget) = &self.budget {
+            budget.release(size);
+        }
+    }
+}
+
+impl BudgetedQueue for Queue {
+    fn queued_bytes(&self) -> usize {
+        self.bytes.load(Ordering::Acquire)
+    }
+
+    fn evict_oldest(&self) -> bool {
+        match self.rx.try_recv() {
+            Ok(out) => {
+                log::debug!(
+                    "Memory budget exhausted: evict publication on '{}' queued for a MQTT client",
+                    out.topic
+                );
+                self.dequeued(&out);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+impl Drop for Queue {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.release(*self.bytes.get_mut());
+        }
+    }
+}
+
+// Keep only the latest of the QoS 0 publications on a same topic (all the QoS 1 ones being kept)
+fn conflate(batch: Vec<Outgoing>) -> Vec<Outgoing> {
+    let mut latest = HashMap::new();
+    for (i, out) in batch.iter().enumerate() {
+        if out.on_ack.is_none() {
+            latest.insert(out.topic.clone(), i);
+        }
+    }
+    batch
+        .into_iter()
+        .enumerate()
+        .filter(|(i, out)| out.on_ack.is_some() || latest.get(&out.topic) == Some(i))
+        .map(|(_, out)| out)
+        .collect()
+}
+
+// The sink of a MQTT client. All the publications to the client are queued and sent by a single
+// writer task, whatever the Zenoh subscriber they come from. Thus they're sent in the order
+// they have been routed, for all topics.
+#[derive(Clone)]
+pub(crate) struct MqttSink {
+    queue: Arc<Queue>,
+    link: Arc<LinkQuality>,
+    // the remote address of the client
+    peer: Option<SocketAddr>,
+    // false if the publications to the client are sent with QoS 0 only
+    qos1: bool,
+}
+
+impl fmt::Debug for MqttSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MqttSink")
+            .field("queued", &self.queue.tx.len())
+            .field("queued_bytes", &self.queue.queued_bytes())
+            .finish()
+    }
+}
 
 impl MqttSink {
+    fn new(
+        raw: RawSink,
+        peer: Option<SocketAddr>,
+        budget: Option<Arc<MemoryBudget>>,
+        stats: Arc<ListenerStats>,
+    ) -> MqttSink {
+        let (tx, rx) = flume::bounded::<Outgoing>(OUTGOING_QUEUE_SIZE);
+        let (disconnect_tx, disconnect_rx) = flume::bounded::<v5::codec::DisconnectReasonCode>(1);
+        let queue = Arc::new(Queue {
+            tx,
+            rx: rx.clone(),
+            bytes: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            budget,
+            disconnect: disconnect_tx,
+            shed_qos0: AtomicBool::new(false),
+            conflate: AtomicBool::new(false),
+        });
+        if let Some(budget) = &queue.budget {
+            let weak: Weak<dyn BudgetedQueue> = Arc::downgrade(&queue) as _;
+            budget.register(weak);
+        }
+        let link = Arc::new(LinkQuality::default());
+        let writer_link = link.clone();
+        let qos1 = raw.supports_qos1();
+        // the writer task runs in the ntex runtime that owns the MQTT connection
+        let writer_queue = Arc::downgrade(&queue);
+        ntex::rt::spawn(async move {
+            'writer: loop {
+                let first = match select(rx.recv_async(), disconnect_rx.recv_async()).await {
+                    Either::Left((Ok(out), _)) => out,
+                    Either::Right((Ok(reason), _)) => {
+                        if let Some(queue) = writer_queue.upgrade() {
+                            queue.closed.store(true, Ordering::Release);
+                        }
+                        raw.disconnect(reason);
+                        break;
+                    }
+                    // the sink has been dropped
+                    _ => break,
+                };
+                // while the routing is paused, the publications are kept in the queue
+                pause::wait_resumed().await;
+                let Some(queue) = writer_queue.upgrade() else {
+                    break;
+                };
+                // the publications queued meanwhile are written at once, without yielding in between,
+                // so they're flushed together to the connection
+                let mut batch = Vec::with_capacity(WRITE_BATCH_SIZE);
+                batch.push(first);
+                if queue.conflate.load(Ordering::Acquire) {
+                    batch.extend(rx.try_iter());
+                } else {
+                    batch.extend(rx.try_iter().take(WRITE_BATCH_SIZE - 1));
+                }
+                for out in &batch {
+                    queue.dequeued(out);
+                }
+                if queue.conflate.load(Ordering::Acquire) {
+                    batch = conflate(batch);
+                }
+                let shed_qos0 = queue.shed_qos0.load(Ordering::Acquire);
+                if shed_qos0 && rx.is_empty() {
+                    queue.shed_qos0.store(false, Ordering::Release);
+                }
+                for out in batch {
+                    if shed_qos0 && out.on_ack.is_none() {
+                        continue;
+                    }
+                    let received = out.received;
+                    let size = out.size();
+                    match out.on_ack {
+                        None => {
+                            if let Err(e) =
+                                raw.publish_at_most_once(out.topic, out.payload, out.properties)
+                            {
+                                log::debug!("Stop sending to MQTT client: {}", e);
+                                stats.error();
+                                queue.closed.store(true, Ordering::Release);
+                                break 'writer;
+                            }
+                            stats.sent(size);
+                            if let Some(received) = received {
+                                latency::record(Direction::ZenohToMqtt, received.elapsed());
+                            }
+                        }
+                        Some(on_ack) => {
+                            let topic = out.topic.clone();
+                            let ack =
+                                raw.publish_at_least_once(out.topic, out.payload, out.properties);
+                            stats.sent(size);
+                            if let Some(received) = received {
+                                latency::record(Direction::ZenohToMqtt, received.elapsed());
+                            }
+                            let inflight = Inflight::new();
+                            let link = writer_link.clone();
+                            let sent = Instant::now();
+                            ntex::rt::spawn(async move {
+                                let _inflight = inflight;
+                                match ack.await {
+                                    Ok(()) => {
+                                        link.acked(sent.elapsed());
+                                        on_ack()
+                                    }
+                                    Err(e) => {
+                                        link.unacked();
+                                        log::debug!(
+                                            "QoS 1 publication on '{}' not acknowledged by MQTT client: {}",
+                                            topic,
+                                            e
+                                        )
+                                    }
+                                }
+                            });
+                        }
+                    }
+                }
+            }
+        });
+        MqttSink {
+            queue,
+            link,
+            peer,
+            qos1,
+        }
+    }
+
+    pub(crate) fn v3(
+        sink: v3::MqttSink,
+        peer: Option<SocketAddr>,
+        budget: Option<Arc<MemoryBudget>>,
+        stats: Arc<ListenerStats>,
+    ) -> MqttSink {
+        MqttSink::new(RawSink::V3(sink), peer, budget, stats)
+    }
+
+    pub(crate) fn v5(
+        sink: v5::MqttSink,
+        peer: Option<SocketAddr>,
+        budget: Option<Arc<MemoryBudget>>,
+        stats: Arc<ListenerStats>,
+    ) -> MqttSink {
+        MqttSink::new(RawSink::V5(sink), peer, budget, stats)
+    }
+
+    // A sink collecting the publications sent to the client, in order (with their MQTT 5 properties)
+    #[cfg(test)]
+    pub(crate) fn test(tx: flume::Sender<(ByteString, Bytes, OutgoingProperties)>) -> MqttSink {
+        MqttSink::new(
+            RawSink::Test(tx),
+            None,
+            None,
+            Arc::new(ListenerStats::default()),
+        )
+    }
+
+    pub(crate) fn sn(
+        sink: SnSink,
+        budget: Option<Arc<MemoryBudget>>,
+        stats: Arc<ListenerStats>,
+    ) -> MqttSink {
+        let peer = Some(sink.addr());
+        MqttSink::new(RawSink::Sn(sink), peer, budget, stats)
+    }
+
+    // The remote address of the client
+    pub(crate) fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    // The quality of the link with the client
+    pub(crate) fn link_quality(&self) -> &LinkQuality {
+        &self.link
+    }
+
+    // The number of publications waiting to be sent to the client
+    pub(crate) fn queued(&self) -> usize {
+        self.queue.tx.len()
+    }
+
+    // The memory used by the publications waiting to be sent to the client
+    pub(crate) fn queued_bytes(&self) -> usize {
+        self.queue.queued_bytes()
+    }
+
+    // Disconnect the client, once the publication being sent (if any) is written
+    pub(crate) fn disconnect(&self) {
+        self.disconnect_with(v5::codec::DisconnectReasonCode::AdministrativeAction);
+    }
+
+    // `reason`: the reason code sent to a MQTT 5 client
+    pub(crate) fn disconnect_with(&self, reason: v5::codec::DisconnectReasonCode) {
+        let _ = self.queue.disconnect.try_send(reason);
+    }
+
+    // Drop the QoS 0 publications queued for the client, until its queue is empty
+    pub(crate) fn shed_qos0(&self) {
+        self.queue.shed_qos0.store(true, Ordering::Release);
+    }
+
+    // Send only the latest of the QoS 0 publications queued on a same topic, for the rest of the connection
+    pub(crate) fn conflate(&self) {
+        self.queue.conflate.store(true, Ordering::Release);
+    }
+
+    fn enqueue(&self, out: Outgoing) -> ZResult<()> {
+        let queue = &self.queue;
+        if queue.closed.load(Ordering::Acquire) {
+            bail!("connection closed");
+        }
+        let size = out.size();
+        if let Some(budget) = &queue.budget {
+            if !budget.reserve(&**queue, size) {
+                bail!(
+                    "memory budget exhausted ({} bytes queued for this client) - publication dropped",
+                    queue.queued_bytes()
+                );
+            }
+        }
+        queue.bytes.fetch_add(size, Ordering::AcqRel);
+        match queue.tx.try_send(out) {
+            Ok(()) => Ok(()),
+            Err(flume::TrySendError::Full(out)) => {
+                queue.dequeued(&out);
+                bail!(
+                    "outgoing queue full ({} publications) - publication dropped",
+                    OUTGOING_QUEUE_SIZE
+                )
+            }
+            Err(flume::TrySendError::Disconnected(out)) => {
+                queue.dequeued(&out);
+                bail!("connection closed")
+            }
+        }
+    }
+
+    // `properties`: only sent to a MQTT 5 client
+    // `received`: if routed from zenoh, the reception time of the zenoh sample (for latency measurement)
     pub(crate) fn publish_at_most_once<U>(
         &self,
         topic: U,
         payload: Bytes,
-    ) -> Result<(), SendPacketError>
+        properties: OutgoingProperties,
+        received: Option<Instant>,
+    ) -> ZResult<()>
     where
         ByteString: From<U>,
     {
-        match self {
-            MqttSink::V3(s) => {
-                let guard = zlock!(s);
-                guard.publish(topic, payload).send_at_most_once()
+        self.enqueue(Outgoing {
+            topic: topic.into(),
+            payload,
+            properties,
+            on_ack: None,
+            received,
+        })
+    }
+
+    pub(crate) fn publish_at_least_once<U>(
+        &self,
+        topic: U,
+        payload: Bytes,
+        properties: OutgoingProperties,
+        on_ack: OnAck,
+        received: Option<Instant>,
+    ) -> ZResult<()>
+    where
+        ByteString: From<U>,
+    {
+        // downgraded to QoS 0 if not supported by the client: never acknowledged, `on_ack` is dropped
+        self.enqueue(Outgoing {
+            topic: topic.into(),
+            payload,
+            properties,
+            on_ack: self.qos1.then_some(on_ack),
+            received,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::sync::Mutex;
+
+    // A level of a MQTT topic name, including the characters escaped in the key expressions
+    // and some escape-like sequences
+    const TOPIC_LEVEL: &str = "([a-zA-Z0-9_.-]|[$*?%]|%2F|%25){1,6}";
+
+    proptest! {
+        #[test]
+        fn prop_escape_roundtrip(level in TOPIC_LEVEL) {
+            prop_assert_eq!(unescape_ke(&escape_topic_level(&level)), level.as_str());
+        }
+
+        #[test]
+        fn prop_mqtt_topic_to_ke_roundtrip(
+            levels in prop::collection::vec(TOPIC_LEVEL, 1..8),
+            scoped in any::<bool>(),
+            max_chunks in prop::option::of(1usize..5),
+        ) {
+            let topic = levels.join("/");
+            let scope = scoped.then(|| OwnedKeyExpr::try_from("scope").unwrap());
+            let ke_chunks = max_chunks.map(|max| KeChunksConfig {
+                max,
+                overflow: KeChunksOverflow::Merge,
+            });
+            let ke = mqtt_topic_to_ke(&topic, &scope, ke_chunks.as_ref()).unwrap();
+            prop_assert!(!ke.is_wild());
+            let mapped = ke_to_mqtt_topic_publish(&ke, &scope).unwrap();
+            prop_assert_eq!(&*mapped, topic.as_str());
+        }
+    }
+
+    #[test]
+    fn test_literal_escape_sequence_roundtrip() {
+        let merge = KeChunksConfig {
+            max: 2,
+            overflow: KeChunksOverflow::Merge,
+        };
+        for ke_chunks in [None, Some(&merge)] {
+            for topic in ["a/100%2F", "a/b/100%2F/c", "%2F/%25/%"] {
+                let ke = mqtt_topic_to_ke(topic, &None, ke_chunks).unwrap();
+                assert_eq!(
+                    &*ke_to_mqtt_topic_publish(&ke, &None).unwrap(),
+                    topic,
+                    "'{topic}' mapped to '{ke}'"
+                );
             }
-            MqttSink::V5(s) => {
-                let guard = zlock!(s);
-                guard.publish(topic, payload).send_at_most_once()
+        }
+    }
+
+    #[test]
+    fn test_topic_matches_filter() {
+        assert!(topic_matches_filter("a/b/c", "a/b/c"));
+        assert!(topic_matches_filter("a/b/c", "a/+/c"));
+        assert!(topic_matches_filter("a/b/c", "a/#"));
+        assert!(topic_matches_filter("a/b/c", "#"));
+        assert!(topic_matches_filter("a", "a/#"));
+        assert!(topic_matches_filter("a//c", "a/+/c"));
+        assert!(!topic_matches_filter("a/b/c", "a/b"));
+        assert!(!topic_matches_filter("a/b", "a/b/c"));
+        assert!(!topic_matches_filter("a/b/c", "a/+"));
+        assert!(!topic_matches_filter("a/b/c", "a/c/+"));
+    }
+
+    #[test]
+    fn test_merge_excess_levels() {
+        let merge = KeChunksConfig {
+            max: 3,
+            overflow: KeChunksOverflow::Merge,
+        };
+        let cases = [
+            ("a", None),
+            ("a/b/c", None),
+            ("a/+/#", None),
+            ("a/b/c/d", Some("a/b/c%2Fd")),
+            ("a/b/c/d/e", Some("a/b/c%2Fd%2Fe")),
+            ("a/+/c/d", Some("a/*/c%2Fd")),
+            ("a/b$/c/d*", Some("a/b%24/c%2Fd%2A")),
+            ("a/b/c/d%2Fe", Some("a/b/c%2Fd%252Fe")),
+        ];
+        for (topic, expected) in cases {
+            let merged = merge_excess_levels(topic, &merge).unwrap();
+            assert_eq!(merged.as_ref().map(|ke| ke.as_str()), expected, "'{topic}'");
+        }
+        // the wildcards can't be merged
+        assert!(merge_excess_levels("a/b/c/+", &merge).is_err());
+        assert!(merge_excess_levels("a/b/c/d/#", &merge).is_err());
+    }
+
+    #[test]
+    fn test_single_wildcard_at_merged_level() {
+        let merge = KeChunksConfig {
+            max: 3,
+            overflow: KeChunksOverflow::Merge,
+        };
+        let topic_ke = mqtt_topic_to_ke("a/b/c/d", &None, Some(&merge)).unwrap();
+        for filter in ["a/b/+", "a/+/+"] {
+            // the key expression of the filter matches the merged topic, that the filter doesn't match
+            let filter_ke = mqtt_topic_to_ke(filter, &None, Some(&merge)).unwrap();
+            assert!(filter_ke.intersects(&topic_ke), "'{filter}'");
+            assert!(!topic_matches_filter("a/b/c/d", filter), "'{filter}'");
+            assert!(needs_filter_check(filter, Some(&merge)), "'{filter}'");
+        }
+        assert!(!needs_filter_check("a/b/+", None));
+        assert!(!needs_filter_check("a/b/#", Some(&merge)));
+        assert!(!needs_filter_check("a/+", Some(&merge)));
+        assert!(!needs_filter_check(
+            "a/b/+",
+            Some(&KeChunksConfig {
+                max: 3,
+                overflow: KeChunksOverflow::Reject,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_sends_retained() {
+        use v5::codec::RetainHandling;
+        // 0: sent at each subscription
+        assert!(sends_retained(RetainHandling::AtSubscribe, false));
+        assert!(sends_retained(RetainHandling::AtSubscribe, true));
+        // 1: only sent for a new subscription, not when an existing one is renewed
+        assert!(sends_retained(RetainHandling::AtSubscribeNew, false));
+        assert!(!sends_retained(RetainHandling::AtSubscribeNew, true));
+        // 2: never sent
+        assert!(!sends_retained(RetainHandling::NoAtSubscribe, false));
+        assert!(!sends_retained(RetainHandling::NoAtSubscribe, true));
+    }
+
+    #[test]
+    fn test_merge_excess_levels_reject() {
+        let reject = KeChunksConfig {
+            max: 3,
+            overflow: KeChunksOverflow::Reject,
+        };
+        assert!(merge_excess_levels("a/b/c", &reject).unwrap().is_none());
+        assert!(merge_excess_levels("a/b/c/d", &reject).is_err());
+    }
+
+    #[test]
+    fn test_merged_levels_unescaped() {
+        for topic in ["a/b/c/d", "a/b/c/d/e", "a/b$/c/d*", "a/b/c/d%2Fe"] {
+            let merged = merge_excess_levels(
+                topic,
+                &KeChunksConfig {
+                    max: 3,
+                    overflow: KeChunksOverflow::Merge,
+                },
+            )
+            .unwrap()
+            .unwrap();
+            assert_eq!(unescape_ke(merged.as_str()), topic);
+        }
+    }
+
+    #[test]
+    fn test_intersect_filters() {
+        let cases = [
+            ("#", "sensors/#", Some("sensors/#")),
+            ("sensors/#", "#", Some("sensors/#")),
+            ("+/+/temp", "sensors/#", Some("sensors/+/temp")),
+            ("+/temp", "sensors/+", Some("sensors/temp")),
+            ("a/b", "a/b", Some("a/b")),
+            ("a/b", "a/+", Some("a/b")),
+            ("a", "a/#", Some("a")),
+            ("a/#", "a", Some("a")),
+            ("a/#", "a/b/#", Some("a/b/#")),
+            ("a/b", "a/c", None),
+            ("a/b", "a", None),
+            ("a/+", "a/b/c", None),
+            ("actuators/#", "sensors/#", None),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(
+                intersect_filters(a, b).as_deref(),
+                expected,
+                "'{a}' and '{b}'"
+            );
+        }
+    }
+
+    #[test]
+    fn test_intersection_matches_both_filters() {
+        // a topic matches the intersection iff it matches both filters
+        let filters = ["#", "a/#", "+/b", "a/+/c", "+/+/c", "a/b/c", "a", "+"];
+        let topics = ["a", "b", "a/b", "c/b", "a/b/c", "a/x/c", "x/y/c", "a/b/c/d"];
+        for a in filters {
+            for b in filters {
+                let intersection = intersect_filters(a, b);
+                for topic in topics {
+                    assert_eq!(
+                        intersection
+                            .as_deref()
+                            .map_or(false, |i| topic_matches_filter(topic, i)),
+                        topic_matches_filter(topic, a) && topic_matches_filter(topic, b),
+                        "'{topic}' with '{a}' and '{b}' (intersection: {intersection:?})"
+                    );
+                }
             }
         }
     }
-}
 
-impl From<v3::MqttSink> for MqttSink {
-    fn from(s: v3::MqttSink) -> Self {
-        #[allow(clippy::arc_with_non_send_sync)] // TODO
-        MqttSink::V3(Arc::new(Mutex::new(s)))
+    #[test]
+    fn test_sink_preserves_order() {
+        const PRODUCERS: usize = 8;
+        const MESSAGES: usize = 2000;
+        ntex::rt::System::new("test").block_on(async move {
+            let (tx, rx) = flume::unbounded();
+            let sink = MqttSink::test(tx);
+            let acked = Arc::new(AtomicUsize::new(0));
+            // the number of publications routed to the sink, by all the producers
+            let routed = Arc::new(Mutex::new(0usize));
+            let producers: Vec<_> = (0..PRODUCERS)
+                .map(|producer| {
+                    let sink = sink.clone();
+                    let acked = acked.clone();
+                    let routed = routed.clone();
+                    std::thread::spawn(move || {
+                        let topic = format!("producer/{producer}");
+                        for _ in 0..MESSAGES {
+                            let mut routed = zlock!(routed);
+                            let payload = Bytes::from(routed.to_string());
+                            // the publications alternate between QoS 0 and QoS 1,
+                            // and are retried while the outgoing queue is full
+                            loop {
+                                let result = if *routed % 2 == 0 {
+                                    sink.publish_at_most_once(
+                                        topic.clone(),
+                                        payload.clone(),
+                                        OutgoingProperties::default(),
+                                        None,
+                                    )
+                                } else {
+                                    let acked = acked.clone();
+                                    sink.publish_at_least_once(
+                                        topic.clone(),
+                                        payload.clone(),
+                                        OutgoingProperties::default(),
+                                        Box::new(move || {
+                                            acked.fetch_add(1, Ordering::Relaxed);
+                                        }),
+                                        None,
+                                    )
+                                };
+                                if result.is_ok() {
+                                    break;
+                                }
+                                std::thread::yield_now();
+                            }
+                            *routed += 1;
+                        }
+                    })
+                })
+                .collect();
+            // the publications are sent in the order they have been routed, whatever their producer
+            for expected in 0..PRODUCERS * MESSAGES {
+                let (_, payload, _) = rx.recv_async().await.unwrap();
+                assert_eq!(payload, Bytes::from(expected.to_string()));
+            }
+            for producer in producers {
+                producer.join().unwrap();
+            }
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            assert_eq!(acked.load(Ordering::Relaxed), PRODUCERS * MESSAGES / 2);
+            assert_eq!(sink.queued(), 0);
+        });
+    }
+
+    #[test]
+    fn test_link_quality_acked_publications() {
+        ntex::rt::System::new("test").block_on(async move {
+            let (tx, rx) = flume::unbounded();
+            let sink = MqttSink::test(tx);
+            for i in 0..3 {
+                sink.publish_at_least_once(
+                    "a/b",
+                    Bytes::from(i.to_string()),
+                    OutgoingProperties::default(),
+                    Box::new(|| ()),
+                    None,
+                )
+                .unwrap();
+            }
+            sink.publish_at_most_once("a/b", Bytes::new(), OutgoingProperties::default(), None)
+                .unwrap();
+            for _ in 0..4 {
+                rx.recv_async().await.unwrap();
+            }
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            // only the QoS 1 publications are measured, none being counted as unacknowledged
+            let rtt = &sink.link_quality().to_json()["rtt"];
+            assert_eq!(rtt["samples"], 3);
+            assert_eq!(rtt["unacked"], 0);
+        });
     }
-}
 
-impl From<v5::MqttSink> for MqttSink {
-    fn from(s: v5::MqttSink) -> Self {
-        #[allow(clippy::arc_with_non_send_sync)] // TODO
-        MqttSink::V5(Arc::new(Mutex::new(s)))
+    #[test]
+    fn test_acl_cache_per_identity_and_generation() {
+        use crate::ke_cache::AclCache;
+        let config = |allow: &str| Config {
+            allow: Some(Regex::new(allow).unwrap()),
+            ..Default::default()
+        };
+        let acl = TopicAcl::new(&config("^a/"));
+        let mut cache = AclCache::new(16);
+        let generation = acl.generation();
+        assert_eq!(cache.get(generation, Some("alice"), "a/b"), None);
+        cache.insert(generation, Some("alice"), "a/b", acl.is_allowed("a/b"));
+        assert_eq!(cache.get(generation, Some("alice"), "a/b"), Some(true));
+        // the decisions are cached per identity
+        assert_eq!(cache.get(generation, Some("bob"), "a/b"), None);
+        assert_eq!(cache.get(generation, None, "a/b"), None);
+        // an unchanged configuration doesn't discard the decisions
+        assert!(!acl.update(&config("^a/")));
+        assert_eq!(
+            cache.get(acl.generation(), Some("alice"), "a/b"),
+            Some(true)
+        );
+        // a hot change of 'allow' discards them
+        assert!(acl.update(&config("^b/")));
+        assert!(!acl.is_allowed("a/b"));
+        assert_eq!(cache.get(acl.generation(), Some("alice"), "a/b"), None);
+        // a decision made on the previous generation is not cached
+        cache.insert(generation, Some("alice"), "a/b", true);
+        assert_eq!(cache.get(acl.generation(), Some("alice"), "a/b"), None);
     }
 }
@@ -11,82 +11,1382 @@
 // Contributors:
 //   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
 //
-use crate::config::Config;
+use crate::access_windows;
+use crate::audit;
+use crate::auth::{decode_sasl_plain, AuthDecision, Authenticator, AUTH_METHOD_PLAIN};
+use crate::chunking::{self, ATTACHMENT_KEY_CHUNK};
+use crate::client_stats::ClientStats;
+use crate::clients::ClientsRegistry;
+use crate::config::{
+    Config, CongestionPolicy, EnvelopeFormat, LargePayloadsConfig, PublicationPriority,
+    PutRetryConfig, ReadOnlyMode, RootWildcardConfig, ScopeCopiesConfig, SubscriberOrigin,
+    SubscriberReliability,
+};
+use crate::encryption;
+use crate::enrollment::Enrollment;
+use crate::envelope;
+use crate::error::MqttPluginError;
+use crate::events::{self, ProtocolVersion, SessionEvent};
+use crate::fanout::*;
+use crate::ke_cache::{AclCache, KeCache, KeCaches};
+use crate::last_values::LastValues;
+use crate::latency;
+use crate::listener_stats::{ListenerStats, LISTENER_MQTT, LISTENER_MQTT_SN};
 use crate::mqtt_helpers::*;
+use crate::pause::{self, BufferedPut};
+use crate::payload_format::{self, DeliveryOptions};
+use crate::put_caps::PutCaps;
+use crate::query_rules::QueryRules;
+use crate::recording::{self, Direction};
+use crate::scripting;
+use crate::sessions::{SessionRegistry, SessionSubscription};
+use crate::shm::ShmProvider;
+use crate::store_forward::StoreForward;
+use crate::sys_topics::*;
+use crate::telemetry;
+use crate::topic_trie::TopicTrie;
+use crate::uns::Uns;
+use crate::BridgeState;
 use async_std::sync::RwLock;
 use lazy_static::__Deref;
 use ntex::util::{ByteString, Bytes};
+use ntex_mqtt::{v5, QoS};
+use std::borrow::Cow;
 use std::convert::TryInto;
-use std::{collections::HashMap, sync::Arc};
+use std::fmt;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 use zenoh::plugins::ZResult;
 use zenoh::prelude::r#async::*;
-use zenoh::subscriber::Subscriber;
+use zenoh::publication::{CongestionControl, Priority};
+use zenoh::sample::Attachment;
+use zenoh::subscriber::{Reliability, Subscriber};
+use zenoh_core::{zlock, zread, zwrite};
+
+// The timeout of the zenoh query fetching the history requested by a subscription
+const HISTORY_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A Zenoh subscriber on "<root>/**" replacing several subscriptions of a client to topics under <root>.
+// The publications are filtered with the topics subscribed by the client.
+#[derive(Debug)]
+pub(crate) struct CoalescedSub<'a> {
+    _subscriber: Subscriber<'a, ()>,
+    filters: Arc<std::sync::RwLock<TopicTrie<()>>>,
+}
+
+// A subscription of a client to a topic, as a member of the zenoh subscriber shared for this topic
+#[derive(Debug)]
+pub(crate) struct TopicSub {
+    // the member for the topic, and for its allowed filters if it's narrowed (see the 'allowed_filters' setting)
+    _members: Vec<TopicMember>,
+    // the QoS requested by the client
+    qos: QoS,
+    no_local: bool,
+    delivery: DeliveryOptions,
+}
+
+// The subscriptions of an idle client, replaced with a single zenoh subscriber on their common prefix
+// (the watcher). The publications are filtered with the topics of the hibernated subscriptions.
+#[derive(Debug)]
+pub(crate) struct HibernatedSubs<'a> {
+    _watcher: Subscriber<'a, ()>,
+    // the hibernated topic filters, with their QoS, "No Local" option and delivery options
+    subs: Vec<(String, QoS, bool, DeliveryOptions)>,
+    // set when a publication matched the hibernated subscriptions
+    woken: Arc<AtomicBool>,
+}
+
+// A MQTT publication to be routed to zenoh (or one of its copies into other scopes)
+#[derive(Clone)]
+struct Publication<'a> {
+    topic: &'a str,
+    ke: KeyExpr<'a>,
+    payload: Bytes,
+    encoding: Encoding,
+    destination: Locality,
+    priority: Option<Priority>,
+}
+
+// How a publication has been routed to zenoh
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Routed {
+    // put on zenoh
+    Put,
+    // buffered while the routing is paused, to be put on resume
+    Buffered,
+    // stored to be forwarded later
+    Stored,
+}
+
+// The identification of a client in the logs and metrics: its id, remote address and listener
+#[derive(Debug)]
+pub(crate) struct ClientTag {
+    pub(crate) client_id: String,
+    pub(crate) peer: Option<SocketAddr>,
+    pub(crate) listener: &'static str,
+}
+
+impl fmt::Display for ClientTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.peer {
+            Some(peer) => write!(f, "{} ({} via {})", self.client_id, peer, self.listener),
+            None => write!(f, "{} (via {})", self.client_id, self.listener),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub(crate) struct MqttSessionState<'a> {
     pub(crate) client_id: String,
+    // the client's id, remote address and listener, for the logs and metrics
+    pub(crate) tag: Arc<ClientTag>,
     pub(crate) zsession: Arc<Session>,
     pub(crate) config: Arc<Config>,
-    pub(crate) subs: RwLock<HashMap<String, Subscriber<'a, ()>>>,
+    // the scope applying to this client (depending on its protocol version)
+    pub(crate) scope: Option<OwnedKeyExpr>,
+    pub(crate) subs: RwLock<TopicTrie<TopicSub>>,
+    pub(crate) coalesced_subs: RwLock<HashMap<String, CoalescedSub<'a>>>,
+    pub(crate) fanout: Arc<FanoutRegistry>,
+    pub(crate) queue_subs: RwLock<HashMap<String, QueueMember>>,
+    pub(crate) sys_subs: RwLock<HashMap<String, Arc<AtomicBool>>>,
+    // the time of the last publication or (un)subscription of the client
+    pub(crate) last_activity: std::sync::Mutex<Instant>,
+    pub(crate) hibernated: RwLock<Option<HibernatedSubs<'a>>>,
+    pub(crate) is_hibernated: AtomicBool,
+    pub(crate) pending_retries: AtomicUsize,
+    // true if the client has a subscription with local echo suppression
+    pub(crate) has_no_local_subs: AtomicBool,
+    pub(crate) shm: Arc<ShmProvider>,
+    pub(crate) auth: Option<Arc<Authenticator>>,
+    pub(crate) store_forward: Option<Arc<StoreForward>>,
+    pub(crate) client_stats: Arc<ClientStats>,
+    pub(crate) uns: Option<Arc<Uns>>,
+    pub(crate) put_caps: Option<Arc<PutCaps>>,
+    pub(crate) query_rules: Option<Arc<QueryRules>>,
+    pub(crate) last_values: Option<Arc<LastValues>>,
+    pub(crate) enrollment: Option<Arc<Enrollment>>,
+    // the key expressions caches (if configured), global and for this client's publications
+    pub(crate) ke_caches: Option<Arc<KeCaches>>,
+    pub(crate) ke_cache: std::sync::Mutex<KeCache>,
+    // the 'allow' and 'deny' settings, and their decisions per client identity and topic
+    pub(crate) acl: Arc<TopicAcl>,
+    pub(crate) acl_cache: std::sync::Mutex<AclCache>,
+    // the distinct topics published by the client (only if 'max_published_topics' is configured)
+    pub(crate) published_topics: std::sync::Mutex<HashSet<String>>,
+    pub(crate) clients: Arc<ClientsRegistry>,
+    // the registry of the persistent sessions shared between bridges (if configured)
+    pub(crate) sessions: Option<Arc<SessionRegistry>>,
+    // true if the client connected without clean session (its subscriptions are persisted)
+    pub(crate) persistent: AtomicBool,
+    // the tenant scope and publications priority selected by the client at connection (if any)
+    pub(crate) tenant_scope: Option<OwnedKeyExpr>,
+    pub(crate) requested_priority: Option<PublicationPriority>,
+    // the zenoh priority of the client's publications (if not the default one), depending on its identity
+    pub(crate) priority: std::sync::RwLock<Option<Priority>>,
+    // the statistics of the listener the client is connected to
+    pub(crate) listener: Arc<ListenerStats>,
+    // the id of this connection in the clients registry
+    pub(crate) connection_id: u64,
+    pub(crate) sink: MqttSink,
+    pub(crate) username: std::sync::RwLock<Option<String>>,
+}
+
+// The priority of the publications of a client: the one it requested at connection if still allowed
+// for its identity, otherwise the one of its profile
+fn publication_priority(
+    config: &Config,
+    requested: Option<PublicationPriority>,
+    username: Option<&str>,
+) -> Option<Priority> {
+    let conf = config.priorities.as_ref()?;
+    requested
+        .filter(|p| conf.is_allowed(username, *p))
+        .or_else(|| conf.profile(username))
+        .map(Priority::from)
 }
 
 impl MqttSessionState<'_> {
     pub(crate) fn new<'a>(
         client_id: String,
-        zsession: Arc<Session>,
-        config: Arc<Config>,
+        bridge: &BridgeState,
+        username: Option<String>,
+        sink: MqttSink,
+        protocol: ProtocolVersion,
+        tenant_scope: Option<OwnedKeyExpr>,
+        priority: Option<PublicationPriority>,
     ) -> MqttSessionState<'a> {
+        // the MQTT-SN gateway may override the 'scope', 'allow' and 'deny' settings
+        let (config, acl) = match (protocol, &bridge.mqtt_sn_config, &bridge.mqtt_sn_acl) {
+            (ProtocolVersion::MqttSn, Some(sn_config), Some(sn_acl)) => {
+                (sn_config.clone(), sn_acl.clone())
+            }
+            _ => (bridge.config.clone(), bridge.acl.clone()),
+        };
+        let protocol_scope = match protocol {
+            ProtocolVersion::V3 => bridge.config.scope_v3.as_ref(),
+            ProtocolVersion::V5 => bridge.config.scope_v5.as_ref(),
+            ProtocolVersion::MqttSn => None,
+        };
+        let scope = match (&config.scope, protocol_scope) {
+            (Some(scope), Some(sub_scope)) => Some(&**scope / &**sub_scope),
+            (None, Some(sub_scope)) => Some(sub_scope.clone()),
+            (scope, None) => scope.clone(),
+        };
+        // the tenant scope selected by the client at connection applies below the configured scopes
+        let scope = match (scope, &tenant_scope) {
+            (Some(scope), Some(tenant_scope)) => Some(&*scope / &**tenant_scope),
+            (None, tenant_scope) => tenant_scope.clone(),
+            (scope, None) => scope,
+        };
+        let requested_priority = priority;
+        let priority =
+            publication_priority(&bridge.config, requested_priority, username.as_deref());
+        let listener_name = match protocol {
+            ProtocolVersion::MqttSn => LISTENER_MQTT_SN,
+            ProtocolVersion::V3 | ProtocolVersion::V5 => LISTENER_MQTT,
+        };
+        let listener = bridge.listeners.get(listener_name);
+        let tag = Arc::new(ClientTag {
+            client_id: client_id.clone(),
+            peer: sink.peer_addr(),
+            listener: listener_name,
+        });
+        listener.connected();
+        let connection_id = bridge.clients.register(
+            &client_id,
+            protocol,
+            username.clone(),
+            scope.clone(),
+            sink.clone(),
+        );
         MqttSessionState {
             client_id,
-            zsession,
+            tag,
+            zsession: bridge.zsession.clone(),
             config,
-            subs: RwLock::new(HashMap::new()),
+            scope,
+            subs: RwLock::new(TopicTrie::new()),
+            coalesced_subs: RwLock::new(HashMap::new()),
+            fanout: bridge.fanout.clone(),
+            queue_subs: RwLock::new(HashMap::new()),
+            sys_subs: RwLock::new(HashMap::new()),
+            last_activity: std::sync::Mutex::new(Instant::now()),
+            hibernated: RwLock::new(None),
+            is_hibernated: AtomicBool::new(false),
+            pending_retries: AtomicUsize::new(0),
+            has_no_local_subs: AtomicBool::new(false),
+            shm: bridge.shm.clone(),
+            auth: bridge.auth.clone(),
+            store_forward: bridge.store_forward.clone(),
+            client_stats: bridge.client_stats.clone(),
+            uns: bridge.uns.clone(),
+            put_caps: bridge.put_caps.clone(),
+            query_rules: bridge.query_rules.clone(),
+            last_values: bridge.last_values.clone(),
+            enrollment: bridge.enrollment.clone(),
+            ke_caches: bridge.ke_cache.clone(),
+            ke_cache: std::sync::Mutex::new(KeCache::new(
+                bridge.ke_cache.as_ref().map_or(0, |c| c.session_size),
+            )),
+            acl,
+            acl_cache: std::sync::Mutex::new(AclCache::new(
+                bridge.config.acl_cache_size.unwrap_or(0),
+            )),
+            published_topics: std::sync::Mutex::new(HashSet::new()),
+            clients: bridge.clients.clone(),
+            sessions: bridge.sessions.clone(),
+            persistent: AtomicBool::new(false),
+            tenant_scope,
+            requested_priority,
+            priority: std::sync::RwLock::new(priority),
+            listener,
+            connection_id,
+            sink,
+            username: std::sync::RwLock::new(username),
+        }
+    }
+
+    // MQTT 5 re-authentication of a connected client (e.g. to rotate its credentials)
+    // Returns true if the identity of the client changed, its subscriptions having to be re-checked
+    pub(crate) fn reauthenticate(&self, packet: &v5::codec::Auth) -> Result<bool, MqttPluginError> {
+        let auth = self.auth.as_ref().ok_or_else(|| {
+            MqttPluginError::AuthFailure(format!(
+                "MQTT client {}: re-authentication requested, but authentication is not configured",
+                self.client_id
+            ))
+        })?;
+        if packet.auth_method.as_deref() != Some(AUTH_METHOD_PLAIN) {
+            return Err(MqttPluginError::AuthFailure(format!(
+                "MQTT client {}: re-authentication with unsupported method {:?}",
+                self.client_id, packet.auth_method
+            )));
+        }
+        let (username, password) = packet
+            .auth_data
+            .as_deref()
+            .and_then(decode_sasl_plain)
+            .ok_or_else(|| {
+                MqttPluginError::AuthFailure(format!(
+                    "MQTT client {}: re-authentication with invalid authentication data",
+                    self.client_id
+                ))
+            })?;
+        let decision = auth.authenticate(&self.client_id, None, Some(username), Some(password));
+        audit::authenticated(&self.client_id, Some(username), None, &decision);
+        match decision {
+            AuthDecision::Granted => {
+                if zread!(self.username).as_deref() == Some(username) {
+                    return Ok(false);
+                }
+                // the state derived from the identity is re-checked for the new one
+                if !events::accept_connection(&self.client_id, Some(username)) {
+                    return Err(MqttPluginError::AclDenied(format!(
+                        "MQTT client {}: re-authentication as '{}' rejected by an event handler",
+                        self.client_id, username
+                    )));
+                }
+                // the tenant scope of a connected client can't change: it must still be allowed
+                if let (Some(conf), Some(tenant_scope)) =
+                    (&self.config.tenant_scopes, &self.tenant_scope)
+                {
+                    if !conf.is_allowed(Some(username), tenant_scope) {
+                        audit::acl_denied(
+                            &self.client_id,
+                            Some(username),
+                            "select_scope",
+                            tenant_scope.as_str(),
+                        );
+                        return Err(MqttPluginError::AclDenied(format!(
+                            "MQTT client {}: re-authentication as '{}' not allowed in scope '{}'",
+                            self.client_id, username, tenant_scope
+                        )));
+                    }
+                }
+                let previous = zwrite!(self.username).replace(username.into());
+                log::info!(
+                    "MQTT client {} re-authenticated as '{}' (previously {:?})",
+                    self.tag,
+                    username,
+                    previous
+                );
+                *zwrite!(self.priority) =
+                    publication_priority(&self.config, self.requested_priority, Some(username));
+                zlock!(self.acl_cache).clear();
+                Ok(true)
+            }
+            decision => Err(MqttPluginError::AuthFailure(format!(
+                "MQTT client {}: re-authentication failed ({:?})",
+                self.client_id, decision
+            ))),
         }
     }
 
+    // Re-check the subscriptions of the client after a change of its identity, removing the ones now refused
+    pub(crate) async fn recheck_subscriptions(&self) {
+        let mut topics: Vec<String> = self
+            .subs
+            .read()
+            .await
+            .iter()
+            .map(|(topic, _)| topic)
+            .collect();
+        for coalesced in self.coalesced_subs.read().await.values() {
+            topics.extend(zread!(coalesced.filters).iter().map(|(topic, _)| topic));
+        }
+        topics.extend(self.queue_subs.read().await.keys().cloned());
+        if let Some(hibernated) = &*self.hibernated.read().await {
+            topics.extend(hibernated.subs.iter().map(|(topic, ..)| topic.clone()));
+        }
+        for topic in topics {
+            if !events::accept_subscription(&self.client_id, &topic) {
+                log::info!(
+                    "MQTT client {}: subscription to '{}' refused for its new identity - removed",
+                    self.tag,
+                    topic
+                );
+                audit::acl_denied(
+                    &self.client_id,
+                    zread!(self.username).as_deref(),
+                    "subscribe",
+                    &topic,
+                );
+                self.unmap_mqtt_subscription(&topic).await;
+            }
+        }
+    }
+
+    // The zenoh priority of the client's publications (if not the default one)
+    fn priority(&self) -> Option<Priority> {
+        *zread!(self.priority)
+    }
+
+    // Returns true if the configuration denies the subscriptions on the whole key space
+    pub(crate) fn is_denied_root_wildcard(&self, topic: &str) -> bool {
+        self.config.root_wildcard.as_ref().map_or(false, |c| c.deny) && is_root_wildcard(topic)
+    }
+
+    // The QoS granted for a subscription: QoS 1 only if the delivery receipts are configured
+    pub(crate) fn granted_qos(&self, requested: QoS) -> QoS {
+        match (&self.config.delivery_receipts, requested) {
+            (Some(_), QoS::AtLeastOnce | QoS::ExactlyOnce) => QoS::AtLeastOnce,
+            _ => QoS::AtMostOnce,
+        }
+    }
+
+    // The attachment identifying this client as the origin of its publications,
+    // required only if they might be filtered out as local echo
+    fn origin_attachment(&self) -> Option<Attachment> {
+        if self.config.no_local || self.has_no_local_subs.load(Ordering::Relaxed) {
+            let mut attachment = Attachment::new();
+            attachment.insert(&ATTACHMENT_KEY_CLIENT_ID, &self.client_id);
+            Some(attachment)
+        } else {
+            None
+        }
+    }
+
+    // The origin of the publications for a subscription to an allowed topic
+    fn allowed_sub_origin(&self) -> Locality {
+        match self.config.allowed_origin {
+            Some(SubscriberOrigin::Remote) => Locality::Remote,
+            Some(SubscriberOrigin::Any) | None => Locality::Any,
+        }
+    }
+
+    // `qos`: the QoS requested by the client (the granted one being applied)
+    // `no_local`: if true, the client doesn't receive its own publications (MQTT 5 "No Local" option)
+    // `delivery`: the delivery options of the publications (MQTT 5 "format", "meta" and "filter" user properties and subscription identifier)
     pub(crate) async fn map_mqtt_subscription<'a>(
+        &'a self,
+        topic: &str,
+        qos: QoS,
+        no_local: bool,
+        delivery: DeliveryOptions,
+    ) -> Result<(), MqttPluginError> {
+        if self.config.write_only {
+            self.listener.write_only_subscription();
+            return Err(MqttPluginError::AclDenied(format!(
+                "MQTT client {}: subscription to '{}' refused in write-only mode",
+                self.client_id, topic
+            )));
+        }
+        self.activity().await;
+        self.check_topic_limits(topic, true)?;
+        // the topic filter must map to a key expression, otherwise the subscription is refused
+        // (rather than confirmed while nothing would be routed)
+        if !is_sys_topic(topic) && !self.is_enrollment_topic(topic) {
+            let filter = parse_queue_topic(topic).map_or(topic, |(_, filter)| filter);
+            mqtt_topic_to_ke(filter, &self.scope, self.config.ke_chunks.as_ref())
+                .map_err(|e| MqttPluginError::KeyExprInvalid(format!("'{topic}': {e}")))?;
+        }
+        let sink = self.sink.clone();
+        let no_local = no_local || self.config.no_local;
+        if no_local {
+            self.has_no_local_subs.store(true, Ordering::Relaxed);
+        }
+        if !events::accept_subscription(&self.client_id, topic) {
+            audit::acl_denied(
+                &self.client_id,
+                zread!(self.username).as_deref(),
+                "subscribe",
+                topic,
+            );
+            return Err(MqttPluginError::AclDenied(format!(
+                "MQTT client {}: subscription to '{}' rejected by an event handler",
+                self.client_id, topic
+            )));
+        }
+        if let Some((group, queue_topic)) = parse_queue_topic(topic) {
+            self.map_mqtt_queue_subscription(
+                topic,
+                group,
+                queue_topic,
+                qos,
+                no_local,
+                delivery.clone(),
+            )
+            .await?;
+        } else if is_sys_topic(topic) {
+            // $SYS topics are not routed over Zenoh, but published by the plugin itself
+            let mut sys_subs = self.sys_subs.write().await;
+            if !sys_subs.contains_key(topic) {
+                let active = spawn_sys_publisher(self.client_id.clone(), topic.into(), sink);
+                sys_subs.insert(topic.into(), active);
+            }
+        } else if self.is_enrollment_topic(topic) {
+            // the enrollment replies are not routed over Zenoh, but published by the plugin itself
+            log::debug!(
+                "MQTT client {} subscribes to enrollment topic {}",
+                self.tag,
+                topic
+            );
+        } else {
+            self.map_mqtt_topic_subscription(topic, sink, qos, no_local, delivery.clone())
+                .await?;
+        }
+        self.clients.subscribed(&self.client_id, topic);
+        if let Some(sessions) = self.persisted_sessions() {
+            let sub = SessionSubscription {
+                qos: qos as u8,
+                no_local,
+                delivery,
+            };
+            sessions.subscribed(&self.client_id, topic, sub).await;
+        }
+        events::notify(SessionEvent::Subscribed {
+            client_id: self.client_id.clone(),
+            topic: topic.into(),
+        });
+        Ok(())
+    }
+
+    fn is_enrollment_topic(&self, topic: &str) -> bool {
+        self.enrollment
+            .as_ref()
+            .map_or(false, |e| e.is_enrollment_topic(topic))
+    }
+
+    // Returns true if the topic is allowed to be routed over zenoh (see the 'allow' and 'deny' settings),
+    // the decision being cached for the client's identity if 'acl_cache_size' is configured
+    fn is_topic_allowed(&self, topic: &str) -> bool {
+        if !self.acl.is_restricted() {
+            return true;
+        }
+        // read before the rules, for a decision on newer rules to be discarded at worst
+        let generation = self.acl.generation();
+        let username = zread!(self.username).clone();
+        if let Some(allowed) = zlock!(self.acl_cache).get(generation, username.as_deref(), topic) {
+            return allowed;
+        }
+        let allowed = self.acl.is_allowed(topic);
+        zlock!(self.acl_cache).insert(generation, username.as_deref(), topic, allowed);
+        allowed
+    }
+
+    // Returns true if the client already subscribes to this topic filter (its subscription being hibernated or not)
+    pub(crate) async fn is_subscribed(&self, topic: &str) -> bool {
+        self.subs.read().await.contains_key(topic)
+            || self
+                .coalesced_subs
+                .read()
+                .await
+                .values()
+                .any(|c| zread!(c.filters).contains_key(topic))
+            || self.queue_subs.read().await.contains_key(topic)
+            || self.sys_subs.read().await.contains_key(topic)
+            || self
+                .hibernated
+                .read()
+                .await
+                .as_ref()
+                .map_or(false, |h| h.subs.iter().any(|(t, ..)| t == topic))
+    }
+
+    // The filters (from the 'allowed_filters' setting) a wildcard subscription that is not allowed is narrowed to
+    pub(crate) fn granted_filters(&self, topic: &str) -> Vec<String> {
+        if !topic.contains(['+', '#']) || self.is_topic_allowed(topic) {
+            return Vec::new();
+        }
+        let mut granted: Vec<String> = Vec::new();
+        for filter in &self.config.allowed_filters {
+            if let Some(f) = intersect_filters(topic, filter) {
+                if self.is_topic_allowed(&f) && !granted.contains(&f) {
+                    granted.push(f);
+                }
+            }
+        }
+        granted
+    }
+
+    // Check a topic (or topic filter) against the configured maximum length and number of levels,
+    // and against the MQTT specification in 'strict' mode, before any conversion to a key expression
+    // `is_filter`: true for a subscription's topic filter, false for a publication's topic name
+    fn check_topic_limits(&self, topic: &str, is_filter: bool) -> Result<(), MqttPluginError> {
+        let strict_violation = self
+            .config
+            .strict
+            .then(|| strict_topic_violation(topic, is_filter))
+            .flatten();
+        let violation = match (
+            strict_violation,
+            self.config.max_topic_length,
+            self.config.max_topic_levels,
+        ) {
+            (Some(violation), _, _) => violation,
+            (_, Some(max), _) if topic.len() > max => {
+                format!("topic has {} bytes, but the maximum is {max}", topic.len())
+            }
+            (_, _, Some(max)) if topic.split('/').count() > max => format!(
+                "topic '{topic}' has {} levels, but the maximum is {max}",
+                topic.split('/').count()
+            ),
+            _ => return Ok(()),
+        };
+        self.listener.rejected_topic();
+        Err(MqttPluginError::TopicNonCompliant(violation))
+    }
+
+    // Check a publication's topic against the configured maximum number of distinct topics published by the client
+    // (the topic being only counted once a publication on it is routed, see record_published_topic())
+    fn check_published_topics(&self, topic: &str) -> Result<(), MqttPluginError> {
+        let Some(max) = self.config.max_published_topics else {
+            return Ok(());
+        };
+        let published = zlock!(self.published_topics);
+        if published.len() >= max && !published.contains(topic) {
+            return Err(MqttPluginError::QuotaExceeded(format!(
+                "MQTT client {}: publication on '{}' rejected - already published on {} distinct topics",
+                self.client_id, topic, max
+            )));
+        }
+        Ok(())
+    }
+
+    // Count the topic of a routed publication in the distinct topics published by the client
+    fn record_published_topic(&self, topic: &str) {
+        if self.config.max_published_topics.is_some() {
+            let mut published = zlock!(self.published_topics);
+            if !published.contains(topic) {
+                published.insert(topic.to_string());
+            }
+        }
+    }
+
+    // Check a publication's topic against the configured access windows, at the time of the publication
+    fn check_access_window(&self, topic: &str) -> Result<(), MqttPluginError> {
+        if access_windows::is_open(topic) {
+            return Ok(());
+        }
+        audit::acl_denied(
+            &self.client_id,
+            zread!(self.username).as_deref(),
+            "publish",
+            topic,
+        );
+        Err(MqttPluginError::AclDenied(format!(
+            "MQTT client {}: publication on '{}' rejected out of its access windows",
+            self.client_id, topic
+        )))
+    }
+
+    // The registry of shared sessions, if this client's session is persistent
+    fn persisted_sessions(&self) -> Option<&SessionRegistry> {
+        self.sessions
+            .as_deref()
+            .filter(|_| self.persistent.load(Ordering::Relaxed))
+    }
+
+    // Resume the persistent session of the client (possibly from another bridge), re-subscribing to its
+    // topics, or discard it for a clean session. Returns true if a session was resumed.
+    pub(crate) async fn resume_session(&self, clean_session: bool) -> bool {
+        let Some(sessions) = &self.sessions else {
+            return false;
+        };
+        if clean_session {
+            sessions.discard(&self.client_id).await;
+            return false;
+        }
+        self.persistent.store(true, Ordering::Relaxed);
+        // a session imported via the admin space is only resumed by the client authenticated as its owner
+        if let Some(owner) = sessions.owner(&self.client_id) {
+            if zread!(self.username).as_deref() != Some(owner.as_str()) {
+                log::warn!(
+                    "MQTT client {}: persistent session imported for username '{}' not resumed",
+                    self.tag,
+                    owner
+                );
+                return false;
+            }
+        }
+        let Some(session) = sessions.resume(&self.client_id).await else {
+            return false;
+        };
+        log::debug!(
+            "MQTT client {}: resume persistent session with {} subscriptions",
+            self.tag,
+            session.len()
+        );
+        for (topic, sub) in session {
+            let qos = match sub.qos {
+                0 => QoS::AtMostOnce,
+                1 => QoS::AtLeastOnce,
+                _ => QoS::ExactlyOnce,
+            };
+            if let Err(e) = self
+                .map_mqtt_subscription(&topic, qos, sub.no_local, sub.delivery)
+                .await
+            {
+                log::warn!(
+                    "MQTT client {}: failed to resume subscription to '{}': {}",
+                    self.tag,
+                    topic,
+                    e
+                );
+            }
+        }
+        true
+    }
+
+    // The session to publish the delivery receipts, if the publications for a subscription must be sent with QoS 1
+    fn receipts_session(&self, qos: QoS) -> Option<Arc<Session>> {
+        (self.granted_qos(qos) == QoS::AtLeastOnce).then(|| self.zsession.clone())
+    }
+
+    // The reliability of the zenoh subscriber for a subscription with the requested QoS
+    fn subscriber_reliability(&self, qos: QoS) -> Reliability {
+        let conf = &self.config.subscriber_reliability;
+        let reliability = match qos {
+            QoS::AtMostOnce => conf.qos0,
+            QoS::AtLeastOnce | QoS::ExactlyOnce => conf.qos1,
+        };
+        match reliability {
+            SubscriberReliability::BestEffort => Reliability::BestEffort,
+            SubscriberReliability::Reliable => Reliability::Reliable,
+        }
+    }
+
+    async fn map_mqtt_topic_subscription<'a>(
         &'a self,
         topic: &str,
         sink: MqttSink,
-    ) -> ZResult<()> {
-        let sub_origin = if is_allowed(topic, &self.config) {
-            // if topic is allowed, subscribe to publications coming from anywhere
-            Locality::Any
+        qos: QoS,
+        no_local: bool,
+        delivery: DeliveryOptions,
+    ) -> Result<(), MqttPluginError> {
+        let allowed = self.is_topic_allowed(topic);
+        let sub_origin = if allowed {
+            // if topic is allowed, subscribe to publications coming from anywhere (unless configured otherwise)
+            self.allowed_sub_origin()
         } else {
             // if topic is NOT allowed, subscribe to publications coming only from this plugin (for MQTT-to-MQTT routing only)
             log::debug!(
                 "MQTT Client {}: topic '{}' is not allowed to be routed over Zenoh (see your 'allow' or 'deny' configuration) - re-publish only from MQTT publishers",
-                self.client_id,
+                self.tag,
                 topic
             );
             Locality::SessionLocal
         };
 
         let mut subs = self.subs.write().await;
-        if !subs.contains_key(topic) {
-            let ke = mqtt_topic_to_ke(topic, &self.config.scope)?;
-            let client_id = self.client_id.clone();
-            let config = self.config.clone();
-            let sub = self
-                .zsession
-                .declare_subscriber(ke)
-                .callback(move |sample| {
-                    if let Err(e) = route_zenoh_to_mqtt(sample, &client_id, &config, &sink) {
+        let mut coalesced_subs = self.coalesced_subs.write().await;
+        if subs.contains_key(topic)
+            || coalesced_subs
+                .values()
+                .any(|c| zread!(c.filters).contains_key(topic))
+        {
+            log::debug!(
+                "MQTT Client {} already subscribes to {} => ignore",
+                self.tag,
+                topic
+            );
+            return Ok(());
+        }
+
+        let ke = mqtt_topic_to_ke(topic, &self.scope, self.config.ke_chunks.as_ref())
+            .map_err(|e| MqttPluginError::KeyExprInvalid(e.to_string()))?;
+
+        // Only the allowed topics can be coalesced, since they all use the same origin.
+        // With delivery receipts the subscriptions might have different QoS, and are not coalesced.
+        // A subscription with a "No Local" option differing from the configured one, or requesting
+        // delivery options, is not coalesced.
+        if let (true, Some(coalesce_conf), None, true) = (
+            allowed,
+            &self.config.coalesce_subs,
+            &self.config.delivery_receipts,
+            no_local == self.config.no_local && delivery.is_default(),
+        ) {
+            if let Some(root) = topic_root(topic, coalesce_conf.depth) {
+                if let Some(coalesced) = coalesced_subs.get(root) {
+                    zwrite!(coalesced.filters).insert(topic, ());
+                    return Ok(());
+                }
+                let siblings: Vec<String> = subs
+                    .keys_under(root)
+                    .into_iter()
+                    .filter(|t| self.is_topic_allowed(t))
+                    .collect();
+                if siblings.len() + 1 >= coalesce_conf.threshold {
+                    let mut filters = TopicTrie::new();
+                    for t in &siblings {
+                        filters.insert(t, ());
+                    }
+                    filters.insert(topic, ());
+                    let root_topic = format!("{root}/#");
+                    let root_ke =
+                        mqtt_topic_to_ke(&root_topic, &self.scope, self.config.ke_chunks.as_ref())
+                            .map_err(|e| MqttPluginError::KeyExprInvalid(e.to_string()))?;
+                    log::debug!(
+                        "MQTT Client {}: coalesce {} subscriptions under '{}' into a single Zenoh subscriber on {}",
+                        self.tag,
+                        filters.len(),
+                        root,
+                        root_ke
+                    );
+                    let filters = Arc::new(std::sync::RwLock::new(filters));
+                    let sub = self
+                        .declare_coalesced_subscriber(root_ke, filters.clone(), sink)
+                        .await
+                        .map_err(MqttPluginError::Internal)?;
+                    for t in &siblings {
+                        subs.remove(t);
+                    }
+                    coalesced_subs.insert(
+                        root.into(),
+                        CoalescedSub {
+                            _subscriber: sub,
+                            filters,
+                        },
+                    );
+                    return Ok(());
+                }
+            }
+        }
+
+        // the publications routed to a subscription on the whole key space might be capped
+        let limiter = match &self.config.root_wildcard {
+            Some(RootWildcardConfig {
+                max_rate: Some(max_rate),
+                ..
+            }) if is_root_wildcard(topic) => {
+                Some(std::sync::Mutex::new(RateLimiter::new(*max_rate)))
+            }
+            _ => None,
+        };
+        let client = self.tag.clone();
+        let config = self.config.clone();
+        let scope = self.scope.clone();
+        let receipts = self.receipts_session(qos);
+        let filter = topic.to_string();
+        let route_delivery = delivery.clone();
+        let check_filter = needs_filter_check(topic, self.config.ke_chunks.as_ref());
+        let handler: SampleHandler = Arc::new(move |sample| {
+            if no_local && is_local_echo(sample, &client.client_id) {
+                return Ok(());
+            }
+            if check_filter && !matches_filter(sample, &scope, &filter) {
+                return Ok(());
+            }
+            if let Some(limiter) = &limiter {
+                if !zlock!(limiter).allow() {
+                    log::trace!(
+                        "MQTT client {}: rate cap on '{}' exceeded - drop publication on {}",
+                        client,
+                        filter,
+                        sample.key_expr
+                    );
+                    return Ok(());
+                }
+            }
+            route_zenoh_to_mqtt(
+                sample,
+                &client,
+                &config,
+                &scope,
+                &sink,
+                receipts.as_ref(),
+                &route_delivery,
+            )
+        });
+        // the clients subscribing to the same key expression share a single zenoh subscriber
+        let mut members = vec![self
+            .fanout
+            .join_topic(
+                &ke,
+                sub_origin,
+                self.subscriber_reliability(qos),
+                handler.clone(),
+            )
+            .map_err(MqttPluginError::Internal)?];
+        // a wildcard subscription that is not allowed also receives the remote publications on its allowed
+        // filters (the local ones being already received)
+        for granted in self.granted_filters(topic) {
+            let granted_ke =
+                mqtt_topic_to_ke(&granted, &self.scope, self.config.ke_chunks.as_ref())
+                    .map_err(|e| MqttPluginError::KeyExprInvalid(e.to_string()))?;
+            log::debug!(
+                "MQTT Client {}: subscription to '{}' narrowed to '{}' over Zenoh",
+                self.tag,
+                topic,
+                granted
+            );
+            members.push(
+                self.fanout
+                    .join_topic(
+                        &granted_ke,
+                        Locality::Remote,
+                        self.subscriber_reliability(qos),
+                        handler.clone(),
+                    )
+                    .map_err(MqttPluginError::Internal)?,
+            );
+        }
+        subs.insert(
+            topic,
+            TopicSub {
+                _members: members,
+                qos,
+                no_local,
+                delivery,
+            },
+        );
+        Ok(())
+    }
+
+    // Declare the zenoh subscriber of coalesced subscriptions, on the key expression of their root topic
+    async fn declare_coalesced_subscriber(
+        &self,
+        root_ke: KeyExpr<'_>,
+        filters: Arc<std::sync::RwLock<TopicTrie<()>>>,
+        sink: MqttSink,
+    ) -> ZResult<Subscriber<'static, ()>> {
+        let client = self.tag.clone();
+        let config = self.config.clone();
+        let scope = self.scope.clone();
+        self.zsession
+            .declare_subscriber(root_ke.into_owned())
+            .callback(move |sample| {
+                if config.no_local && is_local_echo(&sample, &client.client_id) {
+                    return;
+                }
+                let Ok(topic) = ke_to_mqtt_topic_publish(&sample.key_expr, &scope) else {
+                    return;
+                };
+                if zread!(filters).find_match(&topic).is_some() {
+                    if let Err(e) = route_zenoh_to_mqtt(
+                        &sample,
+                        &client,
+                        &config,
+                        &scope,
+                        &sink,
+                        None,
+                        &DeliveryOptions::default(),
+                    ) {
                         log::warn!("{}", e);
                     }
-                })
-                .allowed_origin(sub_origin)
-                .res()
-                .await?;
-            subs.insert(topic.into(), sub);
-            Ok(())
+                }
+            })
+            .allowed_origin(self.allowed_sub_origin())
+            .res()
+            .await
+    }
+
+    // Called at each PINGREQ of the client
+    pub(crate) async fn on_ping(&self) {
+        if self
+            .clients
+            .take_resubscribe(&self.client_id, self.connection_id)
+        {
+            self.resubscribe().await;
+        }
+        self.check_hibernation().await;
+    }
+
+    // Re-declare the zenoh subscribers owned by the client (on the admin space request). Its subscribers shared
+    // with other clients are re-declared by the fanout registry, and its hibernated subscriptions are woken up.
+    async fn resubscribe(&self) {
+        if self.is_hibernated.load(Ordering::Acquire) {
+            self.wake().await;
+        }
+        let mut coalesced_subs = self.coalesced_subs.write().await;
+        let mut count = 0;
+        for (root, coalesced) in coalesced_subs.iter_mut() {
+            let result = match mqtt_topic_to_ke(
+                &format!("{root}/#"),
+                &self.scope,
+                self.config.ke_chunks.as_ref(),
+            ) {
+                Ok(root_ke) => {
+                    self.declare_coalesced_subscriber(
+                        root_ke,
+                        coalesced.filters.clone(),
+                        self.sink.clone(),
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(sub) => {
+                    // the previous subscriber is undeclared once the new one is declared
+                    coalesced._subscriber = sub;
+                    count += 1;
+                }
+                Err(e) => log::warn!(
+                    "MQTT client {}: failed to re-declare coalesced subscriber under '{}': {}",
+                    self.tag,
+                    root,
+                    e
+                ),
+            }
+        }
+        log::info!(
+            "MQTT client {}: re-declared {} coalesced subscribers",
+            self.tag,
+            count
+        );
+    }
+
+    // Record an activity of the client (a publication or (un)subscription), waking its hibernated subscriptions
+    async fn activity(&self) {
+        if self.config.hibernation.is_none() {
+            return;
+        }
+        *zlock!(self.last_activity) = Instant::now();
+        if self.is_hibernated.load(Ordering::Acquire) {
+            self.wake().await;
+        }
+    }
+
+    // Called at each PINGREQ of the client: hibernate its subscriptions if it's idle,
+    // or wake them up if publications matched them meanwhile
+    async fn check_hibernation(&self) {
+        let Some(hibernation) = &self.config.hibernation else {
+            return;
+        };
+        if self.is_hibernated.load(Ordering::Acquire) {
+            let woken = self
+                .hibernated
+                .read()
+                .await
+                .as_ref()
+                .map_or(false, |h| h.woken.load(Ordering::Relaxed));
+            if woken {
+                self.wake().await;
+            }
+        } else if zlock!(self.last_activity).elapsed() >= Duration::from_secs(hibernation.idle_secs)
+        {
+            if let Err(e) = self.hibernate().await {
+                log::warn!(
+                    "MQTT client {}: failed to hibernate subscriptions: {}",
+                    self.tag,
+                    e
+                );
+            }
+        }
+    }
+
+    async fn hibernate(&self) -> ZResult<()> {
+        let mut hibernated = self.hibernated.write().await;
+        if hibernated.is_some() {
+            return Ok(());
+        }
+        let mut subs = self.subs.write().await;
+        let topics: Vec<String> = subs
+            .iter()
+            .filter(|(topic, sub)| {
+                self.is_topic_allowed(topic)
+                    && !is_root_wildcard(topic)
+                    && self.granted_qos(sub.qos) == QoS::AtMostOnce
+                    && sub.delivery.is_default()
+            })
+            .map(|(topic, _)| topic)
+            .collect();
+        if topics.is_empty() {
+            return Ok(());
+        }
+        let mut kes = Vec::with_capacity(topics.len());
+        let mut filters = TopicTrie::new();
+        for topic in &topics {
+            kes.push(
+                mqtt_topic_to_ke(topic, &self.scope, self.config.ke_chunks.as_ref())?.into_owned(),
+            );
+            filters.insert(topic, subs.get(topic).map_or(false, |sub| sub.no_local));
+        }
+        let watcher_ke = common_prefix_ke(kes.iter())?;
+        log::debug!(
+            "MQTT client {}: idle - hibernate {} subscriptions into a watcher on {}",
+            self.tag,
+            topics.len(),
+            watcher_ke
+        );
+        let woken = Arc::new(AtomicBool::new(false));
+        let woken2 = woken.clone();
+        let client = self.tag.clone();
+        let config = self.config.clone();
+        let scope = self.scope.clone();
+        let sink = self.sink.clone();
+        let watcher = self
+            .zsession
+            .declare_subscriber(watcher_ke)
+            .callback(move |sample| {
+                let Ok(topic) = ke_to_mqtt_topic_publish(&sample.key_expr, &scope) else {
+                    return;
+                };
+                let Some(no_local) = filters.find_match(&topic) else {
+                    return;
+                };
+                if *no_local && is_local_echo(&sample, &client.client_id) {
+                    return;
+                }
+                woken2.store(true, Ordering::Relaxed);
+                if let Err(e) = route_zenoh_to_mqtt(
+                    &sample,
+                    &client,
+                    &config,
+                    &scope,
+                    &sink,
+                    None,
+                    &DeliveryOptions::default(),
+                ) {
+                    log::warn!("{}", e);
+                }
+            })
+            .allowed_origin(self.allowed_sub_origin())
+            .res()
+            .await?;
+        // the subscriptions are left once the watcher is declared
+        let hibernated_subs = topics
+            .into_iter()
+            .filter_map(|topic| {
+                let sub = subs.remove(&topic)?;
+                Some((topic, sub.qos, sub.no_local, sub.delivery))
+            })
+            .collect();
+        *hibernated = Some(HibernatedSubs {
+            _watcher: watcher,
+            subs: hibernated_subs,
+            woken,
+        });
+        self.is_hibernated.store(true, Ordering::Release);
+        self.clients
+            .set_hibernated(&self.client_id, self.connection_id, true);
+        Ok(())
+    }
+
+    async fn wake(&self) {
+        let mut hibernated = self.hibernated.write().await;
+        let Some(hibernated_subs) = hibernated.take() else {
+            return;
+        };
+        *zlock!(self.last_activity) = Instant::now();
+        log::debug!(
+            "MQTT client {}: wake {} hibernated subscriptions",
+            self.tag,
+            hibernated_subs.subs.len()
+        );
+        for (topic, qos, no_local, delivery) in &hibernated_subs.subs {
+            if let Err(e) = self
+                .map_mqtt_topic_subscription(
+                    topic,
+                    self.sink.clone(),
+                    *qos,
+                    *no_local,
+                    delivery.clone(),
+                )
+                .await
+            {
+                log::warn!(
+                    "MQTT client {}: failed to re-declare hibernated subscription to '{}': {}",
+                    self.tag,
+                    topic,
+                    e
+                );
+            }
+        }
+        // the watcher is undeclared once the subscriptions are re-declared
+        drop(hibernated_subs);
+        self.is_hibernated.store(false, Ordering::Release);
+        self.clients
+            .set_hibernated(&self.client_id, self.connection_id, false);
+    }
+
+    async fn map_mqtt_queue_subscription(
+        &self,
+        topic: &str,
+        group: &str,
+        queue_topic: &str,
+        qos: QoS,
+        no_local: bool,
+        delivery: DeliveryOptions,
+    ) -> Result<(), MqttPluginError> {
+        let sub_origin = if self.is_topic_allowed(queue_topic) {
+            self.allowed_sub_origin()
+        } else {
+            log::debug!(
+                "MQTT Client {}: topic '{}' is not allowed to be routed over Zenoh (see your 'allow' or 'deny' configuration) - re-publish only from MQTT publishers",
+                self.tag,
+                queue_topic
+            );
+            Locality::SessionLocal
+        };
+
+        let mut queue_subs = self.queue_subs.write().await;
+        if !queue_subs.contains_key(topic) {
+            let ke = mqtt_topic_to_ke(queue_topic, &self.scope, self.config.ke_chunks.as_ref())
+                .map_err(|e| MqttPluginError::KeyExprInvalid(e.to_string()))?;
+            let client = self.tag.clone();
+            let config = self.config.clone();
+            let scope = self.scope.clone();
+            let receipts = self.receipts_session(qos);
+            let sink = self.sink.clone();
+            let filter = queue_topic.to_string();
+            let check_filter = needs_filter_check(queue_topic, self.config.ke_chunks.as_ref());
+            let member = self
+                .fanout
+                .join_queue(
+                    group,
+                    &ke,
+                    sub_origin,
+                    self.subscriber_reliability(qos),
+                    Arc::new(move |sample| {
+                        if no_local && is_local_echo(sample, &client.client_id) {
+                            return Ok(());
+                        }
+                        if check_filter && !matches_filter(sample, &scope, &filter) {
+                            return Ok(());
+                        }
+                        route_zenoh_to_mqtt(
+                            sample,
+                            &client,
+                            &config,
+                            &scope,
+                            &sink,
+                            receipts.as_ref(),
+                            &delivery,
+                        )
+                    }),
+                )
+                .map_err(MqttPluginError::Internal)?;
+            queue_subs.insert(topic.into(), member);
         } else {
             log::debug!(
                 "MQTT Client {} already subscribes to {} => ignore",
-                self.client_id,
+                self.tag,
+                topic
+            );
+        }
+        Ok(())
+    }
+
+    // Fetch via a zenoh query the latest `count` publications matching a subscription,
+    // and send them to the client (in their timestamps order) with the QoS granted to the subscription.
+    // `qos`: the QoS requested by the client (the granted one being applied)
+    pub(crate) async fn replay_history(
+        &self,
+        topic: &str,
+        qos: QoS,
+        count: usize,
+        delivery: DeliveryOptions,
+    ) -> ZResult<()> {
+        // no history for the topics published by the plugin itself
+        if is_sys_topic(topic) || self.is_enrollment_topic(topic) {
+            return Ok(());
+        }
+        // a queue subscription gets the history of its actual topic filter
+        let topic = parse_queue_topic(topic).map_or(topic, |(_, filter)| filter);
+        if !self.is_topic_allowed(topic) {
+            log::debug!(
+                "MQTT client {}: topic '{}' is not allowed to be routed over Zenoh - no history to fetch",
+                self.tag,
+                topic
+            );
+            return Ok(());
+        }
+        let ke = mqtt_topic_to_ke(topic, &self.scope, self.config.ke_chunks.as_ref())?;
+        log::debug!(
+            "MQTT client {}: fetch the {} latest publications on '{}' for subscription to '{}'",
+            self.tag,
+            count,
+            ke,
+            topic
+        );
+        let replies = self
+            .zsession
+            .get(&ke)
+            .timeout(HISTORY_QUERY_TIMEOUT)
+            .res()
+            .await?;
+        let mut samples = Vec::new();
+        while let Ok(reply) = replies.recv_async().await {
+            match reply.sample {
+                Ok(sample) => samples.push(sample),
+                Err(e) => log::debug!(
+                    "MQTT client {}: error reply fetching history on '{}': {}",
+                    self.tag,
+                    ke,
+                    e
+                ),
+            }
+        }
+        samples.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        let skipped = samples.len().saturating_sub(count);
+        let receipts = self.receipts_session(qos);
+        for sample in &samples[skipped..] {
+            route_zenoh_to_mqtt(
+                sample,
+                &self.tag,
+                &self.config,
+                &self.scope,
+                &self.sink,
+                receipts.as_ref(),
+                &delivery,
+            )?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn unmap_mqtt_subscription(&self, topic: &str) {
+        self.activity().await;
+        let removed = if parse_queue_topic(topic).is_some() {
+            self.queue_subs.write().await.remove(topic).is_some()
+        } else if is_sys_topic(topic) {
+            match self.sys_subs.write().await.remove(topic) {
+                Some(active) => {
+                    active.store(false, Ordering::Relaxed);
+                    true
+                }
+                None => false,
+            }
+        } else if self.is_enrollment_topic(topic) {
+            true
+        } else if self.subs.write().await.remove(topic).is_some() {
+            true
+        } else {
+            self.remove_coalesced_filter(topic).await
+        };
+        if removed {
+            self.clients.unsubscribed(&self.client_id, topic);
+            if let Some(sessions) = self.persisted_sessions() {
+                sessions.unsubscribed(&self.client_id, topic).await;
+            }
+            events::notify(SessionEvent::Unsubscribed {
+                client_id: self.client_id.clone(),
+                topic: topic.into(),
+            });
+        } else {
+            log::debug!(
+                "MQTT Client {} unsubscribes from {} but wasn't subscribed => ignore",
+                self.tag,
                 topic
             );
-            Ok(())
+        }
+    }
+
+    async fn remove_coalesced_filter(&self, topic: &str) -> bool {
+        let mut coalesced_subs = self.coalesced_subs.write().await;
+        let root = coalesced_subs
+            .iter()
+            .find(|(_, c)| zread!(c.filters).contains_key(topic))
+            .map(|(root, _)| root.clone());
+        match root {
+            Some(root) => {
+                let is_empty = coalesced_subs.get(&root).map_or(true, |c| {
+                    let mut filters = zwrite!(c.filters);
+                    filters.remove(topic);
+                    filters.is_empty()
+                });
+                if is_empty {
+                    coalesced_subs.remove(&root);
+                }
+                true
+            }
+            None => false,
         }
     }
 
@@ -94,65 +1394,808 @@ impl MqttSessionState<'_> {
         &self,
         mqtt_topic: &ntex::router::Path<ByteString>,
         payload: &Bytes,
-    ) -> ZResult<()> {
-        let topic = mqtt_topic.get_ref().as_str();
-        let destination = if is_allowed(topic, &self.config) {
+        qos: QoS,
+        retain: bool,
+        ke_override: Option<&str>,
+    ) -> Result<(), MqttPluginError> {
+        // the reception time of the MQTT PUBLISH
+        let start = SystemTime::now();
+        self.listener.received(payload.len());
+        self.activity().await;
+        match self.config.read_only {
+            Some(ReadOnlyMode::Reject) => {
+                self.listener.read_only_publication();
+                return Err(MqttPluginError::AclDenied(format!(
+                    "MQTT client {}: publication on '{}' rejected in read-only mode",
+                    self.client_id,
+                    mqtt_topic.get_ref()
+                )));
+            }
+            Some(ReadOnlyMode::Drop) => {
+                self.listener.read_only_publication();
+                log::trace!(
+                    "MQTT client {}: publication on '{}' dropped in read-only mode",
+                    self.tag,
+                    mqtt_topic.get_ref()
+                );
+                return Ok(());
+            }
+            None => (),
+        }
+        self.check_topic_limits(mqtt_topic.get_ref(), false)?;
+        self.check_published_topics(mqtt_topic.get_ref())?;
+        self.check_access_window(mqtt_topic.get_ref())?;
+        // a CSR is forwarded to the enrollment service, not routed to zenoh
+        if let Some(enrollment) = &self.enrollment {
+            if enrollment.is_csr_topic(mqtt_topic.get_ref()) {
+                enrollment.submit(&self.client_id, payload, self.sink.clone());
+                return Ok(());
+            }
+        }
+        // a publication on the trigger topic of a query rule is a request, not routed to zenoh
+        if let Some(query_rules) = &self.query_rules {
+            if query_rules.trigger(&self.client_id, mqtt_topic.get_ref(), payload, &self.scope) {
+                return Ok(());
+            }
+        }
+        let (topic, new_payload) =
+            match scripting::rewrite_mqtt_to_zenoh(mqtt_topic.get_ref().as_str(), payload)
+                .map_err(|e| MqttPluginError::ScriptFailed(e.to_string()))?
+            {
+                Some(rewritten) => rewritten,
+                None => {
+                    log::trace!(
+                        "MQTT client {}: publication on '{}' dropped by script",
+                        self.tag,
+                        mqtt_topic.get_ref()
+                    );
+                    return Ok(());
+                }
+            };
+        let topic = topic.as_ref();
+        if let Some(uns) = &self.uns {
+            uns.validate(topic)
+                .map_err(MqttPluginError::TopicNonCompliant)?;
+            uns.publish_metadata(topic).await;
+        }
+        let payload = &match new_payload {
+            Cow::Borrowed(_) => payload.clone(),
+            Cow::Owned(p) => Bytes::from(p),
+        };
+        let destination = if self.is_topic_allowed(topic) {
             // if topic is allowed, publish to anywhere
             Locality::Any
         } else {
             // if topic is NOT allowed, publish only to this plugin (for MQTT-to-MQTT routing only)
             log::trace!(
                 "MQTT Client {}: topic '{}' is not allowed to be routed over Zenoh (see your 'allow' or 'deny' configuration) - re-publish only to MQTT subscriber",
-                self.client_id,
+                self.tag,
                 topic
             );
             Locality::SessionLocal
         };
 
-        let ke: KeyExpr = if let Some(scope) = &self.config.scope {
-            (scope / topic.try_into()?).into()
-        } else {
-            topic.try_into()?
+        let ke = match (ke_override, &self.ke_caches) {
+            (Some(requested), _) => self.override_ke(requested).map(KeyExpr::from)?,
+            (None, Some(caches)) => caches
+                .get_or_map(&self.ke_cache, &self.scope, topic, || {
+                    mqtt_topic_to_ke(topic, &self.scope, self.config.ke_chunks.as_ref())
+                        .map(OwnedKeyExpr::from)
+                })
+                .map(KeyExpr::from)
+                .map_err(|e| MqttPluginError::KeyExprInvalid(format!("'{topic}': {e}")))?,
+            (None, None) => mqtt_topic_to_ke(topic, &self.scope, self.config.ke_chunks.as_ref())
+                .map_err(|e| MqttPluginError::KeyExprInvalid(format!("'{topic}': {e}")))?,
+        };
+        if ke.is_wild() {
+            return Err(MqttPluginError::KeyExprInvalid(format!(
+                "'{topic}': wildcards are not allowed in a publication topic"
+            )));
+        }
+        // a payload above the threshold of a matching rule is routed to a distinct key suffix, or rejected
+        let ke =
+            match self.config.large_payloads.iter().find(|rule| {
+                payload.len() > rule.threshold && topic_matches_filter(topic, &rule.topic)
+            }) {
+                Some(LargePayloadsConfig {
+                    suffix: Some(suffix),
+                    ..
+                }) => ke
+                    .join(suffix.as_str())
+                    .map_err(|e| MqttPluginError::KeyExprInvalid(format!("'{topic}': {e}")))?,
+                Some(rule) => {
+                    return Err(MqttPluginError::QuotaExceeded(format!(
+                        "'{topic}': payload of {} bytes exceeds the maximum of {} bytes",
+                        payload.len(),
+                        rule.threshold
+                    )))
+                }
+                None => ke,
+            };
+        let (payload, encoding) = match self.config.envelope {
+            Some(format) => {
+                let (envelope, encoding) =
+                    envelope::wrap(format, topic, &self.client_id, qos as u8, retain, payload)
+                        .map_err(|e| MqttPluginError::Unspecified(format!("'{topic}': {e}")))?;
+                (Bytes::from(envelope), encoding)
+            }
+            None => (payload.clone(), guess_encoding(payload.deref())),
+        };
+        // the payload is encrypted if the topic matches an encryption rule
+        let encrypted = encryption::encrypt(topic, &payload)
+            .map_err(|e| MqttPluginError::Unspecified(format!("'{topic}': {e}")))?;
+        let (payload, encoding) = match encrypted {
+            Some(encrypted) => (Bytes::from(encrypted), Encoding::APP_OCTET_STREAM),
+            None => (payload, encoding),
         };
-        let encoding = guess_encoding(payload.deref());
+        let copies = self.scope_copies(topic)?;
         // TODO: check allow/deny
         log::trace!(
             "MQTT client {}: route from MQTT '{}' to Zenoh '{}' (encoding={})",
-            self.client_id,
+            self.tag,
             topic,
             ke,
             encoding
         );
-        self.zsession
-            .put(ke, payload.deref())
-            .encoding(encoding)
-            .allowed_destination(destination)
-            .res()
+        telemetry::payload_routed(latency::Direction::MqttToZenoh, payload.len());
+        recording::record(
+            Direction::MqttToZenoh,
+            &self.client_id,
+            topic,
+            &ke,
+            &encoding,
+            &payload,
+            destination == Locality::SessionLocal,
+        );
+        let publication = Publication {
+            topic,
+            ke,
+            payload,
+            encoding,
+            destination,
+            priority: self.priority(),
+        };
+        let result = match self.put_publication(&publication, qos).await {
+            Ok(routed) => {
+                self.record_published_topic(mqtt_topic.get_ref());
+                self.on_routed(&publication, &copies, routed, retain).await;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        };
+        telemetry::mqtt_to_zenoh_routed(&self.tag, topic, &publication.ke, start, &result);
+        if result.is_err() {
+            self.listener.error();
+        }
+        result
+    }
+
+    // Route a publication to zenoh: buffered while the routing is paused, stored while zenoh is unreachable
+    // or stored publications are waiting to be forwarded, otherwise put (as chunks if larger than the chunk
+    // size) applying the congestion and retry policies, and stored if the put still fails.
+    async fn put_publication(
+        &self,
+        publication: &Publication<'_>,
+        qos: QoS,
+    ) -> Result<Routed, MqttPluginError> {
+        let Publication {
+            topic,
+            ke,
+            payload,
+            encoding,
+            destination,
+            priority,
+        } = publication;
+        // while the routing is paused, the publication is buffered to be put on zenoh on resume
+        if pause::is_paused() {
+            let put = BufferedPut {
+                ke: ke.clone().into_owned(),
+                payload: payload.to_vec(),
+                encoding: encoding.clone(),
+                destination: *destination,
+                priority: *priority,
+                attachment: self.origin_attachment(),
+            };
+            let buffered = pause::buffer(put)
+                .map_err(|e| MqttPluginError::QuotaExceeded(format!("'{topic}': {e}")))?;
+            if buffered {
+                return Ok(Routed::Buffered);
+            }
+        }
+        // while zenoh is unreachable or stored publications are waiting to be forwarded, store the new ones
+        // (whatever their size) to preserve the order
+        if let Some(store_forward) = self.forwarding_store(*destination) {
+            if store_forward.must_store() {
+                store_forward
+                    .store(ke, encoding, payload.deref())
+                    .map_err(|e| MqttPluginError::QuotaExceeded(e.to_string()))?;
+                return Ok(Routed::Stored);
+            }
+        }
+        // a payload larger than the chunk size is put as several chunks
+        let chunks = chunking::split(&self.zsession.zid(), payload.len())
+            .map_err(|e| MqttPluginError::QuotaExceeded(format!("'{topic}': {e}")))?;
+        // held until the put completes (including its retries)
+        let _permit = match &self.put_caps {
+            Some(put_caps) => put_caps.acquire(topic).await,
+            None => None,
+        };
+        // the congestion rules apply to the QoS 1 publications put at once
+        let congestion = match (qos, &chunks) {
+            (QoS::AtLeastOnce, None) => self
+                .config
+                .congestion
+                .iter()
+                .find(|rule| topic_matches_filter(topic, &rule.topic)),
+            _ => None,
+        };
+        let result = match congestion {
+            Some(rule) if rule.policy != CongestionPolicy::Block => {
+                match self.put_congested(publication, rule.timeout_ms).await {
+                    Some(result) => result,
+                    None => return self.on_congestion(publication, rule.policy),
+                }
+            }
+            _ => {
+                self.put_once(publication, chunks.as_deref(), congestion.is_some())
+                    .await
+            }
+        };
+        let result = match (result, &self.config.put_retry) {
+            (Err(e), Some(retry_conf)) => {
+                self.retry_put(publication, chunks.as_deref(), retry_conf, e)
+                    .await
+            }
+            (result, _) => result,
+        };
+        match (result, self.forwarding_store(*destination)) {
+            (Ok(()), _) => Ok(Routed::Put),
+            (Err(e), Some(store_forward)) => {
+                log::debug!(
+                    "MQTT client {}: store publication on '{}' to be forwarded later ({})",
+                    self.tag,
+                    ke,
+                    e
+                );
+                store_forward
+                    .store(ke, encoding, payload.deref())
+                    .map_err(|e| MqttPluginError::QuotaExceeded(e.to_string()))?;
+                Ok(Routed::Stored)
+            }
+            (Err(e), None) => Err(MqttPluginError::ZenohPutFailed(e.to_string())),
+        }
+    }
+
+    // The side effects of a routed publication, whatever its routing path: its last value is recorded,
+    // and its copies into other scopes are routed the same way as the publication itself.
+    // A failed copy is only logged (the publication having been routed).
+    async fn on_routed(
+        &self,
+        publication: &Publication<'_>,
+        copies: &[(KeyExpr<'static>, Option<Priority>)],
+        routed: Routed,
+        retain: bool,
+    ) {
+        if let (Some(last_values), Locality::Any) = (&self.last_values, publication.destination) {
+            last_values.record(
+                &publication.ke,
+                &publication.payload,
+                &publication.encoding,
+                retain,
+            );
+        }
+        for (ke, priority) in copies {
+            let copy = Publication {
+                ke: ke.clone(),
+                priority: priority.or(publication.priority),
+                ..publication.clone()
+            };
+            if let Err(e) = self.route_copy(&copy, routed).await {
+                log::warn!(
+                    "MQTT client {}: failed to route copy of '{}' on '{}': {}",
+                    self.tag,
+                    copy.topic,
+                    copy.ke,
+                    e
+                );
+                self.listener.error();
+            }
+        }
+    }
+
+    // Route the copy of a publication the same way as the publication
+    async fn route_copy(&self, copy: &Publication<'_>, routed: Routed) -> ZResult<()> {
+        if routed == Routed::Buffered {
+            let put = BufferedPut {
+                ke: copy.ke.clone().into_owned(),
+                payload: copy.payload.to_vec(),
+                encoding: copy.encoding.clone(),
+                destination: copy.destination,
+                priority: copy.priority,
+                attachment: self.origin_attachment(),
+            };
+            // if the routing has been resumed meanwhile, the copy is put
+            if pause::buffer(put)? {
+                return Ok(());
+            }
+        }
+        if let (Routed::Stored, Some(store_forward)) =
+            (routed, self.forwarding_store(copy.destination))
+        {
+            return store_forward.store(&copy.ke, &copy.encoding, copy.payload.deref());
+        }
+        let chunks = chunking::split(&self.zsession.zid(), copy.payload.len())?;
+        self.put_once(copy, chunks.as_deref(), false).await
+    }
+
+    // The store-and-forward queue, for the publications routed to zenoh (the ones only re-published
+    // to the local MQTT subscribers are never stored)
+    fn forwarding_store(&self, destination: Locality) -> Option<&StoreForward> {
+        match destination {
+            Locality::Any => self.store_forward.as_deref(),
+            _ => None,
+        }
+    }
+
+    // The key expression requested by the client for a publication, if it's allowed to use it
+    fn override_ke(&self, requested: &str) -> Result<OwnedKeyExpr, MqttPluginError> {
+        let ke = OwnedKeyExpr::try_from(requested.to_string())
+            .map_err(|e| MqttPluginError::KeyExprInvalid(format!("'{requested}': {e}")))?;
+        let allowed = self.config.ke_override.as_ref().map_or(false, |conf| {
+            conf.is_allowed(zread!(self.username).as_deref(), &ke)
+        });
+        if !allowed {
+            audit::acl_denied(
+                &self.client_id,
+                zread!(self.username).as_deref(),
+                "publish",
+                ke.as_str(),
+            );
+            return Err(MqttPluginError::AclDenied(format!(
+                "MQTT client {}: publication on key expression '{}' not allowed",
+                self.client_id, ke
+            )));
+        }
+        Ok(ke)
+    }
+
+    // The key expressions (and priorities) of the copies of a publication into other scopes,
+    // according to the 1st matching rule
+    fn scope_copies(
+        &self,
+        topic: &str,
+    ) -> Result<Vec<(KeyExpr<'static>, Option<Priority>)>, MqttPluginError> {
+        let Some(ScopeCopiesConfig { scopes, .. }) = self
+            .config
+            .scope_copies
+            .iter()
+            .find(|rule| topic_matches_filter(topic, &rule.topic))
+        else {
+            return Ok(Vec::new());
+        };
+        scopes
+            .iter()
+            .map(|copy| {
+                mqtt_topic_to_ke(
+                    topic,
+                    &Some(copy.scope.clone()),
+                    self.config.ke_chunks.as_ref(),
+                )
+                .map(|ke| (ke.into_owned(), copy.priority.map(Priority::from)))
+                .map_err(|e| MqttPluginError::KeyExprInvalid(format!("'{topic}': {e}")))
+            })
+            .collect()
+    }
+
+    // Put a publication on zenoh (as chunks if split), with the blocking congestion control if requested
+    async fn put_once(
+        &self,
+        publication: &Publication<'_>,
+        chunks: Option<&[(String, Range<usize>)]>,
+        block: bool,
+    ) -> ZResult<()> {
+        if let Some(chunks) = chunks {
+            return self.put_chunks(publication, chunks).await;
+        }
+        let mut put = self
+            .zsession
+            .put(
+                &publication.ke,
+                self.shm.make_value(publication.payload.deref()),
+            )
+            .encoding(publication.encoding.clone())
+            .allowed_destination(publication.destination);
+        if block {
+            put = put.congestion_control(CongestionControl::Block);
+        }
+        if let Some(priority) = publication.priority {
+            put = put.priority(priority);
+        }
+        if let Some(attachment) = self.origin_attachment() {
+            put = put.with_attachment(attachment);
+        }
+        put.res().await
+    }
+
+    // Put a publication with the blocking congestion control, waiting at most for the timeout.
+    // Returns None on timeout (the put still being pending in background).
+    async fn put_congested(
+        &self,
+        publication: &Publication<'_>,
+        timeout_ms: u64,
+    ) -> Option<ZResult<()>> {
+        let zsession = self.zsession.clone();
+        let ke = publication.ke.clone().into_owned();
+        let payload = publication.payload.to_vec();
+        let encoding = publication.encoding.clone();
+        let destination = publication.destination;
+        let priority = publication.priority;
+        let attachment = self.origin_attachment();
+        let put = async_std::task::spawn_blocking(move || {
+            let mut put = zsession
+                .put(&ke, payload)
+                .encoding(encoding)
+                .allowed_destination(destination)
+                .congestion_control(CongestionControl::Block);
+            if let Some(priority) = priority {
+                put = put.priority(priority);
+            }
+            if let Some(attachment) = attachment {
+                put = put.with_attachment(attachment);
+            }
+            put.res_sync()
+        });
+        async_std::future::timeout(Duration::from_millis(timeout_ms), put)
             .await
+            .ok()
+    }
+
+    // Apply the congestion policy to a publication whose put timed out
+    fn on_congestion(
+        &self,
+        publication: &Publication<'_>,
+        policy: CongestionPolicy,
+    ) -> Result<Routed, MqttPluginError> {
+        log::debug!(
+            "MQTT client {}: put on '{}' blocked by congestion - apply '{:?}' policy",
+            self.tag,
+            publication.ke,
+            policy
+        );
+        match (policy, self.forwarding_store(publication.destination)) {
+            (CongestionPolicy::Spill, Some(store_forward)) => store_forward
+                .store(
+                    &publication.ke,
+                    &publication.encoding,
+                    publication.payload.deref(),
+                )
+                .map(|()| Routed::Stored)
+                .map_err(|e| MqttPluginError::QuotaExceeded(e.to_string())),
+            _ => Err(MqttPluginError::QuotaExceeded(format!(
+                "'{}': routing to zenoh blocked by congestion",
+                publication.topic
+            ))),
+        }
+    }
+
+    // Put the chunks of a large payload, in order
+    async fn put_chunks(
+        &self,
+        publication: &Publication<'_>,
+        chunks: &[(String, Range<usize>)],
+    ) -> ZResult<()> {
+        log::trace!(
+            "MQTT client {}: put payload of {} bytes on '{}' as {} chunks",
+            self.tag,
+            publication.payload.len(),
+            publication.ke,
+            chunks.len()
+        );
+        for (chunk, range) in chunks {
+            let mut attachment = self.origin_attachment().unwrap_or_else(Attachment::new);
+            attachment.insert(&ATTACHMENT_KEY_CHUNK, chunk);
+            let mut put = self
+                .zsession
+                .put(&publication.ke, &publication.payload[range.clone()])
+                .encoding(publication.encoding.clone())
+                .allowed_destination(publication.destination)
+                .with_attachment(attachment);
+            if let Some(priority) = publication.priority {
+                put = put.priority(priority);
+            }
+            put.res()
+                .await
+                .map_err(|e| zerror!("chunk {}: {}", chunk, e))?;
+        }
+        Ok(())
+    }
+
+    // Retry a failed put with an exponential backoff, as long as the number of
+    // publications being retried for this client doesn't exceed the configured queue size.
+    async fn retry_put(
+        &self,
+        publication: &Publication<'_>,
+        chunks: Option<&[(String, Range<usize>)]>,
+        retry_conf: &PutRetryConfig,
+        err: impl std::fmt::Display,
+    ) -> ZResult<()> {
+        let ke = &publication.ke;
+        if self.pending_retries.fetch_add(1, Ordering::AcqRel) >= retry_conf.queue_size {
+            self.pending_retries.fetch_sub(1, Ordering::AcqRel);
+            bail!(
+                "MQTT client {}: put on '{}' failed and retry queue is full: {}",
+                self.client_id,
+                ke,
+                err
+            );
+        }
+
+        let mut backoff_ms = retry_conf.initial_backoff_ms;
+        let mut last_err = err.to_string();
+        for attempt in 1..=retry_conf.max_retries {
+            log::debug!(
+                "MQTT client {}: put on '{}' failed ({}) - retry {}/{} in {} ms",
+                self.tag,
+                ke,
+                last_err,
+                attempt,
+                retry_conf.max_retries,
+                backoff_ms
+            );
+            async_std::task::sleep(Duration::from_millis(backoff_ms)).await;
+            match self.put_once(publication, chunks, false).await {
+                Ok(()) => {
+                    self.pending_retries.fetch_sub(1, Ordering::AcqRel);
+                    return Ok(());
+                }
+                Err(e) => last_err = e.to_string(),
+            }
+            backoff_ms = (backoff_ms * 2).min(retry_conf.max_backoff_ms);
+        }
+        self.pending_retries.fetch_sub(1, Ordering::AcqRel);
+        bail!(
+            "MQTT client {}: put on '{}' failed after {} retries: {}",
+            self.client_id,
+            ke,
+            retry_conf.max_retries,
+            last_err
+        )
+    }
+}
+
+impl Drop for MqttSessionState<'_> {
+    fn drop(&mut self) {
+        for active in self.sys_subs.get_mut().values() {
+            active.store(false, Ordering::Relaxed);
+        }
+        self.clients.unregister(&self.client_id, self.connection_id);
+        self.listener.disconnected();
+        telemetry::client_disconnected(self.tag.listener);
+        audit::disconnected(&self.client_id, zread!(self.username).as_deref());
+        events::notify(SessionEvent::Disconnected {
+            client_id: self.client_id.clone(),
+        });
     }
 }
 
+// Returns true if the topic of a zenoh publication matches the MQTT topic filter
+fn matches_filter(sample: &Sample, scope: &Option<OwnedKeyExpr>, filter: &str) -> bool {
+    ke_to_mqtt_topic_publish(&sample.key_expr, scope)
+        .map_or(false, |topic| topic_matches_filter(&topic, filter))
+}
+
 fn route_zenoh_to_mqtt(
-    sample: Sample,
-    client_id: &str,
+    sample: &Sample,
+    client: &ClientTag,
     config: &Config,
+    scope: &Option<OwnedKeyExpr>,
     sink: &MqttSink,
+    receipts: Option<&Arc<Session>>,
+    delivery: &DeliveryOptions,
 ) -> ZResult<()> {
-    let topic = ke_to_mqtt_topic_publish(&sample.key_expr, &config.scope)?;
+    let start = SystemTime::now();
+    let received = Instant::now();
+    let topic = ke_to_mqtt_topic_publish(&sample.key_expr, scope)?;
+    if !access_windows::is_open(&topic) {
+        log::trace!(
+            "MQTT client {}: Zenoh publication on '{}' out of its access windows - dropped",
+            client,
+            sample.key_expr
+        );
+        return Ok(());
+    }
+    // a chunk is routed only once all the chunks of its message are received
+    let Some(payload) = chunking::reassemble(&client.client_id, sample)? else {
+        return Ok(());
+    };
+    let payload = match encryption::decrypt(&topic, &payload)? {
+        Some(decrypted) => Cow::Owned(decrypted),
+        None => payload,
+    };
+    let payload = match config.envelope {
+        Some(format) => Cow::Owned(envelope::unwrap(format, payload.into_owned())),
+        None => payload,
+    };
+    if let Some(filter) = &delivery.filter {
+        // the payload of a CBOR envelope is not CBOR encoded
+        let encoding = match config.envelope {
+            Some(_) => &Encoding::APP_JSON,
+            None => &sample.encoding,
+        };
+        if !filter.matches(encoding, &payload) {
+            log::trace!(
+                "MQTT client {}: Zenoh publication on '{}' doesn't match filter '{}' - dropped",
+                client,
+                sample.key_expr,
+                filter
+            );
+            return Ok(());
+        }
+    }
+    // the payload of a CBOR envelope is not CBOR encoded
+    let payload = match delivery.format {
+        Some(format) if config.envelope != Some(EnvelopeFormat::Cbor) => {
+            payload_format::convert(format, &sample.encoding, payload)?
+        }
+        _ => payload,
+    };
+    let Some((topic, payload)) = scripting::rewrite_zenoh_to_mqtt(&topic, &payload)? else {
+        log::trace!(
+            "MQTT client {}: Zenoh publication on '{}' dropped by script",
+            client,
+            sample.key_expr
+        );
+        return Ok(());
+    };
     log::trace!(
         "MQTT client {}: route from Zenoh '{}' to MQTT '{}'",
-        client_id,
+        client,
         sample.key_expr,
         topic
     );
-    sink.publish_at_most_once(topic, sample.payload.contiguous().to_vec().into())
-        .map_err(|e| {
-            zerror!(
-                "MQTT client {}: error re-publishing on MQTT a Zenoh publication on {}: {}",
-                client_id,
-                sample.key_expr,
-                e
+    telemetry::payload_routed(latency::Direction::ZenohToMqtt, payload.len());
+    recording::record(
+        Direction::ZenohToMqtt,
+        &client.client_id,
+        &topic,
+        &sample.key_expr,
+        &sample.encoding,
+        &payload,
+        false,
+    );
+    let properties = OutgoingProperties {
+        user_properties: if delivery.meta {
+            payload_format::sample_metadata(sample)
+        } else {
+            Vec::new()
+        },
+        subscription_id: delivery.subscription_id,
+    };
+    let result = match (receipts, &config.delivery_receipts) {
+        (Some(zsession), Some(receipts_conf)) => {
+            // QoS 1: on PUBACK, publish a delivery receipt on "<prefix>/<original key expression>"
+            let zsession = zsession.clone();
+            let receipt_ke = &*receipts_conf.prefix / &*sample.key_expr;
+            let receipt = serde_json::json!({
+                "client_id": client.client_id,
+                "topic": topic,
+            })
+            .to_string();
+            sink.publish_at_least_once(
+                topic.to_string(),
+                payload.into_owned().into(),
+                properties,
+                Box::new(move || {
+                    use zenoh::prelude::sync::SyncResolve;
+                    if let Err(e) = zsession.put(receipt_ke.clone(), receipt).res_sync() {
+                        log::warn!(
+                            "Failed to publish delivery receipt on {}: {}",
+                            receipt_ke,
+                            e
+                        );
+                    }
+                }),
+                Some(received),
             )
-            .into()
-        })
+        }
+        _ => sink.publish_at_most_once(
+            topic.to_string(),
+            payload.into_owned().into(),
+            properties,
+            Some(received),
+        ),
+    };
+    // while the routing is paused, the publications exceeding the client's outgoing queue are dropped silently
+    if result.is_err() && pause::is_paused() {
+        pause::dropped_to_mqtt();
+        return Ok(());
+    }
+    let result: ZResult<()> = result.map_err(|e| {
+        zerror!(
+            "MQTT client {}: error re-publishing on MQTT a Zenoh publication on {}: {}",
+            client,
+            sample.key_expr,
+            e
+        )
+        .into()
+    });
+    telemetry::zenoh_to_mqtt_routed(client, &topic, &sample.key_expr, start, &result);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::QueueStrategy;
+    use std::num::NonZeroU32;
+    use zenoh::prelude::sync::SyncResolve;
+
+    #[test]
+    fn test_overlapping_subscription_identifiers() {
+        let mut zconfig = zenoh::config::Config::default();
+        zconfig.scouting.multicast.set_enabled(Some(false)).unwrap();
+        let zsession = Arc::new(zenoh::open(zconfig).res_sync().unwrap());
+        let fanout = Arc::new(FanoutRegistry::new(
+            zsession.clone(),
+            None,
+            1,
+            QueueStrategy::RoundRobin,
+        ));
+        let config = Arc::new(Config::default());
+        ntex::rt::System::new("test").block_on(async move {
+            let (tx, rx) = flume::unbounded();
+            let sink = MqttSink::test(tx);
+            let client = Arc::new(ClientTag {
+                client_id: "client".into(),
+                peer: None,
+                listener: LISTENER_MQTT,
+            });
+            // the overlapping subscriptions of the client ("a/#", "a/+" and "b/+"), each with its identifier
+            let _members: Vec<TopicMember> = [("a/**", 1), ("a/*", 2), ("b/*", 3)]
+                .into_iter()
+                .map(|(ke, id)| {
+                    let delivery = DeliveryOptions {
+                        subscription_id: NonZeroU32::new(id),
+                        ..Default::default()
+                    };
+                    let (client, config, sink) = (client.clone(), config.clone(), sink.clone());
+                    let handler: SampleHandler = Arc::new(move |sample| {
+                        route_zenoh_to_mqtt(sample, &client, &config, &None, &sink, None, &delivery)
+                    });
+                    fanout
+                        .join_topic(
+                            &KeyExpr::try_from(ke).unwrap(),
+                            Locality::Any,
+                            Reliability::Reliable,
+                            handler,
+                        )
+                        .unwrap()
+                })
+                .collect();
+            for ke in ["a/b", "a/b/c", "b/c", "c"] {
+                zsession.put(ke, "payload").res_sync().unwrap();
+            }
+            // a publication is sent once per matching subscription, with the identifier of the subscription
+            let mut received = Vec::new();
+            for _ in 0..4 {
+                let (topic, _, properties) = rx.recv_async().await.unwrap();
+                received.push((
+                    topic.to_string(),
+                    properties.subscription_id.map_or(0, NonZeroU32::get),
+                ));
+            }
+            received.sort();
+            assert_eq!(
+                received,
+                vec![
+                    ("a/b".to_string(), 1),
+                    ("a/b".to_string(), 2),
+                    ("a/b/c".to_string(), 1),
+                    ("b/c".to_string(), 3),
+                ]
+            );
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            assert!(rx.is_empty());
+        });
+    }
 }
@@ -0,0 +1,612 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// Experimental MQTT-SN (v1.2) transport: a UDP listener acting as a transparent MQTT-SN gateway.
+// Each MQTT-SN client gets a MqttSessionState, as the MQTT clients, and its topic ids are mapped
+// to topic names per client. Supported features:
+//   - CONNECT (without will), DISCONNECT, PINGREQ and keep alive
+//   - REGISTER from the client and from the gateway (for publications to the client)
+//   - PUBLISH with QoS 0 and 1 from the client, QoS 0 to the client
+//   - SUBSCRIBE/UNSUBSCRIBE with topic names (possibly with wildcards) and short topic names
+// Not supported: QoS 2, QoS -1, predefined topic ids, will topic/message, sleeping clients.
+
+use async_std::net::UdpSocket;
+use ntex::util::{ByteString, Bytes};
+use ntex_mqtt::QoS;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use zenoh_core::zlock;
+
+use crate::audit;
+use crate::clients::Negotiated;
+use crate::error::MqttPluginError;
+use crate::events::{self, ProtocolVersion, SessionEvent};
+use crate::ha;
+use crate::listener_stats::LISTENER_MQTT_SN;
+use crate::mqtt_helpers::{strict_client_id_violation, MqttSink};
+use crate::mqtt_session_state::MqttSessionState;
+use crate::payload_format::DeliveryOptions;
+use crate::{telemetry, BridgeState};
+
+const MAX_PACKET_SIZE: usize = 64 * 1024;
+// period of the check of the clients keep alive
+const KEEP_ALIVE_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+// Message types
+const CONNECT: u8 = 0x04;
+const CONNACK: u8 = 0x05;
+const REGISTER: u8 = 0x0A;
+const REGACK: u8 = 0x0B;
+const PUBLISH: u8 = 0x0C;
+const PUBACK: u8 = 0x0D;
+const SUBSCRIBE: u8 = 0x12;
+const SUBACK: u8 = 0x13;
+const UNSUBSCRIBE: u8 = 0x14;
+const UNSUBACK: u8 = 0x15;
+const PINGREQ: u8 = 0x16;
+const PINGRESP: u8 = 0x17;
+const DISCONNECT: u8 = 0x18;
+
+// Flags
+const FLAG_QOS_MASK: u8 = 0x60;
+const FLAG_QOS_1: u8 = 0x20;
+const FLAG_QOS_0: u8 = 0x00;
+const FLAG_RETAIN: u8 = 0x10;
+const FLAG_WILL: u8 = 0x08;
+const FLAG_CLEAN_SESSION: u8 = 0x04;
+// the protocol id of MQTT-SN 1.2 in CONNECT
+const PROTOCOL_ID: u8 = 0x01;
+const TOPIC_ID_TYPE_MASK: u8 = 0x03;
+const TOPIC_ID_TYPE_NORMAL: u8 = 0x00;
+const TOPIC_ID_TYPE_SHORT: u8 = 0x02;
+
+// Return codes
+const RC_ACCEPTED: u8 = 0x00;
+const RC_CONGESTION: u8 = 0x01;
+const RC_INVALID_TOPIC_ID: u8 = 0x02;
+const RC_NOT_SUPPORTED: u8 = 0x03;
+
+// The topic of a SUBSCRIBE/UNSUBSCRIBE message
+#[derive(Debug)]
+enum SnTopic {
+    Name(String),
+    Id(u8, u16),
+}
+
+#[derive(Debug)]
+enum SnPacket {
+    Connect {
+        flags: u8,
+        duration: u16,
+        client_id: String,
+    },
+    Register {
+        msg_id: u16,
+        topic_name: String,
+    },
+    RegAck,
+    Publish {
+        flags: u8,
+        topic_id: u16,
+        msg_id: u16,
+        data: Bytes,
+    },
+    PubAck,
+    Subscribe {
+        flags: u8,
+        msg_id: u16,
+        topic: SnTopic,
+    },
+    Unsubscribe {
+        msg_id: u16,
+        topic: SnTopic,
+    },
+    PingReq,
+    Disconnect,
+}
+
+fn read_u16(buf: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes(buf.get(..2)?.try_into().ok()?))
+}
+
+fn decode_topic(flags: u8, buf: &[u8]) -> Option<SnTopic> {
+    match flags & TOPIC_ID_TYPE_MASK {
+        TOPIC_ID_TYPE_NORMAL => Some(SnTopic::Name(std::str::from_utf8(buf).ok()?.into())),
+        id_type => Some(SnTopic::Id(id_type, read_u16(buf)?)),
+    }
+}
+
+fn decode(buf: &[u8]) -> Option<SnPacket> {
+    // the length is on 1 byte, or on 3 bytes starting with 0x01
+    let (len, header_len) = match *buf.first()? {
+        0x01 => (read_u16(buf.get(1..)?)? as usize, 3),
+        len => (len as usize, 1),
+    };
+    let buf = buf.get(header_len..len)?;
+    let (msg_type, body) = buf.split_first()?;
+    match *msg_type {
+        CONNECT => Some(SnPacket::Connect {
+            flags: *body.first()?,
+            duration: read_u16(body.get(2..)?)?,
+            client_id: std::str::from_utf8(body.get(4..)?).ok()?.into(),
+        }),
+        REGISTER => Some(SnPacket::Register {
+            msg_id: read_u16(body.get(2..)?)?,
+            topic_name: std::str::from_utf8(body.get(4..)?).ok()?.into(),
+        }),
+        REGACK => Some(SnPacket::RegAck),
+        PUBLISH => Some(SnPacket::Publish {
+            flags: *body.first()?,
+            topic_id: read_u16(body.get(1..)?)?,
+            msg_id: read_u16(body.get(3..)?)?,
+            data: Bytes::copy_from_slice(body.get(5..)?),
+        }),
+        PUBACK => Some(SnPacket::PubAck),
+        SUBSCRIBE => Some(SnPacket::Subscribe {
+            flags: *body.first()?,
+            msg_id: read_u16(body.get(1..)?)?,
+            topic: decode_topic(*body.first()?, body.get(3..)?)?,
+        }),
+        UNSUBSCRIBE => Some(SnPacket::Unsubscribe {
+            msg_id: read_u16(body.get(1..)?)?,
+            topic: decode_topic(*body.first()?, body.get(3..)?)?,
+        }),
+        PINGREQ => Some(SnPacket::PingReq),
+        DISCONNECT => Some(SnPacket::Disconnect),
+        _ => None,
+    }
+}
+
+// Encode a message with its type and body
+fn encode(msg_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(body.len() + 4);
+    if body.len() + 2 <= 255 {
+        buf.push((body.len() + 2) as u8);
+    } else {
+        buf.push(0x01);
+        buf.extend_from_slice(&((body.len() + 4) as u16).to_be_bytes());
+    }
+    buf.push(msg_type);
+    buf.extend_from_slice(body);
+    buf
+}
+
+// The mapping between topic ids and topic names for a client
+#[derive(Debug, Default)]
+struct SnTopics {
+    by_id: HashMap<u16, String>,
+    by_name: HashMap<String, u16>,
+    next_id: u16,
+    next_msg_id: u16,
+}
+
+impl SnTopics {
+    // Returns the topic id for a topic name, and true if it's newly registered
+    fn register(&mut self, name: &str) -> (u16, bool) {
+        if let Some(id) = self.by_name.get(name) {
+            return (*id, false);
+        }
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        self.by_id.insert(self.next_id, name.into());
+        self.by_name.insert(name.into(), self.next_id);
+        (self.next_id, true)
+    }
+
+    fn msg_id(&mut self) -> u16 {
+        self.next_msg_id = self.next_msg_id.wrapping_add(1).max(1);
+        self.next_msg_id
+    }
+}
+
+// The sending side of a MQTT-SN client, used by its MqttSink
+#[derive(Clone)]
+pub(crate) struct SnSink {
+    socket: Arc<std::net::UdpSocket>,
+    addr: SocketAddr,
+    topics: Arc<Mutex<SnTopics>>,
+}
+
+impl SnSink {
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Send a publication with QoS 0, registering its topic first if needed
+    pub(crate) fn publish(&self, topic: &str, payload: &[u8]) -> Result<(), String> {
+        let mut body = Vec::with_capacity(payload.len() + 5);
+        if topic.len() == 2 {
+            body.push(FLAG_QOS_0 | TOPIC_ID_TYPE_SHORT);
+            body.extend_from_slice(topic.as_bytes());
+        } else {
+            let (topic_id, msg_id) = {
+                let mut topics = zlock!(self.topics);
+                match topics.register(topic) {
+                    (id, true) => (id, Some(topics.msg_id())),
+                    (id, false) => (id, None),
+                }
+            };
+            if let Some(msg_id) = msg_id {
+                let mut register = Vec::with_capacity(topic.len() + 4);
+                register.extend_from_slice(&topic_id.to_be_bytes());
+                register.extend_from_slice(&msg_id.to_be_bytes());
+                register.extend_from_slice(topic.as_bytes());
+                self.send(REGISTER, &register)?;
+            }
+            body.push(FLAG_QOS_0 | TOPIC_ID_TYPE_NORMAL);
+            body.extend_from_slice(&topic_id.to_be_bytes());
+        }
+        body.extend_from_slice(&0u16.to_be_bytes());
+        body.extend_from_slice(payload);
+        self.send(PUBLISH, &body)
+    }
+
+    // Send a DISCONNECT to the client
+    pub(crate) fn disconnect(&self) {
+        let _ = self.send(DISCONNECT, &[]);
+    }
+
+    fn send(&self, msg_type: u8, body: &[u8]) -> Result<(), String> {
+        self.socket
+            .send_to(&encode(msg_type, body), self.addr)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+struct SnClient {
+    session: MqttSessionState<'static>,
+    sink: SnSink,
+    keep_alive: Duration,
+    last_seen: Instant,
+}
+
+// Run the MQTT-SN gateway on `addr`, until the socket fails.
+// Must be called within the ntex runtime, as the MqttSinks of the clients.
+pub(crate) async fn run(bridge: Arc<BridgeState>, addr: String) {
+    let socket = match std::net::UdpSocket::bind(&addr).and_then(|s| Ok((s.try_clone()?, s))) {
+        Ok((send_socket, recv_socket)) => {
+            log::info!("MQTT-SN gateway listening on {}", addr);
+            bridge.listeners.get(LISTENER_MQTT_SN).set_address(&addr);
+            (Arc::new(send_socket), UdpSocket::from(recv_socket))
+        }
+        Err(e) => {
+            log::error!("Failed to bind MQTT-SN gateway on {}: {}", addr, e);
+            return;
+        }
+    };
+    let (send_socket, recv_socket) = socket;
+    let mut clients: HashMap<SocketAddr, SnClient> = HashMap::new();
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    loop {
+        let received =
+            async_std::future::timeout(KEEP_ALIVE_CHECK_PERIOD, recv_socket.recv_from(&mut buf))
+                .await;
+        // the clients not seen for 1.5 times their keep alive are disconnected
+        clients.retain(|_, c| {
+            let alive = c.keep_alive.is_zero() || c.last_seen.elapsed() < c.keep_alive * 3 / 2;
+            if !alive {
+                log::info!(
+                    "MQTT-SN client {} disconnected: keep alive expired",
+                    c.session.tag
+                );
+            }
+            alive
+        });
+        let (len, peer) = match received {
+            Ok(Ok(r)) => r,
+            Ok(Err(e)) => {
+                log::error!("MQTT-SN gateway failed to receive: {}", e);
+                return;
+            }
+            Err(_) => continue,
+        };
+        let Some(packet) = decode(&buf[..len]) else {
+            log::debug!("Ignore invalid MQTT-SN packet from {}", peer);
+            continue;
+        };
+        log::trace!("MQTT-SN packet from {}: {:?}", peer, packet);
+        if let SnPacket::Connect {
+            flags,
+            duration,
+            client_id,
+        } = packet
+        {
+            // a new CONNECT from a same address replaces the previous session
+            clients.remove(&peer);
+            let sink = SnSink {
+                socket: send_socket.clone(),
+                addr: peer,
+                topics: Arc::new(Mutex::new(SnTopics::default())),
+            };
+            match connect(&bridge, &sink, flags, duration, client_id) {
+                Some(session) => {
+                    let _ = sink.send(CONNACK, &[RC_ACCEPTED]);
+                    clients.insert(
+                        peer,
+                        SnClient {
+                            session,
+                            sink,
+                            keep_alive: Duration::from_secs(duration.into()),
+                            last_seen: Instant::now(),
+                        },
+                    );
+                }
+                None => {
+                    let _ = sink.send(CONNACK, &[RC_NOT_SUPPORTED]);
+                }
+            }
+            continue;
+        }
+        let Some(client) = clients.get_mut(&peer) else {
+            log::debug!("Ignore MQTT-SN packet from unconnected client {}", peer);
+            continue;
+        };
+        client.last_seen = Instant::now();
+        if let SnPacket::Disconnect = packet {
+            log::debug!("MQTT-SN client {} disconnected", client.session.tag);
+            let _ = client.sink.send(DISCONNECT, &[]);
+            clients.remove(&peer);
+            continue;
+        }
+        if let Err(e) = handle(client, packet).await {
+            log::warn!("MQTT-SN client {}: {}", client.session.tag, e);
+        }
+    }
+}
+
+fn connect(
+    bridge: &Arc<BridgeState>,
+    sink: &SnSink,
+    flags: u8,
+    duration: u16,
+    client_id: String,
+) -> Option<MqttSessionState<'static>> {
+    log::info!(
+        "MQTT-SN client {} connects from {} via '{}' listener",
+        client_id,
+        sink.addr,
+        LISTENER_MQTT_SN
+    );
+    let address = Some(sink.addr.ip());
+    if bridge.refuse_connection() {
+        log::info!("MQTT-SN client {} rejected: plugin overloaded", client_id);
+        audit::rejected(&client_id, address, "plugin overloaded");
+        return None;
+    }
+    if !ha::is_active() {
+        log::info!("MQTT-SN client {} rejected: passive HA bridge", client_id);
+        audit::rejected(&client_id, address, "passive HA bridge");
+        return None;
+    }
+    // the MQTT-SN handshakes can't wait for a slot
+    let _permit = match &bridge.handshakes {
+        Some(handshakes) => match handshakes.try_acquire() {
+            Some(permit) => Some(permit),
+            None => {
+                log::info!("MQTT-SN client {} rejected: too many handshakes", client_id);
+                audit::rejected(&client_id, address, "too many handshakes");
+                return None;
+            }
+        },
+        None => None,
+    };
+    if let Some(violation) = bridge
+        .config
+        .strict
+        .then(|| strict_client_id_violation(&client_id))
+        .flatten()
+    {
+        log::info!("MQTT-SN client {} rejected: {}", client_id, violation);
+        audit::rejected(&client_id, address, &violation);
+        return None;
+    }
+    if flags & FLAG_WILL != 0 {
+        log::info!("MQTT-SN client {} rejected: will not supported", client_id);
+        audit::rejected(&client_id, address, "will not supported");
+        return None;
+    }
+    if bridge.auth.is_some() {
+        // MQTT-SN has no credentials
+        log::info!(
+            "MQTT-SN client {} rejected: authentication is required",
+            client_id
+        );
+        audit::rejected(&client_id, address, "authentication is required");
+        return None;
+    }
+    if let Err(reason) = bridge.bans.check(&client_id, address) {
+        log::info!("MQTT-SN client {} rejected: {}", client_id, reason);
+        audit::rejected(&client_id, address, &reason);
+        return None;
+    }
+    if bridge.client_stats.is_banned(&client_id) {
+        log::info!(
+            "MQTT-SN client {} rejected: banned after too many protocol errors",
+            client_id
+        );
+        audit::rejected(&client_id, address, "banned after too many protocol errors");
+        return None;
+    }
+    if !events::accept_connection(&client_id, None) {
+        log::info!(
+            "MQTT-SN client {} connection rejected by an event handler",
+            client_id
+        );
+        audit::rejected(&client_id, address, "rejected by an event handler");
+        return None;
+    }
+    if let Some(provisioning) = &bridge.provisioning {
+        provisioning.on_connect_no_wait(&client_id, ProtocolVersion::MqttSn, address);
+    }
+    telemetry::client_connected("sn", LISTENER_MQTT_SN);
+    audit::connected(&client_id, ProtocolVersion::MqttSn, None, address);
+    events::notify(SessionEvent::Connected {
+        client_id: client_id.clone(),
+        protocol: ProtocolVersion::MqttSn,
+        address: Some(sink.addr),
+        listener: LISTENER_MQTT_SN,
+    });
+    let session = MqttSessionState::new(
+        client_id,
+        bridge,
+        None,
+        MqttSink::sn(
+            sink.clone(),
+            bridge.memory_budget.clone(),
+            bridge.listeners.get(LISTENER_MQTT_SN),
+        ),
+        ProtocolVersion::MqttSn,
+        None,
+        None,
+    );
+    bridge.clients.set_negotiated(
+        &session.client_id,
+        session.connection_id,
+        Negotiated {
+            protocol_level: PROTOCOL_ID,
+            keep_alive: duration,
+            clean_session: flags & FLAG_CLEAN_SESSION != 0,
+            max_packet_size: None,
+        },
+    );
+    Some(session)
+}
+
+async fn handle(client: &mut SnClient, packet: SnPacket) -> Result<(), String> {
+    match packet {
+        SnPacket::Register { msg_id, topic_name } => {
+            let (topic_id, _) = zlock!(client.sink.topics).register(&topic_name);
+            let mut body = topic_id.to_be_bytes().to_vec();
+            body.extend_from_slice(&msg_id.to_be_bytes());
+            body.push(RC_ACCEPTED);
+            client.sink.send(REGACK, &body)
+        }
+        SnPacket::Publish {
+            flags,
+            topic_id,
+            msg_id,
+            data,
+        } => {
+            let topic = match flags & TOPIC_ID_TYPE_MASK {
+                TOPIC_ID_TYPE_NORMAL => zlock!(client.sink.topics).by_id.get(&topic_id).cloned(),
+                TOPIC_ID_TYPE_SHORT => String::from_utf8(topic_id.to_be_bytes().to_vec()).ok(),
+                _ => None,
+            };
+            let qos = match flags & FLAG_QOS_MASK {
+                FLAG_QOS_0 => QoS::AtMostOnce,
+                FLAG_QOS_1 => QoS::AtLeastOnce,
+                _ => return Err("publication with unsupported QoS".into()),
+            };
+            let rc = match topic {
+                Some(topic) => {
+                    let path = ntex::router::Path::new(ByteString::from(topic));
+                    match client
+                        .session
+                        .route_mqtt_to_zenoh(&path, &data, qos, flags & FLAG_RETAIN != 0, None)
+                        .await
+                    {
+                        Ok(()) => RC_ACCEPTED,
+                        Err(e) => {
+                            log::warn!("{}", e);
+                            match e {
+                                MqttPluginError::QuotaExceeded(_) => RC_CONGESTION,
+                                MqttPluginError::KeyExprInvalid(_)
+                                | MqttPluginError::TopicNonCompliant(_) => RC_INVALID_TOPIC_ID,
+                                _ => RC_NOT_SUPPORTED,
+                            }
+                        }
+                    }
+                }
+                None => RC_INVALID_TOPIC_ID,
+            };
+            if qos == QoS::AtLeastOnce || rc != RC_ACCEPTED {
+                let mut body = topic_id.to_be_bytes().to_vec();
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                body.push(rc);
+                client.sink.send(PUBACK, &body)?;
+            }
+            Ok(())
+        }
+        SnPacket::Subscribe {
+            flags: _,
+            msg_id,
+            topic,
+        } => {
+            let (topic, topic_id) = match topic {
+                SnTopic::Name(name) => {
+                    // no topic id for a topic filter with wildcards
+                    let topic_id = if name.contains(['+', '#']) {
+                        0
+                    } else {
+                        zlock!(client.sink.topics).register(&name).0
+                    };
+                    (Some(name), topic_id)
+                }
+                SnTopic::Id(TOPIC_ID_TYPE_SHORT, id) => {
+                    (String::from_utf8(id.to_be_bytes().to_vec()).ok(), 0)
+                }
+                SnTopic::Id(_, id) => (None, id),
+            };
+            let rc = match &topic {
+                Some(topic) => {
+                    match client
+                        .session
+                        .map_mqtt_subscription(
+                            topic,
+                            QoS::AtMostOnce,
+                            false,
+                            DeliveryOptions::default(),
+                        )
+                        .await
+                    {
+                        Ok(()) => RC_ACCEPTED,
+                        Err(e) => {
+                            log::warn!("Subscription to '{}' failed: {}", topic, e);
+                            RC_NOT_SUPPORTED
+                        }
+                    }
+                }
+                None => RC_INVALID_TOPIC_ID,
+            };
+            let mut body = vec![FLAG_QOS_0];
+            body.extend_from_slice(&topic_id.to_be_bytes());
+            body.extend_from_slice(&msg_id.to_be_bytes());
+            body.push(rc);
+            client.sink.send(SUBACK, &body)
+        }
+        SnPacket::Unsubscribe { msg_id, topic } => {
+            let topic = match topic {
+                SnTopic::Name(name) => Some(name),
+                SnTopic::Id(TOPIC_ID_TYPE_SHORT, id) => {
+                    String::from_utf8(id.to_be_bytes().to_vec()).ok()
+                }
+                SnTopic::Id(_, id) => zlock!(client.sink.topics).by_id.get(&id).cloned(),
+            };
+            if let Some(topic) = topic {
+                client.session.unmap_mqtt_subscription(&topic).await;
+            }
+            client.sink.send(UNSUBACK, &msg_id.to_be_bytes())
+        }
+        SnPacket::PingReq => {
+            client.session.sink.link_quality().ping();
+            client.session.on_ping().await;
+            client.sink.send(PINGRESP, &[])
+        }
+        SnPacket::RegAck | SnPacket::PubAck => Ok(()),
+        SnPacket::Connect { .. } | SnPacket::Disconnect => Ok(()),
+    }
+}
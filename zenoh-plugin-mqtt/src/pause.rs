@@ -0,0 +1,225 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The pause of the routing, e.g. during a planned zenoh maintenance: the MQTT connections are kept alive,
+// but no publication is routed in either direction. The MQTT publications are buffered (within the configured
+// limits) and put on zenoh in order on resume. The zenoh publications are kept in the clients' outgoing queues,
+// whose writers are paused (the ones exceeding a queue being dropped).
+
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zenoh::plugins::ZResult;
+use zenoh::prelude::r#async::*;
+use zenoh::publication::Priority;
+use zenoh::sample::Attachment;
+use zenoh_core::zlock;
+
+use crate::chunking::{self, ATTACHMENT_KEY_CHUNK};
+use crate::config::{Config, PauseConfig};
+use crate::process_wide::ProcessWide;
+
+const RESUME_CHECK_PERIOD: Duration = Duration::from_millis(100);
+
+// A MQTT publication buffered during the pause
+pub(crate) struct BufferedPut {
+    pub(crate) ke: KeyExpr<'static>,
+    pub(crate) payload: Vec<u8>,
+    pub(crate) encoding: Encoding,
+    pub(crate) destination: Locality,
+    pub(crate) priority: Option<Priority>,
+    pub(crate) attachment: Option<Attachment>,
+}
+
+#[derive(Default)]
+struct State {
+    paused: bool,
+    // set while the buffered publications are put on zenoh, after a resume request
+    resuming: bool,
+    // the time of the pause (in seconds since the UNIX epoch)
+    paused_since: Option<u64>,
+    buffer: VecDeque<BufferedPut>,
+    buffered_bytes: usize,
+    dropped_to_zenoh: u64,
+}
+
+// Set while paused or resuming, for a lock-free check on the routing paths
+static PAUSED: AtomicBool = AtomicBool::new(false);
+static DROPPED_TO_MQTT: AtomicU64 = AtomicU64::new(0);
+static LIMITS: ProcessWide<PauseConfig> = ProcessWide::new("pause");
+
+lazy_static::lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State::default());
+}
+
+pub(crate) fn init(config: &Config) -> ZResult<()> {
+    LIMITS.init(&config.pause, || {
+        Ok(Some(config.pause.clone().unwrap_or_default()))
+    })?;
+    Ok(())
+}
+
+pub(crate) fn is_paused() -> bool {
+    PAUSED.load(Ordering::Acquire)
+}
+
+// Pause the routing. Returns false if already paused.
+pub(crate) fn pause() -> bool {
+    let mut state = zlock!(STATE);
+    // a pause during a resume stops putting the buffered publications
+    state.resuming = false;
+    if state.paused {
+        return false;
+    }
+    state.paused = true;
+    state.paused_since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_secs());
+    PAUSED.store(true, Ordering::Release);
+    true
+}
+
+// Resume the routing, spawning the task putting the buffered publications on zenoh before the new ones.
+// Returns false if not paused.
+pub(crate) fn resume(zsession: Arc<Session>) -> bool {
+    {
+        let mut state = zlock!(STATE);
+        if !state.paused || state.resuming {
+            return false;
+        }
+        state.resuming = true;
+    }
+    async_std::task::spawn(async move {
+        let mut forwarded = 0;
+        loop {
+            let put = {
+                let mut state = zlock!(STATE);
+                if !state.resuming {
+                    log::info!("MQTT routing paused again while resuming");
+                    break;
+                }
+                match state.buffer.pop_front() {
+                    Some(put) => {
+                        state.buffered_bytes -= put.payload.len();
+                        put
+                    }
+                    None => {
+                        state.paused = false;
+                        state.resuming = false;
+                        state.paused_since = None;
+                        PAUSED.store(false, Ordering::Release);
+                        log::info!(
+                            "MQTT routing resumed ({} buffered publications forwarded to zenoh)",
+                            forwarded
+                        );
+                        break;
+                    }
+                }
+            };
+            let ke = put.ke.clone();
+            match put_buffered(&zsession, put).await {
+                Ok(()) => forwarded += 1,
+                Err(e) => log::warn!(
+                    "Failed to forward publication on {} buffered during pause: {}",
+                    ke,
+                    e
+                ),
+            }
+        }
+    });
+    true
+}
+
+// Put a buffered publication on zenoh, as several chunks if it's larger than the chunk size
+async fn put_buffered(zsession: &Session, put: BufferedPut) -> ZResult<()> {
+    let chunks = match chunking::split(&zsession.zid(), put.payload.len())? {
+        Some(chunks) => chunks
+            .into_iter()
+            .map(|(chunk, range)| (Some(chunk), range))
+            .collect(),
+        None => vec![(None, 0..put.payload.len())],
+    };
+    for (chunk, range) in chunks {
+        let mut attachment = put.attachment.clone();
+        if let Some(chunk) = chunk {
+            attachment
+                .get_or_insert_with(Attachment::new)
+                .insert(&ATTACHMENT_KEY_CHUNK, &chunk);
+        }
+        let mut builder = zsession
+            .put(&put.ke, &put.payload[range])
+            .encoding(put.encoding.clone())
+            .allowed_destination(put.destination);
+        if let Some(priority) = put.priority {
+            builder = builder.priority(priority);
+        }
+        if let Some(attachment) = attachment {
+            builder = builder.with_attachment(attachment);
+        }
+        builder.res().await?;
+    }
+    Ok(())
+}
+
+// Buffer a MQTT publication while paused. Returns false if the routing has been resumed meanwhile
+// (the publication must then be routed), and an error if the buffer is full.
+pub(crate) fn buffer(put: BufferedPut) -> ZResult<bool> {
+    let limits = LIMITS.get().cloned().unwrap_or_default();
+    let mut state = zlock!(STATE);
+    if !state.paused {
+        return Ok(false);
+    }
+    if state.buffer.len() >= limits.max_buffered_messages
+        || state.buffered_bytes + put.payload.len() > limits.max_buffered_bytes
+    {
+        state.dropped_to_zenoh += 1;
+        bail!(
+            "routing paused and buffer full ({} publications, {} bytes) - publication on {} dropped",
+            state.buffer.len(),
+            state.buffered_bytes,
+            put.ke
+        );
+    }
+    state.buffered_bytes += put.payload.len();
+    state.buffer.push_back(put);
+    Ok(true)
+}
+
+// Count a zenoh publication dropped for a MQTT client while paused (its outgoing queue being full)
+pub(crate) fn dropped_to_mqtt() {
+    DROPPED_TO_MQTT.fetch_add(1, Ordering::Relaxed);
+}
+
+// Wait while the routing is paused (for the writers of the MQTT clients)
+pub(crate) async fn wait_resumed() {
+    while is_paused() {
+        async_std::task::sleep(RESUME_CHECK_PERIOD).await;
+    }
+}
+
+pub(crate) fn to_json() -> Value {
+    let state = zlock!(STATE);
+    serde_json::json!({
+        "paused": state.paused,
+        "resuming": state.resuming,
+        "paused_since": state.paused_since,
+        "buffered_publications": state.buffer.len(),
+        "buffered_bytes": state.buffered_bytes,
+        "dropped_to_zenoh": state.dropped_to_zenoh,
+        "dropped_to_mqtt": DROPPED_TO_MQTT.load(Ordering::Relaxed),
+    })
+}
@@ -0,0 +1,188 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The filter expression requested by a MQTT 5 subscription (via a "filter" user property of SUBSCRIBE), evaluated
+// over the JSON (or CBOR) payloads of the zenoh publications before their routing to the client: only the matching
+// ones are sent (e.g. "$.temp > 30" for the threshold events). An expression compares the value at a path
+// ("$", followed by ".<field>" and "[<index>]" selectors) with a JSON literal (a number, a "string", true, false
+// or null), using one of ==, !=, <, <=, > or >=.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use zenoh::prelude::Encoding;
+
+use crate::envelope::CBOR_ENCODING;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+// the operators, the 2-characters ones first so they're not taken for "<" or ">"
+const OPERATORS: &[(&str, Op)] = &[
+    ("==", Op::Eq),
+    ("!=", Op::Ne),
+    ("<=", Op::Le),
+    (">=", Op::Ge),
+    ("<", Op::Lt),
+    (">", Op::Gt),
+];
+
+#[derive(Debug, Clone)]
+enum Selector {
+    Field(String),
+    Index(usize),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct PayloadFilter {
+    // the expression, as requested
+    source: String,
+    path: Vec<Selector>,
+    op: Op,
+    value: serde_json::Value,
+}
+
+impl PartialEq for PayloadFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for PayloadFilter {}
+
+impl fmt::Display for PayloadFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl From<PayloadFilter> for String {
+    fn from(filter: PayloadFilter) -> Self {
+        filter.source
+    }
+}
+
+impl TryFrom<String> for PayloadFilter {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        PayloadFilter::parse(&source)
+    }
+}
+
+fn parse_path(path: &str) -> Result<Vec<Selector>, String> {
+    let Some(mut rest) = path.strip_prefix('$') else {
+        return Err(format!("path '{path}' doesn't start with '$'"));
+    };
+    let mut selectors = Vec::new();
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix('.') {
+            let end = r.find(['.', '[']).unwrap_or(r.len());
+            if end == 0 {
+                return Err(format!("empty field name in path '{path}'"));
+            }
+            selectors.push(Selector::Field(r[..end].to_string()));
+            rest = &r[end..];
+        } else if let Some(r) = rest.strip_prefix('[') {
+            let Some((index, r)) = r.split_once(']') else {
+                return Err(format!("unclosed '[' in path '{path}'"));
+            };
+            let index = index
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid index '{index}' in path '{path}'"))?;
+            selectors.push(Selector::Index(index));
+            rest = r;
+        } else {
+            return Err(format!("unexpected '{rest}' in path '{path}'"));
+        }
+    }
+    Ok(selectors)
+}
+
+impl PayloadFilter {
+    pub(crate) fn parse(source: &str) -> Result<PayloadFilter, String> {
+        // the 1st operator in the expression (preferring the 2-characters ones at a same position)
+        let (index, op_str, op) = OPERATORS
+            .iter()
+            .filter_map(|(s, op)| source.find(s).map(|i| (i, *s, *op)))
+            .min_by_key(|(i, s, _)| (*i, usize::MAX - s.len()))
+            .ok_or_else(|| format!("no comparison operator in filter '{source}'"))?;
+        let path = parse_path(source[..index].trim())?;
+        let literal = source[index + op_str.len()..].trim();
+        let value = serde_json::from_str(literal)
+            .map_err(|_| format!("invalid JSON literal '{literal}' in filter '{source}'"))?;
+        Ok(PayloadFilter {
+            source: source.to_string(),
+            path,
+            op,
+            value,
+        })
+    }
+
+    // Returns true if the payload is JSON (or CBOR, according to its encoding) and matches the expression
+    pub(crate) fn matches(&self, encoding: &Encoding, payload: &[u8]) -> bool {
+        let document: serde_json::Value = if encoding.to_string().starts_with(CBOR_ENCODING) {
+            match ciborium::de::from_reader(payload) {
+                Ok(document) => document,
+                Err(_) => return false,
+            }
+        } else {
+            match serde_json::from_slice(payload) {
+                Ok(document) => document,
+                Err(_) => return false,
+            }
+        };
+        let selected = self
+            .path
+            .iter()
+            .try_fold(&document, |value, selector| match selector {
+                Selector::Field(field) => value.get(field),
+                Selector::Index(index) => value.get(index),
+            });
+        match selected {
+            Some(selected) => self.compare(selected),
+            None => false,
+        }
+    }
+
+    fn compare(&self, selected: &serde_json::Value) -> bool {
+        use std::cmp::Ordering;
+        let ordering = match (selected, &self.value) {
+            (serde_json::Value::Number(a), serde_json::Value::Number(b)) => {
+                match (a.as_f64(), b.as_f64()) {
+                    (Some(a), Some(b)) => a.partial_cmp(&b),
+                    _ => None,
+                }
+            }
+            (serde_json::Value::String(a), serde_json::Value::String(b)) => Some(a.cmp(b)),
+            (a, b) => (a == b).then_some(Ordering::Equal),
+        };
+        match self.op {
+            Op::Eq => ordering == Some(Ordering::Equal),
+            Op::Ne => ordering != Some(Ordering::Equal),
+            Op::Lt => ordering == Some(Ordering::Less),
+            Op::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            Op::Gt => ordering == Some(Ordering::Greater),
+            Op::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+        }
+    }
+}
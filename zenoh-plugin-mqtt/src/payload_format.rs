@@ -0,0 +1,100 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The payload format requested by a MQTT 5 subscription (via a "format" user property of SUBSCRIBE),
+// the zenoh publications being converted accordingly before their routing to the client
+// (e.g. CBOR to JSON for the thin dashboard clients).
+// A subscription can also request the metadata of the zenoh samples (via a "meta=true" user property),
+// sent as user properties of the PUBLISH packets (e.g. for the provenance without payload enveloping).
+
+use ntex_mqtt::v5::codec::UserProperties;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::num::NonZeroU32;
+use zenoh::plugins::ZResult;
+use zenoh::prelude::*;
+
+use crate::envelope::CBOR_ENCODING;
+use crate::payload_filter::PayloadFilter;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PayloadFormat {
+    Json,
+}
+
+impl PayloadFormat {
+    pub(crate) fn parse(s: &str) -> Option<PayloadFormat> {
+        match s {
+            "json" => Some(PayloadFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+// The delivery options requested by a MQTT 5 subscription via the properties of SUBSCRIBE
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct DeliveryOptions {
+    // if set, the payloads are converted to this format
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) format: Option<PayloadFormat>,
+    // if true, the metadata of the zenoh samples are sent as user properties
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) meta: bool,
+    // if set, only the publications whose payload matches this expression are sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) filter: Option<PayloadFilter>,
+    // if set, the subscription identifier sent with the publications
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) subscription_id: Option<NonZeroU32>,
+}
+
+impl DeliveryOptions {
+    // true if no option is requested (the publications being routed as is)
+    pub(crate) fn is_default(&self) -> bool {
+        *self == DeliveryOptions::default()
+    }
+}
+
+// The metadata of a zenoh sample, as MQTT 5 user properties: its kind, timestamp, source and encoding
+pub(crate) fn sample_metadata(sample: &Sample) -> UserProperties {
+    let mut properties: UserProperties = vec![
+        ("zenoh_kind".into(), sample.kind.to_string().into()),
+        ("zenoh_encoding".into(), sample.encoding.to_string().into()),
+    ];
+    if let Some(timestamp) = &sample.timestamp {
+        properties.push(("zenoh_timestamp".into(), timestamp.to_string().into()));
+    }
+    if let Some(source) = &sample.source_info.source_id {
+        properties.push(("zenoh_source".into(), source.to_string().into()));
+    }
+    properties
+}
+
+// Convert a payload to the requested format, according to its zenoh encoding. The payloads already in the
+// requested format (or whose format cannot be decoded without a schema, e.g. protobuf) are returned as is.
+pub(crate) fn convert<'a>(
+    format: PayloadFormat,
+    encoding: &Encoding,
+    payload: Cow<'a, [u8]>,
+) -> ZResult<Cow<'a, [u8]>> {
+    match format {
+        PayloadFormat::Json if encoding.to_string().starts_with(CBOR_ENCODING) => {
+            let value: serde_json::Value = ciborium::de::from_reader(&*payload)
+                .map_err(|e| zerror!("Failed to decode CBOR payload: {}", e))?;
+            Ok(Cow::Owned(serde_json::to_vec(&value)?))
+        }
+        PayloadFormat::Json => Ok(payload),
+    }
+}
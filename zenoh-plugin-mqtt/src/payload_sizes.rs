@@ -0,0 +1,96 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// Histograms of the size of the payloads routed through the bridge since its start, per direction
+// (as for the latencies). The buckets are powers of 2 (up to 256 MiB, larger payloads being accounted
+// in the last bucket), so a device suddenly sending oversized payloads shows up in the upper buckets.
+
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::latency::Direction;
+
+// the upper bound of the last bucket is 2^28 bytes
+const BUCKETS: usize = 29;
+
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, size: usize) {
+        // the index of the smallest power of 2 greater than or equal to the size
+        let index = size.max(1).next_power_of_two().trailing_zeros() as usize;
+        self.buckets[index.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(size as u64, Ordering::Relaxed);
+        self.max.fetch_max(size as u64, Ordering::Relaxed);
+    }
+
+    fn to_json(&self) -> Value {
+        let count = self.count.load(Ordering::Relaxed);
+        let mean_bytes =
+            (count > 0).then(|| self.sum.load(Ordering::Relaxed) as f64 / count as f64);
+        // the non-empty buckets, by upper bound (in bytes)
+        let mut buckets = serde_json::Map::new();
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            let n = bucket.load(Ordering::Relaxed);
+            if n > 0 {
+                buckets.insert(format!("le_{}", 1u64 << i), n.into());
+            }
+        }
+        serde_json::json!({
+            "count": count,
+            "mean_bytes": mean_bytes,
+            "max_bytes": self.max.load(Ordering::Relaxed),
+            "buckets": buckets,
+        })
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref MQTT_TO_ZENOH: Histogram = Histogram::new();
+    static ref ZENOH_TO_MQTT: Histogram = Histogram::new();
+}
+
+fn histogram(direction: Direction) -> &'static Histogram {
+    match direction {
+        Direction::MqttToZenoh => &MQTT_TO_ZENOH,
+        Direction::ZenohToMqtt => &ZENOH_TO_MQTT,
+    }
+}
+
+pub(crate) fn record(direction: Direction, size: usize) {
+    histogram(direction).record(size);
+}
+
+pub(crate) fn to_json() -> Value {
+    let mut json = serde_json::Map::new();
+    for direction in Direction::ALL {
+        json.insert(direction.as_str().into(), histogram(direction).to_json());
+    }
+    Value::Object(json)
+}
@@ -0,0 +1,83 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The process-wide state of the plugin (e.g. the encryption keys or the audit log), set from the configuration
+// of the 1st MQTT bridge started in the process. As it can't be changed afterwards, the start of another bridge
+// with different settings fails, instead of silently running with the settings of the 1st one.
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+use zenoh::plugins::ZResult;
+
+pub(crate) struct ProcessWide<T> {
+    // the name of the settings
+    setting: &'static str,
+    // the digest of the settings, and the state (None if not configured)
+    cell: OnceLock<(Vec<u8>, Option<T>)>,
+}
+
+impl<T> ProcessWide<T> {
+    pub(crate) const fn new(setting: &'static str) -> ProcessWide<T> {
+        ProcessWide {
+            setting,
+            cell: OnceLock::new(),
+        }
+    }
+
+    // Set the state from the settings (including their secrets), unless it's already set from the same ones.
+    // Returns true if set by this call, and fails if it was set from different settings.
+    pub(crate) fn init<S: Serialize>(
+        &self,
+        settings: &S,
+        state: impl FnOnce() -> ZResult<Option<T>>,
+    ) -> ZResult<bool> {
+        let digest = Sha256::digest(serde_json::to_vec(settings)?).to_vec();
+        let mut set = false;
+        if self.cell.get().is_none() {
+            let state = state()?;
+            set = self.cell.set((digest.clone(), state)).is_ok();
+        }
+        match self.cell.get() {
+            Some((d, _)) if *d != digest => bail!(
+                "The '{}' settings differ from the ones of the MQTT bridge already started in this process (a single bridge can run per process)",
+                self.setting
+            ),
+            _ => Ok(set),
+        }
+    }
+
+    pub(crate) fn get(&self) -> Option<&T> {
+        self.cell.get()?.1.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_wide_settings() {
+        static STATE: ProcessWide<u32> = ProcessWide::new("test");
+        assert!(STATE.init(&Some(1), || Ok(Some(1))).unwrap());
+        assert_eq!(STATE.get(), Some(&1));
+        // the same settings
+        assert!(!STATE.init(&Some(1), || Ok(Some(2))).unwrap());
+        assert_eq!(STATE.get(), Some(&1));
+        // different settings, or no settings
+        assert!(STATE.init(&Some(2), || Ok(Some(2))).is_err());
+        assert!(STATE.init(&None::<u32>, || Ok(None)).is_err());
+        assert_eq!(STATE.get(), Some(&1));
+    }
+}
@@ -0,0 +1,257 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The provisioning of the MQTT clients connecting for the 1st time: a provisioning event is published on
+// "<key_expr>/<client_id>", or sent as the value of a zenoh query on this key expression, the CONNACK being
+// held until a reply (or the timeout). A backend can thus register a device before its traffic flows, and
+// reject its connection with an error reply. The already seen client ids can be saved in a file.
+
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zenoh::plugins::ZResult;
+use zenoh::prelude::r#async::*;
+use zenoh_core::zlock;
+
+use crate::config::ProvisioningConfig;
+use crate::events::ProtocolVersion;
+use crate::mqtt_helpers::escape_topic_level;
+
+#[derive(Debug)]
+pub(crate) struct Provisioning {
+    zsession: Arc<Session>,
+    key_expr: OwnedKeyExpr,
+    // if set, the CONNACK is held until a reply to the provisioning query (or this timeout)
+    wait: Option<Duration>,
+    reject_on_timeout: bool,
+    seen_file: Option<String>,
+    seen: Mutex<HashSet<String>>,
+    provisioned: AtomicU64,
+    rejected: AtomicU64,
+    timeouts: AtomicU64,
+}
+
+impl Provisioning {
+    pub(crate) fn new(zsession: Arc<Session>, config: &ProvisioningConfig) -> Provisioning {
+        let mut seen = HashSet::new();
+        if let Some(path) = &config.seen_file {
+            match std::fs::read_to_string(path) {
+                Ok(content) => seen.extend(
+                    content
+                        .lines()
+                        .filter(|l| !l.is_empty())
+                        .map(ToString::to_string),
+                ),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => log::warn!(
+                    "Failed to read MQTT provisioned clients from {}: {}",
+                    path,
+                    e
+                ),
+            }
+            log::debug!(
+                "{} MQTT clients already provisioned (from {})",
+                seen.len(),
+                path
+            );
+        }
+        Provisioning {
+            zsession,
+            key_expr: config.key_expr.clone(),
+            wait: config.wait_ms.map(Duration::from_millis),
+            reject_on_timeout: config.reject_on_timeout,
+            seen_file: config.seen_file.clone(),
+            seen: Mutex::new(seen),
+            provisioned: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+        }
+    }
+
+    fn event(
+        client_id: &str,
+        protocol: ProtocolVersion,
+        username: Option<&str>,
+        address: Option<IpAddr>,
+    ) -> String {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        serde_json::json!({
+            "client_id": client_id,
+            "protocol": protocol.as_str(),
+            "username": username,
+            "address": address.map(|a| a.to_string()),
+            "timestamp": timestamp,
+        })
+        .to_string()
+    }
+
+    fn client_ke(&self, client_id: &str) -> ZResult<OwnedKeyExpr> {
+        Ok(&*self.key_expr / keyexpr::new(escape_topic_level(client_id).as_ref())?)
+    }
+
+    // Mark the client id as seen. Returns false if it was already.
+    fn first_connection(&self, client_id: &str) -> bool {
+        zlock!(self.seen).insert(client_id.to_string())
+    }
+
+    fn provisioned(&self, client_id: &str) {
+        self.provisioned.fetch_add(1, Ordering::Relaxed);
+        if let Some(path) = &self.seen_file {
+            if let Err(e) = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{client_id}"))
+            {
+                log::warn!("Failed to save MQTT provisioned client to {}: {}", path, e);
+            }
+        }
+    }
+
+    // Provision the client if it connects for the 1st time, waiting for the reply of the backend if configured.
+    // Returns an error (with the reason) if the connection must be rejected.
+    pub(crate) async fn on_connect(
+        &self,
+        client_id: &str,
+        protocol: ProtocolVersion,
+        username: Option<&str>,
+        address: Option<IpAddr>,
+    ) -> Result<(), String> {
+        if !self.first_connection(client_id) {
+            return Ok(());
+        }
+        let Some(wait) = self.wait else {
+            self.publish(client_id, protocol, username, address);
+            return Ok(());
+        };
+        let ke = match self.client_ke(client_id) {
+            Ok(ke) => ke,
+            Err(e) => {
+                log::warn!("MQTT client {} can't be provisioned: {}", client_id, e);
+                return Ok(());
+            }
+        };
+        log::debug!(
+            "MQTT client {} connects for the 1st time - query {}",
+            client_id,
+            ke
+        );
+        let event = Self::event(client_id, protocol, username, address);
+        let replies = match self
+            .zsession
+            .get(&ke)
+            .with_value(Value::from(event).encoding(Encoding::APP_JSON))
+            .timeout(wait)
+            .res()
+            .await
+        {
+            Ok(replies) => replies,
+            Err(e) => {
+                zlock!(self.seen).remove(client_id);
+                return Err(format!("provisioning query failed: {e}"));
+            }
+        };
+        match replies.recv_async().await {
+            Ok(reply) => match reply.sample {
+                Ok(_) => {
+                    self.provisioned(client_id);
+                    Ok(())
+                }
+                Err(e) => {
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+                    zlock!(self.seen).remove(client_id);
+                    Err(format!("provisioning rejected: {e}"))
+                }
+            },
+            // without reply, the client will be provisioned again at its next connection
+            Err(_) => {
+                self.timeouts.fetch_add(1, Ordering::Relaxed);
+                zlock!(self.seen).remove(client_id);
+                log::warn!(
+                    "MQTT client {}: no reply to provisioning query on {}",
+                    client_id,
+                    ke
+                );
+                if self.reject_on_timeout {
+                    Err("provisioning timeout".into())
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    // Publish the provisioning event of the client if it connects for the 1st time, without waiting
+    // (e.g. for the MQTT-SN clients, whose connection can't be held)
+    pub(crate) fn on_connect_no_wait(
+        &self,
+        client_id: &str,
+        protocol: ProtocolVersion,
+        address: Option<IpAddr>,
+    ) {
+        if self.first_connection(client_id) {
+            self.publish(client_id, protocol, None, address);
+        }
+    }
+
+    fn publish(
+        &self,
+        client_id: &str,
+        protocol: ProtocolVersion,
+        username: Option<&str>,
+        address: Option<IpAddr>,
+    ) {
+        let ke = match self.client_ke(client_id) {
+            Ok(ke) => ke,
+            Err(e) => {
+                log::warn!("MQTT client {} can't be provisioned: {}", client_id, e);
+                return;
+            }
+        };
+        log::debug!(
+            "MQTT client {} connects for the 1st time - publish on {}",
+            client_id,
+            ke
+        );
+        let event = Self::event(client_id, protocol, username, address);
+        let zsession = self.zsession.clone();
+        async_std::task::spawn(async move {
+            if let Err(e) = zsession
+                .put(&ke, event)
+                .encoding(Encoding::APP_JSON)
+                .res()
+                .await
+            {
+                log::warn!("Failed to publish MQTT provisioning event on {}: {}", ke, e);
+            }
+        });
+        self.provisioned(client_id);
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "seen_clients": zlock!(self.seen).len(),
+            "provisioned": self.provisioned.load(Ordering::Relaxed),
+            "rejected": self.rejected.load(Ordering::Relaxed),
+            "timeouts": self.timeouts.load(Ordering::Relaxed),
+        })
+    }
+}
@@ -0,0 +1,189 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+//! Pseudonymization of some MQTT topic levels (e.g. device serial numbers) in the Zenoh key expressions.
+//!
+//! For the topics matching a configured rule, a level is replaced with a pseudonym when mapped to a key
+//! expression, and revealed when a key expression is mapped back to a topic for the MQTT clients.
+//! With the configured key, a pseudonym is the hexadecimal encoding of a deterministic AES-256-GCM
+//! encryption of the level (the nonce being derived from the key and the level), so the same level
+//! always gets the same pseudonym and only the key holders can reveal it.
+//!
+//! An application embedding the plugin can provide its own mapping (e.g. a lookup table in a vault)
+//! by registering a [`Pseudonymizer`] with [`register_pseudonymizer()`].
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::sync::{Arc, RwLock};
+use zenoh::plugins::ZResult;
+use zenoh_core::{zread, zwrite};
+
+use crate::config::Config;
+use crate::mqtt_helpers::{topic_matches_filter, MQTT_SEPARATOR};
+use crate::process_wide::ProcessWide;
+
+const NONCE_SIZE: usize = 12;
+
+/// A reversible mapping of MQTT topic levels to pseudonyms.
+pub trait Pseudonymizer: Send + Sync {
+    /// Returns the pseudonym of a topic level. It must be a valid key expression chunk
+    /// (i.e. without '/', '*', '$', '?' or '#').
+    fn pseudonymize(&self, level: &str) -> ZResult<String>;
+    /// Returns the topic level of a pseudonym, if it's one.
+    fn reveal(&self, pseudonym: &str) -> Option<String>;
+}
+
+lazy_static::lazy_static! {
+    static ref PSEUDONYMIZER: RwLock<Option<Arc<dyn Pseudonymizer>>> = RwLock::new(None);
+}
+
+/// Register the pseudonymizer used if no key is configured (replacing the previous one, if any).
+pub fn register_pseudonymizer(pseudonymizer: Arc<dyn Pseudonymizer>) {
+    *zwrite!(PSEUDONYMIZER) = Some(pseudonymizer);
+}
+
+// The pseudonymizer using the configured key
+struct KeyedPseudonymizer {
+    key: Vec<u8>,
+    cipher: Aes256Gcm,
+}
+
+impl Pseudonymizer for KeyedPseudonymizer {
+    fn pseudonymize(&self, level: &str) -> ZResult<String> {
+        let digest = Sha256::new()
+            .chain_update(&self.key)
+            .chain_update(level.as_bytes())
+            .finalize();
+        let nonce = Nonce::from_slice(&digest[..NONCE_SIZE]);
+        let ciphertext = self
+            .cipher
+            .encrypt(nonce, level.as_bytes())
+            .map_err(|e| zerror!("Failed to pseudonymize topic level: {}", e))?;
+        let mut pseudonym = nonce.to_vec();
+        pseudonym.extend_from_slice(&ciphertext);
+        Ok(hex::encode(pseudonym))
+    }
+
+    fn reveal(&self, pseudonym: &str) -> Option<String> {
+        let bytes = hex::decode(pseudonym).ok()?;
+        if bytes.len() < NONCE_SIZE {
+            return None;
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_SIZE);
+        let level = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .ok()?;
+        String::from_utf8(level).ok()
+    }
+}
+
+struct Rules {
+    // the MQTT topic filters and the index of their pseudonymized level
+    rules: Vec<(String, usize)>,
+    keyed: Option<KeyedPseudonymizer>,
+}
+
+static RULES: ProcessWide<Rules> = ProcessWide::new("pseudonyms");
+
+pub(crate) fn init(config: &Config) -> ZResult<()> {
+    // the key is not serialized with the settings
+    let key = config.pseudonyms.as_ref().map(|c| &c.key);
+    RULES.init(&(&config.pseudonyms, key), || {
+        let Some(conf) = &config.pseudonyms else {
+            return Ok(None);
+        };
+        let keyed = match &conf.key {
+            Some(key) => {
+                let key = hex::decode(key)
+                    .ok()
+                    .filter(|k| k.len() == 32)
+                    .ok_or_else(|| {
+                        zerror!(
+                            "Invalid pseudonyms key: must be 64 hexadecimal characters (256 bits)"
+                        )
+                    })?;
+                let cipher = Aes256Gcm::new_from_slice(&key)
+                    .map_err(|_| zerror!("Invalid pseudonyms key: must be 256 bits"))?;
+                Some(KeyedPseudonymizer { key, cipher })
+            }
+            None => None,
+        };
+        let rules = conf
+            .rules
+            .iter()
+            .map(|r| (r.topic.clone(), r.level))
+            .collect();
+        Ok(Some(Rules { rules, keyed }))
+    })?;
+    Ok(())
+}
+
+fn with_pseudonymizer<T>(rules: &Rules, f: impl FnOnce(&dyn Pseudonymizer) -> T) -> Option<T> {
+    match &rules.keyed {
+        Some(keyed) => Some(f(keyed)),
+        None => zread!(PSEUDONYMIZER).as_deref().map(f),
+    }
+}
+
+// The index of the pseudonymized level of a MQTT topic (or topic filter), if it matches a rule
+fn pseudonymized_level(rules: &Rules, topic: &str) -> Option<usize> {
+    rules
+        .rules
+        .iter()
+        .find(|(filter, _)| topic_matches_filter(topic, filter))
+        .map(|(_, level)| *level)
+}
+
+// Replace a level of a MQTT topic (or topic filter) with its pseudonym, if the topic matches a rule.
+// The wildcards are not pseudonymized.
+pub(crate) fn pseudonymize(topic: &str) -> ZResult<Option<String>> {
+    let Some(rules) = RULES.get() else {
+        return Ok(None);
+    };
+    let Some(index) = pseudonymized_level(rules, topic) else {
+        return Ok(None);
+    };
+    let mut levels: Vec<Cow<str>> = topic.split(MQTT_SEPARATOR).map(Cow::Borrowed).collect();
+    let pseudonym = match levels.get(index).map(|l| l.as_ref()) {
+        Some("+" | "#") | None => return Ok(None),
+        Some(level) => {
+            with_pseudonymizer(rules, |p| p.pseudonymize(level)).ok_or_else(|| {
+                zerror!(
+                    "No pseudonyms key configured nor Pseudonymizer registered for '{}'",
+                    topic
+                )
+            })??
+        }
+    };
+    levels[index] = Cow::Owned(pseudonym);
+    Ok(Some(levels.join("/")))
+}
+
+// Reveal the pseudonymized level of a MQTT topic, if it matches a rule and has a valid pseudonym
+pub(crate) fn reveal(topic: &str) -> Option<String> {
+    let rules = RULES.get()?;
+    let index = pseudonymized_level(rules, topic)?;
+    let mut levels: Vec<&str> = topic.split(MQTT_SEPARATOR).collect();
+    let level = with_pseudonymizer(rules, |p| p.reveal(levels.get(index)?))??;
+    levels[index] = &level;
+    Some(levels.join("/"))
+}
+
+// Reveal a single pseudonym (for the admin space)
+pub(crate) fn reveal_pseudonym(pseudonym: &str) -> Option<String> {
+    let rules = RULES.get()?;
+    with_pseudonymizer(rules, |p| p.reveal(pseudonym))?
+}
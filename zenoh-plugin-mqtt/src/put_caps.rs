@@ -0,0 +1,86 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// Caps on the number of concurrent zenoh puts for the MQTT publications on some topics,
+// shared by all the MQTT clients. A publication exceeding the cap waits for the completion
+// of a previous put, slowing down the publishing client rather than the downstream storages.
+
+use zenoh::plugins::ZResult;
+
+use crate::config::InflightPutsConfig;
+use crate::mqtt_helpers::topic_matches_filter;
+
+#[derive(Debug)]
+struct Cap {
+    topic: String,
+    // a bounded channel used as a semaphore: a permit is a message in the channel
+    tx: flume::Sender<()>,
+    rx: flume::Receiver<()>,
+}
+
+#[derive(Debug)]
+pub(crate) struct PutCaps {
+    caps: Vec<Cap>,
+}
+
+// The permission to put a publication, released when dropped
+pub(crate) struct PutPermit {
+    rx: flume::Receiver<()>,
+}
+
+impl Drop for PutPermit {
+    fn drop(&mut self) {
+        let _ = self.rx.try_recv();
+    }
+}
+
+impl PutCaps {
+    pub(crate) fn new(configs: &[InflightPutsConfig]) -> ZResult<PutCaps> {
+        let mut caps = Vec::with_capacity(configs.len());
+        for conf in configs {
+            if conf.max == 0 {
+                bail!(
+                    "Invalid 'max_inflight_puts' for topic '{}': max must be at least 1",
+                    conf.topic
+                );
+            }
+            let (tx, rx) = flume::bounded(conf.max);
+            caps.push(Cap {
+                topic: conf.topic.clone(),
+                tx,
+                rx,
+            });
+        }
+        Ok(PutCaps { caps })
+    }
+
+    // Wait for a permit to put a publication on `topic`, if it matches a capped topic filter
+    // (the 1st matching one in the configuration order)
+    pub(crate) async fn acquire(&self, topic: &str) -> Option<PutPermit> {
+        let cap = self
+            .caps
+            .iter()
+            .find(|cap| topic_matches_filter(topic, &cap.topic))?;
+        if cap.tx.is_full() {
+            log::trace!(
+                "Max in-flight puts reached for '{}' - wait for publication on '{}'",
+                cap.topic,
+                topic
+            );
+        }
+        // never fails since the receiver is owned by the cap
+        let _ = cap.tx.send_async(()).await;
+        Some(PutPermit { rx: cap.rx.clone() })
+    }
+}
@@ -0,0 +1,178 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The "query on publish" rules: a MQTT publication on a trigger topic makes the bridge query zenoh on a
+// templated selector, and publish the replies (as a JSON object of the values per MQTT topic) on a
+// response topic, possibly from a short-lived cache.
+
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use zenoh::plugins::ZResult;
+use zenoh::prelude::r#async::*;
+use zenoh_core::zlock;
+
+use crate::config::{Config, QueryRuleConfig};
+use crate::mqtt_helpers::{
+    ke_to_mqtt_topic_publish, mqtt_topic_to_ke, topic_matches_filter, TopicAcl, MQTT_SEPARATOR,
+};
+
+#[derive(Debug)]
+pub(crate) struct QueryRules {
+    zsession: Arc<Session>,
+    config: Arc<Config>,
+    acl: Arc<TopicAcl>,
+    // the cached responses, per selector
+    cache: Mutex<HashMap<String, (Instant, Vec<u8>)>>,
+}
+
+// Replace "{N}" with the level N of the topic, and "{payload}" with the payload
+fn expand(template: &str, topic: &str, payload: &[u8]) -> String {
+    let mut expanded = template.replace("{payload}", &String::from_utf8_lossy(payload));
+    for (i, level) in topic.split(MQTT_SEPARATOR).enumerate() {
+        expanded = expanded.replace(&format!("{{{i}}}"), level);
+    }
+    expanded
+}
+
+impl QueryRules {
+    pub(crate) fn new(
+        zsession: Arc<Session>,
+        config: Arc<Config>,
+        acl: Arc<TopicAcl>,
+    ) -> QueryRules {
+        QueryRules {
+            zsession,
+            config,
+            acl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // If the topic matches the trigger of a rule, spawn its query and return true
+    pub(crate) fn trigger(
+        self: &Arc<Self>,
+        client_id: &str,
+        topic: &str,
+        payload: &[u8],
+        scope: &Option<OwnedKeyExpr>,
+    ) -> bool {
+        let Some(rule) = self
+            .config
+            .query_rules
+            .iter()
+            .find(|r| topic_matches_filter(topic, &r.trigger))
+        else {
+            return false;
+        };
+        let selector = expand(&rule.selector, topic, payload);
+        let response_topic = expand(&rule.response_topic, topic, payload);
+        log::debug!(
+            "MQTT client {}: publication on '{}' triggers a query on '{}' (response on '{}')",
+            client_id,
+            topic,
+            selector,
+            response_topic
+        );
+        let this = self.clone();
+        let rule = rule.clone();
+        let client_id = client_id.to_string();
+        let scope = scope.clone();
+        async_std::task::spawn(async move {
+            if let Err(e) = this
+                .query_and_respond(&rule, &selector, &response_topic, &scope)
+                .await
+            {
+                log::warn!(
+                    "MQTT client {}: query on '{}' triggered by '{}' failed: {}",
+                    client_id,
+                    selector,
+                    rule.trigger,
+                    e
+                );
+            }
+        });
+        true
+    }
+
+    async fn query_and_respond(
+        &self,
+        rule: &QueryRuleConfig,
+        selector: &str,
+        response_topic: &str,
+        scope: &Option<OwnedKeyExpr>,
+    ) -> ZResult<()> {
+        let cached = rule.cache_ms.and_then(|cache_ms| {
+            let mut cache = zlock!(self.cache);
+            cache.retain(|_, (time, _)| time.elapsed() < Duration::from_millis(cache_ms));
+            cache.get(selector).map(|(_, response)| response.clone())
+        });
+        let response = match cached {
+            Some(response) => response,
+            None => {
+                let response = self.query(rule, selector, scope).await?;
+                if rule.cache_ms.is_some() {
+                    zlock!(self.cache)
+                        .insert(selector.to_string(), (Instant::now(), response.clone()));
+                }
+                response
+            }
+        };
+        let ke = mqtt_topic_to_ke(response_topic, scope, self.config.ke_chunks.as_ref())?;
+        let destination = if self.acl.is_allowed(response_topic) {
+            Locality::Any
+        } else {
+            Locality::SessionLocal
+        };
+        self.zsession
+            .put(&ke, response)
+            .encoding(Encoding::APP_JSON)
+            .allowed_destination(destination)
+            .res()
+            .await
+    }
+
+    // Query zenoh, returning the replies as a JSON object of the values per MQTT topic
+    async fn query(
+        &self,
+        rule: &QueryRuleConfig,
+        selector: &str,
+        scope: &Option<OwnedKeyExpr>,
+    ) -> ZResult<Vec<u8>> {
+        let replies = self
+            .zsession
+            .get(selector)
+            .timeout(Duration::from_millis(rule.timeout_ms))
+            .res()
+            .await?;
+        let mut values = Map::new();
+        while let Ok(reply) = replies.recv_async().await {
+            match reply.sample {
+                Ok(sample) => {
+                    let topic = ke_to_mqtt_topic_publish(&sample.key_expr, scope)
+                        .map(|t| t.to_string())
+                        .unwrap_or_else(|_| sample.key_expr.to_string());
+                    let payload = sample.payload.contiguous();
+                    let value = serde_json::from_slice(&payload).unwrap_or_else(|_| {
+                        Value::String(String::from_utf8_lossy(&payload).into_owned())
+                    });
+                    values.insert(topic, value);
+                }
+                Err(e) => log::debug!("Error reply to query on '{}': {}", selector, e),
+            }
+        }
+        Ok(serde_json::to_vec(&Value::Object(values))?)
+    }
+}
@@ -0,0 +1,55 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// Readiness of the MQTT plugin, for the process supervisors (e.g. systemd): the plugin is ready
+// once its zenoh session is open and its MQTT listener is bound. A failure to start is reported too.
+
+use std::sync::OnceLock;
+use zenoh::plugins::ZResult;
+
+lazy_static::lazy_static! {
+    static ref STARTED: (flume::Sender<()>, flume::Receiver<()>) = flume::unbounded();
+}
+
+// the outcome of the start: Ok once ready, or the error that prevented it
+static OUTCOME: OnceLock<Result<(), String>> = OnceLock::new();
+
+/// Wait for the MQTT plugin to be ready, i.e. its zenoh session is open and its MQTT listener is bound.
+/// Fails if the plugin failed to start (e.g. on an invalid configuration).
+pub async fn ready() -> ZResult<()> {
+    if OUTCOME.get().is_none() {
+        let _ = STARTED.1.recv_async().await;
+        // wake the next waiter, if any
+        let _ = STARTED.0.send(());
+    }
+    match OUTCOME.get() {
+        Some(Err(e)) => bail!("MQTT plugin failed to start: {}", e),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn notify_ready() {
+    notify(Ok(()));
+}
+
+// Notify that the plugin failed to start (ignored once ready)
+pub(crate) fn notify_failed(error: String) {
+    notify(Err(error));
+}
+
+fn notify(outcome: Result<(), String>) {
+    if OUTCOME.set(outcome).is_ok() {
+        let _ = STARTED.0.send(());
+    }
+}
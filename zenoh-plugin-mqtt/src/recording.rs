@@ -0,0 +1,291 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The recording of the routed messages (in both directions) as JSON lines in a file, and their replay at the
+// original or an accelerated speed: the MQTT publications are put on zenoh again, and the zenoh publications
+// are sent again to the MQTT clients which received them (if connected). E.g. to reproduce field bugs or for
+// load testing.
+
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use zenoh::plugins::ZResult;
+use zenoh::prelude::r#async::*;
+
+use crate::clients::ClientsRegistry;
+use crate::config::{Config, ReplayConfig};
+use crate::mqtt_helpers::{topic_matches_filter, OutgoingProperties};
+use crate::process_wide::ProcessWide;
+
+static RECORDER: ProcessWide<(String, flume::Sender<Record>)> = ProcessWide::new("record");
+static REPLAYING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Direction {
+    MqttToZenoh,
+    ZenohToMqtt,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Record {
+    // in microseconds since the UNIX epoch
+    timestamp_us: u64,
+    direction: Direction,
+    client_id: String,
+    topic: String,
+    key_expr: String,
+    encoding: String,
+    // true for a MQTT publication only routed to the local MQTT subscribers (see 'allow' and 'deny')
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    local: bool,
+    // hex encoded
+    payload: String,
+}
+
+// Spawn the task writing the records, if the recording is configured
+pub(crate) fn init(config: &Config) -> ZResult<()> {
+    let mut receiver = None;
+    let set = RECORDER.init(&config.record, || {
+        let Some(conf) = &config.record else {
+            return Ok(None);
+        };
+        let (tx, rx) = flume::unbounded::<Record>();
+        receiver = Some(rx);
+        Ok(Some((conf.topic.clone(), tx)))
+    })?;
+    let (true, Some(conf), Some(rx)) = (set, &config.record, receiver) else {
+        return Ok(());
+    };
+    let path = conf.file.clone();
+    log::info!("Record the routed MQTT messages in {}", path);
+    async_std::task::spawn(async move {
+        let mut file = match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                log::error!("Failed to open MQTT recording file {}: {}", path, e);
+                return;
+            }
+        };
+        while let Ok(record) = rx.recv_async().await {
+            let line = match serde_json::to_string(&record) {
+                Ok(line) => line,
+                Err(e) => {
+                    log::warn!("Failed to serialize MQTT record: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = writeln!(file, "{line}") {
+                log::warn!("Failed to write MQTT record to {}: {}", path, e);
+            }
+        }
+    });
+    Ok(())
+}
+
+// Record a routed message, if the recording is configured and the topic matches its filter
+pub(crate) fn record(
+    direction: Direction,
+    client_id: &str,
+    topic: &str,
+    ke: &keyexpr,
+    encoding: &Encoding,
+    payload: &[u8],
+    local: bool,
+) {
+    let Some((filter, tx)) = RECORDER.get() else {
+        return;
+    };
+    if !topic_matches_filter(topic, filter) {
+        return;
+    }
+    let timestamp_us = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or_default();
+    let _ = tx.send(Record {
+        timestamp_us,
+        direction,
+        client_id: client_id.to_string(),
+        topic: topic.to_string(),
+        key_expr: ke.to_string(),
+        encoding: encoding.to_string(),
+        local,
+        payload: hex::encode(payload),
+    });
+}
+
+// The path of a recording file (relative to the replay directory, or absolute), that must be in the
+// replay directory once the symbolic links and ".." are resolved
+fn recording_path(conf: &ReplayConfig, file: &str) -> ZResult<PathBuf> {
+    let dir = Path::new(&conf.dir)
+        .canonicalize()
+        .map_err(|e| zerror!("invalid replay directory {}: {}", conf.dir, e))?;
+    let path = dir
+        .join(file)
+        .canonicalize()
+        .map_err(|e| zerror!("failed to read MQTT recording file {}: {}", file, e))?;
+    if !path.starts_with(&dir) || !path.is_file() {
+        bail!(
+            "MQTT recording file {} is not a file in the replay directory {}",
+            file,
+            conf.dir
+        );
+    }
+    Ok(path)
+}
+
+// Spawn the replay of a recording file of the replay directory, with the delays between the messages divided
+// by `speed` (e.g. 2.0 for twice faster). Fails if a replay is already running or the file can't be read.
+pub(crate) fn replay(
+    zsession: Arc<Session>,
+    clients: Arc<ClientsRegistry>,
+    conf: &ReplayConfig,
+    file: &str,
+    speed: f64,
+) -> ZResult<()> {
+    if speed.is_nan() || speed <= 0.0 {
+        bail!("invalid replay speed: {}", speed);
+    }
+    let path = recording_path(conf, file)?;
+    let content = std::fs::read_to_string(&path).map_err(|e| {
+        zerror!(
+            "failed to read MQTT recording file {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    if REPLAYING.swap(true, Ordering::AcqRel) {
+        bail!("a replay is already running");
+    }
+    let path = path.display().to_string();
+    log::info!("Replay the MQTT recording {} (speed: {})", path, speed);
+    async_std::task::spawn(async move {
+        let start = Instant::now();
+        let mut first_timestamp = None;
+        let (mut replayed, mut skipped) = (0, 0);
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            let record: Record = match serde_json::from_str(line) {
+                Ok(record) => record,
+                Err(e) => {
+                    log::warn!("Skip invalid record in {}: {}", path, e);
+                    skipped += 1;
+                    continue;
+                }
+            };
+            let first = *first_timestamp.get_or_insert(record.timestamp_us);
+            let delay =
+                Duration::from_micros(record.timestamp_us.saturating_sub(first)).div_f64(speed);
+            if let Some(wait) = delay.checked_sub(start.elapsed()) {
+                async_std::task::sleep(wait).await;
+            }
+            match replay_record(&zsession, &clients, record).await {
+                Ok(true) => replayed += 1,
+                Ok(false) => skipped += 1,
+                Err(e) => {
+                    log::warn!("Failed to replay record from {}: {}", path, e);
+                    skipped += 1;
+                }
+            }
+        }
+        log::info!(
+            "MQTT recording {} replayed ({} messages replayed, {} skipped)",
+            path,
+            replayed,
+            skipped
+        );
+        REPLAYING.store(false, Ordering::Release);
+    });
+    Ok(())
+}
+
+// Replay a record. Returns false if skipped (its MQTT client not being connected).
+async fn replay_record(
+    zsession: &Session,
+    clients: &ClientsRegistry,
+    record: Record,
+) -> ZResult<bool> {
+    let payload = hex::decode(&record.payload).map_err(|e| zerror!("invalid payload: {}", e))?;
+    match record.direction {
+        Direction::MqttToZenoh => {
+            let destination = if record.local {
+                Locality::SessionLocal
+            } else {
+                Locality::Any
+            };
+            zsession
+                .put(record.key_expr, payload)
+                .encoding(Encoding::from(record.encoding))
+                .allowed_destination(destination)
+                .res()
+                .await?;
+            Ok(true)
+        }
+        Direction::ZenohToMqtt => match clients.sink(&record.client_id) {
+            Some(sink) => {
+                sink.publish_at_most_once(
+                    record.topic,
+                    payload.into(),
+                    OutgoingProperties::default(),
+                    None,
+                )?;
+                Ok(true)
+            }
+            None => Ok(false),
+        },
+    }
+}
+
+pub(crate) fn is_replaying() -> bool {
+    REPLAYING.load(Ordering::Acquire)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recording_path_in_replay_dir() {
+        let root = std::env::temp_dir().join(format!("mqtt-replay-{}", std::process::id()));
+        let dir = root.join("recordings");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("field.jsonl"), "").unwrap();
+        std::fs::write(root.join("secret"), "").unwrap();
+        let conf = ReplayConfig {
+            dir: dir.display().to_string(),
+        };
+        let expected = dir.join("field.jsonl").canonicalize().unwrap();
+        assert_eq!(recording_path(&conf, "field.jsonl").unwrap(), expected);
+        assert_eq!(
+            recording_path(&conf, &expected.display().to_string()).unwrap(),
+            expected
+        );
+        // the paths out of the replay directory are refused
+        assert!(recording_path(&conf, "../secret").is_err());
+        assert!(recording_path(&conf, &root.join("secret").display().to_string()).is_err());
+        assert!(recording_path(&conf, "/etc/passwd").is_err());
+        assert!(recording_path(&conf, ".").is_err());
+        assert!(recording_path(&conf, "missing.jsonl").is_err());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("secret"), dir.join("link.jsonl")).unwrap();
+            assert!(recording_path(&conf, "link.jsonl").is_err());
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
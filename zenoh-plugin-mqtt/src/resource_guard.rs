@@ -0,0 +1,163 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// A watchdog of the resources used by the plugin: the open file descriptors of the process (on Linux),
+// the memory of the publications queued for the MQTT clients, and the lag of the periodic checks
+// (revealing starved or blocked tasks). While a resource exceeds its limit, the new MQTT connections
+// are refused to protect the whole process (e.g. zenohd with other plugins).
+
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use zenoh_core::zlock;
+
+use crate::clients::ClientsRegistry;
+use crate::config::ResourceGuardConfig;
+
+#[derive(Debug, Default)]
+struct Measures {
+    open_fds: Option<usize>,
+    max_fds: Option<usize>,
+    queued_bytes: usize,
+    loop_lag: Duration,
+    alerts: Vec<String>,
+    // the time since which the plugin is overloaded (in seconds since the UNIX epoch)
+    overloaded_since: Option<u64>,
+}
+
+#[derive(Debug)]
+pub(crate) struct ResourceGuard {
+    config: ResourceGuardConfig,
+    clients: Arc<ClientsRegistry>,
+    overloaded: AtomicBool,
+    measures: Mutex<Measures>,
+    refused: AtomicU64,
+}
+
+// The number of open file descriptors of the process, and its (soft) limit
+#[cfg(target_os = "linux")]
+fn open_fds() -> (Option<usize>, Option<usize>) {
+    let open = std::fs::read_dir("/proc/self/fd").ok().map(|d| d.count());
+    let max = std::fs::read_to_string("/proc/self/limits")
+        .ok()
+        .and_then(|limits| {
+            limits
+                .lines()
+                .find(|l| l.starts_with("Max open files"))
+                .and_then(|l| l.split_whitespace().nth(3))
+                .and_then(|soft| soft.parse().ok())
+        });
+    (open, max)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_fds() -> (Option<usize>, Option<usize>) {
+    (None, None)
+}
+
+impl ResourceGuard {
+    pub(crate) fn new(config: &ResourceGuardConfig, clients: Arc<ClientsRegistry>) -> Self {
+        ResourceGuard {
+            config: config.clone(),
+            clients,
+            overloaded: AtomicBool::new(false),
+            measures: Mutex::new(Measures::default()),
+            refused: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn spawn_check_task(self: &Arc<Self>) {
+        let this = self.clone();
+        let interval = Duration::from_millis(self.config.check_interval_ms.max(1));
+        async_std::task::spawn(async move {
+            loop {
+                let start = Instant::now();
+                async_std::task::sleep(interval).await;
+                this.check(start.elapsed().saturating_sub(interval));
+            }
+        });
+    }
+
+    fn check(&self, loop_lag: Duration) {
+        let (open_fds, max_fds) = open_fds();
+        let queued_bytes = self.clients.queued_bytes();
+        let mut alerts = Vec::new();
+        if let (Some(open), Some(max)) = (open_fds, max_fds) {
+            if open as f64 > max as f64 * self.config.max_fds_ratio {
+                alerts.push(format!("{open} open file descriptors (limit: {max})"));
+            }
+        }
+        if let Some(max) = self.config.max_queued_bytes {
+            if queued_bytes > max {
+                alerts.push(format!(
+                    "{queued_bytes} bytes queued for the MQTT clients (limit: {max})"
+                ));
+            }
+        }
+        if loop_lag > Duration::from_millis(self.config.max_loop_lag_ms) {
+            alerts.push(format!(
+                "tasks lagging by {} ms (limit: {} ms)",
+                loop_lag.as_millis(),
+                self.config.max_loop_lag_ms
+            ));
+        }
+
+        let overloaded = !alerts.is_empty();
+        let mut measures = zlock!(self.measures);
+        if overloaded != self.overloaded.swap(overloaded, Ordering::AcqRel) {
+            if overloaded {
+                log::warn!(
+                    "MQTT plugin overloaded - refuse new connections: {}",
+                    alerts.join(", ")
+                );
+                measures.overloaded_since = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|d| d.as_secs());
+            } else {
+                log::info!("MQTT plugin no longer overloaded - accept new connections");
+                measures.overloaded_since = None;
+            }
+        }
+        measures.open_fds = open_fds;
+        measures.max_fds = max_fds;
+        measures.queued_bytes = queued_bytes;
+        measures.loop_lag = loop_lag;
+        measures.alerts = alerts;
+    }
+
+    // Returns true if a new connection must be refused (counting it)
+    pub(crate) fn refuse_connection(&self) -> bool {
+        let overloaded = self.overloaded.load(Ordering::Acquire);
+        if overloaded {
+            self.refused.fetch_add(1, Ordering::Relaxed);
+        }
+        overloaded
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        let measures = zlock!(self.measures);
+        serde_json::json!({
+            "overloaded": self.overloaded.load(Ordering::Acquire),
+            "overloaded_since": measures.overloaded_since,
+            "alerts": measures.alerts,
+            "refused_connections": self.refused.load(Ordering::Relaxed),
+            "open_fds": measures.open_fds,
+            "max_fds": measures.max_fds,
+            "queued_bytes": measures.queued_bytes,
+            "loop_lag_ms": measures.loop_lag.as_millis() as u64,
+        })
+    }
+}
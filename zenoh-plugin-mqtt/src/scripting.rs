@@ -0,0 +1,185 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// Rewrite of the topics/key expressions and payloads by Rhai scripts.
+// A script must define a `rewrite(topic, payload)` function returning either:
+//   - a map with the (possibly changed) "topic" and "payload" (as a string or a blob)
+//   - `()` to drop the message
+// Without the "rhai" feature all the functions of this module return the message unchanged.
+
+use std::borrow::Cow;
+use zenoh::plugins::ZResult;
+
+use crate::config::Config;
+
+// The result of a rewrite: None if the message must be dropped
+pub(crate) type Rewritten<'a> = Option<(Cow<'a, str>, Cow<'a, [u8]>)>;
+
+#[cfg(feature = "rhai")]
+mod script {
+    use super::*;
+    use crate::process_wide::ProcessWide;
+    use rhai::{Blob, Dynamic, Engine, Map, Scope, AST};
+    use std::cell::Cell;
+    use std::time::{Duration, Instant};
+
+    const REWRITE_FN: &str = "rewrite";
+
+    thread_local! {
+        // the deadline of the script being executed by this thread
+        static DEADLINE: Cell<Option<Instant>> = Cell::new(None);
+    }
+
+    struct Scripts {
+        engine: Engine,
+        max_time: Duration,
+        mqtt_to_zenoh: Option<AST>,
+        zenoh_to_mqtt: Option<AST>,
+    }
+
+    static SCRIPTS: ProcessWide<Scripts> = ProcessWide::new("scripts");
+
+    pub(super) fn init(config: &Config) -> ZResult<()> {
+        SCRIPTS.init(&config.scripts, || {
+            let Some(conf) = &config.scripts else {
+                return Ok(None);
+            };
+            // The engine is sandboxed: no access to the file system, and a bounded execution
+            let mut engine = Engine::new();
+            engine.set_module_resolver(rhai::module_resolvers::DummyModuleResolver::new());
+            engine.set_max_operations(conf.max_operations);
+            engine.set_max_call_levels(32);
+            engine.set_max_string_size(1024 * 1024);
+            engine.set_max_array_size(64 * 1024);
+            engine.set_max_map_size(1024);
+            engine.on_progress(|_| match DEADLINE.with(Cell::get) {
+                Some(deadline) if Instant::now() > deadline => Some("timeout".into()),
+                _ => None,
+            });
+            let compile = |path: &Option<String>| -> ZResult<Option<AST>> {
+                path.as_ref()
+                    .map(|p| {
+                        engine
+                            .compile_file(p.into())
+                            .map_err(|e| zerror!("Failed to compile script {}: {}", p, e).into())
+                    })
+                    .transpose()
+            };
+            let mqtt_to_zenoh = compile(&conf.mqtt_to_zenoh)?;
+            let zenoh_to_mqtt = compile(&conf.zenoh_to_mqtt)?;
+            Ok(Some(Scripts {
+                engine,
+                max_time: Duration::from_millis(conf.max_time_ms),
+                mqtt_to_zenoh,
+                zenoh_to_mqtt,
+            }))
+        })?;
+        Ok(())
+    }
+
+    fn rewrite<'a>(ast: &AST, topic: &'a str, payload: &'a [u8]) -> ZResult<Rewritten<'a>> {
+        let scripts = SCRIPTS
+            .get()
+            .ok_or_else(|| zerror!("Scripts not initialized"))?;
+        DEADLINE.with(|d| d.set(Some(Instant::now() + scripts.max_time)));
+        let result = scripts.engine.call_fn::<Dynamic>(
+            &mut Scope::new(),
+            ast,
+            REWRITE_FN,
+            (topic.to_string(), Blob::from(payload)),
+        );
+        DEADLINE.with(|d| d.set(None));
+        let result = result.map_err(|e| zerror!("Script error rewriting '{}': {}", topic, e))?;
+        if result.is_unit() {
+            return Ok(None);
+        }
+        let mut map = result.try_cast::<Map>().ok_or_else(|| {
+            zerror!(
+                "Script error rewriting '{}': `rewrite` must return a map or ()",
+                topic
+            )
+        })?;
+        let new_topic = match map.remove("topic") {
+            Some(t) => Cow::Owned(t.into_string().map_err(|t| {
+                zerror!(
+                    "Script error rewriting '{}': invalid topic type {}",
+                    topic,
+                    t
+                )
+            })?),
+            None => Cow::Borrowed(topic),
+        };
+        let new_payload = match map.remove("payload") {
+            Some(p) if p.is_blob() => Cow::Owned(p.cast::<Blob>()),
+            Some(p) if p.is_string() => Cow::Owned(p.cast::<String>().into_bytes()),
+            Some(p) => bail!(
+                "Script error rewriting '{}': invalid payload type {}",
+                topic,
+                p.type_name()
+            ),
+            None => Cow::Borrowed(payload),
+        };
+        Ok(Some((new_topic, new_payload)))
+    }
+
+    pub(super) fn mqtt_to_zenoh<'a>(topic: &'a str, payload: &'a [u8]) -> ZResult<Rewritten<'a>> {
+        match SCRIPTS.get().and_then(|s| s.mqtt_to_zenoh.as_ref()) {
+            Some(ast) => rewrite(ast, topic, payload),
+            None => Ok(Some((Cow::Borrowed(topic), Cow::Borrowed(payload)))),
+        }
+    }
+
+    pub(super) fn zenoh_to_mqtt<'a>(topic: &'a str, payload: &'a [u8]) -> ZResult<Rewritten<'a>> {
+        match SCRIPTS.get().and_then(|s| s.zenoh_to_mqtt.as_ref()) {
+            Some(ast) => rewrite(ast, topic, payload),
+            None => Ok(Some((Cow::Borrowed(topic), Cow::Borrowed(payload)))),
+        }
+    }
+}
+
+pub(crate) fn init(config: &Config) -> ZResult<()> {
+    #[cfg(feature = "rhai")]
+    {
+        script::init(config)
+    }
+    #[cfg(not(feature = "rhai"))]
+    {
+        if config.scripts.is_some() {
+            log::warn!("'scripts' is configured, but the MQTT plugin was built without the 'rhai' feature - ignore it");
+        }
+        Ok(())
+    }
+}
+
+// Rewrite a publication from a MQTT client, before its routing to Zenoh
+pub(crate) fn rewrite_mqtt_to_zenoh<'a>(
+    topic: &'a str,
+    payload: &'a [u8],
+) -> ZResult<Rewritten<'a>> {
+    #[cfg(feature = "rhai")]
+    return script::mqtt_to_zenoh(topic, payload);
+    #[cfg(not(feature = "rhai"))]
+    Ok(Some((Cow::Borrowed(topic), Cow::Borrowed(payload))))
+}
+
+// Rewrite a publication from Zenoh, before its routing to a MQTT client
+pub(crate) fn rewrite_zenoh_to_mqtt<'a>(
+    topic: &'a str,
+    payload: &'a [u8],
+) -> ZResult<Rewritten<'a>> {
+    #[cfg(feature = "rhai")]
+    return script::zenoh_to_mqtt(topic, payload);
+    #[cfg(not(feature = "rhai"))]
+    Ok(Some((Cow::Borrowed(topic), Cow::Borrowed(payload))))
+}
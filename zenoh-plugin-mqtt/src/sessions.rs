@@ -0,0 +1,328 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The persistent MQTT sessions shared between several bridge instances (e.g. behind a TCP load balancer).
+// The subscriptions of the clients connecting without clean session are published in Zenoh on
+// "<key_prefix>/<client id>". Each bridge keeps a copy of those sessions and replies to the queries on them,
+// so a client reconnecting to another bridge instance resumes its subscriptions. A Zenoh storage on
+// "<key_prefix>/**" makes the sessions survive a restart of all the bridges.
+// A session can also be exported as a portable blob and imported on a bridge of another Zenoh system,
+// for a planned migration of devices between gateways.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use zenoh::plugins::ZResult;
+use zenoh::prelude::r#async::*;
+use zenoh::queryable::Queryable;
+use zenoh::subscriber::Subscriber;
+use zenoh_core::zlock;
+
+use crate::config::SharedSessionsConfig;
+use crate::mqtt_helpers::{escape_topic_level, unescape_ke};
+use crate::payload_format::DeliveryOptions;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct SessionSubscription {
+    // the QoS requested by the client
+    pub(crate) qos: u8,
+    pub(crate) no_local: bool,
+    #[serde(flatten)]
+    pub(crate) delivery: DeliveryOptions,
+}
+
+// The subscriptions of a persistent session, per MQTT topic filter
+pub(crate) type PersistentSession = BTreeMap<String, SessionSubscription>;
+
+// A persistent session exported from a bridge, to be imported on another one
+#[derive(Serialize, Deserialize, Debug)]
+struct ExportedSession {
+    client_id: String,
+    // the username the client was authenticated with, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    subscriptions: PersistentSession,
+}
+
+#[derive(Debug)]
+pub(crate) struct SessionRegistry {
+    zsession: Arc<Session>,
+    config: SharedSessionsConfig,
+    // the known persistent sessions, per client id
+    sessions: Mutex<HashMap<String, PersistentSession>>,
+    // the usernames the sessions imported on this bridge are bound to, per client id
+    owners: Mutex<HashMap<String, String>>,
+}
+
+impl SessionRegistry {
+    pub(crate) fn new(zsession: Arc<Session>, config: &SharedSessionsConfig) -> SessionRegistry {
+        SessionRegistry {
+            zsession,
+            config: config.clone(),
+            sessions: Mutex::new(HashMap::new()),
+            owners: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn session_ke(&self, client_id: &str) -> ZResult<KeyExpr<'static>> {
+        let client_ke = keyexpr::new(escape_topic_level(client_id).as_ref())?.to_owned();
+        Ok((&*self.config.key_prefix / &client_ke).into())
+    }
+
+    fn client_id(&self, ke: &keyexpr) -> Option<String> {
+        ke.as_str()
+            .strip_prefix(self.config.key_prefix.as_str())
+            .and_then(|s| s.strip_prefix('/'))
+            .map(|s| unescape_ke(s).into_owned())
+    }
+
+    // Declare the subscriber keeping the copy of the sessions updated by the other bridges,
+    // and the queryable replying with the known sessions
+    pub(crate) async fn declare(
+        self: &Arc<Self>,
+    ) -> ZResult<(Subscriber<'static, ()>, Queryable<'static, ()>)> {
+        let this = self.clone();
+        let subscriber = self
+            .zsession
+            .declare_subscriber(format!("{}/**", self.config.key_prefix))
+            .callback(move |sample| {
+                let Some(client_id) = this.client_id(&sample.key_expr) else {
+                    return;
+                };
+                match sample.kind {
+                    SampleKind::Put => {
+                        match serde_json::from_slice::<PersistentSession>(
+                            &sample.payload.contiguous(),
+                        ) {
+                            Ok(session) => {
+                                zlock!(this.sessions).insert(client_id, session);
+                            }
+                            Err(e) => log::warn!(
+                                "Invalid persistent session received on {}: {}",
+                                sample.key_expr,
+                                e
+                            ),
+                        }
+                    }
+                    SampleKind::Delete => {
+                        zlock!(this.sessions).remove(&client_id);
+                    }
+                }
+            })
+            .allowed_origin(Locality::Remote)
+            .res()
+            .await?;
+        let this = self.clone();
+        let queryable = self
+            .zsession
+            .declare_queryable(format!("{}/**", self.config.key_prefix))
+            .callback(move |query| {
+                let sessions = zlock!(this.sessions);
+                for (client_id, session) in sessions.iter() {
+                    let Ok(ke) = this.session_ke(client_id) else {
+                        continue;
+                    };
+                    if query.selector().key_expr.intersects(&ke) {
+                        use zenoh::prelude::sync::SyncResolve;
+                        let value = Value::from(serde_json::to_vec(session).unwrap_or_default())
+                            .encoding(Encoding::APP_JSON);
+                        if let Err(e) = query.reply(Ok(Sample::new(ke, value))).res_sync() {
+                            log::warn!("Error replying to persistent sessions query: {}", e);
+                        }
+                    }
+                }
+            })
+            .allowed_origin(Locality::Remote)
+            .res()
+            .await?;
+        Ok((subscriber, queryable))
+    }
+
+    // The persistent session of a client, known locally or fetched from the other bridges or a storage
+    pub(crate) async fn resume(&self, client_id: &str) -> Option<PersistentSession> {
+        if let Some(session) = zlock!(self.sessions).get(client_id) {
+            return Some(session.clone());
+        }
+        let ke = match self.session_ke(client_id) {
+            Ok(ke) => ke,
+            Err(e) => {
+                log::warn!(
+                    "MQTT client {}: persistent session not shared: {}",
+                    client_id,
+                    e
+                );
+                return None;
+            }
+        };
+        let replies = match self
+            .zsession
+            .get(&ke)
+            .timeout(Duration::from_millis(self.config.query_timeout_ms))
+            .res()
+            .await
+        {
+            Ok(replies) => replies,
+            Err(e) => {
+                log::warn!(
+                    "MQTT client {}: failed to query persistent session on {}: {}",
+                    client_id,
+                    ke,
+                    e
+                );
+                return None;
+            }
+        };
+        while let Ok(reply) = replies.recv_async().await {
+            match reply.sample.map_err(|e| e.to_string()).and_then(|s| {
+                serde_json::from_slice::<PersistentSession>(&s.payload.contiguous())
+                    .map_err(|e| e.to_string())
+            }) {
+                Ok(session) => {
+                    zlock!(self.sessions).insert(client_id.to_string(), session.clone());
+                    return Some(session);
+                }
+                Err(e) => log::debug!(
+                    "MQTT client {}: error reply fetching persistent session on {}: {}",
+                    client_id,
+                    ke,
+                    e
+                ),
+            }
+        }
+        None
+    }
+
+    // Discard the persistent session of a client (connecting with a clean session)
+    pub(crate) async fn discard(&self, client_id: &str) {
+        zlock!(self.sessions).remove(client_id);
+        zlock!(self.owners).remove(client_id);
+        match self.session_ke(client_id) {
+            Ok(ke) => {
+                if let Err(e) = self.zsession.delete(&ke).res().await {
+                    log::warn!(
+                        "MQTT client {}: failed to discard persistent session on {}: {}",
+                        client_id,
+                        ke,
+                        e
+                    );
+                }
+            }
+            Err(e) => log::debug!(
+                "MQTT client {}: persistent session not shared: {}",
+                client_id,
+                e
+            ),
+        }
+    }
+
+    // The persistent session of a client as a portable blob (the hexadecimal encoding of its JSON form),
+    // with the username of the client (if connected) or the one the session is bound to
+    pub(crate) fn export(&self, client_id: &str, username: Option<String>) -> Option<String> {
+        let subscriptions = zlock!(self.sessions).get(client_id)?.clone();
+        let exported = ExportedSession {
+            client_id: client_id.to_string(),
+            username: username.or_else(|| self.owner(client_id)),
+            subscriptions,
+        };
+        Some(hex::encode(serde_json::to_vec(&exported).ok()?))
+    }
+
+    // Import a persistent session exported by another bridge, replacing the known one for the same client,
+    // once its identity (client id and username) is checked. The session is bound to the username, if any.
+    // Returns the client id.
+    pub(crate) fn import(
+        self: &Arc<Self>,
+        blob: &str,
+        check_identity: impl FnOnce(&str, Option<&str>) -> ZResult<()>,
+    ) -> ZResult<String> {
+        let exported = hex::decode(blob)
+            .map_err(|e| e.to_string())
+            .and_then(|json| {
+                serde_json::from_slice::<ExportedSession>(&json).map_err(|e| e.to_string())
+            })
+            .map_err(|e| zerror!("Invalid exported session: {}", e))?;
+        let ExportedSession {
+            client_id,
+            username,
+            subscriptions,
+        } = exported;
+        check_identity(&client_id, username.as_deref())?;
+        match username {
+            Some(username) => zlock!(self.owners).insert(client_id.clone(), username),
+            None => zlock!(self.owners).remove(&client_id),
+        };
+        zlock!(self.sessions).insert(client_id.clone(), subscriptions.clone());
+        // shared with the other bridges of this system
+        let this = self.clone();
+        let id = client_id.clone();
+        async_std::task::spawn(async move { this.publish(&id, &subscriptions).await });
+        Ok(client_id)
+    }
+
+    // The username an imported session is bound to
+    pub(crate) fn owner(&self, client_id: &str) -> Option<String> {
+        zlock!(self.owners).get(client_id).cloned()
+    }
+
+    pub(crate) async fn subscribed(&self, client_id: &str, topic: &str, sub: SessionSubscription) {
+        let session = {
+            let mut sessions = zlock!(self.sessions);
+            let session = sessions.entry(client_id.to_string()).or_default();
+            session.insert(topic.to_string(), sub);
+            session.clone()
+        };
+        self.publish(client_id, &session).await;
+    }
+
+    pub(crate) async fn unsubscribed(&self, client_id: &str, topic: &str) {
+        let session = match zlock!(self.sessions).get_mut(client_id) {
+            Some(session) => {
+                session.remove(topic);
+                session.clone()
+            }
+            None => return,
+        };
+        self.publish(client_id, &session).await;
+    }
+
+    async fn publish(&self, client_id: &str, session: &PersistentSession) {
+        let ke = match self.session_ke(client_id) {
+            Ok(ke) => ke,
+            Err(e) => {
+                log::debug!(
+                    "MQTT client {}: persistent session not shared: {}",
+                    client_id,
+                    e
+                );
+                return;
+            }
+        };
+        let payload = serde_json::to_vec(session).unwrap_or_default();
+        if let Err(e) = self
+            .zsession
+            .put(&ke, payload)
+            .encoding(Encoding::APP_JSON)
+            .res()
+            .await
+        {
+            log::warn!(
+                "MQTT client {}: failed to publish persistent session on {}: {}",
+                client_id,
+                ke,
+                e
+            );
+        }
+    }
+}
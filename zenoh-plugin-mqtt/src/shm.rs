@@ -0,0 +1,97 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+use std::fmt;
+use zenoh::prelude::*;
+
+use crate::config::Config;
+
+// Allocation of the payloads routed to Zenoh in shared memory.
+// Without the "shared-memory" feature, payloads are never allocated in shared memory.
+pub(crate) struct ShmProvider {
+    #[cfg(feature = "shared-memory")]
+    inner: Option<(usize, std::sync::Mutex<zenoh::shm::SharedMemoryManager>)>,
+}
+
+impl fmt::Debug for ShmProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        #[cfg(feature = "shared-memory")]
+        return f
+            .debug_struct("ShmProvider")
+            .field("threshold", &self.inner.as_ref().map(|(t, _)| t))
+            .finish();
+        #[cfg(not(feature = "shared-memory"))]
+        f.debug_struct("ShmProvider").finish()
+    }
+}
+
+impl ShmProvider {
+    pub(crate) fn new(config: &Config, _zid: &str) -> ShmProvider {
+        #[cfg(feature = "shared-memory")]
+        {
+            let inner = config.shm.as_ref().and_then(|conf| {
+                match zenoh::shm::SharedMemoryManager::make(
+                    format!("zenoh-plugin-mqtt-{_zid}"),
+                    conf.size,
+                ) {
+                    Ok(manager) => {
+                        log::debug!(
+                            "MQTT publications >= {} bytes will be allocated in a {} bytes shared memory segment",
+                            conf.threshold,
+                            conf.size
+                        );
+                        Some((conf.threshold, std::sync::Mutex::new(manager)))
+                    }
+                    Err(e) => {
+                        log::error!("Failed to create shared memory manager: {}", e);
+                        None
+                    }
+                }
+            });
+            ShmProvider { inner }
+        }
+        #[cfg(not(feature = "shared-memory"))]
+        {
+            if config.shm.is_some() {
+                log::warn!("'shm' is configured, but the MQTT plugin was built without the 'shared-memory' feature - ignore it");
+            }
+            ShmProvider {}
+        }
+    }
+
+    // Make a Value from the payload, allocated in shared memory if its size is above the configured threshold
+    pub(crate) fn make_value(&self, payload: &[u8]) -> Value {
+        #[cfg(feature = "shared-memory")]
+        if let Some((threshold, manager)) = &self.inner {
+            if payload.len() >= *threshold {
+                let mut manager = zenoh_core::zlock!(manager);
+                let buf = manager.alloc(payload.len()).or_else(|_| {
+                    manager.garbage_collect();
+                    manager.alloc(payload.len())
+                });
+                match buf {
+                    Ok(mut buf) => {
+                        unsafe { buf.as_mut_slice() }.copy_from_slice(payload);
+                        return buf.into();
+                    }
+                    Err(e) => log::debug!(
+                        "Failed to allocate {} bytes in shared memory, fallback to regular buffer: {}",
+                        payload.len(),
+                        e
+                    ),
+                }
+            }
+        }
+        payload.into()
+    }
+}
@@ -0,0 +1,104 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The detection of the slow consumers: the MQTT clients whose outgoing queue stays above a high-water mark
+// for longer than the configured period. The configured policy is then applied to them (dropping their
+// queued QoS 0 publications, disconnecting them, or conflating their QoS 0 publications per topic).
+
+use ntex_mqtt::v5::codec::DisconnectReasonCode;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use zenoh_core::zlock;
+
+use crate::clients::ClientsRegistry;
+use crate::config::{SlowConsumerPolicy, SlowConsumersConfig};
+use crate::telemetry;
+
+const CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub(crate) struct SlowConsumers {
+    config: SlowConsumersConfig,
+    clients: Arc<ClientsRegistry>,
+    // since when the queue of a client is above the high-water mark, per client id
+    above_since: Mutex<HashMap<String, Instant>>,
+    evictions: AtomicU64,
+}
+
+impl SlowConsumers {
+    pub(crate) fn new(config: &SlowConsumersConfig, clients: Arc<ClientsRegistry>) -> Self {
+        SlowConsumers {
+            config: config.clone(),
+            clients,
+            above_since: Mutex::new(HashMap::new()),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn spawn_check_task(self: &Arc<Self>) {
+        let this = self.clone();
+        async_std::task::spawn(async move {
+            loop {
+                async_std::task::sleep(CHECK_PERIOD).await;
+                this.check();
+            }
+        });
+    }
+
+    fn check(&self) {
+        let period = Duration::from_millis(self.config.period_ms);
+        let mut above_since = zlock!(self.above_since);
+        let mut still_above = HashMap::new();
+        for (client_id, sink) in self.clients.sinks() {
+            let queued = sink.queued();
+            if queued < self.config.high_water_mark {
+                continue;
+            }
+            let since = above_since.remove(&client_id).unwrap_or_else(Instant::now);
+            if since.elapsed() < period {
+                still_above.insert(client_id, since);
+                continue;
+            }
+            let policy = self.config.policy;
+            log::warn!(
+                "MQTT client {} is a slow consumer ({} queued publications for {:?}) - apply '{}' policy",
+                client_id,
+                queued,
+                since.elapsed(),
+                policy.as_str()
+            );
+            match policy {
+                SlowConsumerPolicy::DropQos0 => sink.shed_qos0(),
+                SlowConsumerPolicy::Disconnect => {
+                    sink.disconnect_with(DisconnectReasonCode::QuotaExceeded)
+                }
+                SlowConsumerPolicy::Conflate => sink.conflate(),
+            }
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+            telemetry::slow_consumer_evicted(policy.as_str());
+        }
+        *above_since = still_above;
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        serde_json::json!({
+            "policy": self.config.policy.as_str(),
+            "slow_consumers": zlock!(self.above_since).len(),
+            "evictions": self.evictions.load(Ordering::Relaxed),
+        })
+    }
+}
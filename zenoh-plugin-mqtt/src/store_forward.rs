@@ -0,0 +1,342 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// Store-and-forward of the MQTT publications that couldn't be routed to Zenoh
+// (e.g. when the Zenoh connectivity is lost on an intermittently connected edge site).
+// The publications are stored while no Zenoh router nor peer is reachable (or when their put fails),
+// kept in a bounded queue, persisted in a file (1 JSON record per line), and re-published in order
+// once the connectivity is back. The sequence number of the last forwarded publication is persisted
+// after each one, so a restart after a crash doesn't forward them again.
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zenoh::plugins::ZResult;
+use zenoh::prelude::r#async::*;
+use zenoh::sample::Attachment;
+use zenoh_core::zlock;
+
+use crate::chunking::{self, ATTACHMENT_KEY_CHUNK};
+use crate::config::StoreForwardConfig;
+use crate::pause;
+
+// The attachment key carrying the time at which the publication was received from the MQTT client
+pub(crate) const ATTACHMENT_KEY_TIMESTAMP: &str = "mqtt_timestamp";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredPut {
+    // sequence number (0 for the records of older versions)
+    #[serde(default)]
+    seq: u64,
+    // reception time, in milliseconds since UNIX_EPOCH
+    timestamp: u64,
+    key_expr: String,
+    encoding: String,
+    // hex-encoded
+    payload: String,
+}
+
+#[derive(Debug)]
+pub(crate) struct StoreForward {
+    zsession: Arc<Session>,
+    config: StoreForwardConfig,
+    queue: Mutex<Queue>,
+    // true while a Zenoh router or peer is reachable
+    connected: AtomicBool,
+}
+
+#[derive(Debug)]
+struct Queue {
+    puts: VecDeque<StoredPut>,
+    next_seq: u64,
+}
+
+impl StoreForward {
+    // Create the store, reloading the publications persisted by a previous run
+    pub(crate) fn new(zsession: Arc<Session>, config: &StoreForwardConfig) -> ZResult<Self> {
+        // the publications up to this one were forwarded before the file was compacted
+        let forwarded = read_checkpoint(&checkpoint_path(config));
+        let mut queue = VecDeque::new();
+        match File::open(&config.path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line.map_err(|e| {
+                        zerror!(
+                            "Failed to read store-and-forward file {}: {}",
+                            config.path,
+                            e
+                        )
+                    })?;
+                    match serde_json::from_str::<StoredPut>(&line) {
+                        Ok(put) if !already_forwarded(&put, forwarded) => queue.push_back(put),
+                        Ok(_) => (),
+                        Err(e) => log::warn!(
+                            "Ignore invalid record in store-and-forward file {}: {}",
+                            config.path,
+                            e
+                        ),
+                    }
+                }
+                if !queue.is_empty() {
+                    log::info!(
+                        "Reloaded {} MQTT publications to be forwarded from {}",
+                        queue.len(),
+                        config.path
+                    );
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => bail!(
+                "Failed to open store-and-forward file {}: {}",
+                config.path,
+                e
+            ),
+        }
+        let next_seq = queue
+            .iter()
+            .map(|put| put.seq)
+            .chain(forwarded)
+            .max()
+            .map_or(1, |seq| seq + 1);
+        Ok(StoreForward {
+            zsession,
+            config: config.clone(),
+            queue: Mutex::new(Queue {
+                puts: queue,
+                next_seq,
+            }),
+            connected: AtomicBool::new(true),
+        })
+    }
+
+    // True if some publications are waiting to be forwarded.
+    pub(crate) fn is_pending(&self) -> bool {
+        !zlock!(self.queue).puts.is_empty()
+    }
+
+    // True if a new publication must be stored: while Zenoh is unreachable, or while some publications
+    // are waiting to be forwarded (to preserve the order).
+    pub(crate) fn must_store(&self) -> bool {
+        !self.connected.load(Ordering::Relaxed) || self.is_pending()
+    }
+
+    // Store a publication to be forwarded later. Fails if the queue is full.
+    pub(crate) fn store(
+        &self,
+        ke: &KeyExpr<'_>,
+        encoding: &Encoding,
+        payload: &[u8],
+    ) -> ZResult<()> {
+        let mut queue = zlock!(self.queue);
+        if queue.puts.len() >= self.config.max_messages {
+            bail!(
+                "store-and-forward queue is full ({} publications)",
+                queue.puts.len()
+            );
+        }
+        let put = StoredPut {
+            seq: queue.next_seq,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            key_expr: ke.to_string(),
+            encoding: encoding.to_string(),
+            payload: hex::encode(payload),
+        };
+        self.append_to_file(&put)?;
+        queue.next_seq += 1;
+        queue.puts.push_back(put);
+        Ok(())
+    }
+
+    fn append_to_file(&self, put: &StoredPut) -> ZResult<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)
+            .map_err(|e| {
+                zerror!(
+                    "Failed to open store-and-forward file {}: {}",
+                    self.config.path,
+                    e
+                )
+            })?;
+        writeln!(file, "{}", serde_json::to_string(put)?).map_err(|e| {
+            zerror!(
+                "Failed to write store-and-forward file {}: {}",
+                self.config.path,
+                e
+            )
+        })?;
+        Ok(())
+    }
+
+    fn rewrite_file(&self, queue: &VecDeque<StoredPut>) -> ZResult<()> {
+        let tmp_path = format!("{}.tmp", self.config.path);
+        let mut file = File::create(&tmp_path).map_err(|e| {
+            zerror!(
+                "Failed to create store-and-forward file {}: {}",
+                tmp_path,
+                e
+            )
+        })?;
+        for put in queue {
+            writeln!(file, "{}", serde_json::to_string(put)?).map_err(|e| {
+                zerror!("Failed to write store-and-forward file {}: {}", tmp_path, e)
+            })?;
+        }
+        std::fs::rename(&tmp_path, &self.config.path).map_err(|e| {
+            zerror!(
+                "Failed to replace store-and-forward file {}: {}",
+                self.config.path,
+                e
+            )
+        })?;
+        Ok(())
+    }
+
+    // Persist the sequence number of the last forwarded publication
+    fn write_checkpoint(&self, seq: u64) -> ZResult<()> {
+        let path = checkpoint_path(&self.config);
+        std::fs::write(&path, seq.to_string()).map_err(|e| {
+            zerror!(
+                "Failed to write store-and-forward checkpoint {}: {}",
+                path,
+                e
+            )
+        })?;
+        Ok(())
+    }
+
+    // Put a stored publication (as chunks if it's larger than the chunk size)
+    async fn forward(&self, put: &StoredPut, payload: Vec<u8>) -> ZResult<()> {
+        let encoding = Encoding::from(put.encoding.clone());
+        let Some(chunks) = chunking::split(&self.zsession.zid(), payload.len())? else {
+            let mut attachment = Attachment::new();
+            attachment.insert(&ATTACHMENT_KEY_TIMESTAMP, &put.timestamp.to_string());
+            return self
+                .zsession
+                .put(put.key_expr.as_str(), payload)
+                .encoding(encoding)
+                .with_attachment(attachment)
+                .res()
+                .await;
+        };
+        for (chunk, range) in chunks {
+            let mut attachment = Attachment::new();
+            attachment.insert(&ATTACHMENT_KEY_TIMESTAMP, &put.timestamp.to_string());
+            attachment.insert(&ATTACHMENT_KEY_CHUNK, &chunk);
+            self.zsession
+                .put(put.key_expr.as_str(), &payload[range])
+                .encoding(encoding.clone())
+                .with_attachment(attachment)
+                .res()
+                .await
+                .map_err(|e| zerror!("chunk {}: {}", chunk, e))?;
+        }
+        Ok(())
+    }
+
+    // Forward the stored publications in order, stopping at the 1st failure.
+    // The checkpoint is updated after each forwarded publication, the file being compacted after the batch.
+    async fn flush(&self) {
+        let mut forwarded = 0;
+        loop {
+            let Some(put) = zlock!(self.queue).puts.front().cloned() else {
+                break;
+            };
+            let payload = match hex::decode(&put.payload) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    log::warn!("Drop invalid stored publication on {}: {}", put.key_expr, e);
+                    zlock!(self.queue).puts.pop_front();
+                    continue;
+                }
+            };
+            if let Err(e) = self.forward(&put, payload).await {
+                log::debug!(
+                    "Zenoh still unreachable ({}) - {} stored publications remain to be forwarded",
+                    e,
+                    zlock!(self.queue).puts.len()
+                );
+                break;
+            }
+            zlock!(self.queue).puts.pop_front();
+            if let Err(e) = self.write_checkpoint(put.seq) {
+                log::warn!("{}", e);
+            }
+            forwarded += 1;
+        }
+        if forwarded > 0 {
+            log::info!("Forwarded {} stored MQTT publications to Zenoh", forwarded);
+            let queue = zlock!(self.queue);
+            if let Err(e) = self.rewrite_file(&queue.puts) {
+                log::warn!("{}", e);
+            }
+        }
+    }
+
+    // True if a Zenoh router or peer is reachable
+    async fn is_connected(&self) -> bool {
+        let info = self.zsession.info();
+        info.routers_zid().res().await.next().is_some()
+            || info.peers_zid().res().await.next().is_some()
+    }
+
+    // Spawn the task periodically forwarding the stored publications
+    pub(crate) fn spawn_flush_task(self: &Arc<Self>) {
+        let this = self.clone();
+        let interval = Duration::from_millis(self.config.flush_interval_ms);
+        async_std::task::spawn(async move {
+            loop {
+                let connected = this.is_connected().await;
+                if this.connected.swap(connected, Ordering::Relaxed) != connected {
+                    if connected {
+                        log::info!("Zenoh reachable again - forward the stored MQTT publications");
+                    } else {
+                        log::warn!(
+                            "Zenoh unreachable - store the MQTT publications to be forwarded later"
+                        );
+                    }
+                }
+                // while the routing is paused, the stored publications are kept
+                if connected && this.is_pending() && !pause::is_paused() {
+                    this.flush().await;
+                }
+                async_std::task::sleep(interval).await;
+            }
+        });
+    }
+}
+
+// True if the publication was forwarded before the last checkpoint (the records of older versions have no sequence number)
+fn already_forwarded(put: &StoredPut, forwarded: Option<u64>) -> bool {
+    forwarded.map_or(false, |seq| put.seq != 0 && put.seq <= seq)
+}
+
+fn checkpoint_path(config: &StoreForwardConfig) -> String {
+    format!("{}.forwarded", config.path)
+}
+
+// The sequence number of the last forwarded publication, if persisted
+fn read_checkpoint(path: &str) -> Option<u64> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+}
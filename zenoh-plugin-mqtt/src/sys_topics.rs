@@ -0,0 +1,79 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::mqtt_helpers::{topic_matches_filter, MqttSink, OutgoingProperties};
+use crate::LONG_VERSION;
+
+pub(crate) const SYS_PREFIX: &str = "$SYS/";
+const SYS_INTERVAL: Duration = Duration::from_secs(10);
+
+lazy_static::lazy_static! {
+    pub(crate) static ref START_TIME: Instant = Instant::now();
+}
+
+pub(crate) fn is_sys_topic(topic: &str) -> bool {
+    topic.starts_with(SYS_PREFIX)
+}
+
+pub(crate) fn uptime_secs() -> u64 {
+    START_TIME.elapsed().as_secs()
+}
+
+fn sys_values() -> [(&'static str, String); 2] {
+    [
+        ("$SYS/broker/uptime", format!("{} seconds", uptime_secs())),
+        (
+            "$SYS/broker/version",
+            format!("zenoh-plugin-mqtt {}", LONG_VERSION.as_str()),
+        ),
+    ]
+}
+
+// Spawn a task publishing every SYS_INTERVAL to the client the $SYS topics matching the filter.
+// The task stops when the returned flag is unset, or when the client is disconnected.
+pub(crate) fn spawn_sys_publisher(
+    client_id: String,
+    filter: String,
+    sink: MqttSink,
+) -> Arc<AtomicBool> {
+    let active = Arc::new(AtomicBool::new(true));
+    let active2 = active.clone();
+    async_std::task::spawn(async move {
+        while active2.load(Ordering::Relaxed) {
+            for (topic, value) in sys_values() {
+                if topic_matches_filter(topic, &filter) {
+                    if let Err(e) = sink.publish_at_most_once(
+                        topic,
+                        value.into_bytes().into(),
+                        OutgoingProperties::default(),
+                        None,
+                    ) {
+                        log::debug!(
+                            "MQTT client {}: stop publishing on '{}': {}",
+                            client_id,
+                            filter,
+                            e
+                        );
+                        return;
+                    }
+                }
+            }
+            async_std::task::sleep(SYS_INTERVAL).await;
+        }
+    });
+    active
+}
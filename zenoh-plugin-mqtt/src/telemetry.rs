@@ -0,0 +1,274 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// Export of traces and metrics via OpenTelemetry OTLP.
+// Without the "opentelemetry" feature all the functions of this module are no-ops.
+
+use std::time::SystemTime;
+use zenoh::plugins::ZResult;
+
+use crate::config::Config;
+use crate::error::MqttPluginError;
+use crate::latency::{self, Direction};
+use crate::mqtt_session_state::ClientTag;
+use crate::payload_sizes;
+use crate::process_wide::ProcessWide;
+
+#[cfg(feature = "opentelemetry")]
+mod otel {
+    use super::*;
+    use crate::config::OpenTelemetryConfig;
+    use opentelemetry::metrics::{Counter, Histogram, UpDownCounter};
+    use opentelemetry::trace::{Span, Status, Tracer};
+    use opentelemetry::{global, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, trace, Resource};
+
+    const INSTRUMENTATION_NAME: &str = "zenoh-plugin-mqtt";
+
+    lazy_static::lazy_static! {
+        static ref CONNECTIONS: Counter<u64> = global::meter(INSTRUMENTATION_NAME)
+            .u64_counter("mqtt.connections")
+            .with_description("Number of MQTT connections accepted")
+            .init();
+        static ref ACTIVE_CONNECTIONS: UpDownCounter<i64> = global::meter(INSTRUMENTATION_NAME)
+            .i64_up_down_counter("mqtt.active_connections")
+            .with_description("Number of currently connected MQTT clients")
+            .init();
+        static ref ROUTING_LATENCY: Histogram<f64> = global::meter(INSTRUMENTATION_NAME)
+            .f64_histogram("mqtt.routing.latency")
+            .with_description("Time spent routing a message through the bridge, in seconds")
+            .init();
+        static ref PAYLOAD_SIZE: Histogram<u64> = global::meter(INSTRUMENTATION_NAME)
+            .u64_histogram("mqtt.routing.payload_size")
+            .with_description("Size of the payloads routed through the bridge, in bytes")
+            .init();
+        static ref ROUTING_ERRORS: Counter<u64> = global::meter(INSTRUMENTATION_NAME)
+            .u64_counter("mqtt.routing.errors")
+            .with_description("Number of messages that failed to be routed through the bridge, per error class")
+            .init();
+        static ref SLOW_CONSUMER_EVICTIONS: Counter<u64> = global::meter(INSTRUMENTATION_NAME)
+            .u64_counter("mqtt.slow_consumers.evictions")
+            .with_description("Number of policies applied to slow consumer MQTT clients, per policy")
+            .init();
+    }
+
+    pub(super) fn init(conf: &OpenTelemetryConfig) -> ZResult<()> {
+        let resource = Resource::new(vec![KeyValue::new(
+            "service.name",
+            conf.service_name.clone(),
+        )]);
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(&conf.endpoint),
+            )
+            .with_trace_config(trace::config().with_resource(resource.clone()))
+            .install_batch(runtime::AsyncStd)
+            .map_err(|e| zerror!("Failed to init OpenTelemetry traces export: {}", e))?;
+        let meter_provider = opentelemetry_otlp::new_pipeline()
+            .metrics(runtime::AsyncStd)
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(&conf.endpoint),
+            )
+            .with_resource(resource)
+            .build()
+            .map_err(|e| zerror!("Failed to init OpenTelemetry metrics export: {}", e))?;
+        global::set_meter_provider(meter_provider);
+        init_latency_percentiles();
+        Ok(())
+    }
+
+    // Export the percentiles of the latency histograms as gauges
+    fn init_latency_percentiles() {
+        let meter = global::meter(INSTRUMENTATION_NAME);
+        for (name, p) in [
+            ("mqtt.routing.latency.p50", 0.50),
+            ("mqtt.routing.latency.p95", 0.95),
+            ("mqtt.routing.latency.p99", 0.99),
+        ] {
+            let _ = meter
+                .f64_observable_gauge(name)
+                .with_description(
+                    "Percentile of the time spent routing a message through the bridge, in seconds",
+                )
+                .with_callback(move |observer| {
+                    for direction in Direction::ALL {
+                        if let Some(latency) = latency::percentile(direction, p) {
+                            observer.observe(
+                                latency.as_secs_f64(),
+                                &[KeyValue::new("direction", direction.as_str())],
+                            );
+                        }
+                    }
+                })
+                .init();
+        }
+    }
+
+    pub(super) fn client_connected(protocol: &'static str, listener: &'static str) {
+        CONNECTIONS.add(
+            1,
+            &[
+                KeyValue::new("protocol", protocol),
+                KeyValue::new("listener", listener),
+            ],
+        );
+        ACTIVE_CONNECTIONS.add(1, &[KeyValue::new("listener", listener)]);
+    }
+
+    pub(super) fn client_disconnected(listener: &'static str) {
+        ACTIVE_CONNECTIONS.add(-1, &[KeyValue::new("listener", listener)]);
+    }
+
+    pub(super) fn slow_consumer_evicted(policy: &'static str) {
+        SLOW_CONSUMER_EVICTIONS.add(1, &[KeyValue::new("policy", policy)]);
+    }
+
+    pub(super) fn payload_routed(direction: Direction, size: usize) {
+        PAYLOAD_SIZE.record(
+            size as u64,
+            &[KeyValue::new("direction", direction.as_str())],
+        );
+    }
+
+    pub(super) fn routed(
+        direction: &'static str,
+        client: &ClientTag,
+        topic: &str,
+        key_expr: &str,
+        start: SystemTime,
+        error: Option<(&'static str, String)>,
+    ) {
+        if let Ok(latency) = start.elapsed() {
+            ROUTING_LATENCY.record(
+                latency.as_secs_f64(),
+                &[KeyValue::new("direction", direction)],
+            );
+        }
+        let tracer = global::tracer(INSTRUMENTATION_NAME);
+        let mut span = tracer
+            .span_builder(direction)
+            .with_start_time(start)
+            .with_attributes(vec![
+                KeyValue::new("mqtt.client_id", client.client_id.clone()),
+                KeyValue::new("mqtt.listener", client.listener),
+                KeyValue::new(
+                    "net.peer.address",
+                    client.peer.map_or(String::new(), |a| a.to_string()),
+                ),
+                KeyValue::new("mqtt.topic", topic.to_string()),
+                KeyValue::new("zenoh.key_expr", key_expr.to_string()),
+            ])
+            .start(&tracer);
+        if let Some((class, e)) = error {
+            ROUTING_ERRORS.add(
+                1,
+                &[
+                    KeyValue::new("direction", direction),
+                    KeyValue::new("class", class),
+                ],
+            );
+            span.set_status(Status::error(e));
+        }
+        span.end();
+    }
+}
+
+static EXPORT: ProcessWide<()> = ProcessWide::new("opentelemetry");
+
+// Init the OpenTelemetry export, if configured. A failure of the export only disables it.
+pub(crate) fn init(config: &Config) -> ZResult<()> {
+    EXPORT.init(&config.opentelemetry, || match &config.opentelemetry {
+        #[cfg(feature = "opentelemetry")]
+        Some(conf) => match otel::init(conf) {
+            Ok(()) => Ok(Some(())),
+            Err(e) => {
+                log::error!("{}", e);
+                Ok(None)
+            }
+        },
+        #[cfg(not(feature = "opentelemetry"))]
+        Some(_) => {
+            log::warn!("'opentelemetry' is configured, but the MQTT plugin was built without the 'opentelemetry' feature - ignore it");
+            Ok(None)
+        }
+        None => Ok(None),
+    })?;
+    Ok(())
+}
+
+pub(crate) fn client_connected(_protocol: &'static str, _listener: &'static str) {
+    #[cfg(feature = "opentelemetry")]
+    otel::client_connected(_protocol, _listener);
+}
+
+pub(crate) fn client_disconnected(_listener: &'static str) {
+    #[cfg(feature = "opentelemetry")]
+    otel::client_disconnected(_listener);
+}
+
+pub(crate) fn slow_consumer_evicted(_policy: &'static str) {
+    #[cfg(feature = "opentelemetry")]
+    otel::slow_consumer_evicted(_policy);
+}
+
+pub(crate) fn payload_routed(direction: Direction, size: usize) {
+    payload_sizes::record(direction, size);
+    #[cfg(feature = "opentelemetry")]
+    otel::payload_routed(direction, size);
+}
+
+pub(crate) fn mqtt_to_zenoh_routed<T>(
+    _client: &ClientTag,
+    _topic: &str,
+    _key_expr: &str,
+    _start: SystemTime,
+    _result: &Result<T, MqttPluginError>,
+) {
+    if let Ok(latency) = _start.elapsed() {
+        latency::record(Direction::MqttToZenoh, latency);
+    }
+    #[cfg(feature = "opentelemetry")]
+    otel::routed(
+        "mqtt_to_zenoh",
+        _client,
+        _topic,
+        _key_expr,
+        _start,
+        _result.as_ref().err().map(|e| (e.class(), e.to_string())),
+    );
+}
+
+pub(crate) fn zenoh_to_mqtt_routed<T>(
+    _client: &ClientTag,
+    _topic: &str,
+    _key_expr: &str,
+    _start: SystemTime,
+    _result: &ZResult<T>,
+) {
+    #[cfg(feature = "opentelemetry")]
+    otel::routed(
+        "zenoh_to_mqtt",
+        _client,
+        _topic,
+        _key_expr,
+        _start,
+        _result.as_ref().err().map(|e| ("internal", e.to_string())),
+    );
+}
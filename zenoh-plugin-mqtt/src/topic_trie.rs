@@ -0,0 +1,182 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// The store of the topic filters subscribed by a MQTT client, as a tree of their levels: the levels shared
+// by several filters (e.g. "factory/line1/" for tens of thousands of sensors) are stored only once, and the
+// filters matching a topic are found walking the levels of the topic instead of testing each filter.
+// It's used for the subscriptions of a client and for the filtering of the publications received by its
+// coalesced and hibernated subscriptions (the subscribers shared via the fanout registry being keyed by
+// exact key expression, their matching is done by zenoh).
+
+use std::collections::HashMap;
+
+use crate::mqtt_helpers::MQTT_SEPARATOR;
+
+#[derive(Debug)]
+struct Node<V> {
+    value: Option<V>,
+    children: HashMap<Box<str>, Node<V>>,
+}
+
+impl<V> Default for Node<V> {
+    fn default() -> Self {
+        Node {
+            value: None,
+            children: HashMap::new(),
+        }
+    }
+}
+
+impl<V> Node<V> {
+    fn is_empty(&self) -> bool {
+        self.value.is_none() && self.children.is_empty()
+    }
+
+    fn remove(&mut self, levels: &[&str]) -> Option<V> {
+        let Some((level, rest)) = levels.split_first() else {
+            return self.value.take();
+        };
+        let child = self.children.get_mut(*level)?;
+        let removed = child.remove(rest);
+        // prune the levels which are no longer used
+        if child.is_empty() {
+            self.children.remove(*level);
+        }
+        removed
+    }
+
+    fn collect<'a>(&'a self, path: &mut String, result: &mut Vec<(String, &'a V)>) {
+        if let Some(value) = &self.value {
+            result.push((path.clone(), value));
+        }
+        for (level, child) in &self.children {
+            let len = path.len();
+            if !path.is_empty() {
+                path.push(MQTT_SEPARATOR);
+            }
+            path.push_str(level);
+            child.collect(path, result);
+            path.truncate(len);
+        }
+    }
+
+    // The value of the 1st filter found matching the remaining levels of a topic
+    fn find_match(&self, levels: &[&str]) -> Option<&V> {
+        // "#" also matches the parent level (e.g. "a/#" matches "a")
+        if let Some(value) = self.children.get("#").and_then(|n| n.value.as_ref()) {
+            return Some(value);
+        }
+        let Some((level, rest)) = levels.split_first() else {
+            return self.value.as_ref();
+        };
+        self.children
+            .get(*level)
+            .and_then(|n| n.find_match(rest))
+            .or_else(|| self.children.get("+").and_then(|n| n.find_match(rest)))
+    }
+}
+
+// A map of values per MQTT topic filter
+#[derive(Debug)]
+pub(crate) struct TopicTrie<V> {
+    root: Node<V>,
+    len: usize,
+}
+
+impl<V> Default for TopicTrie<V> {
+    fn default() -> Self {
+        TopicTrie::new()
+    }
+}
+
+impl<V> TopicTrie<V> {
+    pub(crate) fn new() -> TopicTrie<V> {
+        TopicTrie {
+            root: Node::default(),
+            len: 0,
+        }
+    }
+
+    fn levels(topic: &str) -> Vec<&str> {
+        topic.split(MQTT_SEPARATOR).collect()
+    }
+
+    fn node(&self, topic: &str) -> Option<&Node<V>> {
+        topic
+            .split(MQTT_SEPARATOR)
+            .try_fold(&self.root, |node, level| node.children.get(level))
+    }
+
+    pub(crate) fn insert(&mut self, topic: &str, value: V) -> Option<V> {
+        let node = topic
+            .split(MQTT_SEPARATOR)
+            .fold(&mut self.root, |node, level| {
+                node.children.entry(level.into()).or_default()
+            });
+        let previous = node.value.replace(value);
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    pub(crate) fn get(&self, topic: &str) -> Option<&V> {
+        self.node(topic)?.value.as_ref()
+    }
+
+    pub(crate) fn contains_key(&self, topic: &str) -> bool {
+        self.get(topic).is_some()
+    }
+
+    pub(crate) fn remove(&mut self, topic: &str) -> Option<V> {
+        let removed = self.root.remove(&Self::levels(topic));
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // All the topic filters with their value
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (String, &V)> {
+        let mut result = Vec::with_capacity(self.len);
+        self.root.collect(&mut String::new(), &mut result);
+        result.into_iter()
+    }
+
+    // The topic filters below a prefix of levels (e.g. "a/b/c" and "a/b/+" for "a/b"), excluding the prefix itself
+    pub(crate) fn keys_under(&self, prefix: &str) -> Vec<String> {
+        let Some(node) = self.node(prefix) else {
+            return Vec::new();
+        };
+        let mut result = Vec::new();
+        for (level, child) in &node.children {
+            let mut path = format!("{prefix}{MQTT_SEPARATOR}{level}");
+            child.collect(&mut path, &mut result);
+        }
+        result.into_iter().map(|(topic, _)| topic).collect()
+    }
+
+    // The value of a topic filter matching the topic name (the 1st found if several match)
+    pub(crate) fn find_match(&self, topic: &str) -> Option<&V> {
+        self.root.find_match(&Self::levels(topic))
+    }
+}
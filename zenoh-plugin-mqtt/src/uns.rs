@@ -0,0 +1,156 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+
+// Unified Namespace (UNS) mode, for ISA-95-style deployments (e.g. "enterprise/site/area/line/cell/<data>").
+// The topics of MQTT publications are validated against the configured hierarchy, and for each
+// node of the hierarchy seen for the 1st time a metadata description is published in Zenoh on
+// "<meta_prefix>/<node path>". Those descriptions are also available via queries.
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use zenoh::plugins::ZResult;
+use zenoh::prelude::r#async::*;
+use zenoh::queryable::Queryable;
+use zenoh_core::zlock;
+
+use crate::config::{KeChunksConfig, UnsConfig};
+use crate::mqtt_helpers::mqtt_topic_to_ke;
+
+#[derive(Debug)]
+pub(crate) struct Uns {
+    zsession: Arc<Session>,
+    config: UnsConfig,
+    // the cap of the key expressions chunks (see the "ke_chunks" configuration)
+    ke_chunks: Option<KeChunksConfig>,
+    // the metadata of the known nodes, per node path
+    nodes: Mutex<HashMap<String, Value>>,
+}
+
+impl Uns {
+    pub(crate) fn new(
+        zsession: Arc<Session>,
+        config: &UnsConfig,
+        ke_chunks: Option<KeChunksConfig>,
+    ) -> Uns {
+        Uns {
+            zsession,
+            config: config.clone(),
+            ke_chunks,
+            nodes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Check that a MQTT topic complies with the configured hierarchy
+    pub(crate) fn validate(&self, topic: &str) -> Result<(), String> {
+        let depth = topic.split('/').count();
+        if depth < self.config.levels.len() {
+            return Err(format!(
+                "topic '{}' has {} levels, but the hierarchy requires at least {} ({})",
+                topic,
+                depth,
+                self.config.levels.len(),
+                self.config.levels.join("/")
+            ));
+        }
+        if let Some(max_depth) = self.config.max_depth {
+            if depth > max_depth {
+                return Err(format!(
+                    "topic '{topic}' has {depth} levels, but the maximum is {max_depth}"
+                ));
+            }
+        }
+        if let Some(pattern) = &self.config.pattern {
+            if !pattern.is_match(topic) {
+                return Err(format!(
+                    "topic '{topic}' doesn't match the pattern '{pattern}'"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Publish the metadata of the hierarchy nodes of a (valid) topic that were not seen before
+    pub(crate) async fn publish_metadata(&self, topic: &str) {
+        let new_nodes: Vec<(String, Value)> = {
+            let mut nodes = zlock!(self.nodes);
+            let mut new_nodes = Vec::new();
+            let mut path = String::new();
+            for (level, name) in self.config.levels.iter().zip(topic.split('/')) {
+                if !path.is_empty() {
+                    path.push('/');
+                }
+                path.push_str(name);
+                if !nodes.contains_key(&path) {
+                    let meta = serde_json::json!({
+                        "level": level,
+                        "name": name,
+                        "path": path,
+                        "description": self.config.descriptions.get(&path),
+                    });
+                    nodes.insert(path.clone(), meta.clone());
+                    new_nodes.push((path.clone(), meta));
+                }
+            }
+            new_nodes
+        };
+        for (path, meta) in new_nodes {
+            let ke = match mqtt_topic_to_ke(path.as_str(), &None, self.ke_chunks.as_ref()) {
+                Ok(node_ke) => &*self.config.meta_prefix / &node_ke,
+                Err(e) => {
+                    log::warn!("Cannot publish UNS metadata for node '{}': {}", path, e);
+                    continue;
+                }
+            };
+            log::debug!("Publish UNS metadata on {}", ke);
+            if let Err(e) = self
+                .zsession
+                .put(ke.clone(), meta.to_string())
+                .encoding(Encoding::APP_JSON)
+                .res()
+                .await
+            {
+                log::warn!("Failed to publish UNS metadata on {}: {}", ke, e);
+            }
+        }
+    }
+
+    // Declare the queryable replying with the metadata of the known nodes
+    pub(crate) async fn declare_queryable(self: &Arc<Self>) -> ZResult<Queryable<'static, ()>> {
+        let this = self.clone();
+        let queryable = self
+            .zsession
+            .declare_queryable(format!("{}/**", self.config.meta_prefix))
+            .callback(move |query| {
+                let nodes = zlock!(this.nodes);
+                for (path, meta) in nodes.iter() {
+                    let Ok(node_ke) =
+                        mqtt_topic_to_ke(path.as_str(), &None, this.ke_chunks.as_ref())
+                    else {
+                        continue;
+                    };
+                    let ke = &*this.config.meta_prefix / &node_ke;
+                    if query.selector().key_expr.intersects(&ke) {
+                        use zenoh::prelude::sync::SyncResolve;
+                        if let Err(e) = query.reply(Ok(Sample::new(ke, meta.clone()))).res_sync() {
+                            log::warn!("Error replying to UNS metadata query: {}", e);
+                        }
+                    }
+                }
+            })
+            .res()
+            .await?;
+        Ok(queryable)
+    }
+}
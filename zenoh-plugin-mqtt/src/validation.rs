@@ -0,0 +1,150 @@
+//
+// Copyright (c) 2022 ZettaScale Technology
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ZettaScale Zenoh Team, <zenoh@zettascale.tech>
+//
+//! Validation of a MQTT plugin configuration without starting it (no socket bound, no zenoh session).
+//!
+//! Besides its deserialization, the configuration goes through the checks made by the plugin at startup
+//! (addresses, keys, ACL patterns, auth dictionary, scripts...), each failed check being reported as a
+//! [`Diagnostic`]. E.g. to validate the configurations of a fleet of bridges in CI:
+//! `zenoh-bridge-mqtt -c <file> --check-config`.
+
+use serde::Serialize;
+use std::fmt;
+use std::net::ToSocketAddrs;
+
+use crate::auth::Authenticator;
+use crate::bans::BanList;
+use crate::config::Config;
+use crate::put_caps::PutCaps;
+use crate::{access_windows, chunking, encryption, mqtt_helpers, pseudonyms, scripting};
+
+/// The severity of a [`Diagnostic`].
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The plugin would fail to start.
+    Error,
+    /// The plugin would start, but ignore or restrict a setting.
+    Warning,
+}
+
+/// An issue found in a configuration.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The setting concerned (empty if the configuration can't be deserialized at all).
+    pub setting: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        if self.setting.is_empty() {
+            write!(f, "{severity}: {}", self.message)
+        } else {
+            write!(f, "{severity}: '{}': {}", self.setting, self.message)
+        }
+    }
+}
+
+fn error(setting: &str, message: impl ToString) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Error,
+        setting: setting.into(),
+        message: message.to_string(),
+    }
+}
+
+fn warning(setting: &str, message: impl ToString) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Warning,
+        setting: setting.into(),
+        message: message.to_string(),
+    }
+}
+
+fn check_address(setting: &str, address: &str, diagnostics: &mut Vec<Diagnostic>) {
+    match address.to_socket_addrs() {
+        Ok(mut addrs) if addrs.next().is_some() => (),
+        Ok(_) => diagnostics.push(error(setting, format!("cannot resolve address {address}"))),
+        Err(e) => diagnostics.push(error(setting, format!("invalid address {address}: {e}"))),
+    }
+}
+
+/// Validate a MQTT plugin configuration (the JSON value of the "plugins/mqtt" section of a zenoh configuration).
+/// Returns the issues found, none meaning the plugin would start with this configuration.
+/// Note the validated keys and scripts are retained by the process: it's not meant to run the plugin afterwards.
+pub fn validate(config: &serde_json::Value) -> Vec<Diagnostic> {
+    let config: Config = match serde_json::from_value(config.clone()) {
+        Ok(config) => config,
+        Err(e) => return vec![error("", e)],
+    };
+    let mut diagnostics = Vec::new();
+    check_address("port", &config.port, &mut diagnostics);
+    if let Some(mqtt_sn) = &config.mqtt_sn {
+        check_address("mqtt_sn/port", &mqtt_sn.port, &mut diagnostics);
+        if config.auth.is_some() {
+            diagnostics.push(warning(
+                "mqtt_sn",
+                "the MQTT-SN clients are rejected when 'auth' is configured",
+            ));
+        }
+    }
+    if let Some(auth) = &config.auth {
+        if let Err(e) = Authenticator::new(auth) {
+            diagnostics.push(error("auth", e));
+        }
+    }
+    if let Err(e) = BanList::new(config.bans.as_ref()) {
+        diagnostics.push(error("bans", e));
+    }
+    if !config.max_inflight_puts.is_empty() {
+        if let Err(e) = PutCaps::new(&config.max_inflight_puts) {
+            diagnostics.push(error("max_inflight_puts", e));
+        }
+    }
+    if let Err(e) = encryption::init(&config) {
+        diagnostics.push(error("encryption", e));
+    }
+    if let Err(e) = chunking::init(&config) {
+        diagnostics.push(error("chunking", e));
+    }
+    if let Err(e) = pseudonyms::init(&config) {
+        diagnostics.push(error("pseudonyms", e));
+    }
+    if let Err(e) = mqtt_helpers::check_ke_chunks(&config) {
+        diagnostics.push(error("ke_chunks", e));
+    }
+    if let Err(e) = access_windows::init(&config) {
+        diagnostics.push(error("access_windows", e));
+    }
+    if config.scripts.is_some() && cfg!(not(feature = "rhai")) {
+        diagnostics.push(warning(
+            "scripts",
+            "the MQTT plugin was built without the 'rhai' feature - ignored",
+        ));
+    } else if let Err(e) = scripting::init(&config) {
+        diagnostics.push(error("scripts", e));
+    }
+    if config.opentelemetry.is_some() && cfg!(not(feature = "opentelemetry")) {
+        diagnostics.push(warning(
+            "opentelemetry",
+            "the MQTT plugin was built without the 'opentelemetry' feature - ignored",
+        ));
+    }
+    diagnostics
+}